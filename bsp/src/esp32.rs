@@ -0,0 +1,265 @@
+//! The Udoo Key as seen from the esp32
+
+use esp32_hal::{
+    clock::Clocks,
+    gpio::{
+        Floating, Gpio12, Gpio13, Gpio14, Gpio15, Gpio18, Gpio19, Gpio2, Gpio21, Gpio22, Gpio23,
+        Gpio26, Gpio32, Gpio33, Gpio35, Gpio4, Gpio5, Input, OpenDrain, Output, PushPull, Unknown,
+        IO,
+    },
+    peripherals::{
+        self, DPORT, I2C0, LEDC, RADIO, RNG, RTC_CNTL, SPI2, TIMG0, TIMG1, UART1, UART2,
+    },
+    prelude::*,
+    spi::{FullDuplexMode, Spi, SpiMode},
+    system::PeripheralClockControl,
+    uart::{
+        config::{Config, DataBits, Parity, StopBits},
+        TxRxPins,
+    },
+    Uart,
+};
+use fugit::HertzU32;
+
+use crate::LINK_BAUDRATE;
+
+/// Everything on the esp32 side of the board
+pub struct Board {
+    pub leds: Leds,
+    /// Serial connection to the rp2040
+    pub rp_link: RpLinkPins,
+    pub uext: Uext,
+    /// SWD port of the rp2040
+    pub swd: SwdPins,
+    /// Reset pin of the rp2040, driven high so the rp2040 runs
+    pub rp_reset: Gpio23<Output<PushPull>>,
+    pub peripherals: Peripherals,
+}
+
+/// On-board leds wired to the esp32
+pub struct Leds {
+    pub blue: Gpio32<Output<PushPull>>,
+    pub yellow: Gpio33<Output<PushPull>>,
+}
+
+/// Uart1 on Gpio19 (Tx) and Gpio22 (Rx), wired to Gpio0/Gpio1 of the rp2040
+pub struct RpLinkPins {
+    pub tx: Gpio19<Output<PushPull>>,
+    pub rx: Gpio22<Input<Floating>>,
+    pub uart: UART1,
+}
+
+impl RpLinkPins {
+    /// Configure the uart for [`LINK_BAUDRATE`] 8N1, which is what the
+    /// rp2040 expects
+    pub fn into_uart(
+        self,
+        clocks: &Clocks,
+        peripheral_clock_control: &mut PeripheralClockControl,
+    ) -> Uart<'static, UART1> {
+        Uart::new_with_config(
+            self.uart,
+            Some(uart_config(LINK_BAUDRATE)),
+            Some(TxRxPins::new_tx_rx(self.tx, self.rx)),
+            clocks,
+            peripheral_clock_control,
+        )
+    }
+}
+
+/// The UEXT connector
+pub struct Uext {
+    pub spi: UextSpi,
+    pub i2c: UextI2c,
+    pub uart: UextUart,
+}
+
+/// SPI pins of the UEXT connector, the modes are set by the SPI driver.
+/// MISO is on an input only pin.
+pub struct UextSpi {
+    pub sck: Gpio14<Unknown>,
+    pub mosi: Gpio12<Unknown>,
+    pub miso: Gpio35<Unknown>,
+    pub cs: Gpio15<Unknown>,
+    pub spi: SPI2,
+}
+
+impl UextSpi {
+    /// Configure the bus for mode 0 at `frequency`. The chip select is
+    /// handed back as a plain output, driven high, so it can be used with
+    /// `udoo_core::spi_bus` next to chip selects on other pins.
+    pub fn into_spi(
+        self,
+        frequency: HertzU32,
+        clocks: &Clocks,
+        peripheral_clock_control: &mut PeripheralClockControl,
+    ) -> (Spi<'static, SPI2, FullDuplexMode>, Gpio15<Output<PushPull>>) {
+        let spi = Spi::new_no_cs(
+            self.spi,
+            self.sck,
+            self.mosi,
+            self.miso,
+            frequency,
+            SpiMode::Mode0,
+            peripheral_clock_control,
+            clocks,
+        );
+        let mut cs = self.cs.into_push_pull_output();
+        cs.set_high().unwrap();
+        (spi, cs)
+    }
+}
+
+/// I2C pins of the UEXT connector, the modes are set by the I2C driver
+pub struct UextI2c {
+    pub sda: Gpio18<Unknown>,
+    pub scl: Gpio21<Unknown>,
+    pub i2c: I2C0,
+}
+
+impl UextI2c {
+    /// SCL and SDA as released open drain outputs with the internal pull
+    /// ups enabled, for driving the bus in software
+    pub fn into_open_drain(self) -> (Gpio21<Output<OpenDrain>>, Gpio18<Output<OpenDrain>>) {
+        let mut scl = self.scl.into_open_drain_output();
+        scl.internal_pull_up(true);
+        scl.set_high().unwrap();
+        let mut sda = self.sda.into_open_drain_output();
+        sda.internal_pull_up(true);
+        sda.set_high().unwrap();
+        (scl, sda)
+    }
+}
+
+/// Uart pins of the UEXT connector
+pub struct UextUart {
+    pub tx: Gpio13<Output<PushPull>>,
+    pub rx: Gpio26<Input<Floating>>,
+    pub uart: UART2,
+}
+
+impl UextUart {
+    /// Configure the uart for `baudrate` 8N1
+    pub fn into_uart(
+        self,
+        baudrate: u32,
+        clocks: &Clocks,
+        peripheral_clock_control: &mut PeripheralClockControl,
+    ) -> Uart<'static, UART2> {
+        Uart::new_with_config(
+            self.uart,
+            Some(uart_config(baudrate)),
+            Some(TxRxPins::new_tx_rx(self.tx, self.rx)),
+            clocks,
+            peripheral_clock_control,
+        )
+    }
+}
+
+/// SWD connection to the rp2040, shared with the external SWD header
+pub struct SwdPins {
+    /// High routes the rp2040 SWD port to the esp32, low to the header
+    pub select: Gpio5<Output<PushPull>>,
+    /// Open drain with the internal pull up enabled
+    pub swdio: Gpio2<Output<OpenDrain>>,
+    pub swclk: Gpio4<Output<PushPull>>,
+}
+
+impl SwdPins {
+    /// Take the rp2040 SWD port over from the external header and idle
+    /// both lines high
+    pub fn select_esp32(&mut self) {
+        self.select.set_high().unwrap();
+        self.swdio.set_high().unwrap();
+        self.swclk.set_high().unwrap();
+    }
+
+    /// Hand the rp2040 SWD port back to the external header
+    pub fn select_header(&mut self) {
+        self.select.set_low().unwrap();
+    }
+}
+
+/// Peripherals that are not tied to a pin of the board
+#[allow(non_snake_case)]
+pub struct Peripherals {
+    pub DPORT: DPORT,
+    pub LEDC: LEDC,
+    pub RTC_CNTL: RTC_CNTL,
+    pub TIMG0: TIMG0,
+    pub TIMG1: TIMG1,
+    pub RNG: RNG,
+    pub RADIO: RADIO,
+}
+
+impl Board {
+    /// Take the esp32 peripherals and set up the board pins
+    ///
+    /// Panics when called more than once, like `Peripherals::take`.
+    pub fn take() -> Self {
+        let peripherals = peripherals::Peripherals::take();
+        let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
+        let pins = io.pins;
+
+        let mut rp_reset = pins.gpio23.into_push_pull_output();
+        rp_reset.set_high().unwrap();
+
+        let mut swdio = pins.gpio2.into_open_drain_output();
+        swdio.internal_pull_up(true);
+
+        Self {
+            leds: Leds {
+                blue: pins.gpio32.into_push_pull_output(),
+                yellow: pins.gpio33.into_push_pull_output(),
+            },
+            rp_link: RpLinkPins {
+                tx: pins.gpio19.into_push_pull_output(),
+                rx: pins.gpio22.into_floating_input(),
+                uart: peripherals.UART1,
+            },
+            uext: Uext {
+                spi: UextSpi {
+                    sck: pins.gpio14,
+                    mosi: pins.gpio12,
+                    miso: pins.gpio35,
+                    cs: pins.gpio15,
+                    spi: peripherals.SPI2,
+                },
+                i2c: UextI2c {
+                    sda: pins.gpio18,
+                    scl: pins.gpio21,
+                    i2c: peripherals.I2C0,
+                },
+                uart: UextUart {
+                    tx: pins.gpio13.into_push_pull_output(),
+                    rx: pins.gpio26.into_floating_input(),
+                    uart: peripherals.UART2,
+                },
+            },
+            swd: SwdPins {
+                select: pins.gpio5.into_push_pull_output(),
+                swdio,
+                swclk: pins.gpio4.into_push_pull_output(),
+            },
+            rp_reset,
+            peripherals: Peripherals {
+                DPORT: peripherals.DPORT,
+                LEDC: peripherals.LEDC,
+                RTC_CNTL: peripherals.RTC_CNTL,
+                TIMG0: peripherals.TIMG0,
+                TIMG1: peripherals.TIMG1,
+                RNG: peripherals.RNG,
+                RADIO: peripherals.RADIO,
+            },
+        }
+    }
+}
+
+fn uart_config(baudrate: u32) -> Config {
+    Config {
+        baudrate,
+        data_bits: DataBits::DataBits8,
+        parity: Parity::ParityNone,
+        stop_bits: StopBits::STOP1,
+    }
+}
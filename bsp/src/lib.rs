@@ -0,0 +1,25 @@
+//! Board support for the Udoo Key
+//!
+//! The pins wired between the two chips, to the on-board leds and to the
+//! UEXT connector are described once here. Enable the `esp32` or the
+//! `rp2040` feature and call `Board::take` to get them with the types
+//! their drivers expect. See `REFERENCE.md` for the pinout.
+#![no_std]
+
+#[cfg(all(feature = "esp32", feature = "rp2040"))]
+compile_error!("the esp32 and rp2040 features can not be enabled together");
+#[cfg(not(any(feature = "esp32", feature = "rp2040")))]
+compile_error!("enable either the esp32 or the rp2040 feature");
+
+#[cfg(feature = "esp32")]
+mod esp32;
+#[cfg(feature = "esp32")]
+pub use esp32::*;
+
+#[cfg(feature = "rp2040")]
+mod rp2040;
+#[cfg(feature = "rp2040")]
+pub use rp2040::*;
+
+/// Baudrate of the serial connection between the esp32 and the rp2040
+pub const LINK_BAUDRATE: u32 = 9600;
@@ -0,0 +1,195 @@
+//! The Udoo Key as seen from the rp2040
+
+use fugit::{HertzU32, RateExtU32};
+use rp2040_hal::{
+    gpio::{
+        bank0::{
+            Gpio0, Gpio1, Gpio10, Gpio11, Gpio12, Gpio13, Gpio14, Gpio15, Gpio16, Gpio17, Gpio18,
+            Gpio19, Gpio2, Gpio20, Gpio21, Gpio22, Gpio23, Gpio24, Gpio25, Gpio26, Gpio27, Gpio28,
+            Gpio29, Gpio3, Gpio4, Gpio5, Gpio6, Gpio7, Gpio8, Gpio9,
+        },
+        FunctionUart, Pin, PinId, Pins, PushPullOutput,
+    },
+    pac::{self, CLOCKS, PLL_SYS, PLL_USB, PWM, RESETS, ROSC, TIMER, UART0, WATCHDOG, XOSC},
+    pwm::{Channel, FreeRunning, Pwm4, Slice, B},
+    uart::{self, DataBits, Enabled, StopBits, UartConfig, UartPeripheral},
+    Sio,
+};
+
+use crate::LINK_BAUDRATE;
+
+/// Frequency of the crystal next to the rp2040
+pub const XOSC_CRYSTAL_FREQ: u32 = 12_000_000;
+
+/// A pin in the mode it has after reset
+pub type ResetPin<I> = Pin<I, <I as PinId>::Reset>;
+
+/// Uart0 of the rp2040 once [`EspLinkPins::into_uart`] enabled it
+pub type LinkUart =
+    UartPeripheral<Enabled, UART0, (Pin<Gpio0, FunctionUart>, Pin<Gpio1, FunctionUart>)>;
+
+/// Channel B of PWM slice 4 once [`Leds::into_pwm`] routed it to Gpio25
+pub type GreenPwm = Channel<Pwm4, FreeRunning, B>;
+
+/// Everything on the rp2040 side of the board
+pub struct Board {
+    pub leds: Leds,
+    /// Serial connection to the esp32
+    pub esp_link: EspLinkPins,
+    /// Pins not used by the board itself
+    pub pins: FreePins,
+    pub peripherals: Peripherals,
+    pub core: pac::CorePeripherals,
+}
+
+/// On-board leds wired to the rp2040
+pub struct Leds {
+    pub green: Pin<Gpio25, PushPullOutput>,
+}
+
+impl Leds {
+    /// Drive the green led from PWM slice 4 so it can be dimmed, `slice`
+    /// comes from `pwm::Slices::new` on [`Peripherals::PWM`]
+    pub fn into_pwm(self, mut slice: Slice<Pwm4, FreeRunning>) -> GreenPwm {
+        slice.enable();
+        slice.channel_b.output_to(self.green);
+        slice.channel_b
+    }
+}
+
+/// Uart0 on Gpio0 (Tx) and Gpio1 (Rx), wired to Gpio22/Gpio19 of the esp32
+pub struct EspLinkPins {
+    pub tx: Pin<Gpio0, FunctionUart>,
+    pub rx: Pin<Gpio1, FunctionUart>,
+    pub uart: UART0,
+}
+
+impl EspLinkPins {
+    /// Enable the uart with [`LINK_BAUDRATE`] 8N1, which is what the
+    /// esp32 expects
+    pub fn into_uart(
+        self,
+        resets: &mut RESETS,
+        peripheral_clock: HertzU32,
+    ) -> Result<LinkUart, uart::Error> {
+        UartPeripheral::new(self.uart, (self.tx, self.rx), resets).enable(
+            UartConfig::new(LINK_BAUDRATE.Hz(), DataBits::Eight, None, StopBits::One),
+            peripheral_clock,
+        )
+    }
+}
+
+/// Bank 0 pins that are free for the programs to use
+pub struct FreePins {
+    pub gpio2: ResetPin<Gpio2>,
+    pub gpio3: ResetPin<Gpio3>,
+    pub gpio4: ResetPin<Gpio4>,
+    pub gpio5: ResetPin<Gpio5>,
+    pub gpio6: ResetPin<Gpio6>,
+    pub gpio7: ResetPin<Gpio7>,
+    pub gpio8: ResetPin<Gpio8>,
+    pub gpio9: ResetPin<Gpio9>,
+    pub gpio10: ResetPin<Gpio10>,
+    pub gpio11: ResetPin<Gpio11>,
+    pub gpio12: ResetPin<Gpio12>,
+    pub gpio13: ResetPin<Gpio13>,
+    pub gpio14: ResetPin<Gpio14>,
+    pub gpio15: ResetPin<Gpio15>,
+    pub gpio16: ResetPin<Gpio16>,
+    pub gpio17: ResetPin<Gpio17>,
+    pub gpio18: ResetPin<Gpio18>,
+    pub gpio19: ResetPin<Gpio19>,
+    pub gpio20: ResetPin<Gpio20>,
+    pub gpio21: ResetPin<Gpio21>,
+    pub gpio22: ResetPin<Gpio22>,
+    pub gpio23: ResetPin<Gpio23>,
+    pub gpio24: ResetPin<Gpio24>,
+    pub gpio26: ResetPin<Gpio26>,
+    pub gpio27: ResetPin<Gpio27>,
+    pub gpio28: ResetPin<Gpio28>,
+    pub gpio29: ResetPin<Gpio29>,
+}
+
+/// Peripherals that are not tied to a pin of the board
+#[allow(non_snake_case)]
+pub struct Peripherals {
+    pub CLOCKS: CLOCKS,
+    pub PLL_SYS: PLL_SYS,
+    pub PLL_USB: PLL_USB,
+    pub PWM: PWM,
+    pub RESETS: RESETS,
+    pub ROSC: ROSC,
+    pub TIMER: TIMER,
+    pub WATCHDOG: WATCHDOG,
+    pub XOSC: XOSC,
+}
+
+impl Board {
+    /// Take the rp2040 peripherals and set up the board pins
+    ///
+    /// Returns `None` when called more than once, like
+    /// `pac::Peripherals::take`.
+    pub fn take() -> Option<Self> {
+        let mut pac = pac::Peripherals::take()?;
+        let core = pac::CorePeripherals::take()?;
+        let sio = Sio::new(pac.SIO);
+        let pins = Pins::new(
+            pac.IO_BANK0,
+            pac.PADS_BANK0,
+            sio.gpio_bank0,
+            &mut pac.RESETS,
+        );
+
+        Some(Self {
+            leds: Leds {
+                green: pins.gpio25.into_push_pull_output(),
+            },
+            esp_link: EspLinkPins {
+                tx: pins.gpio0.into_mode(),
+                rx: pins.gpio1.into_mode(),
+                uart: pac.UART0,
+            },
+            pins: FreePins {
+                gpio2: pins.gpio2,
+                gpio3: pins.gpio3,
+                gpio4: pins.gpio4,
+                gpio5: pins.gpio5,
+                gpio6: pins.gpio6,
+                gpio7: pins.gpio7,
+                gpio8: pins.gpio8,
+                gpio9: pins.gpio9,
+                gpio10: pins.gpio10,
+                gpio11: pins.gpio11,
+                gpio12: pins.gpio12,
+                gpio13: pins.gpio13,
+                gpio14: pins.gpio14,
+                gpio15: pins.gpio15,
+                gpio16: pins.gpio16,
+                gpio17: pins.gpio17,
+                gpio18: pins.gpio18,
+                gpio19: pins.gpio19,
+                gpio20: pins.gpio20,
+                gpio21: pins.gpio21,
+                gpio22: pins.gpio22,
+                gpio23: pins.gpio23,
+                gpio24: pins.gpio24,
+                gpio26: pins.gpio26,
+                gpio27: pins.gpio27,
+                gpio28: pins.gpio28,
+                gpio29: pins.gpio29,
+            },
+            peripherals: Peripherals {
+                CLOCKS: pac.CLOCKS,
+                PLL_SYS: pac.PLL_SYS,
+                PLL_USB: pac.PLL_USB,
+                PWM: pac.PWM,
+                RESETS: pac.RESETS,
+                ROSC: pac.ROSC,
+                TIMER: pac.TIMER,
+                WATCHDOG: pac.WATCHDOG,
+                XOSC: pac.XOSC,
+            },
+            core,
+        })
+    }
+}
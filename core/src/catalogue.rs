@@ -0,0 +1,348 @@
+//! The rom catalogue of a web server
+//!
+//! Instead of `rom_server.py` the esp32 can get its roms from any web
+//! server over HTTP. `GET /roms` answers with the list of roms as JSON:
+//!
+//! ```json
+//! [{"id": 0, "name": "PONG"}, {"id": 1, "name": "TETRIS"}]
+//! ```
+//!
+//! and `GET /roms/<id>` with the rom itself. Other keys of the entries
+//! are ignored, so the list can carry more for other clients. The web
+//! server of the esp32 lists its roms the same way with [`write`].
+
+use core::fmt::{self, Write};
+
+/// Path of the rom list
+pub const LIST_PATH: &str = "/roms";
+/// Bytes of a rom name that are kept, longer names are cut off
+pub const NAME_SIZE: usize = 64;
+
+/// Nesting of values inside an entry that is skipped
+const MAX_DEPTH: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The list is not valid JSON or not a list of objects
+    Json,
+    /// An entry has no `id`, or it is not a `u16`
+    MissingId,
+}
+
+/// Parse the rom list, calls `entry` with the id and name of each rom
+/// and returns the number of roms
+///
+/// A rom without a name gets an empty one.
+pub fn parse(json: &[u8], mut entry: impl FnMut(u16, &str)) -> Result<usize, Error> {
+    let mut parser = Parser { json, at: 0 };
+    let mut count = 0;
+    parser.expect(b'[')?;
+    if parser.peek() == Some(b']') {
+        parser.at += 1;
+    } else {
+        loop {
+            parser.entry(&mut entry)?;
+            count += 1;
+            match parser.next() {
+                Some(b',') => {}
+                Some(b']') => break,
+                _ => return Err(Error::Json),
+            }
+        }
+    }
+    match parser.peek() {
+        None => Ok(count),
+        Some(_) => Err(Error::Json),
+    }
+}
+
+/// Write a rom list with the id and name of each rom
+pub fn write<'r>(out: &mut dyn Write, roms: impl Iterator<Item = (u16, &'r str)>) -> fmt::Result {
+    out.write_char('[')?;
+    for (index, (id, name)) in roms.enumerate() {
+        if index > 0 {
+            out.write_str(", ")?;
+        }
+        write!(out, "{{\"id\": {id}, \"name\": ")?;
+        write_string(out, name)?;
+        out.write_char('}')?;
+    }
+    out.write_char(']')
+}
+
+/// Write `s` as a JSON string, in quotes and escaped
+pub fn write_string(out: &mut dyn Write, s: &str) -> fmt::Result {
+    out.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => out.write_str("\\\"")?,
+            '\\' => out.write_str("\\\\")?,
+            '\n' => out.write_str("\\n")?,
+            '\r' => out.write_str("\\r")?,
+            '\t' => out.write_str("\\t")?,
+            '\0'..='\u{1f}' => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.write_char(c)?,
+        }
+    }
+    out.write_char('"')
+}
+
+struct Parser<'j> {
+    json: &'j [u8],
+    at: usize,
+}
+
+impl<'j> Parser<'j> {
+    /// The next byte that is not white space, left in place
+    fn peek(&mut self) -> Option<u8> {
+        while let Some(b' ' | b'\t' | b'\r' | b'\n') = self.json.get(self.at) {
+            self.at += 1;
+        }
+        self.json.get(self.at).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.at += 1;
+        Some(byte)
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), Error> {
+        match self.next() {
+            Some(found) if found == byte => Ok(()),
+            _ => Err(Error::Json),
+        }
+    }
+
+    /// One `{"id": .., "name": ..}` object
+    fn entry(&mut self, entry: &mut impl FnMut(u16, &str)) -> Result<(), Error> {
+        let mut id = None;
+        let mut name = [0_u8; NAME_SIZE];
+        let mut name_len = 0;
+        let mut key = [0_u8; 8];
+        self.expect(b'{')?;
+        if self.peek() == Some(b'}') {
+            return Err(Error::MissingId);
+        }
+        loop {
+            let key_len = self.string(&mut key)?;
+            self.expect(b':')?;
+            match &key[..key_len] {
+                b"id" => id = Some(self.number()?),
+                b"name" if self.peek() == Some(b'"') => name_len = self.string(&mut name)?,
+                _ => self.skip_value(0)?,
+            }
+            match self.next() {
+                Some(b',') => {}
+                Some(b'}') => break,
+                _ => return Err(Error::Json),
+            }
+        }
+        let id = id
+            .and_then(|id| u16::try_from(id).ok())
+            .ok_or(Error::MissingId)?;
+        // `string` only cuts off between characters
+        entry(
+            id,
+            core::str::from_utf8(&name[..name_len]).unwrap_or_default(),
+        );
+        Ok(())
+    }
+
+    /// A string decoded into `out`, returns its length. What does not
+    /// fit is dropped.
+    fn string(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        self.expect(b'"')?;
+        let mut len = 0;
+        let mut full = false;
+        loop {
+            let byte = *self.json.get(self.at).ok_or(Error::Json)?;
+            self.at += 1;
+            let c = match byte {
+                b'"' => return Ok(len),
+                b'\\' => self.escape()?,
+                0x00..=0x1f => return Err(Error::Json),
+                0x20..=0x7f => byte as char,
+                _ => {
+                    // Copy a multi-byte character whole
+                    let rest = &self.json[self.at - 1..];
+                    let width = match byte {
+                        0xc0..=0xdf => 2,
+                        0xe0..=0xef => 3,
+                        _ => 4,
+                    };
+                    let c = rest
+                        .get(..width)
+                        .and_then(|bytes| core::str::from_utf8(bytes).ok())
+                        .and_then(|s| s.chars().next())
+                        .ok_or(Error::Json)?;
+                    self.at += width - 1;
+                    c
+                }
+            };
+            let mut encoded = [0; 4];
+            let encoded = c.encode_utf8(&mut encoded).as_bytes();
+            // Once a character was dropped the rest is too
+            full = full || len + encoded.len() > out.len();
+            if !full {
+                out[len..len + encoded.len()].copy_from_slice(encoded);
+                len += encoded.len();
+            }
+        }
+    }
+
+    /// The character after a `\`
+    fn escape(&mut self) -> Result<char, Error> {
+        let byte = *self.json.get(self.at).ok_or(Error::Json)?;
+        self.at += 1;
+        Ok(match byte {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let hex = self.json.get(self.at..self.at + 4).ok_or(Error::Json)?;
+                let hex = core::str::from_utf8(hex).map_err(|_| Error::Json)?;
+                let code = u32::from_str_radix(hex, 16).map_err(|_| Error::Json)?;
+                self.at += 4;
+                // Halves of surrogate pairs are not put back together
+                char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+            }
+            _ => return Err(Error::Json),
+        })
+    }
+
+    /// A non-negative integer
+    fn number(&mut self) -> Result<u32, Error> {
+        self.peek();
+        let start = self.at;
+        while let Some(b'0'..=b'9') = self.json.get(self.at) {
+            self.at += 1;
+        }
+        core::str::from_utf8(&self.json[start..self.at])
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or(Error::MissingId)
+    }
+
+    /// Any value, checked no further than needed to find its end
+    fn skip_value(&mut self, depth: usize) -> Result<(), Error> {
+        if depth == MAX_DEPTH {
+            return Err(Error::Json);
+        }
+        match self.peek().ok_or(Error::Json)? {
+            b'"' => {
+                self.string(&mut [])?;
+            }
+            open @ (b'[' | b'{') => {
+                self.at += 1;
+                let close = if open == b'[' { b']' } else { b'}' };
+                if self.peek() == Some(close) {
+                    self.at += 1;
+                    return Ok(());
+                }
+                loop {
+                    if open == b'{' {
+                        self.string(&mut [])?;
+                        self.expect(b':')?;
+                    }
+                    self.skip_value(depth + 1)?;
+                    match self.next() {
+                        Some(b',') => {}
+                        Some(byte) if byte == close => break,
+                        _ => return Err(Error::Json),
+                    }
+                }
+            }
+            // Numbers, true, false and null
+            _ => {
+                let start = self.at;
+                while let Some(b'-' | b'+' | b'.' | b'0'..=b'9' | b'a'..=b'z' | b'E') =
+                    self.json.get(self.at)
+                {
+                    self.at += 1;
+                }
+                if self.at == start {
+                    return Err(Error::Json);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn roms(json: &str) -> Result<Vec<(u16, String)>, Error> {
+        let mut roms = Vec::new();
+        let count = parse(json.as_bytes(), |id, name| {
+            roms.push((id, name.to_string()))
+        })?;
+        assert_eq!(count, roms.len());
+        Ok(roms)
+    }
+
+    #[test]
+    fn parses_the_list() {
+        assert_eq!(roms(" [ ] "), Ok(vec![]));
+        assert_eq!(
+            roms(
+                r#"[
+                    {"id": 0, "name": "PONG"},
+                    {"size": 246, "tags": ["2 players", {"year": 1990}], "id": 7,
+                     "name": "Space \"Invaders\" é\/", "new": true, "note": null}
+                ]"#
+            ),
+            Ok(vec![
+                (0, "PONG".to_string()),
+                (7, "Space \"Invaders\" é/".to_string())
+            ])
+        );
+        assert_eq!(roms(r#"[{"id": 3}]"#), Ok(vec![(3, String::new())]));
+    }
+
+    #[test]
+    fn cuts_off_long_names() {
+        let long = "ü".repeat(NAME_SIZE);
+        let roms = roms(&format!(r#"[{{"name": "{long}", "id": 1}}]"#)).unwrap();
+        assert_eq!(roms[0].1, "ü".repeat(NAME_SIZE / 2));
+    }
+
+    #[test]
+    fn writes_the_list() {
+        let mut json = String::new();
+        let list = [(2, "PONG"), (5, "say \"hi\"\\\n\u{1}")];
+        write(&mut json, list.into_iter()).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"id": 2, "name": "PONG"}, {"id": 5, "name": "say \"hi\"\\\n\u0001"}]"#
+        );
+        assert_eq!(
+            roms(&json),
+            Ok(vec![
+                (2, "PONG".to_string()),
+                (5, "say \"hi\"\\\n\u{1}".to_string())
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_bad_lists() {
+        assert_eq!(roms(r#"{"id": 1}"#), Err(Error::Json));
+        assert_eq!(roms(r#"[{"id": 1},]"#), Err(Error::Json));
+        assert_eq!(roms(r#"[{"id": 1}] x"#), Err(Error::Json));
+        assert_eq!(roms(r#"[{"id": 1, "name": "PONG"#), Err(Error::Json));
+        assert_eq!(roms(r#"[{"name": "PONG"}]"#), Err(Error::MissingId));
+        assert_eq!(roms(r#"[{"id": 70000}]"#), Err(Error::MissingId));
+        assert_eq!(roms(r#"[{"id": "1"}]"#), Err(Error::MissingId));
+    }
+}
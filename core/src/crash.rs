@@ -0,0 +1,260 @@
+//! Crash reports that survive a reset
+//!
+//! The panic handlers of both chips write a [`CrashRecord`] into a
+//! [`CrashArea`] in RAM that is not initialised at start up, then reset
+//! the chip. On the next boot the program takes the record with
+//! [`CrashArea::boot`] and reports it. The area counts the crashes since
+//! the last boot that got far enough to call [`CrashArea::healthy`].
+//!
+//! RAM that was not written since power up holds random bytes, a magic
+//! number and a CRC tell a crash apart from them.
+
+use core::fmt::{self, Write};
+
+use udoo_link::crc16;
+
+/// Bytes of the panic message that are kept
+pub const MESSAGE_SIZE: usize = 120;
+/// Return addresses that are kept
+pub const BACKTRACE_SIZE: usize = 8;
+
+const MAGIC: u32 = 0x4352_5348;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct CrashRecord {
+    pub uptime_ms: u64,
+    /// Where the panic happened, 0 if unknown
+    pub pc: u32,
+    /// Return addresses, the unused ones are 0
+    pub backtrace: [u32; BACKTRACE_SIZE],
+    message: [u8; MESSAGE_SIZE],
+    message_len: u32,
+}
+
+impl CrashRecord {
+    /// `message` is cut off at [`MESSAGE_SIZE`] bytes
+    pub fn new(uptime_ms: u64, pc: u32, backtrace: &[u32], message: fmt::Arguments<'_>) -> Self {
+        let mut record = Self {
+            uptime_ms,
+            pc,
+            backtrace: [0; BACKTRACE_SIZE],
+            message: [0; MESSAGE_SIZE],
+            message_len: 0,
+        };
+        let depth = backtrace.len().min(BACKTRACE_SIZE);
+        record.backtrace[..depth].copy_from_slice(&backtrace[..depth]);
+        _ = record.write_fmt(message);
+        record
+    }
+
+    pub fn message(&self) -> &str {
+        let len = (self.message_len as usize).min(MESSAGE_SIZE);
+        match core::str::from_utf8(&self.message[..len]) {
+            Ok(message) => message,
+            // Cut off in the middle of a character
+            Err(e) => core::str::from_utf8(&self.message[..e.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+
+    /// Everything but the CRC, in a fixed layout
+    fn bytes(&self) -> [u8; RECORD_BYTES] {
+        let mut bytes = [0; RECORD_BYTES];
+        bytes[..8].copy_from_slice(&self.uptime_ms.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.pc.to_le_bytes());
+        for (index, address) in self.backtrace.iter().enumerate() {
+            let at = 12 + index * 4;
+            bytes[at..at + 4].copy_from_slice(&address.to_le_bytes());
+        }
+        let at = 12 + BACKTRACE_SIZE * 4;
+        bytes[at..at + MESSAGE_SIZE].copy_from_slice(&self.message);
+        bytes[at + MESSAGE_SIZE..].copy_from_slice(&self.message_len.to_le_bytes());
+        bytes
+    }
+}
+
+const RECORD_BYTES: usize = 12 + BACKTRACE_SIZE * 4 + MESSAGE_SIZE + 4;
+
+impl Write for CrashRecord {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.message_len as usize;
+        let count = s.len().min(MESSAGE_SIZE.saturating_sub(len));
+        self.message[len..len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.message_len += count as u32;
+        Ok(())
+    }
+}
+
+impl fmt::Display for CrashRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} after {}.{:03} s",
+            self.message(),
+            self.uptime_ms / 1000,
+            self.uptime_ms % 1000
+        )?;
+        if self.pc != 0 {
+            write!(f, ", pc {:#010x}", self.pc)?;
+        }
+        let mut backtrace = self.backtrace.iter().filter(|&&address| address != 0);
+        if let Some(address) = backtrace.next() {
+            write!(f, ", backtrace {address:#010x}")?;
+            for address in backtrace {
+                write!(f, " {address:#010x}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What [`CrashArea::boot`] found
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Boot {
+    /// The crash that ended the last run
+    pub crash: Option<CrashRecord>,
+    /// Crashes since the last healthy boot
+    pub failures: u32,
+}
+
+/// Memory that keeps its contents over a reset
+///
+/// Any bit pattern is a valid value, so it can live in RAM that is not
+/// initialised.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct CrashArea {
+    magic: u32,
+    failures: u32,
+    /// 1 while the record has not been taken
+    pending: u32,
+    crc: u32,
+    record: CrashRecord,
+}
+
+impl Default for CrashArea {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CrashArea {
+    /// An empty area, the startup code does not run this for RAM that is
+    /// not initialised
+    pub const fn new() -> Self {
+        Self {
+            magic: 0,
+            failures: 0,
+            pending: 0,
+            crc: 0,
+            record: CrashRecord {
+                uptime_ms: 0,
+                pc: 0,
+                backtrace: [0; BACKTRACE_SIZE],
+                message: [0; MESSAGE_SIZE],
+                message_len: 0,
+            },
+        }
+    }
+
+    /// Keep `record` for the next boot, called by the panic handler
+    pub fn record(&mut self, record: CrashRecord) {
+        let failures = if self.is_valid() { self.failures } else { 0 };
+        self.record = record;
+        self.failures = failures.saturating_add(1);
+        self.pending = 1;
+        self.seal();
+    }
+
+    /// Take the crash of the last run, called once at start up. After a
+    /// power cycle the area is cleared.
+    pub fn boot(&mut self) -> Boot {
+        if !self.is_valid() {
+            *self = Self::new();
+            self.seal();
+        }
+        let crash = (self.pending == 1).then_some(self.record);
+        self.pending = 0;
+        self.seal();
+        Boot {
+            crash,
+            failures: self.failures,
+        }
+    }
+
+    /// The program started fine, the crash counter starts over
+    pub fn healthy(&mut self) {
+        self.failures = 0;
+        self.seal();
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut header = [0; 8];
+        header[..4].copy_from_slice(&self.failures.to_le_bytes());
+        header[4..].copy_from_slice(&self.pending.to_le_bytes());
+        (crc16(&header) as u32) << 16 | crc16(&self.record.bytes()) as u32
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC && self.crc == self.checksum()
+    }
+
+    fn seal(&mut self) {
+        self.magic = MAGIC;
+        self.crc = self.checksum();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn reports_crashes_once() {
+        // Whatever was in RAM at power up
+        let mut area = CrashArea::new();
+        area.magic = MAGIC;
+        area.failures = 7;
+        assert_eq!(
+            area.boot(),
+            Boot {
+                crash: None,
+                failures: 0
+            }
+        );
+
+        let record = CrashRecord::new(
+            12_345,
+            0x400d_1234,
+            &[0x400d_0010, 0x400d_0020],
+            format_args!("panicked at src/main.rs:3:5: {}", "boom"),
+        );
+        area.record(record);
+        area.record(record);
+        let boot = area.boot();
+        assert_eq!(boot.crash, Some(record));
+        assert_eq!(boot.failures, 2);
+        assert_eq!(area.boot().crash, None);
+
+        area.healthy();
+        assert_eq!(area.boot().failures, 0);
+    }
+
+    #[test]
+    fn formats_records() {
+        let record = CrashRecord::new(
+            2_005,
+            0x1000_0100,
+            &[0x1000_0200],
+            format_args!("{}", "x".repeat(200)),
+        );
+        assert_eq!(record.message().len(), MESSAGE_SIZE);
+        let record = CrashRecord::new(2_005, 0x1000_0100, &[0x1000_0200], format_args!("oops"));
+        assert_eq!(
+            record.to_string(),
+            "oops after 2.005 s, pc 0x10000100, backtrace 0x10000200"
+        );
+    }
+}
@@ -0,0 +1,536 @@
+//! CMSIS-DAP debug probe on top of the bit-banged SWD host
+//!
+//! [`Dap`] processes CMSIS-DAP v2 command packets, so debuggers on a
+//! laptop can drive the rp2040 SWD port through the esp32. The packets
+//! are carried over TCP with the 8 byte framing of OpenOCD's `tcp`
+//! CMSIS-DAP backend:
+//!
+//! |Offset|Size|Field|
+//! |---|---|---|
+//! |0|4|Signature `DAP\0` as a little endian u32|
+//! |4|2|Packet length, little endian|
+//! |6|1|Packet type, 1 for requests and 2 for responses|
+//! |7|1|Reserved|
+//!
+//! Only SWD is supported. AP reads are not pipelined, every one of them
+//! is followed by a read of `RDBUFF`.
+
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::rp_control::RpControl;
+use crate::swd::{self, dp, Swd};
+
+/// Largest command or response packet
+pub const PACKET_SIZE: usize = 1024;
+/// Default port of OpenOCD's CMSIS-DAP TCP backend
+pub const TCP_PORT: u16 = 4441;
+pub const TCP_HEADER_SIZE: usize = 8;
+
+const TCP_SIGNATURE: u32 = 0x0050_4144;
+const TCP_REQUEST: u8 = 0x01;
+const TCP_RESPONSE: u8 = 0x02;
+
+/// Strings reported by `DAP_Info` include their terminating zero
+const VENDOR: &[u8] = b"Udoo Key\0";
+const PRODUCT: &[u8] = b"Udoo Key ESP32 CMSIS-DAP\0";
+const PROTOCOL_VERSION: &[u8] = b"2.1.0\0";
+
+/// Command ids
+mod command {
+    pub const INFO: u8 = 0x00;
+    pub const HOST_STATUS: u8 = 0x01;
+    pub const CONNECT: u8 = 0x02;
+    pub const DISCONNECT: u8 = 0x03;
+    pub const TRANSFER_CONFIGURE: u8 = 0x04;
+    pub const TRANSFER: u8 = 0x05;
+    pub const TRANSFER_BLOCK: u8 = 0x06;
+    pub const TRANSFER_ABORT: u8 = 0x07;
+    pub const WRITE_ABORT: u8 = 0x08;
+    pub const DELAY: u8 = 0x09;
+    pub const RESET_TARGET: u8 = 0x0a;
+    pub const SWJ_PINS: u8 = 0x10;
+    pub const SWJ_CLOCK: u8 = 0x11;
+    pub const SWJ_SEQUENCE: u8 = 0x12;
+    pub const SWD_CONFIGURE: u8 = 0x13;
+    pub const SWD_SEQUENCE: u8 = 0x1d;
+    pub const INVALID: u8 = 0xff;
+}
+
+const DAP_OK: u8 = 0x00;
+const DAP_ERROR: u8 = 0xff;
+
+/// Transfer acknowledge values reported back to the host
+const ACK_OK: u8 = 0b001;
+const ACK_WAIT: u8 = 0b010;
+const ACK_FAULT: u8 = 0b100;
+const ACK_PROTOCOL_ERROR: u8 = 0x08;
+const ACK_VALUE_MISMATCH: u8 = 0x10;
+
+/// Transfer request bits
+const REQUEST_AP: u8 = 1 << 0;
+const REQUEST_READ: u8 = 1 << 1;
+const REQUEST_VALUE_MATCH: u8 = 1 << 4;
+const REQUEST_MATCH_MASK: u8 = 1 << 5;
+
+/// `DAP_SWJ_Pins` bit of the reset line
+const PIN_NRESET: u8 = 1 << 7;
+
+/// Parse a TCP frame header, returns the length of the request
+/// that follows
+pub fn parse_tcp_header(header: &[u8; TCP_HEADER_SIZE]) -> Option<usize> {
+    let signature = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let len = u16::from_le_bytes([header[4], header[5]]) as usize;
+    if signature != TCP_SIGNATURE || header[6] != TCP_REQUEST || len > PACKET_SIZE {
+        return None;
+    }
+    Some(len)
+}
+
+/// Header for a response of `len` bytes
+pub fn tcp_header(len: usize) -> [u8; TCP_HEADER_SIZE] {
+    let mut header = [0; TCP_HEADER_SIZE];
+    header[0..4].copy_from_slice(&TCP_SIGNATURE.to_le_bytes());
+    header[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+    header[6] = TCP_RESPONSE;
+    header
+}
+
+fn ack(result: &Result<u32, swd::Error>) -> u8 {
+    match result {
+        Ok(_) => ACK_OK,
+        Err(swd::Error::Wait) => ACK_WAIT,
+        Err(swd::Error::Fault) => ACK_FAULT,
+        Err(swd::Error::Protocol(ack)) => *ack,
+        Err(swd::Error::Parity | swd::Error::Timeout) => ACK_PROTOCOL_ERROR,
+    }
+}
+
+/// Reads little endian fields from a request
+struct Request<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let (&first, rest) = self.data.split_first()?;
+        self.data = rest;
+        Some(first)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+pub struct Dap<DIO, CLK, D, P, RD>
+where
+    DIO: InputPin + OutputPin,
+    CLK: OutputPin,
+    D: DelayUs<u32>,
+    P: OutputPin,
+    RD: DelayMs<u32>,
+{
+    swd: Swd<DIO, CLK, D>,
+    control: RpControl<P, RD>,
+    match_retry: u16,
+    match_mask: u32,
+}
+
+impl<DIO, CLK, D, P, RD> Dap<DIO, CLK, D, P, RD>
+where
+    DIO: InputPin + OutputPin,
+    CLK: OutputPin,
+    D: DelayUs<u32>,
+    P: OutputPin,
+    RD: DelayMs<u32>,
+{
+    /// The reset line in `control` is exposed as the probe's nRESET pin
+    pub fn new(swd: Swd<DIO, CLK, D>, control: RpControl<P, RD>) -> Self {
+        Self {
+            swd,
+            control,
+            match_retry: 0,
+            match_mask: 0xffff_ffff,
+        }
+    }
+
+    pub fn free(self) -> (Swd<DIO, CLK, D>, RpControl<P, RD>) {
+        (self.swd, self.control)
+    }
+
+    /// Process a command packet and write the answer into `response`,
+    /// returns the length of the answer
+    pub fn process(&mut self, request: &[u8], response: &mut [u8; PACKET_SIZE]) -> usize {
+        let mut request = Request { data: request };
+        let Some(id) = request.u8() else {
+            return 0;
+        };
+        response[0] = id;
+        let len = match id {
+            command::INFO => request.u8().map(|info| self.info(info, response)),
+            command::HOST_STATUS => Some(status(response, DAP_OK)),
+            command::CONNECT => request.u8().map(|port| {
+                // Port 0 picks the default, which is SWD
+                response[1] = if port <= 1 { 1 } else { 0 };
+                2
+            }),
+            command::DISCONNECT => Some(status(response, DAP_OK)),
+            command::TRANSFER_CONFIGURE => self.transfer_configure(&mut request, response),
+            command::TRANSFER => self.transfer(&mut request, response),
+            command::TRANSFER_BLOCK => self.transfer_block(&mut request, response),
+            // An abort has no answer
+            command::TRANSFER_ABORT => Some(0),
+            command::WRITE_ABORT => request.u8().and(request.u32()).map(|value| {
+                let result = self.swd.write_dp(dp::ABORT, value);
+                status(response, if result.is_ok() { DAP_OK } else { DAP_ERROR })
+            }),
+            command::DELAY => request.u16().map(|us| {
+                self.swd.delay_us(us as u32);
+                status(response, DAP_OK)
+            }),
+            command::RESET_TARGET => {
+                // No device specific reset sequence, hosts reset through AIRCR
+                response[1] = DAP_OK;
+                response[2] = 0;
+                Some(3)
+            }
+            command::SWJ_PINS => self.swj_pins(&mut request, response),
+            command::SWJ_CLOCK => request.u32().map(|hz| {
+                self.swd.set_half_period_us(500_000 / hz.max(1));
+                status(response, DAP_OK)
+            }),
+            command::SWJ_SEQUENCE => self.swj_sequence(&mut request, response),
+            command::SWD_CONFIGURE => request.u8().map(|config| {
+                // Only a single turnaround cycle and no data phase on WAIT/FAULT
+                status(response, if config == 0 { DAP_OK } else { DAP_ERROR })
+            }),
+            command::SWD_SEQUENCE => self.swd_sequence(&mut request, response),
+            _ => None,
+        };
+        len.unwrap_or_else(|| {
+            response[0] = command::INVALID;
+            1
+        })
+    }
+
+    fn info(&mut self, info: u8, response: &mut [u8; PACKET_SIZE]) -> usize {
+        let mut put = |bytes: &[u8]| {
+            response[1] = bytes.len() as u8;
+            response[2..2 + bytes.len()].copy_from_slice(bytes);
+            2 + bytes.len()
+        };
+        match info {
+            // Vendor name
+            0x01 => put(VENDOR),
+            // Product name
+            0x02 => put(PRODUCT),
+            // Protocol version
+            0x04 => put(PROTOCOL_VERSION),
+            // Capabilities, SWD only
+            0xf0 => put(&[0x01]),
+            // Packet count
+            0xfe => put(&[1]),
+            // Packet size
+            0xff => put(&(PACKET_SIZE as u16).to_le_bytes()),
+            _ => put(&[]),
+        }
+    }
+
+    fn transfer_configure(
+        &mut self,
+        request: &mut Request,
+        response: &mut [u8; PACKET_SIZE],
+    ) -> Option<usize> {
+        // Idle cycles and WAIT retries are fixed by the SWD host
+        let _idle_cycles = request.u8()?;
+        let _wait_retry = request.u16()?;
+        self.match_retry = request.u16()?;
+        Some(status(response, DAP_OK))
+    }
+
+    /// Read a register, AP reads are collected from `RDBUFF` right away
+    fn read(&mut self, request: u8) -> Result<u32, swd::Error> {
+        let ap = request & REQUEST_AP != 0;
+        let addr = request & 0x0c;
+        let value = self.swd.transfer(ap, true, addr, 0)?;
+        if ap {
+            self.swd.read_dp(dp::RDBUFF)
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// AP writes are posted too, their outcome shows up on the next
+    /// transfer
+    fn complete_writes(&mut self) -> u8 {
+        ack(&self.swd.read_dp(dp::RDBUFF))
+    }
+
+    fn transfer(
+        &mut self,
+        request: &mut Request,
+        response: &mut [u8; PACKET_SIZE],
+    ) -> Option<usize> {
+        let _dap_index = request.u8()?;
+        let count = request.u8()?;
+        let mut len = 3;
+        let mut done = 0;
+        let mut last_ack = ACK_OK;
+        let mut posted = false;
+        for _ in 0..count {
+            let transfer = request.u8()?;
+            posted = false;
+            let ap = transfer & REQUEST_AP != 0;
+            let addr = transfer & 0x0c;
+            if transfer & REQUEST_READ != 0 {
+                if transfer & REQUEST_VALUE_MATCH != 0 {
+                    let expected = request.u32()?;
+                    let mut result = self.read(transfer);
+                    let mut retries = self.match_retry;
+                    while matches!(result, Ok(value) if value & self.match_mask != expected)
+                        && retries > 0
+                    {
+                        retries -= 1;
+                        result = self.read(transfer);
+                    }
+                    last_ack = ack(&result);
+                    if matches!(result, Ok(value) if value & self.match_mask != expected) {
+                        last_ack |= ACK_VALUE_MISMATCH;
+                    }
+                } else {
+                    let result = self.read(transfer);
+                    last_ack = ack(&result);
+                    if let Ok(value) = result {
+                        if len + 4 > PACKET_SIZE {
+                            break;
+                        }
+                        response[len..len + 4].copy_from_slice(&value.to_le_bytes());
+                        len += 4;
+                    }
+                }
+            } else if transfer & REQUEST_MATCH_MASK != 0 {
+                self.match_mask = request.u32()?;
+                last_ack = ACK_OK;
+            } else {
+                let value = request.u32()?;
+                last_ack = ack(&self.swd.transfer(ap, false, addr, value));
+                posted = ap;
+            }
+            if last_ack != ACK_OK {
+                break;
+            }
+            done += 1;
+        }
+        if posted && last_ack == ACK_OK {
+            last_ack = self.complete_writes();
+        }
+        response[1] = done;
+        response[2] = last_ack;
+        Some(len)
+    }
+
+    fn transfer_block(
+        &mut self,
+        request: &mut Request,
+        response: &mut [u8; PACKET_SIZE],
+    ) -> Option<usize> {
+        let _dap_index = request.u8()?;
+        let count = request.u16()?;
+        let transfer = request.u8()?;
+        let ap = transfer & REQUEST_AP != 0;
+        let addr = transfer & 0x0c;
+        let mut len = 4;
+        let mut done = 0_u16;
+        let mut last_ack = ACK_OK;
+        for _ in 0..count {
+            if transfer & REQUEST_READ != 0 {
+                if len + 4 > PACKET_SIZE {
+                    break;
+                }
+                let result = self.read(transfer);
+                last_ack = ack(&result);
+                if let Ok(value) = result {
+                    response[len..len + 4].copy_from_slice(&value.to_le_bytes());
+                    len += 4;
+                }
+            } else {
+                let value = request.u32()?;
+                last_ack = ack(&self.swd.transfer(ap, false, addr, value));
+            }
+            if last_ack != ACK_OK {
+                break;
+            }
+            done += 1;
+        }
+        if ap && transfer & REQUEST_READ == 0 && last_ack == ACK_OK {
+            last_ack = self.complete_writes();
+        }
+        response[1..3].copy_from_slice(&done.to_le_bytes());
+        response[3] = last_ack;
+        Some(len)
+    }
+
+    /// Only nRESET can be driven, it is wired to the rp2040 reset pin
+    fn swj_pins(
+        &mut self,
+        request: &mut Request,
+        response: &mut [u8; PACKET_SIZE],
+    ) -> Option<usize> {
+        let output = request.u8()?;
+        let select = request.u8()?;
+        let _wait_us = request.u32()?;
+        if select & PIN_NRESET != 0 {
+            if output & PIN_NRESET != 0 {
+                self.control.release();
+            } else {
+                self.control.hold_in_reset();
+            }
+        }
+        response[1] = if self.control.is_held() {
+            0
+        } else {
+            PIN_NRESET
+        };
+        Some(2)
+    }
+
+    fn swj_sequence(
+        &mut self,
+        request: &mut Request,
+        response: &mut [u8; PACKET_SIZE],
+    ) -> Option<usize> {
+        let count = match request.u8()? {
+            0 => 256,
+            count => count as usize,
+        };
+        let bits = request.bytes((count + 7) / 8)?;
+        self.swd.write_sequence(bits, count);
+        Some(status(response, DAP_OK))
+    }
+
+    fn swd_sequence(
+        &mut self,
+        request: &mut Request,
+        response: &mut [u8; PACKET_SIZE],
+    ) -> Option<usize> {
+        let sequences = request.u8()?;
+        let mut len = 2;
+        for _ in 0..sequences {
+            let info = request.u8()?;
+            let count = match info & 0x3f {
+                0 => 64,
+                count => count as usize,
+            };
+            let bytes = (count + 7) / 8;
+            if info & 0x80 != 0 {
+                if len + bytes > PACKET_SIZE {
+                    return None;
+                }
+                let mut remaining = count;
+                while remaining > 0 {
+                    let chunk = remaining.min(32);
+                    let value = self.swd.read_bits(chunk).to_le_bytes();
+                    let chunk_bytes = (chunk + 7) / 8;
+                    response[len..len + chunk_bytes].copy_from_slice(&value[..chunk_bytes]);
+                    len += chunk_bytes;
+                    remaining -= chunk;
+                }
+            } else {
+                let bits = request.bytes(bytes)?;
+                self.swd.write_sequence(bits, count);
+            }
+        }
+        response[1] = DAP_OK;
+        Some(len)
+    }
+}
+
+fn status(response: &mut [u8; PACKET_SIZE], status: u8) -> usize {
+    response[1] = status;
+    2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{NoDelay, Pin};
+
+    type TestDap = Dap<Pin, Pin, NoDelay, Pin, NoDelay>;
+
+    fn dap() -> (TestDap, Pin) {
+        let reset = Pin::default();
+        let swd = Swd::new(Pin::default(), Pin::default(), NoDelay);
+        let control = RpControl::new(reset.clone(), NoDelay);
+        (Dap::new(swd, control), reset)
+    }
+
+    fn process(dap: &mut TestDap, request: &[u8]) -> Vec<u8> {
+        let mut response = [0; PACKET_SIZE];
+        let len = dap.process(request, &mut response);
+        response[..len].to_vec()
+    }
+
+    #[test]
+    fn tcp_headers() {
+        let mut header = tcp_header(12);
+        assert_eq!(&header[..4], b"DAP\0");
+        assert_eq!(parse_tcp_header(&header), None);
+        header[6] = TCP_REQUEST;
+        assert_eq!(parse_tcp_header(&header), Some(12));
+        header[4..6].copy_from_slice(&(PACKET_SIZE as u16 + 1).to_le_bytes());
+        assert_eq!(parse_tcp_header(&header), None);
+    }
+
+    #[test]
+    fn info() {
+        let (mut dap, _) = dap();
+        assert_eq!(
+            process(&mut dap, &[command::INFO, 0xff]),
+            [0x00, 2, 0x00, 0x04]
+        );
+        assert_eq!(process(&mut dap, &[command::INFO, 0xf0]), [0x00, 1, 0x01]);
+        let vendor = process(&mut dap, &[command::INFO, 0x01]);
+        assert_eq!(&vendor[2..], VENDOR);
+    }
+
+    #[test]
+    fn invalid_commands() {
+        let (mut dap, _) = dap();
+        assert_eq!(process(&mut dap, &[]), []);
+        assert_eq!(process(&mut dap, &[0x7f]), [command::INVALID]);
+        // Truncated request
+        assert_eq!(
+            process(&mut dap, &[command::SWJ_CLOCK, 1]),
+            [command::INVALID]
+        );
+        assert_eq!(process(&mut dap, &[command::TRANSFER_ABORT]), []);
+    }
+
+    #[test]
+    fn swj_pins_drive_the_reset_line() {
+        let (mut dap, reset) = dap();
+        let pins = |output| [command::SWJ_PINS, output, PIN_NRESET, 0, 0, 0, 0];
+        assert_eq!(process(&mut dap, &pins(0)), [command::SWJ_PINS, 0]);
+        assert!(!reset.is_high());
+        assert_eq!(
+            process(&mut dap, &pins(PIN_NRESET)),
+            [command::SWJ_PINS, PIN_NRESET]
+        );
+        assert!(reset.is_high());
+    }
+}
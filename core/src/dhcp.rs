@@ -0,0 +1,336 @@
+//! A DHCP server (RFC 2131) for the setup access point
+//!
+//! Phones and laptops that join the access point of the esp32 get an
+//! address from it, with the esp32 as their router and DNS server, so the
+//! setup page opens on its own. Every client gets the next free address
+//! after the server and keeps it until the esp32 resets, there are few of
+//! them and the leases are never taken back.
+
+/// Port the server listens on
+pub const SERVER_PORT: u16 = 67;
+/// Port the answers go to, as broadcasts
+pub const CLIENT_PORT: u16 = 68;
+/// Largest message that is read or written
+pub const MAX_MESSAGE: usize = 576;
+/// Clients that get an address
+pub const MAX_CLIENTS: usize = 8;
+
+/// Seconds a lease lasts
+const LEASE_TIME: u32 = 3600;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// The fixed part of a message up to the magic cookie
+const FIXED_SIZE: usize = 236;
+
+const BOOT_REQUEST: u8 = 1;
+const BOOT_REPLY: u8 = 2;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER: u8 = 54;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The message ends early or is not a DHCP request
+    Malformed,
+    /// The answer does not fit into the buffer
+    TooLarge,
+}
+
+/// Hands out the addresses of a /24 network whose first address is the
+/// server
+pub struct Server {
+    address: [u8; 4],
+    /// The hardware addresses of the clients, the client at `n` has the
+    /// address `n + 1` after the server
+    clients: [Option<[u8; 6]>; MAX_CLIENTS],
+}
+
+impl Server {
+    pub fn new(address: [u8; 4]) -> Self {
+        Self {
+            address,
+            clients: [None; MAX_CLIENTS],
+        }
+    }
+
+    /// Answer a `DISCOVER` with an offer and a `REQUEST` with an ack or,
+    /// for an address of someone else, a nak. Returns the length of the
+    /// answer, which is broadcast to [`CLIENT_PORT`].
+    pub fn answer(&mut self, packet: &[u8], out: &mut [u8]) -> Result<Option<usize>, Error> {
+        if packet.len() < FIXED_SIZE + 4
+            || packet[0] != BOOT_REQUEST
+            || packet[FIXED_SIZE..FIXED_SIZE + 4] != MAGIC_COOKIE
+        {
+            return Err(Error::Malformed);
+        }
+        let mut kind = None;
+        let mut requested: Option<[u8; 4]> = None;
+        let mut server: Option<[u8; 4]> = None;
+        for (code, data) in Options(&packet[FIXED_SIZE + 4..]) {
+            match (code, data.len()) {
+                (OPTION_MESSAGE_TYPE, 1) => kind = Some(data[0]),
+                (OPTION_REQUESTED_ADDRESS, 4) => requested = data.try_into().ok(),
+                (OPTION_SERVER, 4) => server = data.try_into().ok(),
+                _ => {}
+            }
+        }
+        // The hardware address of an Ethernet or wifi client
+        if packet[1] != 1 || packet[2] != 6 {
+            return Ok(None);
+        }
+        let mut hardware = [0; 6];
+        hardware.copy_from_slice(&packet[28..34]);
+        let (reply, address) = match kind {
+            Some(DISCOVER) => match self.lease(hardware) {
+                Some(address) => (OFFER, address),
+                None => return Ok(None),
+            },
+            // Chose another server
+            Some(REQUEST) if server.is_some_and(|server| server != self.address) => {
+                return Ok(None)
+            }
+            Some(REQUEST) => {
+                // A renewing client has its address in `ciaddr`
+                let mut current = [0; 4];
+                current.copy_from_slice(&packet[12..16]);
+                let wanted = requested.unwrap_or(current);
+                match self.leased(hardware) {
+                    Some(address) if address == wanted => (ACK, address),
+                    _ => (NAK, [0; 4]),
+                }
+            }
+            _ => return Ok(None),
+        };
+        self.write(packet, reply, address, out).map(Some)
+    }
+
+    /// The address of the client, a new one if it has none yet
+    fn lease(&mut self, hardware: [u8; 6]) -> Option<[u8; 4]> {
+        if let Some(address) = self.leased(hardware) {
+            return Some(address);
+        }
+        let index = self.clients.iter().position(Option::is_none)?;
+        self.clients[index] = Some(hardware);
+        Some(self.client_address(index))
+    }
+
+    /// The address the client was offered, if any
+    fn leased(&self, hardware: [u8; 6]) -> Option<[u8; 4]> {
+        let index = self.clients.iter().position(|c| *c == Some(hardware))?;
+        Some(self.client_address(index))
+    }
+
+    fn client_address(&self, index: usize) -> [u8; 4] {
+        let mut address = self.address;
+        address[3] = address[3].wrapping_add(1 + index as u8);
+        address
+    }
+
+    fn write(
+        &self,
+        request: &[u8],
+        reply: u8,
+        address: [u8; 4],
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let out = out.get_mut(..MAX_MESSAGE).ok_or(Error::TooLarge)?;
+        out.fill(0);
+        out[0] = BOOT_REPLY;
+        // Hardware type and length, hops
+        out[1..4].copy_from_slice(&request[1..4]);
+        // Transaction id, seconds and flags
+        out[4..12].copy_from_slice(&request[4..12]);
+        out[16..20].copy_from_slice(&address);
+        out[20..24].copy_from_slice(&self.address);
+        // Relay agent and hardware address
+        out[24..44].copy_from_slice(&request[24..44]);
+        out[FIXED_SIZE..FIXED_SIZE + 4].copy_from_slice(&MAGIC_COOKIE);
+        let mut at = FIXED_SIZE + 4;
+        let mut option = |code: u8, data: &[u8]| {
+            out[at] = code;
+            out[at + 1] = data.len() as u8;
+            out[at + 2..at + 2 + data.len()].copy_from_slice(data);
+            at += 2 + data.len();
+        };
+        option(OPTION_MESSAGE_TYPE, &[reply]);
+        option(OPTION_SERVER, &self.address);
+        if reply != NAK {
+            option(OPTION_LEASE_TIME, &LEASE_TIME.to_be_bytes());
+            option(OPTION_SUBNET_MASK, &[255, 255, 255, 0]);
+            option(OPTION_ROUTER, &self.address);
+            option(OPTION_DNS, &self.address);
+        }
+        out[at] = OPTION_END;
+        Ok(at + 1)
+    }
+}
+
+/// The options of a message as code and data, up to the end option
+struct Options<'p>(&'p [u8]);
+
+impl<'p> Iterator for Options<'p> {
+    type Item = (u8, &'p [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (&code, rest) = self.0.split_first()?;
+            match code {
+                OPTION_PAD => self.0 = rest,
+                OPTION_END => return None,
+                _ => {
+                    let (&len, rest) = rest.split_first()?;
+                    let data = rest.get(..len as usize)?;
+                    self.0 = &rest[len as usize..];
+                    return Some((code, data));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: [u8; 4] = [192, 168, 4, 1];
+    const PHONE: [u8; 6] = [0x02, 0x11, 0x22, 0x33, 0x44, 0x55];
+
+    fn request(hardware: [u8; 6], options: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; FIXED_SIZE];
+        packet[..4].copy_from_slice(&[BOOT_REQUEST, 1, 6, 0]);
+        packet[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        // Broadcast flag
+        packet[10] = 0x80;
+        packet[28..34].copy_from_slice(&hardware);
+        packet.extend_from_slice(&MAGIC_COOKIE);
+        packet.extend_from_slice(options);
+        packet.push(OPTION_END);
+        packet
+    }
+
+    fn options(answer: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        Options(&answer[FIXED_SIZE + 4..])
+            .map(|(code, data)| (code, data.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn leases_addresses() {
+        let mut server = Server::new(SERVER);
+        let mut out = [0; MAX_MESSAGE];
+        let discover = request(PHONE, &[OPTION_MESSAGE_TYPE, 1, DISCOVER, OPTION_PAD]);
+        let len = server.answer(&discover, &mut out).unwrap().unwrap();
+        let offer = &out[..len];
+        assert_eq!(offer[0], BOOT_REPLY);
+        assert_eq!(offer[4..8], [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(offer[10], 0x80);
+        assert_eq!(offer[16..20], [192, 168, 4, 2]);
+        assert_eq!(offer[28..34], PHONE);
+        assert_eq!(
+            options(offer),
+            [
+                (OPTION_MESSAGE_TYPE, vec![OFFER]),
+                (OPTION_SERVER, SERVER.to_vec()),
+                (OPTION_LEASE_TIME, vec![0, 0, 0x0e, 0x10]),
+                (OPTION_SUBNET_MASK, vec![255, 255, 255, 0]),
+                (OPTION_ROUTER, SERVER.to_vec()),
+                (OPTION_DNS, SERVER.to_vec()),
+            ]
+        );
+
+        let accepted = request(
+            PHONE,
+            &[
+                OPTION_MESSAGE_TYPE,
+                1,
+                REQUEST,
+                OPTION_REQUESTED_ADDRESS,
+                4,
+                192,
+                168,
+                4,
+                2,
+                OPTION_SERVER,
+                4,
+                192,
+                168,
+                4,
+                1,
+            ],
+        );
+        let len = server.answer(&accepted, &mut out).unwrap().unwrap();
+        assert_eq!(out[16..20], [192, 168, 4, 2]);
+        assert_eq!(options(&out[..len])[0], (OPTION_MESSAGE_TYPE, vec![ACK]));
+
+        // The next client gets the next address
+        let laptop = request([2, 0, 0, 0, 0, 9], &[OPTION_MESSAGE_TYPE, 1, DISCOVER]);
+        server.answer(&laptop, &mut out).unwrap().unwrap();
+        assert_eq!(out[16..20], [192, 168, 4, 3]);
+    }
+
+    #[test]
+    fn refuses_requests() {
+        let mut server = Server::new(SERVER);
+        let mut out = [0; MAX_MESSAGE];
+        // An address from another network
+        let stale = request(
+            PHONE,
+            &[
+                OPTION_MESSAGE_TYPE,
+                1,
+                REQUEST,
+                OPTION_REQUESTED_ADDRESS,
+                4,
+                10,
+                0,
+                0,
+                7,
+            ],
+        );
+        let len = server.answer(&stale, &mut out).unwrap().unwrap();
+        assert_eq!(out[16..20], [0; 4]);
+        assert_eq!(options(&out[..len]).len(), 2);
+        assert_eq!(options(&out[..len])[0], (OPTION_MESSAGE_TYPE, vec![NAK]));
+
+        // Meant for another server
+        let other = request(
+            PHONE,
+            &[
+                OPTION_MESSAGE_TYPE,
+                1,
+                REQUEST,
+                OPTION_SERVER,
+                4,
+                10,
+                0,
+                0,
+                1,
+            ],
+        );
+        assert_eq!(server.answer(&other, &mut out), Ok(None));
+
+        for index in 0..MAX_CLIENTS as u8 {
+            let discover = request([2, 0, 0, 0, 1, index], &[OPTION_MESSAGE_TYPE, 1, DISCOVER]);
+            assert!(server.answer(&discover, &mut out).unwrap().is_some());
+        }
+        let discover = request([2, 0, 0, 0, 2, 0], &[OPTION_MESSAGE_TYPE, 1, DISCOVER]);
+        assert_eq!(server.answer(&discover, &mut out), Ok(None));
+
+        assert_eq!(
+            server.answer(&[BOOT_REQUEST; 100], &mut out),
+            Err(Error::Malformed)
+        );
+    }
+}
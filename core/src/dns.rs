@@ -0,0 +1,547 @@
+//! Server addresses and DNS (RFC 1035)
+//!
+//! [`Address::parse`] reads a `host:port` server address, such as the
+//! `ADDRESS` build variable, whose host is either an IPv4 address or a
+//! name. A name is looked up by sending a [`query`] to a DNS server on
+//! [`PORT`] and reading its answer with [`parse_response`].
+//!
+//! On the setup access point the esp32 is the DNS server of its clients
+//! and [`answer_all`] sends them to itself for every name.
+//!
+//! The message format is shared with [`crate::mdns`].
+
+use core::fmt;
+
+/// Port of DNS servers
+pub const PORT: u16 = 53;
+/// Largest DNS message over UDP
+pub const MAX_MESSAGE: usize = 512;
+
+/// Longest name
+const MAX_NAME: usize = 255;
+/// Longest host name that still fits into a message as labels
+const MAX_HOST: usize = 253;
+/// Longest label of a name
+const MAX_LABEL: usize = 63;
+/// Pointers followed in a name before it is taken as a loop
+const MAX_POINTERS: usize = 16;
+
+pub(crate) const TYPE_A: u16 = 1;
+pub(crate) const CLASS_IN: u16 = 1;
+/// The flag of a response
+pub(crate) const RESPONSE: u16 = 0x8000;
+/// Asks the server to look the name up for the client
+const RECURSION_DESIRED: u16 = 0x0100;
+/// The server looks names up for its clients
+const RECURSION_AVAILABLE: u16 = 0x0080;
+/// Seconds the answers of [`answer_all`] are kept, short so that names
+/// work again once the client leaves the access point
+const CAPTIVE_TTL: u32 = 10;
+/// The response code of a name that does not exist
+const NAME_ERROR: u16 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The message ends early or a name is broken
+    Malformed,
+    /// The message does not fit into the buffer
+    TooLarge,
+    /// The name does not exist or has no IPv4 address
+    NotFound,
+    /// The server could not answer, with its response code
+    Server(u8),
+}
+
+/// The host of a server address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Host<'a> {
+    Ipv4([u8; 4]),
+    /// A name to look up, without a trailing dot
+    Name(&'a str),
+}
+
+/// A `host:port` server address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Address<'a> {
+    pub host: Host<'a>,
+    pub port: u16,
+}
+
+/// Why a server address cannot be used
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressError {
+    /// There is no `:port`
+    NoPort,
+    /// The port is not a number from 1 to 65535
+    BadPort,
+    /// The host is neither four octets nor a valid name
+    BadHost,
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NoPort => "the address has no port, write it as host:port",
+            Self::BadPort => "the port is not a number from 1 to 65535",
+            Self::BadHost => "the host is neither an IPv4 address nor a valid name",
+        })
+    }
+}
+
+impl<'a> Address<'a> {
+    /// Parse `host:port`, such as `192.168.1.2:5000` or
+    /// `roms.lab.example:5000`
+    pub fn parse(address: &'a str) -> Result<Self, AddressError> {
+        let (host, port) = address
+            .trim()
+            .rsplit_once(':')
+            .ok_or(AddressError::NoPort)?;
+        // `u16::from_str` also takes a sign
+        let port = Some(port)
+            .filter(|port| port.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|port| port.parse().ok())
+            .filter(|&port| port != 0)
+            .ok_or(AddressError::BadPort)?;
+        Ok(Self {
+            host: Host::parse(host)?,
+            port,
+        })
+    }
+}
+
+impl<'a> Host<'a> {
+    fn parse(host: &'a str) -> Result<Self, AddressError> {
+        // Digits and dots are meant as an address, `10.0.1` is not a name
+        if host.bytes().all(|b| b.is_ascii_digit() || b == b'.') {
+            return parse_ipv4(host)
+                .map(Self::Ipv4)
+                .ok_or(AddressError::BadHost);
+        }
+        let name = host.strip_suffix('.').unwrap_or(host);
+        if name.len() <= MAX_HOST && name.split('.').all(is_label) {
+            Ok(Self::Name(name))
+        } else {
+            Err(AddressError::BadHost)
+        }
+    }
+}
+
+/// Four dotted octets, without signs
+pub(crate) fn parse_ipv4(host: &str) -> Option<[u8; 4]> {
+    if !host.bytes().all(|b| b.is_ascii_digit() || b == b'.') {
+        return None;
+    }
+    let mut octets = [0; 4];
+    let mut parts = host.split('.');
+    for octet in &mut octets {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    parts.next().is_none().then_some(octets)
+}
+
+/// Letters, digits and hyphens, but no hyphen at either end
+pub(crate) fn is_label(label: &str) -> bool {
+    (1..=MAX_LABEL).contains(&label.len())
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+}
+
+/// Write a question for the IPv4 address of `name`, returns the length
+///
+/// The answer carries the same `id`.
+pub fn query(id: u16, name: &str, out: &mut [u8]) -> Result<usize, Error> {
+    let mut writer = Writer { out, len: 0 };
+    writer.u16(id)?;
+    writer.u16(RECURSION_DESIRED)?;
+    // One question and no records
+    writer.put(&[0, 1, 0, 0, 0, 0, 0, 0])?;
+    writer.name(name)?;
+    writer.u16(TYPE_A)?;
+    writer.u16(CLASS_IN)?;
+    Ok(writer.len)
+}
+
+/// Read the answer to the query `id`, returns the first IPv4 address in
+/// it or `None` when the packet is not that answer
+///
+/// Servers send the records of aliases along, so the address of the name
+/// an alias stands for is in the answer too.
+pub fn parse_response(packet: &[u8], id: u16) -> Result<Option<[u8; 4]>, Error> {
+    let header = Header::parse(packet)?;
+    if u16_at(packet, 0)? != id || header.flags & RESPONSE == 0 {
+        return Ok(None);
+    }
+    match header.flags & 0x000f {
+        0 => {}
+        NAME_ERROR => return Err(Error::NotFound),
+        code => return Err(Error::Server(code as u8)),
+    }
+    for record in header.records(packet).take(header.answers) {
+        let record = record?;
+        if record.kind == TYPE_A {
+            let data = record.data(packet)?;
+            return data.try_into().map(Some).map_err(|_| Error::Malformed);
+        }
+    }
+    Err(Error::NotFound)
+}
+
+/// Answer a query for the IPv4 address of any name with `address`,
+/// returns the length of the answer or `None` for other packets
+///
+/// Other questions, such as for IPv6 addresses, get an empty answer.
+pub fn answer_all(packet: &[u8], address: [u8; 4], out: &mut [u8]) -> Result<Option<usize>, Error> {
+    let header = Header::parse(packet)?;
+    if header.flags & RESPONSE != 0 || header.questions != 1 {
+        return Ok(None);
+    }
+    let (_, end) = Name::read(packet, 12)?;
+    let kind = u16_at(packet, end)?;
+    let class = u16_at(packet, end + 2)?;
+    let answers = u16::from(kind == TYPE_A && class == CLASS_IN);
+    let mut writer = Writer { out, len: 0 };
+    writer.put(&packet[..2])?;
+    // A response that the server looked up, keeping the recursion bit
+    writer.u16(RESPONSE | RECURSION_AVAILABLE | header.flags & RECURSION_DESIRED)?;
+    writer.u16(1)?;
+    writer.u16(answers)?;
+    writer.put(&[0, 0, 0, 0])?;
+    writer.put(&packet[12..end + 4])?;
+    if answers == 1 {
+        // The name of the question
+        writer.u16(0xc00c)?;
+        writer.u16(TYPE_A)?;
+        writer.u16(CLASS_IN)?;
+        writer.put(&CAPTIVE_TTL.to_be_bytes())?;
+        writer.u16(4)?;
+        writer.put(&address)?;
+    }
+    Ok(Some(writer.len))
+}
+
+pub(crate) fn u16_at(packet: &[u8], at: usize) -> Result<u16, Error> {
+    packet
+        .get(at..at + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or(Error::Malformed)
+}
+
+pub(crate) struct Header {
+    pub flags: u16,
+    pub questions: u16,
+    pub answers: usize,
+    /// Answers, authority and additional records
+    pub records: usize,
+}
+
+impl Header {
+    pub fn parse(packet: &[u8]) -> Result<Self, Error> {
+        let count = |at| u16_at(packet, at).map(usize::from);
+        let answers = count(6)?;
+        Ok(Self {
+            flags: u16_at(packet, 2)?,
+            questions: u16_at(packet, 4)?,
+            answers,
+            records: answers + count(8)? + count(10)?,
+        })
+    }
+
+    pub fn records<'p>(&self, packet: &'p [u8]) -> Records<'p> {
+        Records {
+            packet,
+            questions: self.questions,
+            at: 12,
+            left: self.records,
+        }
+    }
+}
+
+/// A resource record of a message
+pub(crate) struct Record {
+    pub name: Name,
+    pub kind: u16,
+    pub data_at: usize,
+    data_len: usize,
+}
+
+impl Record {
+    pub fn data<'p>(&self, packet: &'p [u8]) -> Result<&'p [u8], Error> {
+        packet
+            .get(self.data_at..self.data_at + self.data_len)
+            .ok_or(Error::Malformed)
+    }
+}
+
+pub(crate) struct Records<'p> {
+    packet: &'p [u8],
+    /// Questions that are still to be skipped
+    questions: u16,
+    at: usize,
+    left: usize,
+}
+
+impl<'p> Records<'p> {
+    fn read(&mut self) -> Result<Record, Error> {
+        while self.questions > 0 {
+            self.at = Name::read(self.packet, self.at)?.1 + 4;
+            self.questions -= 1;
+        }
+        let (name, at) = Name::read(self.packet, self.at)?;
+        let kind = u16_at(self.packet, at)?;
+        // Class and TTL
+        let data_len = u16_at(self.packet, at + 8)? as usize;
+        let data_at = at + 10;
+        if data_at + data_len > self.packet.len() {
+            return Err(Error::Malformed);
+        }
+        self.at = data_at + data_len;
+        Ok(Record {
+            name,
+            kind,
+            data_at,
+            data_len,
+        })
+    }
+}
+
+impl<'p> Iterator for Records<'p> {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        let record = self.read();
+        if record.is_err() {
+            self.left = 0;
+        }
+        Some(record)
+    }
+}
+
+/// A name read from a message, as dotted labels
+pub(crate) struct Name {
+    bytes: [u8; MAX_NAME],
+    pub len: usize,
+}
+
+impl Name {
+    pub fn new() -> Self {
+        Self {
+            bytes: [0; MAX_NAME],
+            len: 0,
+        }
+    }
+
+    /// Read the name at `at`, returns it and where it ends in the message
+    pub fn read(packet: &[u8], mut at: usize) -> Result<(Self, usize), Error> {
+        let mut name = Self::new();
+        let mut end = None;
+        let mut pointers = 0;
+        loop {
+            let len = *packet.get(at).ok_or(Error::Malformed)? as usize;
+            match len {
+                0 => break,
+                0xc0..=0xff => {
+                    if pointers == MAX_POINTERS {
+                        return Err(Error::Malformed);
+                    }
+                    pointers += 1;
+                    let pointer = u16_at(packet, at)? as usize & 0x3fff;
+                    end.get_or_insert(at + 2);
+                    at = pointer;
+                }
+                0x40..=0xbf => return Err(Error::Malformed),
+                _ => {
+                    let label = packet.get(at + 1..at + 1 + len).ok_or(Error::Malformed)?;
+                    let dot = usize::from(name.len > 0);
+                    let bytes = name
+                        .bytes
+                        .get_mut(name.len..name.len + dot + len)
+                        .ok_or(Error::Malformed)?;
+                    bytes[dot..].copy_from_slice(label);
+                    if dot == 1 {
+                        bytes[0] = b'.';
+                    }
+                    name.len += dot + len;
+                    at += 1 + len;
+                }
+            }
+        }
+        Ok((name, end.unwrap_or(at + 1)))
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+
+    pub fn is(&self, name: &str) -> bool {
+        self.bytes[..self.len].eq_ignore_ascii_case(name.as_bytes())
+    }
+
+    /// The name is `<host>.local`
+    pub fn is_host(&self, host: &str) -> bool {
+        let name = &self.bytes[..self.len];
+        name.len() == host.len() + ".local".len()
+            && name[..host.len()].eq_ignore_ascii_case(host.as_bytes())
+            && name[host.len()..].eq_ignore_ascii_case(b".local")
+    }
+}
+
+pub(crate) struct Writer<'o> {
+    pub out: &'o mut [u8],
+    pub len: usize,
+}
+
+impl<'o> Writer<'o> {
+    pub fn put(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.out
+            .get_mut(self.len..self.len + bytes.len())
+            .ok_or(Error::TooLarge)?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    pub fn u16(&mut self, value: u16) -> Result<(), Error> {
+        self.put(&value.to_be_bytes())
+    }
+
+    pub fn label(&mut self, label: &str) -> Result<(), Error> {
+        let len = u8::try_from(label.len())
+            .ok()
+            .filter(|&len| (1..0x40).contains(&len))
+            .ok_or(Error::TooLarge)?;
+        self.put(&[len])?;
+        self.put(label.as_bytes())
+    }
+
+    /// A whole dotted name, without compression
+    pub fn name(&mut self, name: &str) -> Result<(), Error> {
+        for label in name.split('.') {
+            self.label(label)?;
+        }
+        self.put(&[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The answer of a resolver for an alias, with compressed names
+    const RESPONSE: &[u8] = b"\x12\x34\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00\
+        \x04roms\x03lab\x07example\x00\x00\x01\x00\x01\
+        \xc0\x0c\x00\x05\x00\x01\x00\x00\x0e\x10\x00\x07\x04host\xc0\x11\
+        \xc0\x2e\x00\x01\x00\x01\x00\x00\x0e\x10\x00\x04\x0a\x00\x01\x07";
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(
+            Address::parse("192.168.1.2:5000"),
+            Ok(Address {
+                host: Host::Ipv4([192, 168, 1, 2]),
+                port: 5000
+            })
+        );
+        assert_eq!(
+            Address::parse(" roms.lab.example.:80\n"),
+            Ok(Address {
+                host: Host::Name("roms.lab.example"),
+                port: 80
+            })
+        );
+        assert_eq!(
+            Address::parse("rom-server:5000").map(|a| a.host),
+            Ok(Host::Name("rom-server"))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_addresses() {
+        assert_eq!(Address::parse("192.168.1.2"), Err(AddressError::NoPort));
+        for port in ["", "0", "65536", "+80", "http"] {
+            let address = format!("192.168.1.2:{port}");
+            assert_eq!(Address::parse(&address), Err(AddressError::BadPort));
+        }
+        for host in [
+            "",
+            "192.168.1",
+            "192.168.1.2.3",
+            "192.168.1.256",
+            "192..1.2",
+            "-roms.lab",
+            "roms..lab",
+            "roms_lab",
+            "[::1]",
+        ] {
+            let address = format!("{host}:5000");
+            assert_eq!(Address::parse(&address), Err(AddressError::BadHost));
+        }
+        let long = format!("{}:5000", ["a"; 128].join("."));
+        assert_eq!(Address::parse(&long), Err(AddressError::BadHost));
+    }
+
+    #[test]
+    fn queries_names() {
+        let mut out = [0; MAX_MESSAGE];
+        let len = query(0x1234, "roms.lab.example", &mut out).unwrap();
+        assert_eq!(out[..12], *b"\x12\x34\x01\x00\x00\x01\0\0\0\0\0\0");
+        assert_eq!(out[12..len], RESPONSE[12..len]);
+        // A query is not an answer
+        assert_eq!(parse_response(&out[..len], 0x1234), Ok(None));
+        assert_eq!(query(1, "roms.lab", &mut [0; 16]), Err(Error::TooLarge));
+    }
+
+    #[test]
+    fn answers_everything() {
+        let mut question = [0; MAX_MESSAGE];
+        let len = query(0x1234, "connectivitycheck.gstatic.com", &mut question).unwrap();
+        let mut out = [0; MAX_MESSAGE];
+        let answered = answer_all(&question[..len], [192, 168, 4, 1], &mut out)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            parse_response(&out[..answered], 0x1234),
+            Ok(Some([192, 168, 4, 1]))
+        );
+        assert_eq!(out[2..4], [0x81, 0x80]);
+        assert_eq!(out[12..len], question[12..len]);
+
+        // No IPv6 address
+        question[len - 3] = 28;
+        let answered = answer_all(&question[..len], [192, 168, 4, 1], &mut out)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            parse_response(&out[..answered], 0x1234),
+            Err(Error::NotFound)
+        );
+        assert_eq!(answer_all(RESPONSE, [192, 168, 4, 1], &mut out), Ok(None));
+    }
+
+    #[test]
+    fn reads_answers() {
+        assert_eq!(parse_response(RESPONSE, 0x1234), Ok(Some([10, 0, 1, 7])));
+        // The answer to another query
+        assert_eq!(parse_response(RESPONSE, 0x4321), Ok(None));
+
+        let mut failed = RESPONSE.to_vec();
+        failed[3] = 0x83;
+        assert_eq!(parse_response(&failed, 0x1234), Err(Error::NotFound));
+        failed[3] = 0x82;
+        assert_eq!(parse_response(&failed, 0x1234), Err(Error::Server(2)));
+        // Only the alias
+        let mut alias = RESPONSE[..RESPONSE.len() - 16].to_vec();
+        alias[7] = 1;
+        assert_eq!(parse_response(&alias, 0x1234), Err(Error::NotFound));
+        assert_eq!(
+            parse_response(&RESPONSE[..40], 0x1234),
+            Err(Error::Malformed)
+        );
+    }
+}
@@ -0,0 +1,597 @@
+//! Over the air updates of the esp32 firmware
+//!
+//! The esp32 has two app partitions, `ota_0` and `ota_1`. The second stage
+//! bootloader picks one of them from the `otadata` partition, which holds
+//! a select entry in each of its two sectors. The entry with the highest
+//! sequence number wins and boots app partition `(seq - 1) % 2`, entries
+//! marked invalid or aborted are skipped.
+//!
+//! New images are written to the app partition that is not running and
+//! checked against the signature and SHA-256 in their header before a new
+//! select entry points the bootloader at them. That entry starts out as
+//! `New`, becomes `PendingVerify` in [`EspOta::boot`] and `Valid` in
+//! [`EspOta::mark_valid`]. When the firmware resets before marking itself
+//! valid the next call to [`EspOta::boot`] aborts the entry, so the
+//! bootloader falls back to the previous image.
+
+use embedded_io::blocking::Read;
+use embedded_storage::{ReadStorage, Storage};
+use sha2::{Digest, Sha256};
+
+use crate::ota::{self, Header, Target, HEADER_SIZE, PUBLIC_KEY_SIZE, SIGNATURE_SIZE};
+use crate::partition::{
+    Partition, PartitionTable, SUBTYPE_OTA_0, SUBTYPE_OTA_DATA, TYPE_APP, TYPE_DATA,
+};
+
+const SECTOR_SIZE: usize = 4096;
+const ENTRY_SIZE: usize = 32;
+/// First byte of an esp32 app image
+const APP_MAGIC: u8 = 0xe9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+    Ota0 = 0x0,
+    Ota1 = 0x1,
+}
+
+impl Slot {
+    pub fn other(self) -> Self {
+        match self {
+            Slot::Ota0 => Slot::Ota1,
+            Slot::Ota1 => Slot::Ota0,
+        }
+    }
+
+    /// The app partition the bootloader boots for a sequence number
+    fn from_seq(seq: u32) -> Self {
+        if seq % 2 == 1 {
+            Slot::Ota0
+        } else {
+            Slot::Ota1
+        }
+    }
+}
+
+/// State of a select entry, with the values used by the bootloader
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    /// Selected but not booted yet
+    New = 0x0,
+    /// Booted and waiting for the firmware to mark itself valid
+    PendingVerify = 0x1,
+    Valid = 0x2,
+    Invalid = 0x3,
+    /// A trial boot was not confirmed
+    Aborted = 0x4,
+    Undefined = 0xffff_ffff,
+}
+
+impl From<u32> for State {
+    fn from(value: u32) -> Self {
+        match value {
+            0x0 => State::New,
+            0x1 => State::PendingVerify,
+            0x2 => State::Valid,
+            0x3 => State::Invalid,
+            0x4 => State::Aborted,
+            _ => State::Undefined,
+        }
+    }
+}
+
+/// What [`EspOta::boot`] found out about the running image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Boot {
+    /// The running image does not have to be confirmed
+    Confirmed,
+    /// First boot of a new image, it has to call [`EspOta::mark_valid`]
+    Trial,
+    /// A trial boot was not confirmed and has been aborted, resetting
+    /// boots the previous image
+    RolledBack,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Reading or writing the esp32 flash failed
+    Storage,
+    /// A partition is missing from the partition table
+    MissingPartition(&'static str),
+    Header(ota::Error),
+    /// The image is meant for the rp2040
+    WrongTarget,
+    /// Esp32 images have to be signed
+    Unsigned,
+    /// The image is not newer than the running one
+    NotNewer {
+        running: u32,
+        offered: u32,
+    },
+    /// The image does not fit into an app partition
+    TooLarge(u32),
+    /// The download ended early or failed
+    Download,
+    /// The SHA-256 of the payload does not match the header
+    Hash,
+    /// The payload is not an esp32 app image
+    NotAnApp,
+}
+
+impl From<ota::Error> for Error {
+    fn from(e: ota::Error) -> Self {
+        Error::Header(e)
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// CRC-32 of the sequence number, as checked by the bootloader
+fn seq_crc(seq: u32) -> u32 {
+    let mut crc = 0_u32;
+    for byte in seq.to_le_bytes() {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Select entry in one of the `otadata` sectors
+///
+/// |Offset|Size|Field|
+/// |---|---|---|
+/// |0 |4 |Sequence number|
+/// |4 |20|Label, unused|
+/// |24|4 |State|
+/// |28|4 |CRC-32 of the sequence number|
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Entry {
+    seq: u32,
+    state: State,
+}
+
+impl Entry {
+    fn parse(bytes: &[u8; ENTRY_SIZE]) -> Option<Self> {
+        let seq = u32_at(bytes, 0);
+        if seq == u32::MAX || seq == 0 || u32_at(bytes, 28) != seq_crc(seq) {
+            return None;
+        }
+        Some(Self {
+            seq,
+            state: State::from(u32_at(bytes, 24)),
+        })
+    }
+
+    fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0xff; ENTRY_SIZE];
+        bytes[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[24..28].copy_from_slice(&(self.state as u32).to_le_bytes());
+        bytes[28..32].copy_from_slice(&seq_crc(self.seq).to_le_bytes());
+        bytes
+    }
+
+    /// Whether the bootloader considers the entry
+    fn bootable(&self) -> bool {
+        !matches!(self.state, State::Invalid | State::Aborted)
+    }
+}
+
+pub struct EspOta<S>
+where
+    S: ReadStorage + Storage,
+{
+    storage: S,
+    slots: [Partition; 2],
+    otadata: Partition,
+    entries: [Option<Entry>; 2],
+}
+
+impl<S> EspOta<S>
+where
+    S: ReadStorage + Storage,
+{
+    /// Find the app and `otadata` partitions and load the select entries
+    pub fn new(mut storage: S) -> Result<Self, Error> {
+        let table = PartitionTable::read(&mut storage).map_err(|_| Error::Storage)?;
+        let find = |kind, subtype, label| {
+            table
+                .find_type(kind, subtype)
+                .ok_or(Error::MissingPartition(label))
+        };
+        let slots = [
+            find(TYPE_APP, SUBTYPE_OTA_0, "ota_0")?,
+            find(TYPE_APP, SUBTYPE_OTA_0 + 1, "ota_1")?,
+        ];
+        let otadata = find(TYPE_DATA, SUBTYPE_OTA_DATA, "otadata")?;
+
+        let mut entries = [None; 2];
+        for (sector, entry) in entries.iter_mut().enumerate() {
+            let mut bytes = [0_u8; ENTRY_SIZE];
+            storage
+                .read(otadata.offset + (sector * SECTOR_SIZE) as u32, &mut bytes)
+                .map_err(|_| Error::Storage)?;
+            *entry = Entry::parse(&bytes);
+        }
+        Ok(Self {
+            storage,
+            slots,
+            otadata,
+            entries,
+        })
+    }
+
+    /// The entry the bootloader follows and the sector it is in
+    fn selected(&self) -> Option<(usize, Entry)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(sector, entry)| entry.map(|entry| (sector, entry)))
+            .filter(|(_, entry)| entry.bootable())
+            .max_by_key(|(_, entry)| entry.seq)
+    }
+
+    /// The app partition the esp32 is running from, without any
+    /// select entry the bootloader boots `ota_0`
+    pub fn running(&self) -> Slot {
+        self.selected()
+            .map_or(Slot::Ota0, |(_, entry)| Slot::from_seq(entry.seq))
+    }
+
+    /// State of the running image, `None` when it was flashed over USB
+    pub fn state(&self) -> Option<State> {
+        self.selected().map(|(_, entry)| entry.state)
+    }
+
+    fn write_entry(&mut self, sector: usize, entry: Entry) -> Result<(), Error> {
+        self.storage
+            .write(
+                self.otadata.offset + (sector * SECTOR_SIZE) as u32,
+                &entry.to_bytes(),
+            )
+            .map_err(|_| Error::Storage)?;
+        self.entries[sector] = Some(entry);
+        Ok(())
+    }
+
+    fn set_state(&mut self, state: State) -> Result<(), Error> {
+        if let Some((sector, entry)) = self.selected() {
+            self.write_entry(sector, Entry { state, ..entry })?;
+        }
+        Ok(())
+    }
+
+    /// Has to be called early after every boot
+    ///
+    /// A new image is moved on to `PendingVerify`. Finding an image still
+    /// pending means it was reset before it marked itself valid, so it is
+    /// aborted and the caller is expected to reset the esp32.
+    pub fn boot(&mut self) -> Result<Boot, Error> {
+        match self.state() {
+            Some(State::New) => {
+                self.set_state(State::PendingVerify)?;
+                Ok(Boot::Trial)
+            }
+            Some(State::PendingVerify) => {
+                self.set_state(State::Aborted)?;
+                Ok(Boot::RolledBack)
+            }
+            _ => Ok(Boot::Confirmed),
+        }
+    }
+
+    /// Keep the running image, it will not be rolled back anymore
+    pub fn mark_valid(&mut self) -> Result<(), Error> {
+        match self.state() {
+            Some(State::New | State::PendingVerify) => self.set_state(State::Valid),
+            _ => Ok(()),
+        }
+    }
+
+    /// Download a signed image from `source` into the app partition that
+    /// is not running
+    ///
+    /// The signature is checked before anything is written and the
+    /// payload is hashed both while downloading and again from flash.
+    pub fn stage<R: Read>(
+        &mut self,
+        source: &mut R,
+        public_key: &[u8; PUBLIC_KEY_SIZE],
+        running_version: u32,
+    ) -> Result<(Slot, Header), Error> {
+        let slot = self.running().other();
+        let partition = self.slots[slot as usize];
+
+        let mut header_bytes = [0_u8; HEADER_SIZE];
+        source
+            .read_exact(&mut header_bytes)
+            .map_err(|_| Error::Download)?;
+        let header = Header::parse(&header_bytes)?;
+        if header.target != Target::Esp32 {
+            return Err(Error::WrongTarget);
+        }
+        if !header.signed {
+            return Err(Error::Unsigned);
+        }
+        let mut signature = [0_u8; SIGNATURE_SIZE];
+        source
+            .read_exact(&mut signature)
+            .map_err(|_| Error::Download)?;
+        ota::verify_signature(&header_bytes, &signature, public_key)?;
+        if header.version <= running_version {
+            return Err(Error::NotNewer {
+                running: running_version,
+                offered: header.version,
+            });
+        }
+        if header.length as usize > partition.size as usize {
+            return Err(Error::TooLarge(header.length));
+        }
+
+        let mut sector = [0xff_u8; SECTOR_SIZE];
+        let mut offset = partition.offset;
+        let mut remaining = header.length as usize;
+        let mut hasher = Sha256::new();
+        while remaining > 0 {
+            let len = remaining.min(SECTOR_SIZE);
+            source
+                .read_exact(&mut sector[..len])
+                .map_err(|_| Error::Download)?;
+            if offset == partition.offset && sector[0] != APP_MAGIC {
+                return Err(Error::NotAnApp);
+            }
+            hasher.update(&sector[..len]);
+            self.storage
+                .write(offset, &sector[..len])
+                .map_err(|_| Error::Storage)?;
+            offset += SECTOR_SIZE as u32;
+            remaining -= len;
+        }
+        if hasher.finalize()[..] != header.sha256 {
+            return Err(Error::Hash);
+        }
+        self.check(slot, &header)?;
+        Ok((slot, header))
+    }
+
+    /// Hash an image from flash and compare it to its header
+    pub fn check(&mut self, slot: Slot, header: &Header) -> Result<(), Error> {
+        let partition = self.slots[slot as usize];
+        let mut hasher = Sha256::new();
+        let mut chunk = [0_u8; 512];
+        let mut offset = partition.offset;
+        let mut remaining = header.length as usize;
+        while remaining > 0 {
+            let len = remaining.min(chunk.len());
+            self.storage
+                .read(offset, &mut chunk[..len])
+                .map_err(|_| Error::Storage)?;
+            hasher.update(&chunk[..len]);
+            offset += len as u32;
+            remaining -= len;
+        }
+        if hasher.finalize()[..] != header.sha256 {
+            return Err(Error::Hash);
+        }
+        Ok(())
+    }
+
+    /// Make the bootloader boot `slot` on the next reset
+    ///
+    /// The new entry goes into the sector that does not hold the running
+    /// entry, so the running image stays selectable for a rollback.
+    pub fn activate(&mut self, slot: Slot) -> Result<(), Error> {
+        let highest = self.entries.iter().flatten().map(|e| e.seq).max();
+        let mut seq = highest.unwrap_or(0) + 1;
+        if Slot::from_seq(seq) != slot {
+            seq += 1;
+        }
+        let sector = self.selected().map_or(0, |(sector, _)| 1 - sector);
+        self.write_entry(
+            sector,
+            Entry {
+                seq,
+                state: State::New,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use ed25519_compact::{KeyPair, Seed};
+
+    use super::*;
+    use crate::testing::RamFlash;
+
+    const OTADATA: u32 = 0xd000;
+    const OTA_0: u32 = 0x10000;
+    const OTA_1: u32 = 0x20000;
+    const APP_SIZE: u32 = 0x10000;
+
+    fn flash() -> RamFlash {
+        RamFlash::new(0x30000).with_partitions(&[
+            (TYPE_DATA, SUBTYPE_OTA_DATA, OTADATA, 0x2000, "otadata"),
+            (TYPE_APP, SUBTYPE_OTA_0, OTA_0, APP_SIZE, "ota_0"),
+            (TYPE_APP, SUBTYPE_OTA_0 + 1, OTA_1, APP_SIZE, "ota_1"),
+        ])
+    }
+
+    fn key() -> KeyPair {
+        KeyPair::from_seed(Seed::new([1; 32]))
+    }
+
+    /// A packed image like `pack_firmware.py` writes it
+    fn image(version: u32, payload: &[u8], key: &KeyPair) -> Vec<u8> {
+        let header = Header {
+            target: Target::Esp32,
+            signed: true,
+            version,
+            length: payload.len() as u32,
+            sha256: Sha256::digest(payload).into(),
+        }
+        .to_bytes();
+        let mut image = header.to_vec();
+        image.extend_from_slice(key.sk.sign(header, None).as_ref());
+        image.extend_from_slice(payload);
+        image
+    }
+
+    fn payload() -> Vec<u8> {
+        let mut payload: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        payload[0] = APP_MAGIC;
+        payload
+    }
+
+    /// Stage and activate a new image, like a reset into it would find it
+    fn install(flash: &RamFlash) -> Slot {
+        let mut ota = EspOta::new(flash.clone()).unwrap();
+        let image = image(2, &payload(), &key());
+        let (slot, _) = ota.stage(&mut &image[..], &key().pk, 1).unwrap();
+        ota.activate(slot).unwrap();
+        slot
+    }
+
+    #[test]
+    fn seq_crc_matches_the_bootloader() {
+        assert_eq!(seq_crc(1), 0x4743_989a);
+    }
+
+    #[test]
+    fn entries() {
+        let entry = Entry {
+            seq: 3,
+            state: State::Valid,
+        };
+        assert_eq!(Entry::parse(&entry.to_bytes()), Some(entry));
+        assert_eq!(Entry::parse(&[0xff; ENTRY_SIZE]), None);
+
+        let mut bytes = entry.to_bytes();
+        bytes[28] ^= 1;
+        assert_eq!(Entry::parse(&bytes), None);
+    }
+
+    #[test]
+    fn flashed_over_usb() {
+        let mut ota = EspOta::new(flash()).unwrap();
+        assert_eq!(ota.running(), Slot::Ota0);
+        assert_eq!(ota.state(), None);
+        assert_eq!(ota.boot(), Ok(Boot::Confirmed));
+    }
+
+    #[test]
+    fn missing_partitions() {
+        let flash = RamFlash::new(0x30000).with_partitions(&[(
+            TYPE_APP,
+            SUBTYPE_OTA_0,
+            OTA_0,
+            APP_SIZE,
+            "ota_0",
+        )]);
+        assert_eq!(
+            EspOta::new(flash).err(),
+            Some(Error::MissingPartition("ota_1"))
+        );
+    }
+
+    #[test]
+    fn confirmed_update() {
+        let flash = flash();
+        assert_eq!(install(&flash), Slot::Ota1);
+        assert_eq!(flash.bytes(OTA_1, 5000), payload());
+
+        let mut ota = EspOta::new(flash.clone()).unwrap();
+        assert_eq!(ota.running(), Slot::Ota1);
+        assert_eq!(ota.boot(), Ok(Boot::Trial));
+        ota.mark_valid().unwrap();
+
+        let mut ota = EspOta::new(flash).unwrap();
+        assert_eq!(ota.state(), Some(State::Valid));
+        assert_eq!(ota.boot(), Ok(Boot::Confirmed));
+        assert_eq!(ota.running(), Slot::Ota1);
+    }
+
+    #[test]
+    fn unconfirmed_update_rolls_back() {
+        let flash = flash();
+        install(&flash);
+
+        let mut ota = EspOta::new(flash.clone()).unwrap();
+        assert_eq!(ota.boot(), Ok(Boot::Trial));
+
+        // Reset before mark_valid
+        let mut ota = EspOta::new(flash.clone()).unwrap();
+        assert_eq!(ota.boot(), Ok(Boot::RolledBack));
+
+        let ota = EspOta::new(flash).unwrap();
+        assert_eq!(ota.running(), Slot::Ota0);
+    }
+
+    #[test]
+    fn updates_alternate_between_slots() {
+        let flash = flash();
+        install(&flash);
+        EspOta::new(flash.clone()).unwrap().mark_valid().unwrap();
+
+        let mut ota = EspOta::new(flash.clone()).unwrap();
+        let image = image(3, &payload(), &key());
+        let (slot, _) = ota.stage(&mut &image[..], &key().pk, 2).unwrap();
+        assert_eq!(slot, Slot::Ota0);
+        ota.activate(slot).unwrap();
+        assert_eq!(EspOta::new(flash).unwrap().running(), Slot::Ota0);
+    }
+
+    #[test]
+    fn rejects_images() {
+        let mut ota = EspOta::new(flash()).unwrap();
+        let stage = |ota: &mut EspOta<RamFlash>, image: &[u8], running| {
+            ota.stage(&mut &image[..], &key().pk, running).err()
+        };
+
+        let image_v2 = image(2, &payload(), &key());
+        assert_eq!(
+            stage(&mut ota, &image_v2, 2),
+            Some(Error::NotNewer {
+                running: 2,
+                offered: 2
+            })
+        );
+
+        let other_key = KeyPair::from_seed(Seed::new([2; 32]));
+        assert_eq!(
+            stage(&mut ota, &image(2, &payload(), &other_key), 1),
+            Some(Error::Header(ota::Error::Signature))
+        );
+
+        let mut unsigned = image_v2.clone();
+        unsigned[6] = 0;
+        assert_eq!(stage(&mut ota, &unsigned, 1), Some(Error::Unsigned));
+
+        assert_eq!(
+            stage(&mut ota, &image(2, &[0; 16], &key()), 1),
+            Some(Error::NotAnApp)
+        );
+        assert_eq!(
+            stage(&mut ota, &image_v2[..image_v2.len() - 1], 1),
+            Some(Error::Download)
+        );
+        assert_eq!(
+            stage(&mut ota, &image(2, &[APP_MAGIC; 0x10001], &key()), 1),
+            Some(Error::TooLarge(0x10001))
+        );
+    }
+}
@@ -0,0 +1,386 @@
+//! Programming the rp2040 flash over SWD
+//!
+//! The rp2040 cannot be written to directly from the debug port because
+//! its flash sits behind the XIP controller. Instead core 0 is halted and
+//! made to call the flash routines in the rp2040 boot ROM, with the data
+//! staged in rp2040 RAM by the debug port.
+//!
+//! Data is collected one 4KB flash sector at a time, so segments have
+//! to be written in ascending address order.
+
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::image::{Image, Segment, FLASH_END, FLASH_START};
+use crate::swd::{self, CoreRegister, MemAp, Swd};
+
+/// Size of a flash sector, the smallest erasable unit
+pub const SECTOR_SIZE: usize = 4096;
+
+/// Address of a `bkpt` instruction that ROM calls return to
+const TRAMPOLINE: u32 = 0x2000_0000;
+/// RAM buffer that holds the sector being programmed
+const BUFFER: u32 = 0x2000_1000;
+/// Stack used while calling into the ROM
+const STACK_TOP: u32 = 0x2004_1000;
+
+/// Pointer to the ROM function table in the boot ROM
+const ROM_FUNC_TABLE: u32 = 0x14;
+
+/// Erase command used by `flash_range_erase` for 64KB blocks
+const BLOCK_ERASE_CMD: u32 = 0xd8;
+const BLOCK_SIZE: u32 = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    Swd(swd::Error),
+    Image(crate::image::Error),
+    /// A boot ROM function could not be found in the function table
+    MissingRomFunction(u16),
+    /// A segment lies outside of the rp2040 flash
+    OutOfRange(u32),
+    /// A segment goes back to a sector that has already been programmed
+    Unordered(u32),
+    /// Flash contents do not match the image at this address
+    Verify(u32),
+}
+
+impl From<swd::Error> for Error {
+    fn from(e: swd::Error) -> Self {
+        Error::Swd(e)
+    }
+}
+
+impl From<crate::image::Error> for Error {
+    fn from(e: crate::image::Error) -> Self {
+        Error::Image(e)
+    }
+}
+
+const fn rom_code(code: &[u8; 2]) -> u16 {
+    code[0] as u16 | (code[1] as u16) << 8
+}
+
+/// Boot ROM flash routines, looked up from the ROM function table
+#[derive(Clone, Copy, Debug)]
+struct RomFunctions {
+    connect_internal_flash: u32,
+    flash_exit_xip: u32,
+    flash_range_erase: u32,
+    flash_range_program: u32,
+    flash_flush_cache: u32,
+    flash_enter_cmd_xip: u32,
+}
+
+/// Walk the ROM function table looking for `code`
+fn lookup<DIO, CLK, D>(mem: &mut MemAp<DIO, CLK, D>, code: u16) -> Result<u32, Error>
+where
+    DIO: InputPin + OutputPin,
+    CLK: OutputPin,
+    D: DelayUs<u32>,
+{
+    let mut entry = mem.read_halfword(ROM_FUNC_TABLE)? as u32;
+    loop {
+        let entry_code = mem.read_halfword(entry)?;
+        if entry_code == 0 {
+            return Err(Error::MissingRomFunction(code));
+        }
+        if entry_code == code {
+            return Ok(mem.read_halfword(entry + 2)? as u32);
+        }
+        entry += 4;
+    }
+}
+
+/// Start a ROM function with up to four arguments on the halted core,
+/// it returns to the breakpoint at [`TRAMPOLINE`]
+fn start<DIO, CLK, D>(
+    mem: &mut MemAp<DIO, CLK, D>,
+    function: u32,
+    args: &[u32],
+) -> Result<(), Error>
+where
+    DIO: InputPin + OutputPin,
+    CLK: OutputPin,
+    D: DelayUs<u32>,
+{
+    let registers = [
+        CoreRegister::R0,
+        CoreRegister::R1,
+        CoreRegister::R2,
+        CoreRegister::R3,
+    ];
+    for (register, arg) in registers.iter().zip(args) {
+        mem.write_register(*register, *arg)?;
+    }
+    mem.write_register(CoreRegister::Sp, STACK_TOP)?;
+    mem.write_register(CoreRegister::Lr, TRAMPOLINE | 1)?;
+    mem.write_register(CoreRegister::Pc, function & !1)?;
+    // Thumb state
+    mem.write_register(CoreRegister::Xpsr, 1 << 24)?;
+    mem.run()?;
+    Ok(())
+}
+
+/// Reboot the rp2040 into its USB bootloader, where it shows up as a
+/// mass storage device that takes UF2 files
+pub fn reset_to_usb_boot<DIO, CLK, D>(swd: &mut Swd<DIO, CLK, D>) -> Result<(), Error>
+where
+    DIO: InputPin + OutputPin,
+    CLK: OutputPin,
+    D: DelayUs<u32>,
+{
+    swd.connect(swd::RP2040_CORE0)?;
+    let mut mem = MemAp::new(swd)?;
+    mem.halt()?;
+    let function = lookup(&mut mem, rom_code(b"UB"))?;
+    // No activity led and both the mass storage and PICOBOOT interfaces
+    start(&mut mem, function, &[0, 0])
+}
+
+pub struct Flasher<'a, DIO, CLK, D>
+where
+    DIO: InputPin + OutputPin,
+    CLK: OutputPin,
+    D: DelayUs<u32>,
+{
+    mem: MemAp<'a, DIO, CLK, D>,
+    rom: RomFunctions,
+    sector: [u8; SECTOR_SIZE],
+    current: Option<u32>,
+    last: Option<u32>,
+}
+
+impl<'a, DIO, CLK, D> Flasher<'a, DIO, CLK, D>
+where
+    DIO: InputPin + OutputPin,
+    CLK: OutputPin,
+    D: DelayUs<u32>,
+{
+    /// Connect to core 0, halt it and get the flash ready for programming
+    pub fn new(swd: &'a mut Swd<DIO, CLK, D>) -> Result<Self, Error> {
+        swd.connect(swd::RP2040_CORE0)?;
+        let mut mem = MemAp::new(swd)?;
+        mem.halt()?;
+        // bkpt #0, the core halts here when a ROM function returns
+        mem.write_word(TRAMPOLINE, 0xbe00_be00)?;
+
+        let rom = RomFunctions {
+            connect_internal_flash: lookup(&mut mem, rom_code(b"IF"))?,
+            flash_exit_xip: lookup(&mut mem, rom_code(b"EX"))?,
+            flash_range_erase: lookup(&mut mem, rom_code(b"RE"))?,
+            flash_range_program: lookup(&mut mem, rom_code(b"RP"))?,
+            flash_flush_cache: lookup(&mut mem, rom_code(b"FC"))?,
+            flash_enter_cmd_xip: lookup(&mut mem, rom_code(b"CX"))?,
+        };
+        let mut flasher = Self {
+            mem,
+            rom,
+            sector: [0xff; SECTOR_SIZE],
+            current: None,
+            last: None,
+        };
+        flasher.call(flasher.rom.connect_internal_flash, &[])?;
+        flasher.call(flasher.rom.flash_exit_xip, &[])?;
+        Ok(flasher)
+    }
+
+    /// Call a ROM function with up to four arguments and wait for it to return
+    fn call(&mut self, function: u32, args: &[u32]) -> Result<u32, Error> {
+        start(&mut self.mem, function, args)?;
+        // Erasing a 64KB block can take a while
+        self.mem.wait_for_halt(2000)?;
+        Ok(self.mem.read_register(CoreRegister::R0)?)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Some(address) = self.current.take() {
+            let offset = address - FLASH_START;
+            self.mem.write_block(BUFFER, &self.sector)?;
+            self.call(
+                self.rom.flash_range_erase,
+                &[offset, SECTOR_SIZE as u32, BLOCK_SIZE, BLOCK_ERASE_CMD],
+            )?;
+            self.call(
+                self.rom.flash_range_program,
+                &[offset, BUFFER, SECTOR_SIZE as u32],
+            )?;
+            self.last = Some(address);
+            self.sector = [0xff; SECTOR_SIZE];
+        }
+        Ok(())
+    }
+
+    /// Queue a segment for programming, sectors are written out as
+    /// soon as a segment moves past them
+    pub fn write(&mut self, segment: Segment) -> Result<(), Error> {
+        let end = segment.address as u64 + segment.data.len() as u64;
+        if segment.address < FLASH_START || end > FLASH_END as u64 {
+            return Err(Error::OutOfRange(segment.address));
+        }
+        let mut address = segment.address;
+        let mut data = segment.data;
+        while !data.is_empty() {
+            let sector = address & !(SECTOR_SIZE as u32 - 1);
+            if self.current != Some(sector) {
+                if self.last.is_some_and(|last| sector <= last)
+                    || self.current.is_some_and(|current| sector < current)
+                {
+                    return Err(Error::Unordered(address));
+                }
+                self.flush()?;
+                self.current = Some(sector);
+            }
+            let start = (address - sector) as usize;
+            let len = data.len().min(SECTOR_SIZE - start);
+            self.sector[start..start + len].copy_from_slice(&data[..len]);
+            address += len as u32;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Write out the last sector and put the flash back into XIP mode
+    pub fn finish(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.call(self.rom.flash_flush_cache, &[])?;
+        self.call(self.rom.flash_enter_cmd_xip, &[])?;
+        Ok(())
+    }
+
+    /// Compare a segment against the flash contents, the flash has
+    /// to be back in XIP mode (see [`Flasher::finish`])
+    pub fn verify(&mut self, segment: Segment) -> Result<(), Error> {
+        let mut word = [0; 4];
+        for (i, byte) in segment.data.iter().enumerate() {
+            let address = segment.address + i as u32;
+            if i == 0 || address & 0x3 == 0 {
+                word = self.mem.read_word(address & !0x3)?.to_le_bytes();
+            }
+            if word[(address & 0x3) as usize] != *byte {
+                return Err(Error::Verify(address));
+            }
+        }
+        Ok(())
+    }
+
+    /// Program and verify a whole image
+    pub fn program(&mut self, image: &Image) -> Result<(), Error> {
+        for segment in image.segments() {
+            self.write(segment?)?;
+        }
+        self.finish()?;
+        for segment in image.segments() {
+            self.verify(segment?)?;
+        }
+        Ok(())
+    }
+
+    /// Release the core through a system reset
+    pub fn release(mut self) -> Result<(), Error> {
+        self.mem.system_reset()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{NoDelay, SwdPin, SwdWire};
+
+    /// A boot ROM with its function table at 0x7a
+    fn rom(wire: &SwdWire) {
+        wire.write_bytes(ROM_FUNC_TABLE, &0x7a_u16.to_le_bytes());
+        let mut table = Vec::new();
+        for (i, code) in [b"IF", b"EX", b"RE", b"RP", b"FC", b"CX"]
+            .iter()
+            .enumerate()
+        {
+            table.extend_from_slice(&rom_code(code).to_le_bytes());
+            table.extend_from_slice(&(0x2000 + i as u16 * 0x100).to_le_bytes());
+        }
+        table.extend_from_slice(&[0, 0]);
+        wire.write_bytes(0x7a, &table);
+    }
+
+    fn flasher(swd: &mut Swd<SwdPin, SwdPin, NoDelay>) -> Flasher<'_, SwdPin, SwdPin, NoDelay> {
+        Flasher {
+            mem: MemAp::new(swd).unwrap(),
+            rom: RomFunctions {
+                connect_internal_flash: 0x2000,
+                flash_exit_xip: 0x2100,
+                flash_range_erase: 0x2200,
+                flash_range_program: 0x2300,
+                flash_flush_cache: 0x2400,
+                flash_enter_cmd_xip: 0x2500,
+            },
+            sector: [0xff; SECTOR_SIZE],
+            current: None,
+            last: None,
+        }
+    }
+
+    #[test]
+    fn looks_up_rom_functions() {
+        let wire = SwdWire::default();
+        rom(&wire);
+        let mut swd = Swd::new(wire.swdio(), wire.swclk(), NoDelay);
+        let mut mem = MemAp::new(&mut swd).unwrap();
+        assert_eq!(lookup(&mut mem, rom_code(b"IF")), Ok(0x2000));
+        assert_eq!(lookup(&mut mem, rom_code(b"RP")), Ok(0x2300));
+        assert_eq!(lookup(&mut mem, rom_code(b"CX")), Ok(0x2500));
+        assert_eq!(
+            lookup(&mut mem, rom_code(b"UB")),
+            Err(Error::MissingRomFunction(rom_code(b"UB")))
+        );
+    }
+
+    #[test]
+    fn stages_sectors_in_ram() {
+        let wire = SwdWire::default();
+        let mut swd = Swd::new(wire.swdio(), wire.swclk(), NoDelay);
+        let mut flasher = flasher(&mut swd);
+        let first = Segment {
+            address: FLASH_START + 0x10,
+            data: &[1, 2, 3, 4, 5],
+        };
+        flasher.write(first).unwrap();
+        // Nothing leaves before the segments move past the sector
+        assert_eq!(wire.word(BUFFER + 0x10), 0);
+
+        let second = Segment {
+            address: FLASH_START + SECTOR_SIZE as u32,
+            data: &[6],
+        };
+        flasher.write(second).unwrap();
+        assert_eq!(wire.word(BUFFER), 0xffff_ffff);
+        assert_eq!(wire.word(BUFFER + 0x10), 0x0403_0201);
+        assert_eq!(wire.word(BUFFER + 0x14), 0xffff_ff05);
+
+        assert_eq!(flasher.write(first), Err(Error::Unordered(first.address)));
+        let outside = Segment {
+            address: FLASH_END - 2,
+            data: &[0; 4],
+        };
+        assert_eq!(
+            flasher.write(outside),
+            Err(Error::OutOfRange(outside.address))
+        );
+    }
+
+    #[test]
+    fn release_resets_the_core() {
+        let wire = SwdWire::default();
+        let mut swd = Swd::new(wire.swdio(), wire.swclk(), NoDelay);
+        flasher(&mut swd).release().unwrap();
+        assert_eq!(
+            wire.word(swd::scs::AIRCR),
+            swd::scs::AIRCR_VECTKEY | swd::scs::AIRCR_SYSRESETREQ
+        );
+
+        let mut swd = Swd::new(wire.swdio(), wire.swclk(), NoDelay);
+        let flasher = flasher(&mut swd);
+        wire.fault(true);
+        assert_eq!(flasher.release(), Err(Error::Swd(swd::Error::Fault)));
+    }
+}
@@ -0,0 +1,511 @@
+//! HTTP/1.1 over a TCP stream
+//!
+//! Just enough of a client to download small files from a web server into
+//! a buffer: one request per connection, the response is read until its
+//! `Content-Length` or until the server closes the connection, and chunked
+//! bodies are decoded in place.
+//!
+//! The server side reads one request per connection with
+//! [`read_request`] and answers with [`write_head`] and the body, then
+//! closes the connection.
+
+use core::fmt::{self, Write as _};
+
+use embedded_io::blocking::{Read, Write};
+
+/// Longest status line and headers of a request or response
+pub const HEAD_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Reading or writing the stream failed
+    Io,
+    /// The request or response is not HTTP or ends early
+    Malformed,
+    /// The status line and headers do not fit in [`HEAD_SIZE`] bytes
+    HeadTooLarge,
+    /// The body does not fit into the buffer
+    TooLarge,
+    /// The server answered with another status than 200
+    Status(u16),
+}
+
+/// Passes formatted text on to the stream, such as a response body
+pub struct Writer<'s, S>(pub &'s mut S);
+
+impl<'s, S: Write> fmt::Write for Writer<'s, S> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Download `path` from `host` into `body`, returns the length of the
+/// body
+///
+/// `host` is sent as the `Host` header, such as `192.168.1.2:8000`. The
+/// server is asked to close the connection after the response.
+pub fn get<S: Read + Write>(
+    stream: &mut S,
+    host: &str,
+    path: fmt::Arguments<'_>,
+    body: &mut [u8],
+) -> Result<usize, Error> {
+    write!(
+        Writer(&mut *stream),
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+    )
+    .map_err(|_| Error::Io)?;
+    stream.flush().map_err(|_| Error::Io)?;
+
+    let mut head = [0_u8; HEAD_SIZE];
+    let (end, len) = read_head(stream, &mut head)?;
+    let response = Response::parse(&head[..end])?;
+    if response.status != 200 {
+        return Err(Error::Status(response.status));
+    }
+
+    let early = &head[end + 4..len];
+    let received = match response.content_length {
+        Some(length) => {
+            read_body(
+                stream,
+                early,
+                body.get_mut(..length).ok_or(Error::TooLarge)?,
+            )?;
+            length
+        }
+        None => {
+            let mut received = early.len();
+            body.get_mut(..received)
+                .ok_or(Error::TooLarge)?
+                .copy_from_slice(early);
+            let mut spare = [0_u8];
+            loop {
+                let full = received == body.len();
+                let free = if full {
+                    &mut spare[..]
+                } else {
+                    &mut body[received..]
+                };
+                match stream.read(free) {
+                    // Only a byte past the buffer shows the body does not fit
+                    Ok(count) if count > 0 && full => return Err(Error::TooLarge),
+                    Ok(count) if count > 0 => received += count,
+                    // Without a length the body ends with the connection
+                    _ => break received,
+                }
+            }
+        }
+    };
+    if response.chunked {
+        dechunk(&mut body[..received])
+    } else {
+        Ok(received)
+    }
+}
+
+/// Read the first line and the headers into `head`, returns where they
+/// end and how many bytes were read. The bytes after the end belong to
+/// the body.
+fn read_head<S: Read>(stream: &mut S, head: &mut [u8]) -> Result<(usize, usize), Error> {
+    let mut len = 0;
+    loop {
+        if let Some(end) = find(&head[..len], b"\r\n\r\n") {
+            return Ok((end, len));
+        }
+        if len == head.len() {
+            return Err(Error::HeadTooLarge);
+        }
+        match stream.read(&mut head[len..]) {
+            Ok(0) => return Err(Error::Malformed),
+            Ok(count) => len += count,
+            Err(_) => return Err(Error::Io),
+        }
+    }
+}
+
+/// Fill `body` with the bytes that came with the head and then from the
+/// stream
+pub fn read_body<S: Read>(stream: &mut S, early: &[u8], body: &mut [u8]) -> Result<(), Error> {
+    let count = early.len().min(body.len());
+    body[..count].copy_from_slice(&early[..count]);
+    stream
+        .read_exact(&mut body[count..])
+        .map_err(|_| Error::Malformed)
+}
+
+/// A request to the server
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Request<'h> {
+    pub method: &'h str,
+    /// The path without the query
+    pub path: &'h str,
+    /// What follows the `?`, empty without a query
+    pub query: &'h str,
+    pub content_length: Option<usize>,
+    /// The `Sec-WebSocket-Key` of a request for a WebSocket
+    pub websocket_key: Option<&'h str>,
+}
+
+impl<'h> Request<'h> {
+    /// Parse the request line and headers, without the empty line
+    fn parse(head: &'h [u8]) -> Result<Self, Error> {
+        let head = core::str::from_utf8(head).map_err(|_| Error::Malformed)?;
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let (Some(method), Some(target), Some(version), None) = (
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+        ) else {
+            return Err(Error::Malformed);
+        };
+        if method.is_empty() || !version.starts_with("HTTP/1.") {
+            return Err(Error::Malformed);
+        }
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let mut content_length = None;
+        let mut websocket_key = None;
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(Error::Malformed)?;
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = Some(value.trim().parse().map_err(|_| Error::Malformed)?);
+            } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+                websocket_key = Some(value.trim());
+            }
+        }
+        Ok(Self {
+            method,
+            path,
+            query,
+            content_length,
+            websocket_key,
+        })
+    }
+
+    /// The value of a query parameter, still percent-encoded
+    pub fn param(&self, name: &str) -> Option<&'h str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+            .find(|&(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Read a request into `head`, returns it with the bytes of its body that
+/// were read along
+pub fn read_request<'h, S: Read>(
+    stream: &mut S,
+    head: &'h mut [u8],
+) -> Result<(Request<'h>, &'h [u8]), Error> {
+    let (end, len) = read_head(stream, head)?;
+    let head: &'h [u8] = head;
+    Ok((Request::parse(&head[..end])?, &head[end + 4..len]))
+}
+
+/// Write the status line and headers of a response, the body follows.
+/// Without a `content_length` the body ends when the connection is
+/// closed.
+pub fn write_head<S: Write>(
+    stream: &mut S,
+    status: u16,
+    content_type: &str,
+    content_length: Option<usize>,
+) -> Result<(), Error> {
+    let mut writer = Writer(stream);
+    let mut head = || {
+        write!(writer, "HTTP/1.1 {status} {}\r\n", reason(status))?;
+        write!(writer, "Content-Type: {content_type}\r\n")?;
+        if let Some(length) = content_length {
+            write!(writer, "Content-Length: {length}\r\n")?;
+        }
+        writer.write_str("Cache-Control: no-store\r\nConnection: close\r\n\r\n")
+    };
+    head().map_err(|_| Error::Io)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        _ => "",
+    }
+}
+
+/// Decode the `%XX` escapes and `+` of a query parameter into `out`
+pub fn percent_decode<'o>(value: &str, out: &'o mut [u8]) -> Result<&'o str, Error> {
+    let bytes = value.as_bytes();
+    let mut len = 0;
+    let mut at = 0;
+    while at < bytes.len() {
+        let byte = match bytes[at] {
+            b'%' => {
+                let hex = value.get(at + 1..at + 3).ok_or(Error::Malformed)?;
+                at += 2;
+                u8::from_str_radix(hex, 16).map_err(|_| Error::Malformed)?
+            }
+            b'+' => b' ',
+            byte => byte,
+        };
+        *out.get_mut(len).ok_or(Error::TooLarge)? = byte;
+        len += 1;
+        at += 1;
+    }
+    core::str::from_utf8(&out[..len]).map_err(|_| Error::Malformed)
+}
+
+/// The parts of the response head the client needs
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Response {
+    status: u16,
+    content_length: Option<usize>,
+    chunked: bool,
+}
+
+impl Response {
+    /// Parse the status line and headers, without the empty line
+    fn parse(head: &[u8]) -> Result<Self, Error> {
+        let head = core::str::from_utf8(head).map_err(|_| Error::Malformed)?;
+        let mut lines = head.split("\r\n");
+        let mut status_line = lines.next().unwrap_or_default().split(' ');
+        if !status_line
+            .next()
+            .unwrap_or_default()
+            .starts_with("HTTP/1.")
+        {
+            return Err(Error::Malformed);
+        }
+        let status = status_line
+            .next()
+            .and_then(|code| code.parse().ok())
+            .ok_or(Error::Malformed)?;
+
+        let mut response = Self {
+            status,
+            content_length: None,
+            chunked: false,
+        };
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(Error::Malformed)?;
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                response.content_length = Some(value.parse().map_err(|_| Error::Malformed)?);
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                response.chunked = value.eq_ignore_ascii_case("chunked");
+            }
+        }
+        // The length of a chunked body is in the chunks
+        if response.chunked {
+            response.content_length = None;
+        }
+        Ok(response)
+    }
+}
+
+/// Decode a chunked body in place, returns the length of the data
+fn dechunk(body: &mut [u8]) -> Result<usize, Error> {
+    let mut read = 0;
+    let mut written = 0;
+    loop {
+        let line_end = find(&body[read..], b"\r\n").ok_or(Error::Malformed)? + read;
+        let line = core::str::from_utf8(&body[read..line_end]).map_err(|_| Error::Malformed)?;
+        // Chunk extensions follow a ';'
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| Error::Malformed)?;
+        if size == 0 {
+            return Ok(written);
+        }
+        let start = line_end + 2;
+        let end = start.checked_add(size).ok_or(Error::Malformed)?;
+        if !body.get(end..).unwrap_or_default().starts_with(b"\r\n") {
+            return Err(Error::Malformed);
+        }
+        body.copy_within(start..end, written);
+        written += size;
+        read = end + 2;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// A peer that answers with `response` and keeps what was sent
+    struct Server {
+        sent: Vec<u8>,
+        response: &'static [u8],
+        /// Bytes handed out per read, like TCP segments
+        segment: usize,
+    }
+
+    impl Server {
+        fn new(response: &'static [u8]) -> Self {
+            Self {
+                sent: Vec::new(),
+                response,
+                segment: 7,
+            }
+        }
+    }
+
+    impl embedded_io::Io for Server {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Read for Server {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let count = buf.len().min(self.segment).min(self.response.len());
+            buf[..count].copy_from_slice(&self.response[..count]);
+            self.response = &self.response[count..];
+            Ok(count)
+        }
+    }
+
+    impl Write for Server {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.sent.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn get_from(server: &mut Server) -> Result<Vec<u8>, Error> {
+        let mut body = [0; 64];
+        let len = get(
+            server,
+            "10.0.0.2:8000",
+            format_args!("/roms/{}", 3),
+            &mut body,
+        )?;
+        Ok(body[..len].to_vec())
+    }
+
+    #[test]
+    fn downloads_a_body() {
+        let mut server = Server::new(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\
+              content-length: 5\r\n\r\n\x12\x34\x56\x78\x9a",
+        );
+        assert_eq!(
+            get_from(&mut server),
+            Ok(vec![0x12, 0x34, 0x56, 0x78, 0x9a])
+        );
+        assert_eq!(
+            server.sent,
+            b"GET /roms/3 HTTP/1.1\r\nHost: 10.0.0.2:8000\r\nAccept: */*\r\n\
+              Connection: close\r\n\r\n"
+        );
+
+        // Read until the connection closes
+        let mut server = Server::new(b"HTTP/1.0 200 OK\r\n\r\n[{\"id\": 1}]");
+        assert_eq!(get_from(&mut server), Ok(b"[{\"id\": 1}]".to_vec()));
+
+        // A body that exactly fills the buffer
+        let response = [&b"HTTP/1.0 200 OK\r\n\r\n"[..], &[7; 64]].concat();
+        let mut server = Server::new(response.leak());
+        assert_eq!(get_from(&mut server), Ok(vec![7; 64]));
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let mut server = Server::new(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              4\r\nchip\r\n1;ext=1\r\n8\r\n0\r\n\r\n",
+        );
+        assert_eq!(get_from(&mut server), Ok(b"chip8".to_vec()));
+
+        let mut server = Server::new(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              ffffffffffffffff\r\nchip\r\n0\r\n\r\n",
+        );
+        assert_eq!(get_from(&mut server), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn reads_requests() {
+        let mut stream: &[u8] = b"POST /roms?name=Space+Invaders%21&x HTTP/1.1\r\n\
+              Host: 10.0.0.5\r\nContent-Length: 4\r\n\r\n\x12\x34\x56\x78";
+        let mut head = [0; HEAD_SIZE];
+        let (request, early) = read_request(&mut stream, &mut head).unwrap();
+        assert_eq!(
+            request,
+            Request {
+                method: "POST",
+                path: "/roms",
+                query: "name=Space+Invaders%21&x",
+                content_length: Some(4),
+                websocket_key: None,
+            }
+        );
+        let mut body = [0; 4];
+        read_body(&mut stream, early, &mut body).unwrap();
+        assert_eq!(body, [0x12, 0x34, 0x56, 0x78]);
+
+        let mut name = [0; 32];
+        let encoded = request.param("name").unwrap();
+        assert_eq!(percent_decode(encoded, &mut name), Ok("Space Invaders!"));
+        assert_eq!(request.param("x"), Some(""));
+        assert_eq!(request.param("y"), None);
+        assert_eq!(percent_decode("%e", &mut name), Err(Error::Malformed));
+        assert_eq!(percent_decode("%ff", &mut name), Err(Error::Malformed));
+
+        let mut stream: &[u8] = b"GET /live HTTP/1.1\r\nUpgrade: websocket\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let (request, _) = read_request(&mut stream, &mut head).unwrap();
+        assert_eq!(request.websocket_key, Some("dGhlIHNhbXBsZSBub25jZQ=="));
+
+        let mut stream: &[u8] = b"GET /\r\n\r\n";
+        assert_eq!(
+            read_request(&mut stream, &mut head).err(),
+            Some(Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn writes_responses() {
+        let mut server = Server::new(b"");
+        write_head(&mut server, 404, "text/plain", Some(12)).unwrap();
+        assert_eq!(
+            server.sent,
+            b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 12\r\n\
+              Cache-Control: no-store\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn rejects_responses() {
+        let mut server = Server::new(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(get_from(&mut server), Err(Error::Status(404)));
+        let mut server = Server::new(b"SSH-2.0-OpenSSH\r\n\r\n");
+        assert_eq!(get_from(&mut server), Err(Error::Malformed));
+        let mut server = Server::new(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n");
+        assert_eq!(get_from(&mut server), Err(Error::TooLarge));
+        let response = [&b"HTTP/1.0 200 OK\r\n\r\n"[..], &[7; 65]].concat();
+        let mut server = Server::new(response.leak());
+        assert_eq!(get_from(&mut server), Err(Error::TooLarge));
+        let mut server = Server::new(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort");
+        assert_eq!(get_from(&mut server), Err(Error::Malformed));
+        let mut server = Server::new(b"HTTP/1.1 200 OK\r\n");
+        assert_eq!(get_from(&mut server), Err(Error::Malformed));
+    }
+}
@@ -0,0 +1,427 @@
+//! Bit-banged I2C master for the UEXT connector
+//!
+//! The UEXT SDA and SCL lines (Gpio18 and Gpio21 on the esp32) are driven
+//! as open drain outputs. Driving them in software lets [`SoftI2c`] follow
+//! devices that stretch the clock, give up on devices that hold it for
+//! too long and free a bus that a device left SDA low on, for example
+//! after a reset in the middle of a read.
+//!
+//! [`SoftI2c`] implements the `embedded-hal` I2C traits, so sensor drivers
+//! can use it directly or through a shared [`I2cBus`](crate::i2c_bus::I2cBus).
+
+use core::fmt;
+
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+/// Half a clock period at 100 kHz
+const HALF_PERIOD_US: u32 = 5;
+/// How long a device may stretch the clock
+const STRETCH_TIMEOUT_US: u32 = 25_000;
+/// Clocks that free a device stuck in the middle of a byte
+const RECOVERY_CLOCKS: usize = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// No device answered to the address
+    AddressNack,
+    /// The device did not acknowledge a written byte
+    DataNack,
+    /// A device held the clock low for too long
+    Timeout,
+    /// Another master or a stuck device pulled SDA low while it was
+    /// released
+    ArbitrationLost,
+    /// SDA or SCL stayed low after a bus recovery
+    Stuck,
+}
+
+/// Addresses that answered a [`scan`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Addresses(u128);
+
+impl Addresses {
+    pub fn insert(&mut self, address: u8) {
+        self.0 |= 1 << (address & 0x7f);
+    }
+
+    pub fn contains(&self, address: u8) -> bool {
+        address < 0x80 && self.0 & 1 << address != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..0x80).filter(|address| self.contains(*address))
+    }
+}
+
+/// Hex addresses separated by spaces, like `0x3c 0x48`
+impl fmt::Display for Addresses {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, address) in self.iter().enumerate() {
+            if index > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{address:#04x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Look for devices at every address that is not reserved (0x08-0x77)
+/// by writing nothing to them
+pub fn scan<B: Write>(bus: &mut B) -> Addresses {
+    let mut found = Addresses::default();
+    for address in 0x08..0x78 {
+        if bus.write(address, &[]).is_ok() {
+            found.insert(address);
+        }
+    }
+    found
+}
+
+pub struct SoftI2c<SCL, SDA, D>
+where
+    SCL: InputPin + OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs<u32>,
+{
+    scl: SCL,
+    sda: SDA,
+    delay: D,
+    half_period_us: u32,
+}
+
+impl<SCL, SDA, D> SoftI2c<SCL, SDA, D>
+where
+    SCL: InputPin + OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs<u32>,
+{
+    /// Both pins are expected to be open drain outputs with pull ups,
+    /// the bus runs at 100 kHz and is released straight away
+    pub fn new(mut scl: SCL, mut sda: SDA, delay: D) -> Self {
+        scl.set_high().ok();
+        sda.set_high().ok();
+        Self {
+            scl,
+            sda,
+            delay,
+            half_period_us: HALF_PERIOD_US,
+        }
+    }
+
+    /// Set the length of half a clock period in microseconds
+    pub fn set_half_period_us(&mut self, half_period_us: u32) {
+        self.half_period_us = half_period_us;
+    }
+
+    /// Free the bus from a device that holds SDA low, by clocking until
+    /// it lets go and ending with a stop condition
+    pub fn recover(&mut self) -> Result<(), Error> {
+        self.sda.set_high().ok();
+        for _ in 0..RECOVERY_CLOCKS {
+            if self.sda_is_high() {
+                break;
+            }
+            self.scl.set_low().ok();
+            self.wait();
+            self.scl_high()?;
+            self.wait();
+        }
+        self.scl.set_low().ok();
+        self.wait();
+        self.sda.set_low().ok();
+        self.wait();
+        self.stop().map_err(|_| Error::Stuck)?;
+        if self.sda_is_high() {
+            Ok(())
+        } else {
+            Err(Error::Stuck)
+        }
+    }
+
+    pub fn free(self) -> (SCL, SDA, D) {
+        (self.scl, self.sda, self.delay)
+    }
+
+    /// Run a transaction and always end it with a stop condition. A
+    /// clock timeout or a lost arbitration leaves the bus in an unknown
+    /// state, so it is recovered.
+    fn transaction(&mut self, f: impl FnOnce(&mut Self) -> Result<(), Error>) -> Result<(), Error> {
+        let result = self.start().and_then(|_| f(self));
+        let result = match result {
+            Err(Error::Timeout | Error::ArbitrationLost | Error::Stuck) => result,
+            _ => self.stop().and(result),
+        };
+        if let Err(Error::Timeout | Error::ArbitrationLost) = result {
+            self.recover().ok();
+        }
+        result
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        if !self.sda_is_high() || !self.scl_is_high() {
+            self.recover()?;
+        }
+        self.sda.set_low().ok();
+        self.wait();
+        self.scl.set_low().ok();
+        Ok(())
+    }
+
+    fn repeated_start(&mut self) -> Result<(), Error> {
+        self.sda.set_high().ok();
+        self.wait();
+        self.scl_high()?;
+        self.wait();
+        self.start()
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        self.sda.set_low().ok();
+        self.wait();
+        self.scl_high()?;
+        self.wait();
+        self.sda.set_high().ok();
+        self.wait();
+        Ok(())
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), Error> {
+        if bit {
+            self.sda.set_high().ok();
+        } else {
+            self.sda.set_low().ok();
+        }
+        self.wait();
+        self.scl_high()?;
+        let lost = bit && !self.sda_is_high();
+        self.wait();
+        self.scl.set_low().ok();
+        if lost {
+            Err(Error::ArbitrationLost)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_bit(&mut self) -> Result<bool, Error> {
+        self.sda.set_high().ok();
+        self.wait();
+        self.scl_high()?;
+        let bit = self.sda_is_high();
+        self.wait();
+        self.scl.set_low().ok();
+        Ok(bit)
+    }
+
+    /// Write a byte and return whether it was acknowledged
+    fn write_byte(&mut self, byte: u8) -> Result<bool, Error> {
+        for bit in (0..8).rev() {
+            self.write_bit(byte >> bit & 1 == 1)?;
+        }
+        Ok(!self.read_bit()?)
+    }
+
+    fn read_byte(&mut self, ack: bool) -> Result<u8, Error> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = byte << 1 | self.read_bit()? as u8;
+        }
+        self.write_bit(!ack)?;
+        Ok(byte)
+    }
+
+    fn address(&mut self, address: u8, read: bool) -> Result<(), Error> {
+        match self.write_byte(address << 1 | read as u8)? {
+            true => Ok(()),
+            false => Err(Error::AddressNack),
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        for byte in bytes {
+            if !self.write_byte(*byte)? {
+                return Err(Error::DataNack);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let last = buffer.len().saturating_sub(1);
+        for (index, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.read_byte(index < last)?;
+        }
+        Ok(())
+    }
+
+    /// Release SCL and wait for devices that stretch the clock
+    fn scl_high(&mut self) -> Result<(), Error> {
+        self.scl.set_high().ok();
+        for _ in 0..STRETCH_TIMEOUT_US {
+            if self.scl_is_high() {
+                return Ok(());
+            }
+            self.delay.delay_us(1);
+        }
+        Err(Error::Timeout)
+    }
+
+    fn scl_is_high(&self) -> bool {
+        self.scl.is_high().unwrap_or(false)
+    }
+
+    fn sda_is_high(&self) -> bool {
+        self.sda.is_high().unwrap_or(false)
+    }
+
+    fn wait(&mut self) {
+        self.delay.delay_us(self.half_period_us);
+    }
+}
+
+impl<SCL, SDA, D> Write for SoftI2c<SCL, SDA, D>
+where
+    SCL: InputPin + OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs<u32>,
+{
+    type Error = Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        self.transaction(|i2c| {
+            i2c.address(address, false)?;
+            i2c.write_bytes(bytes)
+        })
+    }
+}
+
+impl<SCL, SDA, D> Read for SoftI2c<SCL, SDA, D>
+where
+    SCL: InputPin + OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs<u32>,
+{
+    type Error = Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        self.transaction(|i2c| {
+            i2c.address(address, true)?;
+            i2c.read_bytes(buffer)
+        })
+    }
+}
+
+impl<SCL, SDA, D> WriteRead for SoftI2c<SCL, SDA, D>
+where
+    SCL: InputPin + OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs<u32>,
+{
+    type Error = Error;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        self.transaction(|i2c| {
+            i2c.address(address, false)?;
+            i2c.write_bytes(bytes)?;
+            i2c.repeated_start()?;
+            i2c.address(address, true)?;
+            i2c.read_bytes(buffer)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::ToString;
+
+    use super::*;
+    use crate::testing::{I2cPin, I2cWire, NoDelay};
+
+    const ADDRESS: u8 = 0x48;
+
+    fn i2c(wire: &I2cWire) -> SoftI2c<I2cPin, I2cPin, NoDelay> {
+        SoftI2c::new(wire.scl(), wire.sda(), NoDelay)
+    }
+
+    #[test]
+    fn writes_and_reads_registers() {
+        let wire = I2cWire::new(ADDRESS);
+        let mut i2c = i2c(&wire);
+        i2c.write(ADDRESS, &[2, 0xab, 0xcd]).unwrap();
+        assert_eq!(wire.registers()[2..4], [0xab, 0xcd]);
+
+        let mut buffer = [0; 3];
+        i2c.write_read(ADDRESS, &[1], &mut buffer).unwrap();
+        assert_eq!(buffer, [0, 0xab, 0xcd]);
+        i2c.read(ADDRESS, &mut buffer[..1]).unwrap();
+        assert_eq!(buffer[0], 0);
+        assert!(wire.is_idle());
+        assert_eq!(wire.stops(), 3);
+    }
+
+    #[test]
+    fn missing_devices() {
+        let wire = I2cWire::new(ADDRESS);
+        let mut i2c = i2c(&wire);
+        assert_eq!(i2c.write(0x50, &[1]), Err(Error::AddressNack));
+        assert!(wire.is_idle());
+        assert_eq!(wire.stops(), 1);
+    }
+
+    #[test]
+    fn scans_the_bus() {
+        let wire = I2cWire::new(ADDRESS);
+        let found = scan(&mut i2c(&wire));
+        assert_eq!(found.iter().collect::<std::vec::Vec<_>>(), [ADDRESS]);
+        assert_eq!(found.to_string(), "0x48");
+        assert!(Addresses::default().is_empty());
+    }
+
+    #[test]
+    fn follows_clock_stretching() {
+        let wire = I2cWire::new(ADDRESS);
+        wire.stretch(100);
+        let mut i2c = i2c(&wire);
+        i2c.write(ADDRESS, &[0, 0x12]).unwrap();
+        let mut buffer = [0];
+        i2c.write_read(ADDRESS, &[0], &mut buffer).unwrap();
+        assert_eq!(buffer, [0x12]);
+    }
+
+    #[test]
+    fn gives_up_on_a_held_clock() {
+        let wire = I2cWire::new(ADDRESS);
+        let mut i2c = i2c(&wire);
+        wire.stretch(STRETCH_TIMEOUT_US * 2);
+        assert_eq!(i2c.write(ADDRESS, &[0, 1]), Err(Error::Timeout));
+        wire.stretch(0);
+        i2c.write(ADDRESS, &[0, 2]).unwrap();
+        assert_eq!(wire.registers()[0], 2);
+
+        wire.hold_scl(true);
+        assert_eq!(i2c.write(ADDRESS, &[0, 3]), Err(Error::Stuck));
+        wire.hold_scl(false);
+        i2c.write(ADDRESS, &[0, 4]).unwrap();
+        assert_eq!(wire.registers()[0], 4);
+    }
+
+    #[test]
+    fn recovers_a_stuck_data_line() {
+        let wire = I2cWire::new(ADDRESS);
+        let mut i2c = i2c(&wire);
+        wire.hold_sda(5);
+        i2c.write(ADDRESS, &[0, 1]).unwrap();
+        assert_eq!(wire.registers()[0], 1);
+
+        wire.hold_sda(20);
+        assert_eq!(i2c.recover(), Err(Error::Stuck));
+        assert_eq!(i2c.recover(), Ok(()));
+    }
+}
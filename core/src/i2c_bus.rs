@@ -0,0 +1,165 @@
+//! Several drivers on one I2C bus
+//!
+//! Sensor drivers usually want to own their bus. [`I2cBus`] owns it
+//! instead and hands out a [`Proxy`] per driver, which forwards every
+//! transfer to the bus. Transfers are complete transactions, so drivers
+//! cannot get in each other's way.
+//!
+//! The bus lives in a `RefCell`, so all proxies have to be used from the
+//! same context.
+
+use core::cell::RefCell;
+use core::fmt;
+
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::i2c::{self, Addresses, SoftI2c};
+use crate::shell::{self, Args, Command, Commands};
+
+pub struct I2cBus<B> {
+    bus: RefCell<B>,
+}
+
+impl<B> I2cBus<B> {
+    pub fn new(bus: B) -> Self {
+        Self {
+            bus: RefCell::new(bus),
+        }
+    }
+
+    /// A handle for one driver
+    pub fn acquire(&self) -> Proxy<'_, B> {
+        Proxy { bus: &self.bus }
+    }
+
+    /// Run `f` with the bus itself, for example to recover it
+    pub fn with<R>(&self, f: impl FnOnce(&mut B) -> R) -> R {
+        f(&mut self.bus.borrow_mut())
+    }
+
+    pub fn free(self) -> B {
+        self.bus.into_inner()
+    }
+}
+
+impl<B: Write> I2cBus<B> {
+    /// Addresses of the devices on the bus, see [`i2c::scan`]
+    pub fn scan(&self) -> Addresses {
+        i2c::scan(&mut *self.bus.borrow_mut())
+    }
+}
+
+/// `i2c` console commands
+impl<SCL, SDA, D> Commands for &I2cBus<SoftI2c<SCL, SDA, D>>
+where
+    SCL: InputPin + OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs<u32>,
+{
+    fn commands(&self) -> &'static [Command] {
+        &[
+            Command {
+                name: "i2c scan",
+                args: "",
+                help: "List the devices on the I2C bus",
+            },
+            Command {
+                name: "i2c recover",
+                args: "",
+                help: "Free an I2C bus a device holds low",
+            },
+        ]
+    }
+
+    fn run(
+        &mut self,
+        name: &str,
+        _: Args<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), shell::Error> {
+        if name == "i2c scan" {
+            match self.scan() {
+                found if found.is_empty() => _ = write!(out, "No I2C devices\r\n"),
+                found => _ = write!(out, "I2C devices: {found}\r\n"),
+            }
+            return Ok(());
+        }
+        self.with(|i2c| i2c.recover())
+            .map_err(|_| shell::Error::Failed("the I2C bus is stuck"))?;
+        _ = write!(out, "I2C bus is free\r\n");
+        Ok(())
+    }
+}
+
+/// A driver's handle to an [`I2cBus`]
+pub struct Proxy<'a, B> {
+    bus: &'a RefCell<B>,
+}
+
+impl<'a, B: Write> Write for Proxy<'a, B> {
+    type Error = B::Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.bus.borrow_mut().write(address, bytes)
+    }
+}
+
+impl<'a, B: Read> Read for Proxy<'a, B> {
+    type Error = B::Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.bus.borrow_mut().read(address, buffer)
+    }
+}
+
+impl<'a, B: WriteRead> WriteRead for Proxy<'a, B> {
+    type Error = B::Error;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.bus.borrow_mut().write_read(address, bytes, buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use super::*;
+    use crate::shell::Shell;
+    use crate::testing::{I2cWire, NoDelay};
+
+    #[test]
+    fn drivers_share_the_bus() {
+        let wire = I2cWire::new(0x48);
+        let bus = I2cBus::new(SoftI2c::new(wire.scl(), wire.sda(), NoDelay));
+        let mut first = bus.acquire();
+        let mut second = bus.acquire();
+
+        first.write(0x48, &[0, 1, 2]).unwrap();
+        let mut buffer = [0; 2];
+        second.write_read(0x48, &[0], &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2]);
+        assert!(bus.scan().contains(0x48));
+        assert_eq!(bus.with(|i2c| i2c.recover()), Ok(()));
+    }
+
+    #[test]
+    fn console_commands() {
+        let wire = I2cWire::new(0x48);
+        let bus = I2cBus::new(SoftI2c::new(wire.scl(), wire.sda(), NoDelay));
+        let mut shell = Shell::new();
+        let mut out = String::new();
+        for byte in b"i2c scan\ri2c recover\r" {
+            shell.feed(*byte, &mut [&mut &bus], &mut out);
+        }
+        assert!(out.contains("I2C devices: 0x48\r\n"));
+        assert!(out.contains("I2C bus is free\r\n"));
+    }
+}
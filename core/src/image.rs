@@ -0,0 +1,280 @@
+//! Parsing of rp2040 firmware images
+//!
+//! Both UF2 files (as produced by `elf2uf2-rs`) and ELF files (as produced
+//! by `cargo build` in the rp2040 directory) are supported. An image is
+//! turned into a list of segments, each a run of bytes at an rp2040 flash
+//! address.
+
+/// Start of the rp2040 flash in the address space
+pub const FLASH_START: u32 = 0x1000_0000;
+/// End of the largest flash the rp2040 can address
+pub const FLASH_END: u32 = 0x1100_0000;
+
+pub(crate) const UF2_MAGIC_START0: u32 = 0x0a32_4655;
+pub(crate) const UF2_MAGIC_START1: u32 = 0x9e5d_5157;
+pub(crate) const UF2_MAGIC_END: u32 = 0x0ab1_6f30;
+const UF2_FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+const UF2_FLAG_FAMILY_ID: u32 = 0x0000_2000;
+/// UF2 family id of the rp2040
+pub const UF2_FAMILY_RP2040: u32 = 0xe48b_ff56;
+/// Size of a single UF2 block
+pub const UF2_BLOCK_SIZE: usize = 512;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_32: u8 = 1;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_MACHINE_ARM: u16 = 0x28;
+const ELF_PT_LOAD: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The bytes are neither a UF2 nor an ELF file
+    UnknownFormat,
+    /// A UF2 block has bad magic numbers or payload size
+    InvalidBlock(usize),
+    /// A UF2 block is meant for a different chip family
+    WrongFamily(u32),
+    /// The ELF file is not a 32 bit little endian ARM file
+    UnsupportedElf,
+    /// A header points outside of the file
+    Truncated,
+}
+
+/// A run of bytes to be written at `address`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment<'a> {
+    pub address: u32,
+    pub data: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Image<'a> {
+    Uf2(&'a [u8]),
+    Elf(&'a [u8]),
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, Error> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(Error::Truncated)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, Error> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(Error::Truncated)
+}
+
+/// Parse a single UF2 block, returns `None` for blocks that do not
+/// target the main flash
+pub fn parse_uf2_block(index: usize, block: &[u8]) -> Result<Option<Segment<'_>>, Error> {
+    if block.len() != UF2_BLOCK_SIZE
+        || u32_at(block, 0)? != UF2_MAGIC_START0
+        || u32_at(block, 4)? != UF2_MAGIC_START1
+        || u32_at(block, 508)? != UF2_MAGIC_END
+    {
+        return Err(Error::InvalidBlock(index));
+    }
+    let flags = u32_at(block, 8)?;
+    let address = u32_at(block, 12)?;
+    let size = u32_at(block, 16)? as usize;
+    let family = u32_at(block, 28)?;
+    if size > 476 {
+        return Err(Error::InvalidBlock(index));
+    }
+    if flags & UF2_FLAG_FAMILY_ID != 0 && family != UF2_FAMILY_RP2040 {
+        return Err(Error::WrongFamily(family));
+    }
+    if flags & UF2_FLAG_NOT_MAIN_FLASH != 0 {
+        return Ok(None);
+    }
+    Ok(Some(Segment {
+        address,
+        data: &block[32..32 + size],
+    }))
+}
+
+impl<'a> Image<'a> {
+    /// Detect the format of `bytes`
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        if bytes.starts_with(&ELF_MAGIC) {
+            if bytes.get(4) != Some(&ELF_CLASS_32)
+                || bytes.get(5) != Some(&ELF_DATA_LSB)
+                || u16_at(bytes, 0x12)? != ELF_MACHINE_ARM
+            {
+                return Err(Error::UnsupportedElf);
+            }
+            Ok(Image::Elf(bytes))
+        } else if u32_at(bytes, 0) == Ok(UF2_MAGIC_START0) {
+            if bytes.len() % UF2_BLOCK_SIZE != 0 {
+                return Err(Error::Truncated);
+            }
+            Ok(Image::Uf2(bytes))
+        } else {
+            Err(Error::UnknownFormat)
+        }
+    }
+
+    /// Iterate over the segments of the image that land in flash
+    pub fn segments(&self) -> Segments<'a> {
+        Segments {
+            image: *self,
+            index: 0,
+        }
+    }
+}
+
+pub struct Segments<'a> {
+    image: Image<'a>,
+    index: usize,
+}
+
+impl<'a> Segments<'a> {
+    fn next_uf2(&mut self, bytes: &'a [u8]) -> Option<Result<Segment<'a>, Error>> {
+        loop {
+            let index = self.index;
+            let start = index * UF2_BLOCK_SIZE;
+            let block = bytes.get(start..start + UF2_BLOCK_SIZE)?;
+            self.index += 1;
+            match parse_uf2_block(index, block) {
+                Ok(Some(segment)) => return Some(Ok(segment)),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    fn next_elf(&mut self, bytes: &'a [u8]) -> Option<Result<Segment<'a>, Error>> {
+        let header = || -> Result<(usize, usize, usize), Error> {
+            Ok((
+                u32_at(bytes, 0x1c)? as usize,
+                u16_at(bytes, 0x2a)? as usize,
+                u16_at(bytes, 0x2c)? as usize,
+            ))
+        };
+        let (phoff, phentsize, phnum) = match header() {
+            Ok(header) => header,
+            Err(e) => return Some(Err(e)),
+        };
+        while self.index < phnum {
+            let ph = phoff + self.index * phentsize;
+            self.index += 1;
+            let segment = || -> Result<Option<Segment<'a>>, Error> {
+                let p_type = u32_at(bytes, ph)?;
+                let offset = u32_at(bytes, ph + 4)? as usize;
+                let address = u32_at(bytes, ph + 12)?;
+                let size = u32_at(bytes, ph + 16)? as usize;
+                if p_type != ELF_PT_LOAD || size == 0 || !(FLASH_START..FLASH_END).contains(&address)
+                {
+                    return Ok(None);
+                }
+                let data = bytes.get(offset..offset + size).ok_or(Error::Truncated)?;
+                Ok(Some(Segment { address, data }))
+            };
+            match segment() {
+                Ok(Some(segment)) => return Some(Ok(segment)),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Result<Segment<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.image {
+            Image::Uf2(bytes) => self.next_uf2(bytes),
+            Image::Elf(bytes) => self.next_elf(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::uf2_block;
+
+    /// ELF header and program headers only, the data follows them
+    fn elf(segments: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let phoff = 0x34;
+        let mut elf = vec![0; phoff + segments.len() * 32];
+        elf[..4].copy_from_slice(&ELF_MAGIC);
+        elf[4] = ELF_CLASS_32;
+        elf[5] = ELF_DATA_LSB;
+        elf[0x12..0x14].copy_from_slice(&ELF_MACHINE_ARM.to_le_bytes());
+        elf[0x1c..0x20].copy_from_slice(&(phoff as u32).to_le_bytes());
+        elf[0x2a..0x2c].copy_from_slice(&32_u16.to_le_bytes());
+        elf[0x2c..0x2e].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        for (index, (p_type, address, data)) in segments.iter().enumerate() {
+            let offset = elf.len() as u32;
+            let ph = phoff + index * 32;
+            elf[ph..ph + 4].copy_from_slice(&p_type.to_le_bytes());
+            elf[ph + 4..ph + 8].copy_from_slice(&offset.to_le_bytes());
+            elf[ph + 12..ph + 16].copy_from_slice(&address.to_le_bytes());
+            elf[ph + 16..ph + 20].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            elf.extend_from_slice(data);
+        }
+        elf
+    }
+
+    #[test]
+    fn uf2_segments() {
+        let mut uf2 = uf2_block(UF2_FLAG_FAMILY_ID, FLASH_START, &[1, 2, 3]);
+        uf2.extend(uf2_block(UF2_FLAG_NOT_MAIN_FLASH, 0x2000_0000, &[4]));
+        uf2.extend(uf2_block(0, FLASH_START + 0x100, &[5, 6]));
+        let image = Image::parse(&uf2).unwrap();
+        let segments: Result<Vec<_>, _> = image.segments().collect();
+        assert_eq!(
+            segments.unwrap(),
+            [
+                Segment {
+                    address: FLASH_START,
+                    data: &[1, 2, 3]
+                },
+                Segment {
+                    address: FLASH_START + 0x100,
+                    data: &[5, 6]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_uf2_blocks() {
+        let mut block = uf2_block(UF2_FLAG_FAMILY_ID, FLASH_START, &[]);
+        block[28..32].copy_from_slice(&0x1234_u32.to_le_bytes());
+        assert_eq!(parse_uf2_block(3, &block), Err(Error::WrongFamily(0x1234)));
+        block[508] = 0;
+        assert_eq!(parse_uf2_block(3, &block), Err(Error::InvalidBlock(3)));
+        assert_eq!(
+            Image::parse(&block[..UF2_BLOCK_SIZE - 1]).unwrap_err(),
+            Error::Truncated
+        );
+    }
+
+    #[test]
+    fn elf_segments_in_flash() {
+        let elf = elf(&[
+            (ELF_PT_LOAD, FLASH_START, &[1, 2]),
+            (ELF_PT_LOAD, 0x2000_0000, &[3]),
+            (0, FLASH_START + 0x100, &[4]),
+            (ELF_PT_LOAD, FLASH_START + 0x200, &[5]),
+        ]);
+        let image = Image::parse(&elf).unwrap();
+        let addresses: Vec<_> = image.segments().map(|s| s.unwrap().address).collect();
+        assert_eq!(addresses, [FLASH_START, FLASH_START + 0x200]);
+    }
+
+    #[test]
+    fn unknown_formats() {
+        assert_eq!(Image::parse(b"hello").unwrap_err(), Error::UnknownFormat);
+        let mut elf = elf(&[]);
+        elf[0x12] = 0x3e;
+        assert_eq!(Image::parse(&elf).unwrap_err(), Error::UnsupportedElf);
+    }
+}
@@ -0,0 +1,218 @@
+//! How the esp32 gets its IPv4 address
+//!
+//! By default DHCP hands out the address, optionally for a host name the
+//! DHCP server can register. On networks without a DHCP server the
+//! address, gateway and DNS server are fixed instead, see
+//! [`Addressing::parse`].
+
+use core::fmt;
+
+use crate::dns;
+
+/// Longest host name sent to the DHCP server
+pub const MAX_HOSTNAME: usize = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The address is not `a.b.c.d/prefix`, or is the network or
+    /// broadcast address of its subnet
+    Address,
+    /// A gateway or DNS server without a static address
+    NotStatic,
+    /// The gateway is missing, is not an address or is outside the subnet
+    Gateway,
+    /// The DNS server is not an address
+    Dns,
+    /// The host name is not a single label of letters, digits and hyphens
+    Hostname,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Address => "the static address is not a host address/prefix like 192.168.1.50/24",
+            Self::NotStatic => "a gateway or DNS server needs a static address",
+            Self::Gateway => "the gateway is missing or not an address in the subnet",
+            Self::Dns => "the DNS server is not an IPv4 address",
+            Self::Hostname => "the host name is not up to 30 letters, digits and hyphens",
+        })
+    }
+}
+
+/// A fixed address, like `192.168.1.50/24` with the gateway `192.168.1.1`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Static {
+    pub address: [u8; 4],
+    /// Length of the subnet prefix in bits
+    pub prefix: u8,
+    pub gateway: [u8; 4],
+    pub dns: Option<[u8; 4]>,
+}
+
+impl Static {
+    /// The subnet mask of the prefix
+    pub fn netmask(&self) -> [u8; 4] {
+        netmask(self.prefix)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Addressing<'a> {
+    /// Ask a DHCP server, which may register the host name
+    Dhcp {
+        hostname: Option<&'a str>,
+    },
+    Static(Static),
+}
+
+impl<'a> Addressing<'a> {
+    /// The addressing of the `STATIC_IP`, `GATEWAY`, `DNS` and `HOSTNAME`
+    /// build variables
+    ///
+    /// Without `STATIC_IP` the address comes from DHCP. With it `GATEWAY`
+    /// is needed too, `DNS` is optional and the host name is not used.
+    pub fn parse(
+        address: Option<&str>,
+        gateway: Option<&str>,
+        dns: Option<&str>,
+        hostname: Option<&'a str>,
+    ) -> Result<Self, Error> {
+        let hostname = hostname
+            .map(|name| {
+                Some(name.trim())
+                    .filter(|name| name.len() <= MAX_HOSTNAME && dns::is_label(name))
+                    .ok_or(Error::Hostname)
+            })
+            .transpose()?;
+        let Some(address) = address else {
+            if gateway.is_some() || dns.is_some() {
+                return Err(Error::NotStatic);
+            }
+            return Ok(Self::Dhcp { hostname });
+        };
+        let (address, prefix) = address
+            .trim()
+            .split_once('/')
+            .and_then(|(address, prefix)| {
+                let prefix = prefix
+                    .parse()
+                    .ok()
+                    .filter(|prefix| (1..=30).contains(prefix))?;
+                Some((dns::parse_ipv4(address)?, prefix))
+            })
+            .ok_or(Error::Address)?;
+        let host = !u32::from_be_bytes(netmask(prefix));
+        let host_part = u32::from_be_bytes(address) & host;
+        if host_part == 0 || host_part == host {
+            return Err(Error::Address);
+        }
+        let gateway = gateway
+            .and_then(|gateway| dns::parse_ipv4(gateway.trim()))
+            .filter(|&gateway| gateway != address && same_subnet(address, gateway, prefix))
+            .ok_or(Error::Gateway)?;
+        let dns = dns
+            .map(|dns| dns::parse_ipv4(dns.trim()).ok_or(Error::Dns))
+            .transpose()?;
+        Ok(Self::Static(Static {
+            address,
+            prefix,
+            gateway,
+            dns,
+        }))
+    }
+}
+
+/// The prefix is 1 to 30 bits long
+fn netmask(prefix: u8) -> [u8; 4] {
+    (u32::MAX << (32 - u32::from(prefix))).to_be_bytes()
+}
+
+fn same_subnet(a: [u8; 4], b: [u8; 4], prefix: u8) -> bool {
+    let mask = u32::from_be_bytes(netmask(prefix));
+    u32::from_be_bytes(a) & mask == u32::from_be_bytes(b) & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_static_addresses() {
+        let addressing = Addressing::parse(
+            Some("192.168.1.50/24"),
+            Some("192.168.1.1"),
+            Some("1.1.1.1"),
+            Some("ignored"),
+        );
+        let expected = Static {
+            address: [192, 168, 1, 50],
+            prefix: 24,
+            gateway: [192, 168, 1, 1],
+            dns: Some([1, 1, 1, 1]),
+        };
+        assert_eq!(addressing, Ok(Addressing::Static(expected)));
+        assert_eq!(expected.netmask(), [255, 255, 255, 0]);
+
+        let Ok(Addressing::Static(wide)) =
+            Addressing::parse(Some("10.2.3.4/12"), Some("10.15.0.1"), None, None)
+        else {
+            panic!("not static");
+        };
+        assert_eq!(wide.netmask(), [255, 240, 0, 0]);
+        assert_eq!(wide.dns, None);
+    }
+
+    #[test]
+    fn parses_dhcp() {
+        assert_eq!(
+            Addressing::parse(None, None, None, None),
+            Ok(Addressing::Dhcp { hostname: None })
+        );
+        assert_eq!(
+            Addressing::parse(None, None, None, Some("udoo-lab-3")),
+            Ok(Addressing::Dhcp {
+                hostname: Some("udoo-lab-3")
+            })
+        );
+        for hostname in ["", "udoo.lab", "udoo_key", "-udoo", &"a".repeat(31)] {
+            assert_eq!(
+                Addressing::parse(None, None, None, Some(hostname)),
+                Err(Error::Hostname)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_addresses() {
+        let gateway = Some("192.168.1.1");
+        for address in [
+            "192.168.1.50",
+            "192.168.1.50/",
+            "192.168.1.50/31",
+            "192.168.1.50/0",
+            "192.168.1/24",
+            "192.168.1.0/24",
+            "192.168.1.255/24",
+        ] {
+            assert_eq!(
+                Addressing::parse(Some(address), gateway, None, None),
+                Err(Error::Address)
+            );
+        }
+        let address = Some("192.168.1.50/24");
+        for gateway in [None, Some("192.168.2.1"), Some("192.168.1.50"), Some("gw")] {
+            assert_eq!(
+                Addressing::parse(address, gateway, None, None),
+                Err(Error::Gateway)
+            );
+        }
+        assert_eq!(
+            Addressing::parse(address, gateway, Some("dns.lab"), None),
+            Err(Error::Dns)
+        );
+        assert_eq!(
+            Addressing::parse(None, gateway, None, None),
+            Err(Error::NotStatic)
+        );
+    }
+}
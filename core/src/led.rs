@@ -0,0 +1,584 @@
+//! Status patterns for the on-board leds
+//!
+//! Every program shows what it is doing with the same [`Pattern`]s, so a
+//! blinking led means the same thing whichever firmware is running. A
+//! [`StatusLed`] plays a pattern on a [`Led`] and is ticked every
+//! [`TICK_MS`] from a timer interrupt. Leds on a PWM output can be dimmed,
+//! the others are either on or off.
+//!
+//! Either chip can set the leds of the other one by sending the
+//! [`message`] for a pattern over the serial connection.
+
+use core::fmt::Write;
+use core::str::FromStr;
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::PwmPin;
+use udoo_link::{Message, LED_PATTERN_SIZE};
+
+use crate::shell::{self, Args, Command};
+
+/// How often the status leds are updated
+pub const TICK_MS: u32 = 10;
+
+/// The on-board leds, the values are the codes the uart programs send
+/// each other
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Color {
+    /// Gpio33 of the esp32
+    Yellow = 0x1,
+    /// Gpio32 of the esp32
+    Blue = 0x2,
+    /// Gpio25 of the rp2040
+    Green = 0x3,
+}
+
+impl TryFrom<u8> for Color {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            x if x == Self::Yellow as u8 => Ok(Self::Yellow),
+            x if x == Self::Blue as u8 => Ok(Self::Blue),
+            x if x == Self::Green as u8 => Ok(Self::Green),
+            x => Err(x),
+        }
+    }
+}
+
+/// Colour names in lower case, like `yellow`
+impl FromStr for Color {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "yellow" => Ok(Self::Yellow),
+            "blue" => Ok(Self::Blue),
+            "green" => Ok(Self::Green),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+    Off,
+    On,
+    /// Fast blinking while the program starts up
+    Boot,
+    /// Slow blinking while looking for the access point
+    WifiConnecting,
+    /// Flickering while a rom or firmware image is moving
+    Transfer,
+    /// A short flash every two seconds, everything is fine
+    Heartbeat,
+    /// Fading in and out, only leds that can be dimmed fade
+    Breathing,
+    /// The code as a number of flashes followed by a pause
+    Error(u8),
+    /// A fixed brightness
+    Level(u8),
+    /// From one brightness to another over `ms`, then stays there
+    Fade {
+        from: u8,
+        to: u8,
+        ms: u16,
+    },
+}
+
+fn number<'a, T: FromStr>(words: &mut impl Iterator<Item = &'a str>) -> Option<T> {
+    words.next()?.parse().ok()
+}
+
+/// Brightness of a led that is on for `on_ms` at the start of a period
+fn blink(ms: u32, on_ms: u32) -> u8 {
+    if ms < on_ms {
+        u8::MAX
+    } else {
+        0
+    }
+}
+
+impl Pattern {
+    /// Parse a pattern typed on a console, like `heartbeat`, `error 3`,
+    /// `level 128` or `fade 0 255 1000`
+    pub fn parse<'a>(words: &mut impl Iterator<Item = &'a str>) -> Option<Self> {
+        Some(match words.next()? {
+            "off" => Pattern::Off,
+            "on" => Pattern::On,
+            "boot" => Pattern::Boot,
+            "wifi" => Pattern::WifiConnecting,
+            "transfer" => Pattern::Transfer,
+            "heartbeat" => Pattern::Heartbeat,
+            "breathing" => Pattern::Breathing,
+            "error" => Pattern::Error(number(words)?),
+            "level" => Pattern::Level(number(words)?),
+            "fade" => Pattern::Fade {
+                from: number(words)?,
+                to: number(words)?,
+                ms: number(words)?,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Length of one repetition of the pattern
+    pub fn period_ms(self) -> u32 {
+        match self {
+            Pattern::Off | Pattern::On => 1,
+            Pattern::Boot => 200,
+            Pattern::WifiConnecting => 1000,
+            Pattern::Transfer => 100,
+            Pattern::Heartbeat | Pattern::Breathing => 2000,
+            Pattern::Error(code) => code.max(1) as u32 * 500 + 1500,
+            Pattern::Level(_) => 1,
+            Pattern::Fade { ms, .. } => ms.max(1) as u32,
+        }
+    }
+
+    /// Whether the pattern starts over after [`Pattern::period_ms`]
+    pub fn repeats(self) -> bool {
+        !matches!(self, Pattern::Fade { .. })
+    }
+
+    /// Brightness `ms` milliseconds into the pattern, 0 is off and 255
+    /// fully on
+    pub fn brightness(self, ms: u32) -> u8 {
+        let ms = if self.repeats() {
+            ms % self.period_ms()
+        } else {
+            ms.min(self.period_ms())
+        };
+        match self {
+            Pattern::Off => 0,
+            Pattern::On => u8::MAX,
+            Pattern::Boot => blink(ms, 100),
+            Pattern::WifiConnecting => blink(ms, 500),
+            Pattern::Transfer => blink(ms, 50),
+            Pattern::Heartbeat => blink(ms, 100),
+            Pattern::Breathing => {
+                let half = self.period_ms() / 2;
+                let ramp = if ms < half { ms } else { 2 * half - ms };
+                (ramp * u8::MAX as u32 / half) as u8
+            }
+            Pattern::Error(code) => {
+                let flashes = code.max(1) as u32 * 500;
+                if ms < flashes {
+                    blink(ms % 500, 200)
+                } else {
+                    0
+                }
+            }
+            Pattern::Level(brightness) => brightness,
+            Pattern::Fade { from, to, .. } => {
+                let (from, to) = (from as i32, to as i32);
+                (from + (to - from) * ms as i32 / self.period_ms() as i32) as u8
+            }
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; LED_PATTERN_SIZE] {
+        match self {
+            Pattern::Off => [0x0, 0, 0, 0, 0],
+            Pattern::On => [0x1, 0, 0, 0, 0],
+            Pattern::Boot => [0x2, 0, 0, 0, 0],
+            Pattern::WifiConnecting => [0x3, 0, 0, 0, 0],
+            Pattern::Transfer => [0x4, 0, 0, 0, 0],
+            Pattern::Heartbeat => [0x5, 0, 0, 0, 0],
+            Pattern::Breathing => [0x6, 0, 0, 0, 0],
+            Pattern::Error(code) => [0x7, code, 0, 0, 0],
+            Pattern::Level(brightness) => [0x8, brightness, 0, 0, 0],
+            Pattern::Fade { from, to, ms } => {
+                let [high, low] = ms.to_be_bytes();
+                [0x9, from, to, high, low]
+            }
+        }
+    }
+
+    pub fn from_bytes(bytes: [u8; LED_PATTERN_SIZE]) -> Option<Self> {
+        Some(match bytes[0] {
+            0x0 => Pattern::Off,
+            0x1 => Pattern::On,
+            0x2 => Pattern::Boot,
+            0x3 => Pattern::WifiConnecting,
+            0x4 => Pattern::Transfer,
+            0x5 => Pattern::Heartbeat,
+            0x6 => Pattern::Breathing,
+            0x7 => Pattern::Error(bytes[1]),
+            0x8 => Pattern::Level(bytes[1]),
+            0x9 => Pattern::Fade {
+                from: bytes[1],
+                to: bytes[2],
+                ms: u16::from_be_bytes([bytes[3], bytes[4]]),
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// Ask the other chip to play `pattern` on one of its leds
+pub fn message(color: Color, pattern: Pattern) -> Message<'static> {
+    Message::Led {
+        color: color as u8,
+        pattern: pattern.to_bytes(),
+    }
+}
+
+/// The led and pattern of a `Led` message, `None` for other messages
+/// and unknown leds or patterns
+pub fn parse_message(message: &Message) -> Option<(Color, Pattern)> {
+    match *message {
+        Message::Led { color, pattern } => {
+            Some((Color::try_from(color).ok()?, Pattern::from_bytes(pattern)?))
+        }
+        _ => None,
+    }
+}
+
+/// PWM duty for `brightness` out of `max_duty`
+///
+/// The eye is more sensitive to changes of dim light, squaring the
+/// brightness makes equal steps look about equal.
+pub fn gamma(brightness: u8, max_duty: u32) -> u32 {
+    let brightness = brightness as u64;
+    (brightness * brightness * max_duty as u64 / (255 * 255)) as u32
+}
+
+/// Something that lights up
+pub trait Led {
+    fn set_brightness(&mut self, brightness: u8);
+}
+
+/// A led on a plain output pin, lit from half brightness up
+pub struct Switched<P>(pub P);
+
+impl<P: OutputPin> Led for Switched<P> {
+    fn set_brightness(&mut self, brightness: u8) {
+        if brightness >= 0x80 {
+            self.0.set_high().ok();
+        } else {
+            self.0.set_low().ok();
+        }
+    }
+}
+
+/// A led on a PWM output, the brightness is gamma corrected
+pub struct Dimmed<P>(pub P);
+
+impl<P: PwmPin<Duty = u16>> Led for Dimmed<P> {
+    fn set_brightness(&mut self, brightness: u8) {
+        let duty = gamma(brightness, self.0.get_max_duty() as u32);
+        self.0.set_duty(duty as u16);
+    }
+}
+
+/// Plays a [`Pattern`] on a led
+pub struct StatusLed<L: Led> {
+    led: L,
+    pattern: Pattern,
+    elapsed_ms: u32,
+    brightness: Option<u8>,
+}
+
+impl<L: Led> StatusLed<L> {
+    /// Start with the led off
+    pub fn new(led: L) -> Self {
+        let mut status = Self {
+            led,
+            pattern: Pattern::Off,
+            elapsed_ms: 0,
+            brightness: None,
+        };
+        status.update();
+        status
+    }
+
+    /// Switch to `pattern`, showing the pattern that is already playing
+    /// does not restart it
+    pub fn show(&mut self, pattern: Pattern) {
+        if pattern != self.pattern {
+            self.pattern = pattern;
+            self.elapsed_ms = 0;
+            self.update();
+        }
+    }
+
+    pub fn pattern(&self) -> Pattern {
+        self.pattern
+    }
+
+    /// Move the pattern on by `ms`, the led is only written when its
+    /// brightness changes
+    pub fn tick(&mut self, ms: u32) {
+        let period = self.pattern.period_ms();
+        self.elapsed_ms = if self.pattern.repeats() {
+            (self.elapsed_ms + ms) % period
+        } else {
+            (self.elapsed_ms + ms).min(period)
+        };
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let brightness = self.pattern.brightness(self.elapsed_ms);
+        if self.brightness != Some(brightness) {
+            self.led.set_brightness(brightness);
+            self.brightness = Some(brightness);
+        }
+    }
+
+    pub fn free(self) -> L {
+        self.led
+    }
+}
+
+const PATTERN_NAMES: &str = "patterns: off, on, boot, wifi, transfer, heartbeat, breathing, \
+    error <n>, level <0-255>, fade <from> <to> <ms>";
+
+/// The `led` console command, `F` shows a pattern on a led
+pub struct Commands<F: FnMut(Color, Pattern)>(pub F);
+
+impl<F: FnMut(Color, Pattern)> shell::Commands for Commands<F> {
+    fn commands(&self) -> &'static [Command] {
+        &[Command {
+            name: "led",
+            args: "<color> <pattern>",
+            help: "Show a status pattern",
+        }]
+    }
+
+    fn run(
+        &mut self,
+        _: &str,
+        mut args: Args<'_>,
+        out: &mut dyn Write,
+    ) -> Result<(), shell::Error> {
+        let color = args.parse()?;
+        let Some(pattern) = Pattern::parse(&mut args) else {
+            _ = write!(out, "{PATTERN_NAMES}\r\n");
+            return Err(shell::Error::Usage);
+        };
+        (self.0)(color, pattern);
+        _ = write!(out, "{color:?}: {pattern:?}\r\n");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use super::*;
+    use crate::shell::Shell;
+    use crate::testing::Pin;
+
+    /// Remembers every brightness it was set to
+    #[derive(Default)]
+    struct Recorder(Vec<u8>);
+
+    impl Led for Recorder {
+        fn set_brightness(&mut self, brightness: u8) {
+            self.0.push(brightness);
+        }
+    }
+
+    #[derive(Default)]
+    struct Pwm {
+        duty: u16,
+    }
+
+    impl PwmPin for Pwm {
+        type Duty = u16;
+
+        fn disable(&mut self) {}
+
+        fn enable(&mut self) {}
+
+        fn get_duty(&self) -> u16 {
+            self.duty
+        }
+
+        fn get_max_duty(&self) -> u16 {
+            1000
+        }
+
+        fn set_duty(&mut self, duty: u16) {
+            self.duty = duty;
+        }
+    }
+
+    /// Brightness over one period in steps of [`TICK_MS`]
+    fn frames(pattern: Pattern) -> Vec<u8> {
+        (0..pattern.period_ms())
+            .step_by(TICK_MS as usize)
+            .map(|ms| pattern.brightness(ms))
+            .collect()
+    }
+
+    fn flashes(pattern: Pattern) -> usize {
+        let frames = frames(pattern);
+        frames.windows(2).filter(|w| w[0] == 0 && w[1] != 0).count() + (frames[0] != 0) as usize
+    }
+
+    #[test]
+    fn colors() {
+        for color in [Color::Yellow, Color::Blue, Color::Green] {
+            assert_eq!(Color::try_from(color as u8), Ok(color));
+        }
+        assert_eq!(Color::try_from(0x7), Err(0x7));
+    }
+
+    #[test]
+    fn error_codes_flash_their_number() {
+        assert_eq!(flashes(Pattern::Error(0)), 1);
+        assert_eq!(flashes(Pattern::Error(1)), 1);
+        assert_eq!(flashes(Pattern::Error(3)), 3);
+        assert_eq!(flashes(Pattern::Heartbeat), 1);
+    }
+
+    #[test]
+    fn breathing_fades() {
+        let pattern = Pattern::Breathing;
+        assert_eq!(pattern.brightness(0), 0);
+        assert_eq!(pattern.brightness(500), 127);
+        assert_eq!(pattern.brightness(1000), 255);
+        assert_eq!(pattern.brightness(1500), 127);
+        assert_eq!(pattern.brightness(2000), 0);
+    }
+
+    #[test]
+    fn fades_and_stays() {
+        let pattern = Pattern::Fade {
+            from: 200,
+            to: 0,
+            ms: 1000,
+        };
+        assert_eq!(pattern.brightness(0), 200);
+        assert_eq!(pattern.brightness(250), 150);
+        assert_eq!(pattern.brightness(1000), 0);
+        assert_eq!(pattern.brightness(5000), 0);
+
+        let mut led = StatusLed::new(Recorder::default());
+        led.show(pattern);
+        for _ in 0..500 {
+            led.tick(TICK_MS);
+        }
+        let levels = led.free().0;
+        assert_eq!(levels.first(), Some(&0));
+        assert_eq!(levels[1], 200);
+        assert_eq!(levels.last(), Some(&0));
+        assert!(levels[1..].windows(2).all(|w| w[0] > w[1]));
+    }
+
+    #[test]
+    fn gamma_correction() {
+        assert_eq!(gamma(0, 0xffff), 0);
+        assert_eq!(gamma(255, 0xffff), 0xffff);
+        assert_eq!(gamma(128, 0xffff), 0x4080);
+        assert!(gamma(16, 1 << 13) < 40);
+    }
+
+    #[test]
+    fn dims_pwm_leds() {
+        let mut led = Dimmed(Pwm::default());
+        led.set_brightness(255);
+        assert_eq!(led.0.duty, 1000);
+        led.set_brightness(51);
+        assert_eq!(led.0.duty, 40);
+    }
+
+    #[test]
+    fn console_command() {
+        let mut shown = Vec::new();
+        let mut commands = Commands(|color, pattern| shown.push((color, pattern)));
+        let mut shell = Shell::new();
+        let mut out = String::new();
+        for byte in b"led blue error 3\rled green fade 0 255 1000\rled yellow blink\r" {
+            shell.feed(*byte, &mut [&mut commands], &mut out);
+        }
+        assert!(out.contains("usage: led <color> <pattern>"));
+        assert_eq!(
+            shown,
+            [
+                (Color::Blue, Pattern::Error(3)),
+                (
+                    Color::Green,
+                    Pattern::Fade {
+                        from: 0,
+                        to: 255,
+                        ms: 1000
+                    }
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn patterns_roundtrip() {
+        let patterns = [
+            Pattern::Off,
+            Pattern::On,
+            Pattern::Boot,
+            Pattern::WifiConnecting,
+            Pattern::Transfer,
+            Pattern::Heartbeat,
+            Pattern::Breathing,
+            Pattern::Error(5),
+            Pattern::Level(12),
+            Pattern::Fade {
+                from: 1,
+                to: 2,
+                ms: 300,
+            },
+        ];
+        for pattern in patterns {
+            assert_eq!(Pattern::from_bytes(pattern.to_bytes()), Some(pattern));
+        }
+        assert_eq!(Pattern::from_bytes([0xa, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn led_messages() {
+        let sent = message(Color::Green, Pattern::Error(2));
+        assert_eq!(
+            parse_message(&sent),
+            Some((Color::Green, Pattern::Error(2)))
+        );
+        assert_eq!(parse_message(&Message::RomEnd), None);
+        let unknown = Message::Led {
+            color: 9,
+            pattern: Pattern::On.to_bytes(),
+        };
+        assert_eq!(parse_message(&unknown), None);
+    }
+
+    #[test]
+    fn plays_patterns() {
+        let pin = Pin::default();
+        let mut led = StatusLed::new(Switched(pin.clone()));
+        led.show(Pattern::Boot);
+        for _ in 0..39 {
+            led.tick(TICK_MS);
+        }
+        // Off, then two periods of 100ms on and 100ms off
+        assert_eq!(pin.levels(), [false, true, false, true, false]);
+        led.show(Pattern::On);
+        led.show(Pattern::On);
+        led.tick(TICK_MS);
+        assert_eq!(pin.levels().len(), 6);
+        assert!(pin.is_high());
+    }
+
+    #[test]
+    fn showing_the_same_pattern_does_not_restart_it() {
+        let pin = Pin::default();
+        let mut led = StatusLed::new(Switched(pin.clone()));
+        led.show(Pattern::WifiConnecting);
+        for _ in 0..60 {
+            led.tick(TICK_MS);
+            led.show(Pattern::WifiConnecting);
+        }
+        assert!(!pin.is_high());
+    }
+}
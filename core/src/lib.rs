@@ -0,0 +1,41 @@
+//! Target independent code shared by the esp32 and rp2040 programs
+//!
+//! Nothing in here depends on a HAL. Pins, uarts and flash are handed in
+//! through the `embedded-hal` and `embedded-storage` traits, so all of it
+//! builds and runs its tests on the host with `cargo test`.
+#![cfg_attr(not(test), no_std)]
+
+pub mod catalogue;
+pub mod crash;
+pub mod dap;
+pub mod dhcp;
+pub mod dns;
+pub mod esp_ota;
+pub mod flasher;
+pub mod http;
+pub mod i2c;
+pub mod i2c_bus;
+pub mod image;
+pub mod ip_config;
+pub mod led;
+pub mod log_queue;
+pub mod logger;
+pub mod mdns;
+pub mod mqtt;
+pub mod ota;
+pub mod partition;
+pub mod rom;
+pub mod rp_control;
+pub mod rp_link;
+pub mod rp_ota;
+pub mod screen;
+pub mod sd;
+pub mod settings;
+pub mod shell;
+pub mod spi_bus;
+pub mod swd;
+pub mod tftp;
+pub mod websocket;
+
+#[cfg(test)]
+mod testing;
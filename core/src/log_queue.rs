@@ -0,0 +1,106 @@
+//! Log frames of the rp2040 waiting for the link
+//!
+//! The defmt logger of the rp2040 encodes every log call into a frame
+//! that ends with a zero byte. [`LogQueue`] holds whole frames until the
+//! program sends them to the esp32 in `Log` messages. A frame that does
+//! not fit is dropped, so the decoder on the host never sees half of one.
+
+/// `N` bytes of frames
+pub struct LogQueue<const N: usize> {
+    buffer: [u8; N],
+    /// Index of the oldest byte
+    start: usize,
+    len: usize,
+    dropped: u32,
+}
+
+impl<const N: usize> Default for LogQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LogQueue<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Frames that did not fit since the queue was created
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Queue a whole frame, returns false if it was dropped
+    pub fn push(&mut self, frame: &[u8]) -> bool {
+        if frame.len() > N - self.len {
+            self.dropped = self.dropped.wrapping_add(1);
+            return false;
+        }
+        for &byte in frame {
+            self.buffer[(self.start + self.len) % N] = byte;
+            self.len += 1;
+        }
+        true
+    }
+
+    /// Move the oldest bytes into `out`, returns how many were moved. The
+    /// bytes can end in the middle of a frame, the rest follows with the
+    /// next call.
+    pub fn take(&mut self, out: &mut [u8]) -> usize {
+        let count = self.len.min(out.len());
+        for byte in &mut out[..count] {
+            *byte = self.buffer[self.start];
+            self.start = (self.start + 1) % N;
+        }
+        self.len -= count;
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_frames_whole() {
+        let mut queue = LogQueue::<8>::new();
+        assert!(queue.push(&[1, 2, 0]));
+        assert!(queue.push(&[3, 4, 0]));
+        // Only two bytes are left
+        assert!(!queue.push(&[5, 6, 0]));
+        assert_eq!(queue.dropped(), 1);
+
+        let mut out = [0; 4];
+        assert_eq!(queue.take(&mut out), 4);
+        assert_eq!(out, [1, 2, 0, 3]);
+        // The frame wraps around the end of the buffer
+        assert!(queue.push(&[7, 8, 9, 0]));
+        assert_eq!(queue.take(&mut [0; 8][..]), 6);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn takes_in_order_across_the_end() {
+        let mut queue = LogQueue::<4>::new();
+        let mut out = [0; 4];
+        for round in 0..5_u8 {
+            assert!(queue.push(&[round, round + 1, 0]));
+            assert_eq!(queue.take(&mut out), 3);
+            assert_eq!(out[..3], [round, round + 1, 0]);
+        }
+        assert_eq!(queue.len(), 0);
+    }
+}
@@ -0,0 +1,221 @@
+//! Log records and where they go
+//!
+//! The esp32 programs log with the `log` macros. Every record becomes one
+//! line, see [`write_record`], that is sent to the selected [`Sink`]. The
+//! lines are also kept in a [`History`], so the last records can be shown
+//! on the console and the ones written while a sink was not connected are
+//! sent once it is.
+
+use core::fmt::{self, Write};
+use core::str::FromStr;
+
+use log::Level;
+
+/// Where log records go
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sink {
+    /// The USB serial port
+    Usb,
+    /// The serial port on the UEXT connector
+    Uext,
+    /// Only the history
+    Memory,
+    /// A TCP connection to a log server
+    Network,
+}
+
+impl FromStr for Sink {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "usb" => Ok(Self::Usb),
+            "uext" => Ok(Self::Uext),
+            "memory" => Ok(Self::Memory),
+            "network" => Ok(Self::Network),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Usb => "usb",
+            Self::Uext => "uext",
+            Self::Memory => "memory",
+            Self::Network => "network",
+        })
+    }
+}
+
+/// Write a record as one line, like
+/// `[   12.345] INFO  udoo_esp32::wifi: connected`
+pub fn write_record(
+    out: &mut dyn Write,
+    millis: u64,
+    level: Level,
+    target: &str,
+    args: fmt::Arguments<'_>,
+) -> fmt::Result {
+    write!(
+        out,
+        "[{:>5}.{:03}] {:<5} {}: {}\r\n",
+        millis / 1000,
+        millis % 1000,
+        level,
+        target,
+        args
+    )
+}
+
+/// The last `N` bytes of log lines
+///
+/// When it is full the oldest lines are dropped. Characters that are not
+/// ascii are stored as `?`.
+pub struct History<const N: usize> {
+    buffer: [u8; N],
+    /// Index of the oldest byte
+    start: usize,
+    len: usize,
+    /// Bytes at the end that have not been sent to the sink
+    unsent: usize,
+}
+
+impl<const N: usize> Default for History<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> History<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            start: 0,
+            len: 0,
+            unsent: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copy the bytes from `offset` on, counted from the oldest byte.
+    /// Returns how many were copied.
+    pub fn copy(&self, offset: usize, buffer: &mut [u8]) -> usize {
+        let count = self.len.saturating_sub(offset).min(buffer.len());
+        for (index, byte) in buffer[..count].iter_mut().enumerate() {
+            *byte = self.buffer[(self.start + offset + index) % N];
+        }
+        count
+    }
+
+    /// Copy the oldest bytes that were not sent yet and count them as sent
+    pub fn take_unsent(&mut self, buffer: &mut [u8]) -> usize {
+        let count = self.copy(self.len - self.unsent, buffer);
+        self.unsent -= count;
+        count
+    }
+
+    /// Count everything as sent, for sinks that are written right away
+    pub fn mark_sent(&mut self) {
+        self.unsent = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if N == 0 {
+            return;
+        }
+        if self.len == N {
+            self.drop_line();
+        }
+        self.buffer[(self.start + self.len) % N] = byte;
+        self.len += 1;
+        self.unsent += 1;
+    }
+
+    /// Drop the oldest line, or what is left of it
+    fn drop_line(&mut self) {
+        while self.len > 0 {
+            let byte = self.buffer[self.start];
+            self.start = (self.start + 1) % N;
+            self.len -= 1;
+            if byte == b'\n' {
+                break;
+            }
+        }
+        self.unsent = self.unsent.min(self.len);
+    }
+}
+
+impl<const N: usize> Write for History<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.push(if c.is_ascii() { c as u8 } else { b'?' });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use super::*;
+
+    fn contents<const N: usize>(history: &History<N>) -> String {
+        let mut buffer = [0; N];
+        let len = history.copy(0, &mut buffer);
+        String::from_utf8(buffer[..len].into()).unwrap()
+    }
+
+    #[test]
+    fn formats_records() {
+        let mut out = String::new();
+        write_record(
+            &mut out,
+            12_345,
+            Level::Warn,
+            "udoo_esp32::wifi",
+            format_args!("lost {}", "µ"),
+        )
+        .unwrap();
+        assert_eq!(out, "[   12.345] WARN  udoo_esp32::wifi: lost µ\r\n");
+        assert_eq!("uext".parse(), Ok(Sink::Uext));
+        assert_eq!(Sink::Network.to_string(), "network");
+    }
+
+    #[test]
+    fn drops_the_oldest_lines() {
+        let mut history = History::<16>::new();
+        history.write_str("one\r\ntwo\r\n").unwrap();
+        history.write_str("three µ\r\n").unwrap();
+        assert_eq!(contents(&history), "two\r\nthree ?\r\n");
+        // A line longer than the history keeps only its end
+        history.write_str("a very long line of text\r\n").unwrap();
+        assert_eq!(contents(&history), " of text\r\n");
+    }
+
+    #[test]
+    fn sends_new_lines_once() {
+        let mut history = History::<16>::new();
+        let mut buffer = [0; 4];
+        history.write_str("one\r\n").unwrap();
+        history.mark_sent();
+        history.write_str("two\r\n").unwrap();
+        assert_eq!(history.take_unsent(&mut buffer), 4);
+        assert_eq!(&buffer, b"two\r");
+        assert_eq!(history.take_unsent(&mut buffer), 1);
+        assert_eq!(history.take_unsent(&mut buffer), 0);
+        // Unsent lines that are dropped are not sent
+        history.write_str("three\r\nfour\r\nfive\r\n").unwrap();
+        assert_eq!(history.take_unsent(&mut [0; 32]), 12);
+        assert_eq!(history.len(), 12);
+    }
+}
@@ -0,0 +1,289 @@
+//! Multicast DNS (RFC 6762) and DNS service discovery (RFC 6763)
+//!
+//! Just enough to find a rom server on the LAN and to answer for the name
+//! of the board: [`query`] asks for the instances of a service, such as
+//! [`ROM_SERVICE`], and [`find_service`] picks the address, port and TXT
+//! record of one out of the responses. [`answer`] answers the questions
+//! for the address of a [`Host`] and [`announce`] tells the LAN about it
+//! unasked.
+//!
+//! Messages go to [`GROUP`] on [`PORT`], names are compared without
+//! regard to case and may be compressed.
+
+use crate::dns::{self, Header, Name, Writer, CLASS_IN, RESPONSE, TYPE_A};
+
+/// Port of all mDNS messages
+pub const PORT: u16 = 5353;
+/// The IPv4 multicast group of mDNS
+pub const GROUP: [u8; 4] = [224, 0, 0, 251];
+/// The service `rom_server.py` and `rom_web_server.py` announce, their
+/// TXT record holds the `protocol` of [`crate::rom::Protocol`]
+pub const ROM_SERVICE: &str = "_chip8rom._tcp.local";
+/// Largest mDNS message that is sent or read
+pub const MAX_MESSAGE: usize = 512;
+
+/// Seconds the answers of the board are kept by others
+const TTL: u32 = 120;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+/// The record replaces what others have cached for the name
+const CACHE_FLUSH: u16 = 0x8000;
+/// The flags of a response: an authoritative answer
+const AUTHORITATIVE: u16 = RESPONSE | 0x0400;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The message ends early or a name is broken
+    Malformed,
+    /// The message does not fit into the buffer
+    TooLarge,
+}
+
+impl From<dns::Error> for Error {
+    fn from(e: dns::Error) -> Self {
+        match e {
+            dns::Error::TooLarge => Self::TooLarge,
+            _ => Self::Malformed,
+        }
+    }
+}
+
+/// The board as it answers for its name
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Host<'n> {
+    /// The name without `.local`, such as `udoo-key-246f28a1b2c3`
+    pub name: &'n str,
+    pub address: [u8; 4],
+}
+
+/// An instance of a service found in a response
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Service<'p> {
+    pub address: [u8; 4],
+    pub port: u16,
+    /// The TXT record, strings that each start with their length
+    txt: &'p [u8],
+}
+
+impl<'p> Service<'p> {
+    /// The value of a `key=value` string of the TXT record
+    pub fn txt(&self, key: &str) -> Option<&'p str> {
+        let mut rest = self.txt;
+        while let Some((&len, tail)) = rest.split_first() {
+            let entry = tail.get(..len as usize)?;
+            rest = &tail[len as usize..];
+            let entry = core::str::from_utf8(entry).ok()?;
+            match entry.split_once('=') {
+                Some((name, value)) if name.eq_ignore_ascii_case(key) => return Some(value),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// Write a question for the instances of `service`, returns the length
+pub fn query(service: &str, out: &mut [u8]) -> Result<usize, Error> {
+    let mut writer = Writer { out, len: 0 };
+    // Id, flags, one question and no records
+    writer.put(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+    writer.name(service)?;
+    writer.u16(TYPE_PTR)?;
+    writer.u16(CLASS_IN)?;
+    Ok(writer.len)
+}
+
+/// Look through a response for an instance of `service` whose address
+/// and port are in it
+///
+/// Queries and responses without a whole instance give `None`.
+pub fn find_service<'p>(packet: &'p [u8], service: &str) -> Result<Option<Service<'p>>, Error> {
+    let header = Header::parse(packet)?;
+    if header.flags & RESPONSE == 0 {
+        return Ok(None);
+    }
+    // The instance from a PTR record of the service
+    let mut instance = Name::new();
+    for record in header.records(packet) {
+        let record = record?;
+        if record.kind == TYPE_PTR && record.name.is(service) {
+            instance = Name::read(packet, record.data_at)?.0;
+            break;
+        }
+    }
+    if instance.len == 0 {
+        return Ok(None);
+    }
+    let mut target = Name::new();
+    let mut port = None;
+    let mut txt: &[u8] = &[];
+    for record in header.records(packet) {
+        let record = record?;
+        if !record.name.is(instance.as_str()) {
+            continue;
+        }
+        match record.kind {
+            TYPE_SRV => {
+                let data = record.data(packet)?;
+                if data.len() < 7 {
+                    return Err(Error::Malformed);
+                }
+                port = Some(u16::from_be_bytes([data[4], data[5]]));
+                target = Name::read(packet, record.data_at + 6)?.0;
+            }
+            TYPE_TXT => txt = record.data(packet)?,
+            _ => {}
+        }
+    }
+    let Some(port) = port else {
+        return Ok(None);
+    };
+    for record in header.records(packet) {
+        let record = record?;
+        if record.kind == TYPE_A && record.name.is(target.as_str()) {
+            let data = record.data(packet)?;
+            let address = data.try_into().map_err(|_| Error::Malformed)?;
+            return Ok(Some(Service { address, port, txt }));
+        }
+    }
+    Ok(None)
+}
+
+/// Answer the questions of a query for the address of `host`, returns
+/// the length of the response or `None` when nothing was asked of it
+pub fn answer(packet: &[u8], host: &Host<'_>, out: &mut [u8]) -> Result<Option<usize>, Error> {
+    let header = Header::parse(packet)?;
+    if header.flags & RESPONSE != 0 {
+        return Ok(None);
+    }
+    let mut at = 12;
+    for _ in 0..header.questions {
+        let (name, end) = Name::read(packet, at)?;
+        let kind = dns::u16_at(packet, end)?;
+        at = end + 4;
+        if matches!(kind, TYPE_A | TYPE_ANY) && name.is_host(host.name) {
+            return announce(host, out).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Write a response with the address of `host` that nobody asked for,
+/// returns its length
+pub fn announce(host: &Host<'_>, out: &mut [u8]) -> Result<usize, Error> {
+    let mut writer = Writer { out, len: 0 };
+    writer.u16(0)?;
+    writer.u16(AUTHORITATIVE)?;
+    // No questions, one answer
+    writer.put(&[0, 0, 0, 1, 0, 0, 0, 0])?;
+    writer.label(host.name)?;
+    writer.name("local")?;
+    writer.u16(TYPE_A)?;
+    writer.u16(CACHE_FLUSH | CLASS_IN)?;
+    writer.put(&TTL.to_be_bytes())?;
+    writer.u16(4)?;
+    writer.put(&host.address)?;
+    Ok(writer.len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: Host = Host {
+        name: "udoo-key-246f28a1b2c3",
+        address: [192, 168, 1, 40],
+    };
+
+    /// What `rom_server.py` answers to a query, with compressed names
+    const RESPONSE: &[u8] = b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x03\
+        \x09_chip8rom\x04_tcp\x05local\x00\x00\x0c\x00\x01\x00\x00\x00\x78\x00\x0d\
+        \x0arom_server\xc0\x0c\
+        \xc0\x2c\x00\x21\x80\x01\x00\x00\x00\x78\x00\x0d\x00\x00\x00\x00\x10\xe1\
+        \x04dell\xc0\x1b\
+        \xc0\x2c\x00\x10\x80\x01\x00\x00\x00\x78\x00\x10\x0fprotocol=legacy\
+        \xc0\x4b\x00\x01\x80\x01\x00\x00\x00\x78\x00\x04\xc0\xa8\x01\x02";
+
+    #[test]
+    fn queries_services() {
+        let mut out = [0; MAX_MESSAGE];
+        let len = query(ROM_SERVICE, &mut out).unwrap();
+        assert_eq!(
+            out[..len],
+            *b"\0\0\0\0\0\x01\0\0\0\0\0\0\x09_chip8rom\x04_tcp\x05local\0\0\x0c\0\x01"
+        );
+        // A query is not an answer
+        assert_eq!(find_service(&out[..len], ROM_SERVICE), Ok(None));
+    }
+
+    #[test]
+    fn finds_services() {
+        let service = find_service(RESPONSE, "_CHIP8ROM._tcp.local")
+            .unwrap()
+            .unwrap();
+        assert_eq!(service.address, [192, 168, 1, 2]);
+        assert_eq!(service.port, 4321);
+        assert_eq!(service.txt("protocol"), Some("legacy"));
+        assert_eq!(service.txt("path"), None);
+
+        assert_eq!(find_service(RESPONSE, "_http._tcp.local"), Ok(None));
+        // Without its address
+        let len = RESPONSE.len() - 16;
+        let mut cut = RESPONSE[..len].to_vec();
+        cut[11] = 2;
+        assert_eq!(find_service(&cut, ROM_SERVICE), Ok(None));
+    }
+
+    #[test]
+    fn rejects_broken_names() {
+        let mut looped = RESPONSE.to_vec();
+        // The name of the answer points at itself
+        looped[12..14].copy_from_slice(&[0xc0, 0x0c]);
+        assert_eq!(find_service(&looped, ROM_SERVICE), Err(Error::Malformed));
+        assert_eq!(
+            find_service(&RESPONSE[..40], ROM_SERVICE),
+            Err(Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn answers_for_the_host() {
+        let mut question = [0; MAX_MESSAGE];
+        let mut writer = Writer {
+            out: &mut question,
+            len: 0,
+        };
+        writer.put(&[0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0]).unwrap();
+        writer.name("printer.local").unwrap();
+        writer.put(&[0, 1, 0, 1]).unwrap();
+        writer.name("UDOO-KEY-246f28a1b2c3.local").unwrap();
+        // Asked with the unicast response bit
+        writer.put(&[0, 1, 0x80, 1]).unwrap();
+        let len = writer.len;
+
+        let mut out = [0; MAX_MESSAGE];
+        let answered = answer(&question[..len], &HOST, &mut out).unwrap().unwrap();
+        let mut announced = [0; MAX_MESSAGE];
+        let len = announce(&HOST, &mut announced).unwrap();
+        assert_eq!(out[..answered], announced[..len]);
+        assert_eq!(
+            out[..answered],
+            *b"\0\0\x84\0\0\0\0\x01\0\0\0\0\x15udoo-key-246f28a1b2c3\x05local\0\
+               \0\x01\x80\x01\0\0\0\x78\0\x04\xc0\xa8\x01\x28"
+        );
+
+        // Other names and responses are not answered
+        writer = Writer {
+            out: &mut question,
+            len: 0,
+        };
+        writer.put(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+        writer.name("udoo-key-246f28a1b2c3.lan").unwrap();
+        writer.put(&[0, 1, 0, 1]).unwrap();
+        let len = writer.len;
+        assert_eq!(answer(&question[..len], &HOST, &mut out), Ok(None));
+        assert_eq!(answer(RESPONSE, &HOST, &mut out), Ok(None));
+    }
+}
@@ -0,0 +1,325 @@
+//! MQTT 3.1.1 packets for the telemetry of the board
+//!
+//! The esp32 connects to a broker, publishes how it is doing and takes
+//! commands from a topic. Only what it needs is here: connecting with a
+//! last will, publishing and subscribing with QoS 0 and pinging. Packets
+//! are written into and read from buffers, moving them over TCP is up to
+//! the caller.
+
+/// Port of a broker without TLS
+pub const PORT: u16 = 1883;
+/// Largest packet that is read or written
+pub const MAX_PACKET: usize = 512;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+const PROTOCOL: &[u8] = b"MQTT";
+/// Version 3.1.1
+const LEVEL: u8 = 4;
+const CLEAN_SESSION: u8 = 0x02;
+const WILL: u8 = 0x04;
+const WILL_RETAIN: u8 = 0x20;
+const PASSWORD: u8 = 0x40;
+const USER_NAME: u8 = 0x80;
+const RETAIN: u8 = 0x01;
+/// The return code of a refused subscription
+pub const SUBSCRIPTION_FAILED: u8 = 0x80;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The packet does not fit into the buffer
+    TooLarge,
+    /// The packet is not one the broker would send
+    Malformed,
+}
+
+/// What the client tells the broker when it connects
+pub struct Connect<'a> {
+    pub client_id: &'a str,
+    /// Seconds the broker waits for a packet before it gives up on the
+    /// client
+    pub keep_alive: u16,
+    /// Topic and message the broker publishes, retained, when the client
+    /// goes away without disconnecting
+    pub will: Option<(&'a str, &'a [u8])>,
+    pub user: Option<(&'a str, &'a str)>,
+}
+
+/// A packet from the broker
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Packet<'a> {
+    /// The answer to connecting, code 0 accepts the client
+    ConnAck {
+        code: u8,
+    },
+    Publish {
+        topic: &'a str,
+        payload: &'a [u8],
+    },
+    /// The answer to a subscription with the QoS granted, or
+    /// [`SUBSCRIPTION_FAILED`]
+    SubAck {
+        id: u16,
+        granted: u8,
+    },
+    PingResp,
+}
+
+pub fn connect(connect: &Connect<'_>, out: &mut [u8]) -> Result<usize, Error> {
+    let mut flags = CLEAN_SESSION;
+    let mut len = 2 + PROTOCOL.len() + 1 + 1 + 2 + 2 + connect.client_id.len();
+    if let Some((topic, message)) = connect.will {
+        flags |= WILL | WILL_RETAIN;
+        len += 2 + topic.len() + 2 + message.len();
+    }
+    if let Some((user, password)) = connect.user {
+        flags |= USER_NAME | PASSWORD;
+        len += 2 + user.len() + 2 + password.len();
+    }
+    let mut writer = Writer::new(out, CONNECT << 4, len)?;
+    writer.string(PROTOCOL)?;
+    writer.bytes(&[LEVEL, flags])?;
+    writer.bytes(&connect.keep_alive.to_be_bytes())?;
+    writer.string(connect.client_id.as_bytes())?;
+    if let Some((topic, message)) = connect.will {
+        writer.string(topic.as_bytes())?;
+        writer.string(message)?;
+    }
+    if let Some((user, password)) = connect.user {
+        writer.string(user.as_bytes())?;
+        writer.string(password.as_bytes())?;
+    }
+    Ok(writer.at)
+}
+
+/// A message with QoS 0, kept by the broker for new subscribers when
+/// `retain` is set
+pub fn publish(topic: &str, payload: &[u8], retain: bool, out: &mut [u8]) -> Result<usize, Error> {
+    let first = PUBLISH << 4 | if retain { RETAIN } else { 0 };
+    let mut writer = Writer::new(out, first, 2 + topic.len() + payload.len())?;
+    writer.string(topic.as_bytes())?;
+    writer.bytes(payload)?;
+    Ok(writer.at)
+}
+
+/// Subscribe to a topic with QoS 0, the broker answers with a
+/// [`Packet::SubAck`] for `id`
+pub fn subscribe(id: u16, topic: &str, out: &mut [u8]) -> Result<usize, Error> {
+    // The flags of SUBSCRIBE are fixed
+    let mut writer = Writer::new(out, SUBSCRIBE << 4 | 0x02, 2 + 2 + topic.len() + 1)?;
+    writer.bytes(&id.to_be_bytes())?;
+    writer.string(topic.as_bytes())?;
+    writer.bytes(&[0])?;
+    Ok(writer.at)
+}
+
+pub fn ping() -> [u8; 2] {
+    [PINGREQ << 4, 0]
+}
+
+pub fn disconnect() -> [u8; 2] {
+    [DISCONNECT << 4, 0]
+}
+
+/// The first packet of `input` and its length, `None` until all of it
+/// has arrived
+pub fn parse(input: &[u8]) -> Result<Option<(Packet<'_>, usize)>, Error> {
+    let Some(&first) = input.first() else {
+        return Ok(None);
+    };
+    // The remaining length takes up to four bytes, seven bits each
+    let mut remaining = 0;
+    let mut at = 1;
+    loop {
+        let Some(&byte) = input.get(at) else {
+            return Ok(None);
+        };
+        remaining |= usize::from(byte & 0x7f) << (7 * (at - 1));
+        at += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if at > 4 {
+            return Err(Error::Malformed);
+        }
+    }
+    let end = at + remaining;
+    let Some(body) = input.get(at..end) else {
+        return Ok(None);
+    };
+    let packet = match (first >> 4, body) {
+        (CONNACK, &[_, code]) => Packet::ConnAck { code },
+        (PUBLISH, [high, low, rest @ ..]) => {
+            let topic_len = usize::from(u16::from_be_bytes([*high, *low]));
+            let topic = rest.get(..topic_len).ok_or(Error::Malformed)?;
+            let mut payload = &rest[topic_len..];
+            // A packet id follows the topic from QoS 1 on
+            if first & 0x06 != 0 {
+                payload = payload.get(2..).ok_or(Error::Malformed)?;
+            }
+            Packet::Publish {
+                topic: core::str::from_utf8(topic).map_err(|_| Error::Malformed)?,
+                payload,
+            }
+        }
+        (SUBACK, &[high, low, granted]) => Packet::SubAck {
+            id: u16::from_be_bytes([high, low]),
+            granted,
+        },
+        (PINGRESP, []) => Packet::PingResp,
+        _ => return Err(Error::Malformed),
+    };
+    Ok(Some((packet, end)))
+}
+
+/// Writes a packet after its fixed header
+struct Writer<'o> {
+    out: &'o mut [u8],
+    at: usize,
+}
+
+impl<'o> Writer<'o> {
+    /// Start a packet whose variable header and payload are `remaining`
+    /// bytes long
+    fn new(out: &'o mut [u8], first: u8, remaining: usize) -> Result<Self, Error> {
+        let mut writer = Self { out, at: 0 };
+        writer.bytes(&[first])?;
+        let mut len = remaining;
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            if len == 0 {
+                writer.bytes(&[byte])?;
+                break;
+            }
+            writer.bytes(&[byte | 0x80])?;
+        }
+        if writer.at + remaining > writer.out.len() {
+            return Err(Error::TooLarge);
+        }
+        Ok(writer)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.at + bytes.len();
+        self.out
+            .get_mut(self.at..end)
+            .ok_or(Error::TooLarge)?
+            .copy_from_slice(bytes);
+        self.at = end;
+        Ok(())
+    }
+
+    /// Bytes after their length
+    fn string(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let len = u16::try_from(bytes.len()).map_err(|_| Error::TooLarge)?;
+        self.bytes(&len.to_be_bytes())?;
+        self.bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_packets() {
+        let mut out = [0; MAX_PACKET];
+        let len = connect(
+            &Connect {
+                client_id: "udoo",
+                keep_alive: 60,
+                will: Some(("udoo/online", b"no")),
+                user: None,
+            },
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            &out[..len],
+            b"\x10\x21\x00\x04MQTT\x04\x26\x00\x3c\x00\x04udoo\x00\x0budoo/online\x00\x02no"
+        );
+        let len = connect(
+            &Connect {
+                client_id: "udoo",
+                keep_alive: 60,
+                will: None,
+                user: Some(("lab", "secret")),
+            },
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(out[9], CLEAN_SESSION | USER_NAME | PASSWORD);
+        assert_eq!(&out[len - 13..len], b"\x00\x03lab\x00\x06secret");
+
+        let len = publish("udoo/rssi", b"-61", true, &mut out).unwrap();
+        assert_eq!(&out[..len], b"\x31\x0e\x00\x09udoo/rssi-61");
+        let len = subscribe(1, "udoo/command", &mut out).unwrap();
+        assert_eq!(&out[..len], b"\x82\x11\x00\x01\x00\x0cudoo/command\x00");
+        assert_eq!(ping(), [0xc0, 0]);
+        assert_eq!(disconnect(), [0xe0, 0]);
+
+        // The remaining length takes two bytes from 128 on
+        let len = publish("t", &[7; 200], false, &mut out).unwrap();
+        assert_eq!(out[..5], [0x30, 0xcb, 0x01, 0x00, 0x01]);
+        assert_eq!(len, 3 + 203);
+        assert_eq!(
+            publish("t", &[0; MAX_PACKET], false, &mut out),
+            Err(Error::TooLarge)
+        );
+    }
+
+    #[test]
+    fn reads_packets() {
+        assert_eq!(
+            parse(b"\x20\x02\x00\x00"),
+            Ok(Some((Packet::ConnAck { code: 0 }, 4)))
+        );
+        let input = b"\x30\x16\x00\x0cudoo/commandrp reset\x90\x03\x00\x01\x00";
+        assert_eq!(
+            parse(input),
+            Ok(Some((
+                Packet::Publish {
+                    topic: "udoo/command",
+                    payload: b"rp reset"
+                },
+                24
+            )))
+        );
+        assert_eq!(
+            parse(&input[24..]),
+            Ok(Some((Packet::SubAck { id: 1, granted: 0 }, 5)))
+        );
+        assert_eq!(parse(b"\xd0\x00"), Ok(Some((Packet::PingResp, 2))));
+
+        // QoS 1 carries a packet id
+        assert_eq!(
+            parse(b"\x32\x07\x00\x01t\x00\x05on"),
+            Ok(Some((
+                Packet::Publish {
+                    topic: "t",
+                    payload: b"on"
+                },
+                9
+            )))
+        );
+    }
+
+    #[test]
+    fn waits_for_whole_packets() {
+        assert_eq!(parse(b""), Ok(None));
+        assert_eq!(parse(b"\x30"), Ok(None));
+        assert_eq!(parse(b"\x30\x80"), Ok(None));
+        assert_eq!(parse(b"\x30\x05\x00\x01t"), Ok(None));
+        assert_eq!(parse(b"\x30\xff\xff\xff\xff\x01"), Err(Error::Malformed));
+        assert_eq!(parse(b"\x20\x01\x00"), Err(Error::Malformed));
+        assert_eq!(parse(b"\x30\x03\x00\x05t"), Err(Error::Malformed));
+    }
+}
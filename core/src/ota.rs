@@ -0,0 +1,202 @@
+//! Header of the firmware images served for over the air updates
+//!
+//! Images are packed by `src/pack_firmware.py` and start with a fixed
+//! size little endian header:
+//!
+//! |Offset|Size|Field|
+//! |---|---|---|
+//! |0 |4 |Magic `UKFW`|
+//! |4 |1 |Header format version|
+//! |5 |1 |Target chip, 0 for the rp2040 and 1 for the esp32|
+//! |6 |2 |Flags, bit 0 is set for signed images|
+//! |8 |4 |Firmware version|
+//! |12|4 |Payload length|
+//! |16|32|SHA-256 of the payload|
+//!
+//! Signed images have a 64 byte Ed25519 signature of the header between
+//! the header and the payload. Since the header holds the SHA-256 of the
+//! payload the signature covers the whole image.
+
+pub const HEADER_SIZE: usize = 48;
+pub const MAGIC: [u8; 4] = *b"UKFW";
+pub const SIGNATURE_SIZE: usize = 64;
+pub const PUBLIC_KEY_SIZE: usize = 32;
+const FORMAT_VERSION: u8 = 1;
+const FLAG_SIGNED: u16 = 0x1;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Rp2040 = 0x0,
+    Esp32 = 0x1,
+}
+
+impl TryFrom<u8> for Target {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            x if x == Self::Rp2040 as u8 => Ok(Self::Rp2040),
+            x if x == Self::Esp32 as u8 => Ok(Self::Esp32),
+            x => Err(Error::Target(x)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The image does not start with `UKFW`
+    Magic,
+    /// The header was written by a newer packing tool
+    Format(u8),
+    /// Unknown target chip
+    Target(u8),
+    /// The signature does not match the header and public key
+    Signature,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub target: Target,
+    /// A signature follows the header
+    pub signed: bool,
+    pub version: u32,
+    pub length: u32,
+    pub sha256: [u8; 32],
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8; HEADER_SIZE]) -> Result<Self, Error> {
+        if bytes[0..4] != MAGIC {
+            return Err(Error::Magic);
+        }
+        if bytes[4] != FORMAT_VERSION {
+            return Err(Error::Format(bytes[4]));
+        }
+        let mut sha256 = [0; 32];
+        sha256.copy_from_slice(&bytes[16..48]);
+        Ok(Self {
+            target: Target::try_from(bytes[5])?,
+            signed: u16::from_le_bytes([bytes[6], bytes[7]]) & FLAG_SIGNED != 0,
+            version: u32_at(bytes, 8),
+            length: u32_at(bytes, 12),
+            sha256,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4] = FORMAT_VERSION;
+        bytes[5] = self.target as u8;
+        if self.signed {
+            bytes[6..8].copy_from_slice(&FLAG_SIGNED.to_le_bytes());
+        }
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.length.to_le_bytes());
+        bytes[16..48].copy_from_slice(&self.sha256);
+        bytes
+    }
+}
+
+/// Check the Ed25519 signature of a header
+pub fn verify_signature(
+    header: &[u8; HEADER_SIZE],
+    signature: &[u8; SIGNATURE_SIZE],
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+) -> Result<(), Error> {
+    let public_key = ed25519_compact::PublicKey::new(*public_key);
+    let signature = ed25519_compact::Signature::new(*signature);
+    public_key
+        .verify(header, &signature)
+        .map_err(|_| Error::Signature)
+}
+
+/// Parse a public key given as 64 hex digits, like the one printed by
+/// `pack_firmware.py keygen`
+pub fn parse_public_key(hex: &str) -> Option<[u8; PUBLIC_KEY_SIZE]> {
+    let hex = hex.trim().as_bytes();
+    if hex.len() != PUBLIC_KEY_SIZE * 2 {
+        return None;
+    }
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    let mut key = [0; PUBLIC_KEY_SIZE];
+    for (byte, pair) in key.iter_mut().zip(hex.chunks(2)) {
+        *byte = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_compact::{KeyPair, Seed};
+
+    fn header() -> Header {
+        Header {
+            target: Target::Esp32,
+            signed: true,
+            version: 7,
+            length: 0x1234,
+            sha256: [0x5a; 32],
+        }
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let bytes = header().to_bytes();
+        assert_eq!(&bytes[0..8], b"UKFW\x01\x01\x01\x00");
+        assert_eq!(Header::parse(&bytes), Ok(header()));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut bytes = header().to_bytes();
+        bytes[0] = b'X';
+        assert_eq!(Header::parse(&bytes), Err(Error::Magic));
+
+        let mut bytes = header().to_bytes();
+        bytes[4] = 2;
+        assert_eq!(Header::parse(&bytes), Err(Error::Format(2)));
+
+        let mut bytes = header().to_bytes();
+        bytes[5] = 9;
+        assert_eq!(Header::parse(&bytes), Err(Error::Target(9)));
+    }
+
+    #[test]
+    fn signatures() {
+        let key = KeyPair::from_seed(Seed::new([3; 32]));
+        let bytes = header().to_bytes();
+        let signature = key.sk.sign(bytes, None);
+
+        assert_eq!(verify_signature(&bytes, &signature, &key.pk), Ok(()));
+
+        let mut tampered = bytes;
+        tampered[8] += 1;
+        assert_eq!(
+            verify_signature(&tampered, &signature, &key.pk),
+            Err(Error::Signature)
+        );
+    }
+
+    #[test]
+    fn public_keys() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF\n";
+        let key = parse_public_key(hex).unwrap();
+        assert_eq!(key[..4], [0x00, 0x11, 0x22, 0x33]);
+        assert_eq!(key[31], 0xff);
+
+        assert_eq!(parse_public_key("0011"), None);
+        assert_eq!(parse_public_key(&"zz".repeat(PUBLIC_KEY_SIZE)), None);
+    }
+}
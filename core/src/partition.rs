@@ -0,0 +1,113 @@
+//! Reading the esp32 partition table
+//!
+//! The table is written by `espflash` at 0x8000 from `partitions.csv`
+//! and is made of 32 byte entries, each starting with the magic bytes
+//! `0xaa 0x50`.
+
+use embedded_storage::ReadStorage;
+
+/// Flash offset of the partition table
+pub const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+/// Space reserved for the partition table
+pub const PARTITION_TABLE_SIZE: usize = 0xc00;
+
+pub const TYPE_APP: u8 = 0x00;
+pub const TYPE_DATA: u8 = 0x01;
+
+/// Data partition holding the selected ota app partition
+pub const SUBTYPE_OTA_DATA: u8 = 0x00;
+/// First ota app partition, `ota_1` is 0x11 and so on
+pub const SUBTYPE_OTA_0: u8 = 0x10;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_MAGIC: [u8; 2] = [0xaa, 0x50];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Partition {
+    pub kind: u8,
+    pub subtype: u8,
+    pub offset: u32,
+    pub size: u32,
+    label: [u8; 16],
+}
+
+impl Partition {
+    fn parse(entry: &[u8]) -> Option<Self> {
+        if entry[0..2] != ENTRY_MAGIC {
+            return None;
+        }
+        let mut label = [0; 16];
+        label.copy_from_slice(&entry[12..28]);
+        Some(Self {
+            kind: entry[2],
+            subtype: entry[3],
+            offset: u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+            size: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+            label,
+        })
+    }
+
+    pub fn label(&self) -> &str {
+        let len = self.label.iter().position(|&b| b == 0).unwrap_or(16);
+        core::str::from_utf8(&self.label[..len]).unwrap_or("")
+    }
+}
+
+pub struct PartitionTable {
+    bytes: [u8; PARTITION_TABLE_SIZE],
+}
+
+impl PartitionTable {
+    pub fn read<S: ReadStorage>(storage: &mut S) -> Result<Self, S::Error> {
+        let mut bytes = [0; PARTITION_TABLE_SIZE];
+        storage.read(PARTITION_TABLE_OFFSET, &mut bytes)?;
+        Ok(Self { bytes })
+    }
+
+    pub fn from_bytes(bytes: [u8; PARTITION_TABLE_SIZE]) -> Self {
+        Self { bytes }
+    }
+
+    /// Iterate over the entries up to the end of the table
+    pub fn iter(&self) -> impl Iterator<Item = Partition> + '_ {
+        self.bytes.chunks(ENTRY_SIZE).map_while(Partition::parse)
+    }
+
+    pub fn find(&self, label: &str) -> Option<Partition> {
+        self.iter().find(|p| p.label() == label)
+    }
+
+    pub fn find_type(&self, kind: u8, subtype: u8) -> Option<Partition> {
+        self.iter().find(|p| p.kind == kind && p.subtype == subtype)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RamFlash;
+
+    #[test]
+    fn finds_partitions() {
+        let mut flash = RamFlash::new(0x10000).with_partitions(&[
+            (TYPE_DATA, 0x02, 0x9000, 0x4000, "nvs"),
+            (TYPE_DATA, SUBTYPE_OTA_DATA, 0xd000, 0x2000, "otadata"),
+            (TYPE_APP, SUBTYPE_OTA_0, 0x10000, 0x180000, "ota_0"),
+        ]);
+        let table = PartitionTable::read(&mut flash).unwrap();
+
+        assert_eq!(table.iter().count(), 3);
+        let nvs = table.find("nvs").unwrap();
+        assert_eq!((nvs.offset, nvs.size), (0x9000, 0x4000));
+        let ota_0 = table.find_type(TYPE_APP, SUBTYPE_OTA_0).unwrap();
+        assert_eq!(ota_0.label(), "ota_0");
+        assert_eq!(table.find("ota_1"), None);
+    }
+
+    #[test]
+    fn stops_at_the_first_blank_entry() {
+        let mut bytes = [0xff; PARTITION_TABLE_SIZE];
+        bytes[32..34].copy_from_slice(&ENTRY_MAGIC);
+        assert_eq!(PartitionTable::from_bytes(bytes).iter().count(), 0);
+    }
+}
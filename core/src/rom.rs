@@ -0,0 +1,290 @@
+//! Roms on their way from the rom server to the chip8 interpreter
+//!
+//! The esp32 asks the rom server for roms with a four byte [`Request`]
+//! and passes them on to the rp2040 as `RomBegin`, `RomData` and `RomEnd`
+//! messages, which the rp2040 collects with a [`RomLoader`]. A web server
+//! can stand in for the rom server, see [`crate::catalogue`].
+
+use core::fmt;
+use core::str::FromStr;
+
+use udoo_link::Message;
+
+use crate::catalogue::NAME_SIZE;
+use crate::ota::Target;
+
+/// Largest program that fits in the Chip8 memory after 0x200
+pub const MAX_ROM_SIZE: usize = 0x1000 - 0x200;
+
+/// Request sent to the rom server, see `esp32/src/rom_server.py`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Request {
+    /// The number of roms followed by the id and name of each
+    RomList,
+    /// The size of the rom followed by the rom
+    Rom(u16),
+    /// A packed firmware image for the target
+    Firmware(Target),
+}
+
+impl Request {
+    /// Two bytes of argument, a zero byte and the command
+    pub fn to_bytes(self) -> [u8; 4] {
+        match self {
+            Request::RomList => [0x0, 0x0, 0x0, 0x1],
+            Request::Rom(id) => {
+                let [high, low] = id.to_be_bytes();
+                [high, low, 0x0, 0x2]
+            }
+            Request::Firmware(target) => [0x0, target as u8, 0x0, 0x3],
+        }
+    }
+}
+
+/// How the esp32 gets its roms
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// [`Request`]s to `rom_server.py`
+    Legacy,
+    /// The [`crate::catalogue`] of a web server
+    Http,
+    /// The files of a [`crate::tftp`] server
+    Tftp,
+}
+
+impl FromStr for Protocol {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "legacy" => Ok(Self::Legacy),
+            "http" => Ok(Self::Http),
+            "tftp" => Ok(Self::Tftp),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Legacy => "legacy",
+            Self::Http => "http",
+            Self::Tftp => "tftp",
+        })
+    }
+}
+
+/// Collects a rom sent over the link
+pub struct RomLoader {
+    buffer: [u8; MAX_ROM_SIZE],
+    size: Option<usize>,
+    complete: bool,
+}
+
+impl Default for RomLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RomLoader {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; MAX_ROM_SIZE],
+            size: None,
+            complete: false,
+        }
+    }
+
+    /// Feed a message received from the esp32, other messages than rom
+    /// transfers are ignored. A rom that is too large is dropped.
+    pub fn handle(&mut self, message: &Message) {
+        match *message {
+            Message::RomBegin(size) => {
+                let size = size as usize;
+                self.size = (size <= MAX_ROM_SIZE).then_some(size);
+                self.complete = false;
+            }
+            Message::RomData { offset, data } => {
+                let offset = offset as usize;
+                if let Some(slice) = self.buffer.get_mut(offset..offset + data.len()) {
+                    slice.copy_from_slice(data);
+                }
+            }
+            Message::RomEnd => self.complete = self.size.is_some(),
+            _ => {}
+        }
+    }
+
+    /// The rom once it has been received completely, returned only once
+    pub fn take(&mut self) -> Option<&[u8]> {
+        if !self.complete {
+            return None;
+        }
+        self.complete = false;
+        self.size.map(|size| &self.buffer[..size])
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CachedRom {
+    id: u16,
+    name: [u8; NAME_SIZE],
+    name_len: usize,
+    data: [u8; MAX_ROM_SIZE],
+    size: usize,
+}
+
+/// Roms kept in RAM, such as the ones uploaded to the web server of the
+/// esp32
+pub struct RomCache<const N: usize> {
+    /// Oldest first, the free slots are at the end
+    roms: [Option<CachedRom>; N],
+    next_id: u16,
+}
+
+impl<const N: usize> Default for RomCache<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> RomCache<N> {
+    pub const fn new() -> Self {
+        Self {
+            roms: [None; N],
+            next_id: 0,
+        }
+    }
+
+    /// Keep a rom and return its id, the oldest rom makes room when the
+    /// cache is full. A rom larger than [`MAX_ROM_SIZE`] is not kept.
+    /// A long name is cut off.
+    pub fn insert(&mut self, name: &str, rom: &[u8]) -> Option<u16> {
+        if rom.len() > MAX_ROM_SIZE || N == 0 {
+            return None;
+        }
+        let slot = match self.roms.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                self.roms.rotate_left(1);
+                N - 1
+            }
+        };
+        let mut name_len = name.len().min(NAME_SIZE);
+        while !name.is_char_boundary(name_len) {
+            name_len -= 1;
+        }
+        let mut cached = CachedRom {
+            id: self.next_id,
+            name: [0; NAME_SIZE],
+            name_len,
+            data: [0; MAX_ROM_SIZE],
+            size: rom.len(),
+        };
+        cached.name[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+        cached.data[..rom.len()].copy_from_slice(rom);
+        self.roms[slot] = Some(cached);
+        self.next_id = self.next_id.wrapping_add(1);
+        Some(cached.id)
+    }
+
+    /// The name and contents of a rom
+    pub fn get(&self, id: u16) -> Option<(&str, &[u8])> {
+        self.roms
+            .iter()
+            .flatten()
+            .find(|rom| rom.id == id)
+            .map(|rom| (rom_name(rom), &rom.data[..rom.size]))
+    }
+
+    /// The id and name of each rom, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.roms
+            .iter()
+            .flatten()
+            .map(|rom| (rom.id, rom_name(rom)))
+    }
+}
+
+fn rom_name(rom: &CachedRom) -> &str {
+    // `insert` only cuts off between characters
+    core::str::from_utf8(&rom.name[..rom.name_len]).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        assert_eq!(Request::RomList.to_bytes(), [0, 0, 0, 1]);
+        assert_eq!(Request::Rom(0x0102).to_bytes(), [1, 2, 0, 2]);
+        assert_eq!(
+            Request::Firmware(Target::Esp32).to_bytes(),
+            [0, Target::Esp32 as u8, 0, 3]
+        );
+    }
+
+    #[test]
+    fn caches_roms() {
+        let mut cache = RomCache::<2>::new();
+        assert_eq!(cache.insert("PONG", &[1, 2]), Some(0));
+        assert_eq!(cache.insert("TETRIS", &[3]), Some(1));
+        assert_eq!(cache.get(0), Some(("PONG", &[1, 2][..])));
+        // The oldest rom makes room
+        assert_eq!(cache.insert("ÜFO", &[4, 5, 6]), Some(2));
+        assert_eq!(cache.get(0), None);
+        assert_eq!(
+            cache.iter().collect::<Vec<_>>(),
+            [(1, "TETRIS"), (2, "ÜFO")]
+        );
+        assert_eq!(cache.insert("BIG", &[0; MAX_ROM_SIZE + 1]), None);
+        let long = "ü".repeat(NAME_SIZE);
+        let id = cache.insert(&long, &[7]).unwrap();
+        assert_eq!(cache.get(id).unwrap().0.len(), NAME_SIZE);
+    }
+
+    #[test]
+    fn loads_a_rom() {
+        let mut loader = RomLoader::new();
+        loader.handle(&Message::RomBegin(5));
+        loader.handle(&Message::RomData {
+            offset: 0,
+            data: &[1, 2, 3],
+        });
+        assert_eq!(loader.take(), None);
+        loader.handle(&Message::RomData {
+            offset: 3,
+            data: &[4, 5],
+        });
+        loader.handle(&Message::RomEnd);
+        assert_eq!(loader.take(), Some(&[1, 2, 3, 4, 5][..]));
+        assert_eq!(loader.take(), None);
+    }
+
+    #[test]
+    fn drops_oversized_roms() {
+        let mut loader = RomLoader::new();
+        loader.handle(&Message::RomBegin(MAX_ROM_SIZE as u16 + 1));
+        loader.handle(&Message::RomEnd);
+        assert_eq!(loader.take(), None);
+    }
+
+    #[test]
+    fn ignores_data_outside_the_buffer() {
+        let mut loader = RomLoader::new();
+        loader.handle(&Message::RomBegin(2));
+        loader.handle(&Message::RomData {
+            offset: MAX_ROM_SIZE as u16 - 1,
+            data: &[1, 2],
+        });
+        loader.handle(&Message::RomData {
+            offset: 0,
+            data: &[7, 8],
+        });
+        loader.handle(&Message::RomEnd);
+        assert_eq!(loader.take(), Some(&[7, 8][..]));
+    }
+}
@@ -0,0 +1,375 @@
+//! Resetting the rp2040 and keeping an eye on it
+//!
+//! Gpio23 on the esp32 drives the rp2040 reset pin. [`RpControl`] resets
+//! the rp2040, holds it in reset or reboots it into its USB bootloader.
+//! [`Watchdog`] sends heartbeat pings over the serial connection and
+//! power cycles the rp2040 when it stops answering. The rp2040 supply is
+//! not switchable from the esp32, so a power cycle is a long reset pulse.
+
+use core::fmt::{self, Write};
+
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal::serial::{Read as SerialRead, Write as SerialWrite};
+use udoo_link::Message;
+
+use crate::flasher;
+use crate::rp_link::RpLink;
+use crate::shell::{self, Args, Command, Commands};
+use crate::swd::Swd;
+
+/// How long the reset line is held low for a reset
+const RESET_PULSE_MS: u32 = 10;
+/// How long the reset line is held low for a power cycle
+const POWER_CYCLE_MS: u32 = 500;
+/// Answers to this many of the latest pings count as heartbeats
+const LATE_PONGS: u16 = 4;
+
+/// What the rp2040 boots into after a reset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootMode {
+    /// The firmware in its flash
+    Flash,
+    /// The USB bootloader, as if BOOTSEL was held
+    Usb,
+}
+
+pub struct RpControl<P, D>
+where
+    P: OutputPin,
+    D: DelayMs<u32>,
+{
+    reset: P,
+    delay: D,
+    held: bool,
+}
+
+impl<P, D> RpControl<P, D>
+where
+    P: OutputPin,
+    D: DelayMs<u32>,
+{
+    /// Take over the reset pin and let the rp2040 run
+    pub fn new(mut reset: P, delay: D) -> Self {
+        reset.set_high().ok();
+        Self {
+            reset,
+            delay,
+            held: false,
+        }
+    }
+
+    /// Pulse the reset line, the rp2040 boots from its flash
+    pub fn reset_rp2040(&mut self) {
+        self.pulse(RESET_PULSE_MS);
+    }
+
+    /// Keep the rp2040 in reset until [`RpControl::release`]
+    pub fn hold_in_reset(&mut self) {
+        self.reset.set_low().ok();
+        self.held = true;
+    }
+
+    /// Let the rp2040 boot after [`RpControl::hold_in_reset`]
+    pub fn release(&mut self) {
+        self.reset.set_high().ok();
+        self.held = false;
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    /// Hold the rp2040 in reset long enough for it to come back up
+    /// as if its power had been cycled
+    pub fn power_cycle(&mut self) {
+        self.pulse(POWER_CYCLE_MS);
+    }
+
+    /// Reset the rp2040 into `mode`, the USB bootloader is started over
+    /// SWD so Gpio5 has to select the internal SWD connection
+    pub fn boot<DIO, CLK, SD>(
+        &mut self,
+        mode: BootMode,
+        swd: &mut Swd<DIO, CLK, SD>,
+    ) -> Result<(), flasher::Error>
+    where
+        DIO: InputPin + OutputPin,
+        CLK: OutputPin,
+        SD: DelayUs<u32>,
+    {
+        match mode {
+            BootMode::Flash => {
+                self.reset_rp2040();
+                Ok(())
+            }
+            BootMode::Usb => {
+                if self.held {
+                    self.release();
+                }
+                flasher::reset_to_usb_boot(swd)
+            }
+        }
+    }
+
+    fn pulse(&mut self, ms: u32) {
+        self.reset.set_low().ok();
+        self.delay.delay_ms(ms);
+        self.reset.set_high().ok();
+        self.held = false;
+    }
+
+    pub fn free(self) -> (P, D) {
+        (self.reset, self.delay)
+    }
+}
+
+/// `rp` console commands
+impl<P, D> Commands for RpControl<P, D>
+where
+    P: OutputPin,
+    D: DelayMs<u32>,
+{
+    fn commands(&self) -> &'static [Command] {
+        &[
+            Command {
+                name: "rp reset",
+                args: "",
+                help: "Reset the rp2040",
+            },
+            Command {
+                name: "rp hold",
+                args: "",
+                help: "Hold the rp2040 in reset",
+            },
+            Command {
+                name: "rp release",
+                args: "",
+                help: "Let the rp2040 run again",
+            },
+        ]
+    }
+
+    fn run(&mut self, name: &str, _: Args<'_>, out: &mut dyn Write) -> Result<(), shell::Error> {
+        match name {
+            "rp reset" => self.reset_rp2040(),
+            "rp hold" => self.hold_in_reset(),
+            _ => self.release(),
+        }
+        let state = if self.held {
+            "held in reset"
+        } else {
+            "running"
+        };
+        _ = write!(out, "rp2040 {state}\r\n");
+        Ok(())
+    }
+}
+
+/// Something the [`Watchdog`] wants logged
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// The rp2040 answered its first ping, or answered again after
+    /// being power cycled
+    Alive,
+    /// No answer for this many milliseconds, the rp2040 was power cycled
+    PowerCycled { silent_ms: u64 },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Alive => write!(f, "rp2040 is answering heartbeats"),
+            Event::PowerCycled { silent_ms } => {
+                write!(f, "rp2040 silent for {silent_ms}ms, power cycled")
+            }
+        }
+    }
+}
+
+/// Pings the rp2040 and power cycles it when the pings go unanswered
+///
+/// Messages from the link are read by the caller, which has to hand
+/// every `Pong` to [`Watchdog::pong`].
+pub struct Watchdog {
+    interval_ms: u64,
+    timeout_ms: u64,
+    seq: u16,
+    next_ping: u64,
+    last_answer: u64,
+    alive: bool,
+}
+
+impl Watchdog {
+    /// Ping every `interval_ms`, power cycle after `timeout_ms` without
+    /// an answer. The timeout includes the time the rp2040 takes to boot.
+    pub fn new(interval_ms: u64, timeout_ms: u64, now: u64) -> Self {
+        Self {
+            interval_ms,
+            timeout_ms,
+            seq: 0,
+            next_ping: now,
+            last_answer: now,
+            alive: false,
+        }
+    }
+
+    /// The rp2040 answers heartbeats
+    pub fn is_alive(&self) -> bool {
+        self.alive
+    }
+
+    /// Feed the sequence number of a `Pong` received from the rp2040
+    pub fn pong(&mut self, seq: u16, now: u64) -> Option<Event> {
+        // Answers can sit in the uart while the caller is busy elsewhere
+        if self.seq.wrapping_sub(seq) >= LATE_PONGS {
+            return None;
+        }
+        self.last_answer = now;
+        if self.alive {
+            return None;
+        }
+        self.alive = true;
+        Some(Event::Alive)
+    }
+
+    /// Send a ping when one is due and power cycle the rp2040 when it
+    /// has been silent for too long. Nothing happens while the rp2040 is
+    /// held in reset on purpose.
+    pub fn poll<UART, P, D>(
+        &mut self,
+        now: u64,
+        link: &mut RpLink<UART>,
+        control: &mut RpControl<P, D>,
+    ) -> Option<Event>
+    where
+        UART: SerialRead<u8> + SerialWrite<u8>,
+        P: OutputPin,
+        D: DelayMs<u32>,
+    {
+        if control.is_held() {
+            self.last_answer = now;
+            return None;
+        }
+        if now.saturating_sub(self.last_answer) >= self.timeout_ms {
+            let silent_ms = now - self.last_answer;
+            control.power_cycle();
+            self.alive = false;
+            self.last_answer = now;
+            self.next_ping = now;
+            return Some(Event::PowerCycled { silent_ms });
+        }
+        if now >= self.next_ping {
+            self.seq = self.seq.wrapping_add(1);
+            _ = link.send(&Message::Ping(self.seq));
+            self.next_ping = now + self.interval_ms;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::Shell;
+    use crate::testing::{NoDelay, Pin, Serial};
+    use udoo_link::Decoder;
+
+    fn sent(serial: &Serial) -> Vec<u16> {
+        let mut decoder = Decoder::new();
+        let mut pings = Vec::new();
+        for &byte in serial.tx.borrow().iter() {
+            if let Some(Ok(Message::Ping(seq))) = decoder.push(byte) {
+                pings.push(seq);
+            }
+        }
+        pings
+    }
+
+    #[test]
+    fn holding_in_reset() {
+        let reset = Pin::default();
+        let mut control = RpControl::new(reset.clone(), NoDelay);
+        control.hold_in_reset();
+        assert!(control.is_held());
+        control.release();
+        control.reset_rp2040();
+        assert!(!control.is_held());
+        assert_eq!(reset.levels(), [true, false, true, false, true]);
+    }
+
+    #[test]
+    fn console_commands() {
+        let reset = Pin::default();
+        let mut control = RpControl::new(reset.clone(), NoDelay);
+        let mut shell = Shell::new();
+        let mut out = String::new();
+        for byte in b"rp hold\rrp reset\r" {
+            shell.feed(*byte, &mut [&mut control], &mut out);
+        }
+        assert!(out.contains("rp2040 held in reset\r\n"));
+        assert!(out.ends_with("rp2040 running\r\n> "));
+        assert_eq!(reset.levels(), [true, false, false, true]);
+    }
+
+    #[test]
+    fn pings_at_the_interval() {
+        let serial = Serial::default();
+        let mut link = RpLink::new(serial.clone());
+        let mut control = RpControl::new(Pin::default(), NoDelay);
+        let mut watchdog = Watchdog::new(100, 1000, 0);
+        for now in [0, 50, 100, 150, 200] {
+            assert_eq!(watchdog.poll(now, &mut link, &mut control), None);
+        }
+        assert_eq!(sent(&serial), [1, 2, 3]);
+    }
+
+    #[test]
+    fn answers_keep_the_rp2040_running() {
+        let reset = Pin::default();
+        let mut link = RpLink::new(Serial::default());
+        let mut control = RpControl::new(reset.clone(), NoDelay);
+        let mut watchdog = Watchdog::new(100, 1000, 0);
+        for now in (0..3000).step_by(100) {
+            watchdog.poll(now, &mut link, &mut control);
+            let answer = watchdog.pong(watchdog.seq, now);
+            assert_eq!(answer, (now == 0).then_some(Event::Alive));
+        }
+        assert_eq!(reset.levels(), [true]);
+    }
+
+    #[test]
+    fn power_cycles_a_silent_rp2040() {
+        let reset = Pin::default();
+        let mut link = RpLink::new(Serial::default());
+        let mut control = RpControl::new(reset.clone(), NoDelay);
+        let mut watchdog = Watchdog::new(100, 1000, 0);
+        assert_eq!(watchdog.poll(0, &mut link, &mut control), None);
+        assert_eq!(
+            watchdog.poll(1200, &mut link, &mut control),
+            Some(Event::PowerCycled { silent_ms: 1200 })
+        );
+        assert_eq!(reset.levels(), [true, false, true]);
+        // Stale answers from before the power cycle do not count
+        watchdog.poll(1300, &mut link, &mut control);
+        watchdog.poll(1400, &mut link, &mut control);
+        watchdog.poll(1500, &mut link, &mut control);
+        watchdog.poll(1600, &mut link, &mut control);
+        assert_eq!(watchdog.pong(1, 1600), None);
+        assert!(!watchdog.is_alive());
+        assert_eq!(watchdog.pong(watchdog.seq, 1600), Some(Event::Alive));
+        assert!(watchdog.is_alive());
+    }
+
+    #[test]
+    fn leaves_a_held_rp2040_alone() {
+        let serial = Serial::default();
+        let mut link = RpLink::new(serial.clone());
+        let mut control = RpControl::new(Pin::default(), NoDelay);
+        let mut watchdog = Watchdog::new(100, 1000, 0);
+        control.hold_in_reset();
+        assert_eq!(watchdog.poll(5000, &mut link, &mut control), None);
+        assert!(control.is_held());
+        assert!(sent(&serial).is_empty());
+    }
+}
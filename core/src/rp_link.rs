@@ -0,0 +1,52 @@
+//! Serial connection to the rp2040 speaking the `udoo-link` framing
+
+use embedded_hal::serial::{Read as SerialRead, Write as SerialWrite};
+use nb::block;
+use udoo_link::{Decoder, Error, Message, MAX_FRAME};
+
+pub struct RpLink<UART>
+where
+    UART: SerialRead<u8> + SerialWrite<u8>,
+{
+    uart: UART,
+    decoder: Decoder,
+}
+
+impl<UART> RpLink<UART>
+where
+    UART: SerialRead<u8> + SerialWrite<u8>,
+{
+    pub fn new(uart: UART) -> Self {
+        Self {
+            uart,
+            decoder: Decoder::new(),
+        }
+    }
+
+    /// Encode and send a message, blocks until the last byte is queued
+    pub fn send(&mut self, message: &Message) -> Result<(), Error> {
+        let mut frame = [0_u8; MAX_FRAME];
+        let len = message.encode(&mut frame)?;
+        for byte in &frame[..len] {
+            _ = block!(self.uart.write(*byte));
+        }
+        _ = block!(self.uart.flush());
+        Ok(())
+    }
+
+    /// Read whatever bytes are waiting, returns a message once a whole
+    /// frame has arrived
+    pub fn poll(&mut self) -> Option<Result<Message<'_>, Error>> {
+        loop {
+            let byte = self.uart.read().ok()?;
+            if byte == 0 {
+                return self.decoder.push(byte);
+            }
+            self.decoder.push(byte);
+        }
+    }
+
+    pub fn free(self) -> UART {
+        self.uart
+    }
+}
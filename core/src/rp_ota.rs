@@ -0,0 +1,460 @@
+//! Over the air updates of the rp2040 firmware
+//!
+//! New rp2040 images are downloaded into one of two staging partitions
+//! in the esp32 flash (`rp_a` and `rp_b`), checked against the SHA-256
+//! and version in their header and then programmed into the rp2040 over
+//! SWD. The `rp_state` partition remembers which staged image is known
+//! to run, so the rp2040 can be put back on it when a new image does not
+//! answer heartbeats after booting.
+//!
+//! Staged images carry a UF2 file as payload.
+
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_io::blocking::Read;
+use embedded_storage::{ReadStorage, Storage};
+use sha2::{Digest, Sha256};
+
+use crate::flasher::{self, Flasher};
+use crate::image::{self, parse_uf2_block, UF2_BLOCK_SIZE};
+use crate::ota::{self, Header, Target, HEADER_SIZE, SIGNATURE_SIZE};
+use crate::partition::{Partition, PartitionTable};
+use crate::swd::Swd;
+
+pub const SLOT_A_LABEL: &str = "rp_a";
+pub const SLOT_B_LABEL: &str = "rp_b";
+pub const STATE_LABEL: &str = "rp_state";
+
+const STATE_MAGIC: [u8; 4] = *b"UKRS";
+const SECTOR_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+    A = 0x0,
+    B = 0x1,
+}
+
+impl Slot {
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Reading or writing the esp32 flash failed
+    Storage,
+    /// A partition is missing from the partition table
+    MissingPartition(&'static str),
+    Header(ota::Error),
+    /// The image is meant for the esp32
+    WrongTarget,
+    /// The image is not newer than the active one
+    NotNewer {
+        active: u32,
+        offered: u32,
+    },
+    /// The image does not fit into a staging partition
+    TooLarge(u32),
+    /// The download ended early or failed
+    Download,
+    /// The SHA-256 of the payload does not match the header
+    Hash,
+    Image(image::Error),
+    Flash(flasher::Error),
+}
+
+impl From<ota::Error> for Error {
+    fn from(e: ota::Error) -> Self {
+        Error::Header(e)
+    }
+}
+
+impl From<image::Error> for Error {
+    fn from(e: image::Error) -> Self {
+        Error::Image(e)
+    }
+}
+
+impl From<flasher::Error> for Error {
+    fn from(e: flasher::Error) -> Self {
+        Error::Flash(e)
+    }
+}
+
+pub struct RpOta<S>
+where
+    S: ReadStorage + Storage,
+{
+    storage: S,
+    slots: [Partition; 2],
+    state: Partition,
+    active: Option<Slot>,
+}
+
+impl<S> RpOta<S>
+where
+    S: ReadStorage + Storage,
+{
+    /// Find the staging partitions and load which slot is active
+    pub fn new(mut storage: S) -> Result<Self, Error> {
+        let table = PartitionTable::read(&mut storage).map_err(|_| Error::Storage)?;
+        let find = |label| table.find(label).ok_or(Error::MissingPartition(label));
+        let slots = [find(SLOT_A_LABEL)?, find(SLOT_B_LABEL)?];
+        let state = find(STATE_LABEL)?;
+
+        let mut record = [0_u8; 5];
+        storage
+            .read(state.offset, &mut record)
+            .map_err(|_| Error::Storage)?;
+        let active = match (record[0..4] == STATE_MAGIC, record[4]) {
+            (true, 0x0) => Some(Slot::A),
+            (true, 0x1) => Some(Slot::B),
+            _ => None,
+        };
+        Ok(Self {
+            storage,
+            slots,
+            state,
+            active,
+        })
+    }
+
+    /// The slot holding the image the rp2040 is known to run
+    pub fn active(&self) -> Option<Slot> {
+        self.active
+    }
+
+    fn partition(&self, slot: Slot) -> Partition {
+        self.slots[slot as usize]
+    }
+
+    /// Read the header of a staged image
+    pub fn header(&mut self, slot: Slot) -> Result<Header, Error> {
+        let mut bytes = [0_u8; HEADER_SIZE];
+        self.storage
+            .read(self.partition(slot).offset, &mut bytes)
+            .map_err(|_| Error::Storage)?;
+        Ok(Header::parse(&bytes)?)
+    }
+
+    /// Download an image from `source` into the inactive slot
+    ///
+    /// The header is checked before anything is written and the payload
+    /// is hashed both while downloading and again from flash.
+    pub fn stage<R: Read>(&mut self, source: &mut R) -> Result<(Slot, Header), Error> {
+        let slot = self.active.map_or(Slot::A, Slot::other);
+        let partition = self.partition(slot);
+
+        let mut header_bytes = [0_u8; HEADER_SIZE];
+        source
+            .read_exact(&mut header_bytes)
+            .map_err(|_| Error::Download)?;
+        let header = Header::parse(&header_bytes)?;
+        if header.target != Target::Rp2040 {
+            return Err(Error::WrongTarget);
+        }
+        if header.signed {
+            // Staged rp2040 images are only checked against their hash
+            let mut signature = [0_u8; SIGNATURE_SIZE];
+            source
+                .read_exact(&mut signature)
+                .map_err(|_| Error::Download)?;
+        }
+        if let Some(active) = self.active.and_then(|slot| self.header(slot).ok()) {
+            if header.version <= active.version {
+                return Err(Error::NotNewer {
+                    active: active.version,
+                    offered: header.version,
+                });
+            }
+        }
+        if header.length as usize + HEADER_SIZE > partition.size as usize {
+            return Err(Error::TooLarge(header.length));
+        }
+        if header.length as usize % UF2_BLOCK_SIZE != 0 {
+            return Err(image::Error::Truncated.into());
+        }
+
+        let mut sector = [0xff_u8; SECTOR_SIZE];
+        sector[..HEADER_SIZE].copy_from_slice(&header_bytes);
+        let mut filled = HEADER_SIZE;
+        let mut offset = partition.offset;
+        let mut remaining = header.length as usize;
+        let mut hasher = Sha256::new();
+        while remaining > 0 || filled > 0 {
+            let len = remaining.min(SECTOR_SIZE - filled);
+            let chunk = &mut sector[filled..filled + len];
+            source.read_exact(chunk).map_err(|_| Error::Download)?;
+            hasher.update(&*chunk);
+            filled += len;
+            remaining -= len;
+            if filled == SECTOR_SIZE || remaining == 0 {
+                self.storage
+                    .write(offset, &sector[..filled])
+                    .map_err(|_| Error::Storage)?;
+                offset += SECTOR_SIZE as u32;
+                filled = 0;
+            }
+        }
+        if hasher.finalize()[..] != header.sha256 {
+            return Err(Error::Hash);
+        }
+        self.check(slot)?;
+        self.check_blocks(slot, &header)?;
+        Ok((slot, header))
+    }
+
+    /// Hash a staged image from flash and compare it to its header
+    pub fn check(&mut self, slot: Slot) -> Result<Header, Error> {
+        let header = self.header(slot)?;
+        let partition = self.partition(slot);
+        if header.length as usize + HEADER_SIZE > partition.size as usize {
+            return Err(Error::TooLarge(header.length));
+        }
+        if header.length as usize % UF2_BLOCK_SIZE != 0 {
+            return Err(image::Error::Truncated.into());
+        }
+        let mut hasher = Sha256::new();
+        let mut chunk = [0_u8; 512];
+        let mut offset = partition.offset + HEADER_SIZE as u32;
+        let mut remaining = header.length as usize;
+        while remaining > 0 {
+            let len = remaining.min(chunk.len());
+            self.storage
+                .read(offset, &mut chunk[..len])
+                .map_err(|_| Error::Storage)?;
+            hasher.update(&chunk[..len]);
+            offset += len as u32;
+            remaining -= len;
+        }
+        if hasher.finalize()[..] != header.sha256 {
+            return Err(Error::Hash);
+        }
+        Ok(header)
+    }
+
+    /// Parse every UF2 block of a staged image
+    fn check_blocks(&mut self, slot: Slot, header: &Header) -> Result<(), Error> {
+        let start = self.partition(slot).offset + HEADER_SIZE as u32;
+        let mut block = [0_u8; UF2_BLOCK_SIZE];
+        for index in 0..header.length as usize / UF2_BLOCK_SIZE {
+            self.storage
+                .read(start + (index * UF2_BLOCK_SIZE) as u32, &mut block)
+                .map_err(|_| Error::Storage)?;
+            parse_uf2_block(index, &block)?;
+        }
+        Ok(())
+    }
+
+    /// Program a staged image into the rp2040 and verify it
+    pub fn program<DIO, CLK, D>(
+        &mut self,
+        slot: Slot,
+        swd: &mut Swd<DIO, CLK, D>,
+    ) -> Result<Header, Error>
+    where
+        DIO: InputPin + OutputPin,
+        CLK: OutputPin,
+        D: DelayUs<u32>,
+    {
+        let header = self.check(slot)?;
+        let start = self.partition(slot).offset + HEADER_SIZE as u32;
+        let blocks = header.length as usize / UF2_BLOCK_SIZE;
+        let mut flasher = Flasher::new(swd)?;
+        let mut block = [0_u8; UF2_BLOCK_SIZE];
+        for verify in [false, true] {
+            if verify {
+                flasher.finish()?;
+            }
+            for index in 0..blocks {
+                self.storage
+                    .read(start + (index * UF2_BLOCK_SIZE) as u32, &mut block)
+                    .map_err(|_| Error::Storage)?;
+                if let Some(segment) = parse_uf2_block(index, &block)? {
+                    if verify {
+                        flasher.verify(segment)?;
+                    } else {
+                        flasher.write(segment)?;
+                    }
+                }
+            }
+        }
+        flasher.release()?;
+        Ok(header)
+    }
+
+    /// Remember `slot` as the image the rp2040 is known to run
+    pub fn commit(&mut self, slot: Slot) -> Result<(), Error> {
+        let mut record = [0_u8; 5];
+        record[0..4].copy_from_slice(&STATE_MAGIC);
+        record[4] = slot as u8;
+        self.storage
+            .write(self.state.offset, &record)
+            .map_err(|_| Error::Storage)?;
+        self.active = Some(slot);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::image::FLASH_START;
+    use crate::partition::TYPE_DATA;
+    use crate::testing::{uf2_block, RamFlash};
+
+    const STATE: u32 = 0x10000;
+    const RP_A: u32 = 0x11000;
+    const RP_B: u32 = 0x15000;
+    const SLOT_SIZE: u32 = 0x4000;
+
+    fn flash() -> RamFlash {
+        RamFlash::new(0x20000).with_partitions(&[
+            (TYPE_DATA, 0x80, STATE, 0x1000, STATE_LABEL),
+            (TYPE_DATA, 0x81, RP_A, SLOT_SIZE, SLOT_A_LABEL),
+            (TYPE_DATA, 0x81, RP_B, SLOT_SIZE, SLOT_B_LABEL),
+        ])
+    }
+
+    /// A packed rp2040 image, the payload ends in the middle of a sector
+    fn image(version: u32, payload: &[u8]) -> Vec<u8> {
+        let header = Header {
+            target: Target::Rp2040,
+            signed: false,
+            version,
+            length: payload.len() as u32,
+            sha256: Sha256::digest(payload).into(),
+        };
+        let mut image = header.to_bytes().to_vec();
+        image.extend_from_slice(payload);
+        image
+    }
+
+    /// Ten UF2 blocks of 256 bytes each
+    fn payload(seed: u8) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for index in 0..10 {
+            let data: Vec<u8> = (0..256).map(|i| (i as u8).wrapping_add(seed)).collect();
+            payload.extend(uf2_block(0, FLASH_START + index * 256, &data));
+        }
+        payload
+    }
+
+    #[test]
+    fn stages_into_both_slots() {
+        let flash = flash();
+        let mut ota = RpOta::new(flash.clone()).unwrap();
+        assert_eq!(ota.active(), None);
+
+        let (slot, header) = ota.stage(&mut &image(1, &payload(0))[..]).unwrap();
+        assert_eq!(slot, Slot::A);
+        assert_eq!(header.version, 1);
+        assert_eq!(flash.bytes(RP_A + HEADER_SIZE as u32, 5120), payload(0));
+        ota.commit(Slot::A).unwrap();
+
+        let (slot, _) = ota.stage(&mut &image(2, &payload(1))[..]).unwrap();
+        assert_eq!(slot, Slot::B);
+        assert_eq!(flash.bytes(RP_B + HEADER_SIZE as u32, 5120), payload(1));
+        assert_eq!(ota.check(Slot::B).map(|h| h.version), Ok(2));
+        // The rest of the last sector is left erased
+        let end = RP_B + HEADER_SIZE as u32 + 5120;
+        assert_eq!(flash.bytes(end, 16), [0xff; 16]);
+    }
+
+    #[test]
+    fn rejects_images() {
+        let mut ota = RpOta::new(flash()).unwrap();
+        ota.stage(&mut &image(2, &payload(0))[..]).unwrap();
+        ota.commit(Slot::A).unwrap();
+
+        for version in [1, 2] {
+            assert_eq!(
+                ota.stage(&mut &image(version, &payload(0))[..]),
+                Err(Error::NotNewer {
+                    active: 2,
+                    offered: version
+                })
+            );
+        }
+
+        let mut bad_hash = image(3, &payload(0));
+        *bad_hash.last_mut().unwrap() ^= 1;
+        assert_eq!(ota.stage(&mut &bad_hash[..]), Err(Error::Hash));
+
+        let large = image(3, &[0; SLOT_SIZE as usize]);
+        assert_eq!(ota.stage(&mut &large[..]), Err(Error::TooLarge(SLOT_SIZE)));
+
+        let short = image(3, &payload(0));
+        assert_eq!(ota.stage(&mut &short[..100]), Err(Error::Download));
+
+        let partial = image(3, &payload(0)[..5000]);
+        assert_eq!(
+            ota.stage(&mut &partial[..]),
+            Err(Error::Image(image::Error::Truncated))
+        );
+
+        let mut not_uf2 = payload(0);
+        not_uf2[3 * UF2_BLOCK_SIZE + 508] ^= 1;
+        assert_eq!(
+            ota.stage(&mut &image(3, &not_uf2)[..]),
+            Err(Error::Image(image::Error::InvalidBlock(3)))
+        );
+
+        let mut esp32 = image(3, &payload(0));
+        let mut header = Header::parse(esp32[..HEADER_SIZE].try_into().unwrap()).unwrap();
+        header.target = Target::Esp32;
+        esp32[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+        assert_eq!(ota.stage(&mut &esp32[..]), Err(Error::WrongTarget));
+    }
+
+    #[test]
+    fn check_finds_corrupted_slots() {
+        let flash = flash();
+        let mut ota = RpOta::new(flash.clone()).unwrap();
+        let (slot, _) = ota.stage(&mut &image(1, &payload(0))[..]).unwrap();
+        assert!(ota.check(slot).is_ok());
+
+        // Break the magic of the first block
+        let start = RP_A + HEADER_SIZE as u32;
+        flash.clone().write(start, &[0]).unwrap();
+        assert_eq!(ota.check(slot), Err(Error::Hash));
+        assert_eq!(ota.check(Slot::B), Err(Error::Header(ota::Error::Magic)));
+    }
+
+    #[test]
+    fn commit_survives_a_restart() {
+        let flash = flash();
+        let mut ota = RpOta::new(flash.clone()).unwrap();
+        ota.stage(&mut &image(1, &payload(0))[..]).unwrap();
+        ota.commit(Slot::A).unwrap();
+        ota.stage(&mut &image(2, &payload(1))[..]).unwrap();
+        ota.commit(Slot::B).unwrap();
+
+        let mut ota = RpOta::new(flash.clone()).unwrap();
+        assert_eq!(ota.active(), Some(Slot::B));
+        // The next image goes into the slot that is not running
+        let (slot, _) = ota.stage(&mut &image(3, &payload(2))[..]).unwrap();
+        assert_eq!(slot, Slot::A);
+    }
+
+    #[test]
+    fn missing_partitions() {
+        let flash = RamFlash::new(0x20000).with_partitions(&[(
+            TYPE_DATA,
+            0x81,
+            RP_A,
+            SLOT_SIZE,
+            SLOT_A_LABEL,
+        )]);
+        assert_eq!(
+            RpOta::new(flash).err(),
+            Some(Error::MissingPartition(SLOT_B_LABEL))
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! The 64x32 chip8 screen as a bitmap that tracks changed rows
+//!
+//! The rp2040 draws into a [`Screen`] and sends the changed rows to the
+//! esp32 in `Screen` messages, the esp32 applies them to its own copy and
+//! passes the changed rows on to a browser. Both ends send whole rows in
+//! the layout of the link: one bit per pixel with the leftmost pixel in
+//! the high bit.
+//!
+//! The link drops frames with a bad CRC, so the rp2040 also sends a few
+//! unchanged rows now and then with [`Screen::refresh`] until a lost row
+//! is sent again.
+
+pub use udoo_link::SCREEN_ROW_SIZE as ROW_SIZE;
+
+pub const WIDTH: usize = 8 * ROW_SIZE;
+pub const HEIGHT: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Screen {
+    pixels: [u8; ROW_SIZE * HEIGHT],
+    /// Bit `y` is set when row `y` changed since it was last taken
+    dirty: u32,
+    /// Next row for `refresh`
+    refresh: usize,
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Screen {
+    pub const fn new() -> Self {
+        Self {
+            pixels: [0; ROW_SIZE * HEIGHT],
+            dirty: 0,
+            refresh: 0,
+        }
+    }
+
+    /// Turn a pixel on or off, pixels off the screen are ignored
+    pub fn set(&mut self, x: usize, y: usize, on: bool) {
+        if x >= WIDTH || y >= HEIGHT {
+            return;
+        }
+        let byte = &mut self.pixels[y * ROW_SIZE + x / 8];
+        let bit = 0x80 >> (x % 8);
+        let old = *byte;
+        if on {
+            *byte |= bit;
+        } else {
+            *byte &= !bit;
+        }
+        if *byte != old {
+            self.dirty |= 1 << y;
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        x < WIDTH && y < HEIGHT && self.pixels[y * ROW_SIZE + x / 8] & 0x80 >> (x % 8) != 0
+    }
+
+    /// Turn all pixels off
+    pub fn clear(&mut self) {
+        for y in 0..HEIGHT {
+            self.apply(y as u8, &[0; ROW_SIZE]);
+        }
+    }
+
+    /// All rows from the top
+    pub fn bytes(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// Copy in consecutive rows starting at `row` from a `Screen`
+    /// message, rows off the screen are ignored
+    pub fn apply(&mut self, row: u8, pixels: &[u8]) {
+        for (y, new) in (row as usize..HEIGHT).zip(pixels.chunks_exact(ROW_SIZE)) {
+            let old = &mut self.pixels[y * ROW_SIZE..][..ROW_SIZE];
+            if old != new {
+                old.copy_from_slice(new);
+                self.dirty |= 1 << y;
+            }
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty != 0
+    }
+
+    /// Take the first run of changed rows, at most `max_rows` of them.
+    /// Returns the first row and the pixels of the rows.
+    pub fn take_update(&mut self, max_rows: usize) -> Option<(u8, &[u8])> {
+        if self.dirty == 0 || max_rows == 0 {
+            return None;
+        }
+        let first = self.dirty.trailing_zeros() as usize;
+        let mut rows = 0;
+        while first + rows < HEIGHT && rows < max_rows && self.dirty & 1 << (first + rows) != 0 {
+            self.dirty &= !(1 << (first + rows));
+            rows += 1;
+        }
+        let pixels = &self.pixels[first * ROW_SIZE..(first + rows) * ROW_SIZE];
+        Some((first as u8, pixels))
+    }
+
+    /// Mark the next `rows` rows as changed, going round the screen on
+    /// each call
+    pub fn refresh(&mut self, rows: usize) {
+        for _ in 0..rows.min(HEIGHT) {
+            self.dirty |= 1 << self.refresh;
+            self.refresh = (self.refresh + 1) % HEIGHT;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_pixels() {
+        let mut screen = Screen::new();
+        screen.set(0, 0, true);
+        screen.set(63, 31, true);
+        screen.set(64, 0, true);
+        screen.set(0, 32, true);
+        assert!(screen.get(0, 0));
+        assert!(screen.get(63, 31));
+        assert!(!screen.get(1, 0));
+        assert!(!screen.get(64, 0));
+        assert_eq!(screen.bytes()[0], 0x80);
+        assert_eq!(screen.bytes()[ROW_SIZE * HEIGHT - 1], 0x01);
+        screen.set(0, 0, false);
+        assert!(!screen.get(0, 0));
+    }
+
+    #[test]
+    fn takes_changed_rows() {
+        let mut screen = Screen::new();
+        assert!(!screen.is_dirty());
+        assert_eq!(screen.take_update(3), None);
+        for y in [2, 3, 4, 5, 9] {
+            screen.set(8, y, true);
+        }
+        // Setting a pixel that is already on changes nothing
+        while screen.take_update(HEIGHT).is_some() {}
+        screen.set(8, 9, true);
+        assert_eq!(screen.take_update(3), None);
+
+        for y in [2, 3, 4, 5, 9] {
+            screen.set(8, y, false);
+        }
+        let (row, pixels) = screen.take_update(3).unwrap();
+        assert_eq!((row, pixels.len()), (2, 3 * ROW_SIZE));
+        assert_eq!(screen.take_update(3), Some((5, &[0_u8; ROW_SIZE][..])));
+        assert_eq!(screen.take_update(3), Some((9, &[0_u8; ROW_SIZE][..])));
+        assert!(!screen.is_dirty());
+    }
+
+    #[test]
+    fn applies_rows() {
+        let mut rp2040 = Screen::new();
+        let mut esp32 = Screen::new();
+        rp2040.set(10, 30, true);
+        rp2040.set(20, 31, true);
+        let (row, pixels) = rp2040.take_update(2).unwrap();
+        esp32.apply(row, pixels);
+        assert_eq!(esp32.bytes(), rp2040.bytes());
+        assert_eq!(esp32.take_update(HEIGHT).unwrap().0, 30);
+
+        // Rows past the bottom are dropped
+        esp32.apply(31, &[0xff; 2 * ROW_SIZE]);
+        assert_eq!(esp32.bytes()[31 * ROW_SIZE..], [0xff; ROW_SIZE]);
+
+        esp32.clear();
+        assert_eq!(esp32.bytes(), [0; ROW_SIZE * HEIGHT]);
+    }
+
+    #[test]
+    fn refreshes_all_rows_in_turn() {
+        let mut screen = Screen::new();
+        let mut sent = [false; HEIGHT];
+        for _ in 0..11 {
+            screen.refresh(3);
+            while let Some((row, pixels)) = screen.take_update(3) {
+                for y in 0..pixels.len() / ROW_SIZE {
+                    sent[row as usize + y] = true;
+                }
+            }
+        }
+        assert_eq!(sent, [true; HEIGHT]);
+    }
+}
@@ -0,0 +1,573 @@
+//! SD cards in SPI mode, like the Olimex MOD-SDMMC on the UEXT connector
+//!
+//! [`SdCard`] talks to the card through a [`Device`] on a shared
+//! [`SpiBus`](crate::spi_bus::SpiBus) and reads and writes single 512
+//! byte blocks. Version 1 cards and SDHC/SDXC cards are supported, MMC
+//! cards are not. The bus has to run at 400 kHz or less while the card
+//! is initialised.
+
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::spi_bus::{self, Bus, Device};
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// Commands, application commands follow `APP_CMD`
+mod command {
+    pub const GO_IDLE_STATE: u8 = 0;
+    pub const SEND_IF_COND: u8 = 8;
+    pub const SEND_CSD: u8 = 9;
+    pub const SET_BLOCKLEN: u8 = 16;
+    pub const READ_SINGLE_BLOCK: u8 = 17;
+    pub const WRITE_BLOCK: u8 = 24;
+    pub const SD_SEND_OP_COND: u8 = 41;
+    pub const APP_CMD: u8 = 55;
+    pub const READ_OCR: u8 = 58;
+}
+
+/// R1 response bits
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+
+/// 2.7-3.6 V and a check pattern
+const IF_COND: u32 = 0x1aa;
+/// Host supports high capacity cards, in `SD_SEND_OP_COND` and the OCR
+const HCS: u32 = 1 << 30;
+const DATA_TOKEN: u8 = 0xfe;
+const DATA_ACCEPTED: u8 = 0x05;
+
+/// The card answers within 8 bytes of a command
+const RESPONSE_BYTES: usize = 8;
+const IDLE_ATTEMPTS: usize = 10;
+/// Initialisation takes up to a second
+const INIT_ATTEMPTS: u32 = 100;
+const INIT_POLL_MS: u32 = 10;
+/// Bytes to wait for the start of a block, about 100 ms at 400 kHz
+const READ_BYTES: usize = 5000;
+/// Writing a block takes up to 250 ms
+const WRITE_TIMEOUT_MS: u32 = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CardType {
+    /// Version 1 card, addressed in bytes
+    Sd1,
+    /// Version 2 standard capacity card, addressed in bytes
+    Sd2,
+    /// Version 2 high or extended capacity card, addressed in blocks
+    Sdhc,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    Spi,
+    /// Nothing answered the reset command
+    NoCard,
+    /// The card does not support 3.3 V or is not an SD card
+    Unsupported,
+    /// The card did not finish initialising or writing in time
+    Timeout,
+    /// The card answered a command with an error
+    Command {
+        command: u8,
+        response: u8,
+    },
+    /// The card sent something else than the start of a block
+    DataToken(u8),
+    /// The card did not accept a written block
+    WriteRejected(u8),
+    /// [`SdCard::init`] has not succeeded yet
+    NotInitialized,
+    /// The block lies beyond what the card can address
+    OutOfRange(u32),
+    /// The CSD register of the card makes no sense
+    InvalidCsd,
+}
+
+impl From<spi_bus::Error> for Error {
+    fn from(_: spi_bus::Error) -> Self {
+        Error::Spi
+    }
+}
+
+pub struct SdCard<'a, B, CS, D> {
+    device: Device<'a, B, CS>,
+    delay: D,
+    card: Option<CardType>,
+}
+
+impl<'a, B, CS, D> SdCard<'a, B, CS, D>
+where
+    B: Transfer<u8> + Write<u8>,
+    CS: OutputPin,
+    D: DelayMs<u32>,
+{
+    /// The card is not touched until [`SdCard::init`]
+    pub fn new(device: Device<'a, B, CS>, delay: D) -> Self {
+        Self {
+            device,
+            delay,
+            card: None,
+        }
+    }
+
+    /// Put the card into SPI mode and wait until it is ready
+    pub fn init(&mut self) -> Result<CardType, Error> {
+        self.card = None;
+        // At least 74 clocks with the card deselected
+        self.device.deselected(|bus| bus.write(&[0xff; 10]))?;
+
+        let mut attempts = 0;
+        while self.command(command::GO_IDLE_STATE, 0)? != R1_IDLE {
+            attempts += 1;
+            if attempts == IDLE_ATTEMPTS {
+                return Err(Error::NoCard);
+            }
+        }
+
+        let (response, if_cond) = self.command_r7(command::SEND_IF_COND, IF_COND)?;
+        let version2 = response & R1_ILLEGAL_COMMAND == 0;
+        if version2 && if_cond & 0xfff != IF_COND {
+            return Err(Error::Unsupported);
+        }
+
+        let argument = if version2 { HCS } else { 0 };
+        let mut attempts = 0;
+        loop {
+            self.command(command::APP_CMD, 0)?;
+            match self.command(command::SD_SEND_OP_COND, argument)? {
+                0 => break,
+                R1_IDLE => {}
+                _ => return Err(Error::Unsupported),
+            }
+            attempts += 1;
+            if attempts == INIT_ATTEMPTS {
+                return Err(Error::Timeout);
+            }
+            self.delay.delay_ms(INIT_POLL_MS);
+        }
+
+        let card = if version2 {
+            let (response, ocr) = self.command_r7(command::READ_OCR, 0)?;
+            check(command::READ_OCR, response)?;
+            if ocr & HCS != 0 {
+                CardType::Sdhc
+            } else {
+                CardType::Sd2
+            }
+        } else {
+            CardType::Sd1
+        };
+        if card != CardType::Sdhc {
+            let response = self.command(command::SET_BLOCKLEN, BLOCK_SIZE as u32)?;
+            check(command::SET_BLOCKLEN, response)?;
+        }
+        self.card = Some(card);
+        Ok(card)
+    }
+
+    /// The type of the card once it is initialised
+    pub fn card_type(&self) -> Option<CardType> {
+        self.card
+    }
+
+    /// Size of the card in blocks, read from its CSD register
+    pub fn num_blocks(&mut self) -> Result<u32, Error> {
+        self.card.ok_or(Error::NotInitialized)?;
+        let mut csd = [0_u8; 16];
+        self.device.transaction(|bus| {
+            check(command::SEND_CSD, send(bus, command::SEND_CSD, 0)?)?;
+            read_data(bus, &mut csd)
+        })?;
+        csd_blocks(&csd)
+    }
+
+    pub fn read_block(&mut self, block: u32, data: &mut [u8; BLOCK_SIZE]) -> Result<(), Error> {
+        let address = self.address(block)?;
+        self.device.transaction(|bus| {
+            let response = send(bus, command::READ_SINGLE_BLOCK, address)?;
+            check(command::READ_SINGLE_BLOCK, response)?;
+            read_data(bus, data)
+        })
+    }
+
+    pub fn write_block(&mut self, block: u32, data: &[u8; BLOCK_SIZE]) -> Result<(), Error> {
+        let address = self.address(block)?;
+        let Self { device, delay, .. } = self;
+        device.transaction(|bus| {
+            let response = send(bus, command::WRITE_BLOCK, address)?;
+            check(command::WRITE_BLOCK, response)?;
+            bus.write(&[0xff, DATA_TOKEN])?;
+            bus.write(data)?;
+            // The CRC is not checked in SPI mode
+            bus.write(&[0xff, 0xff])?;
+            let response = bus.exchange(0xff)? & 0x1f;
+            if response != DATA_ACCEPTED {
+                return Err(Error::WriteRejected(response));
+            }
+            // The card holds the data line low while it is busy
+            for _ in 0..WRITE_TIMEOUT_MS {
+                if bus.exchange(0xff)? != 0 {
+                    return Ok(());
+                }
+                delay.delay_ms(1);
+            }
+            Err(Error::Timeout)
+        })
+    }
+
+    /// Give back the device
+    pub fn free(self) -> Device<'a, B, CS> {
+        self.device
+    }
+
+    fn address(&self, block: u32) -> Result<u32, Error> {
+        match self.card.ok_or(Error::NotInitialized)? {
+            CardType::Sdhc => Ok(block),
+            CardType::Sd1 | CardType::Sd2 => block
+                .checked_mul(BLOCK_SIZE as u32)
+                .ok_or(Error::OutOfRange(block)),
+        }
+    }
+
+    fn command(&mut self, command: u8, argument: u32) -> Result<u8, Error> {
+        self.device.transaction(|bus| send(bus, command, argument))
+    }
+
+    /// A command with a four byte R3 or R7 response
+    fn command_r7(&mut self, command: u8, argument: u32) -> Result<(u8, u32), Error> {
+        self.device.transaction(|bus| {
+            let response = send(bus, command, argument)?;
+            let mut extra = [0xff; 4];
+            bus.transfer(&mut extra)?;
+            Ok((response, u32::from_be_bytes(extra)))
+        })
+    }
+}
+
+/// Send a command and return the R1 response
+fn send<B>(bus: &mut Bus<'_, B>, command: u8, argument: u32) -> Result<u8, Error>
+where
+    B: Transfer<u8> + Write<u8>,
+{
+    let mut frame = [0_u8; 7];
+    frame[0] = 0xff;
+    frame[1] = 0x40 | command;
+    frame[2..6].copy_from_slice(&argument.to_be_bytes());
+    frame[6] = crc7(&frame[1..6]) << 1 | 1;
+    bus.write(&frame)?;
+    for _ in 0..RESPONSE_BYTES {
+        let response = bus.exchange(0xff)?;
+        if response & 0x80 == 0 {
+            return Ok(response);
+        }
+    }
+    Err(Error::NoCard)
+}
+
+/// Wait for the start of a data block and read it with its CRC
+fn read_data<B>(bus: &mut Bus<'_, B>, data: &mut [u8]) -> Result<(), Error>
+where
+    B: Transfer<u8> + Write<u8>,
+{
+    let mut token = 0xff;
+    for _ in 0..READ_BYTES {
+        token = bus.exchange(0xff)?;
+        if token != 0xff {
+            break;
+        }
+    }
+    match token {
+        DATA_TOKEN => {}
+        0xff => return Err(Error::Timeout),
+        token => return Err(Error::DataToken(token)),
+    }
+    data.fill(0xff);
+    bus.transfer(data)?;
+    bus.transfer(&mut [0xff; 2])?;
+    Ok(())
+}
+
+fn check(command: u8, response: u8) -> Result<(), Error> {
+    match response {
+        0 => Ok(()),
+        response => Err(Error::Command { command, response }),
+    }
+}
+
+/// CRC of a command, only checked for the first commands in SPI mode
+fn crc7(bytes: &[u8]) -> u8 {
+    let mut crc = 0_u8;
+    for byte in bytes {
+        for bit in 0..8 {
+            crc <<= 1;
+            if ((byte << bit) ^ crc) & 0x80 != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc & 0x7f
+}
+
+fn csd_blocks(csd: &[u8; 16]) -> Result<u32, Error> {
+    match csd[0] >> 6 {
+        1 => {
+            // Version 2, C_SIZE counts 512 KiB
+            let size = (csd[7] as u32 & 0x3f) << 16 | (csd[8] as u32) << 8 | csd[9] as u32;
+            Ok((size + 1) * 1024)
+        }
+        0 => {
+            // Blocks of 512, 1024 or 2048 bytes
+            let read_bl_len = csd[5] as u32 & 0x0f;
+            if !(9..=11).contains(&read_bl_len) {
+                return Err(Error::InvalidCsd);
+            }
+            let size = (csd[6] as u32 & 0x03) << 10 | (csd[7] as u32) << 2 | (csd[8] as u32) >> 6;
+            let mult = (csd[9] as u32 & 0x03) << 1 | (csd[10] as u32) >> 7;
+            Ok((size + 1) << (mult + 2 + read_bl_len - 9))
+        }
+        _ => Err(Error::InvalidCsd),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::spi_bus::SpiBus;
+    use crate::testing::{NoDelay, Pin, SpiLog};
+
+    enum State {
+        Command(Vec<u8>),
+        AwaitToken(usize),
+        Receive(usize, Vec<u8>),
+    }
+
+    /// A card that answers in SPI mode, clones share the blocks
+    #[derive(Clone)]
+    struct Card {
+        version2: bool,
+        sdhc: bool,
+        blocks: Rc<RefCell<Vec<[u8; BLOCK_SIZE]>>>,
+        csd: [u8; 16],
+        out: Rc<RefCell<VecDeque<u8>>>,
+        state: Rc<RefCell<State>>,
+        busy_polls: Rc<RefCell<u8>>,
+    }
+
+    impl Card {
+        fn new(version2: bool, sdhc: bool, csd: [u8; 16]) -> Self {
+            Self {
+                version2,
+                sdhc,
+                blocks: Rc::new(RefCell::new(vec![[0; BLOCK_SIZE]; 16])),
+                csd,
+                out: Default::default(),
+                state: Rc::new(RefCell::new(State::Command(Vec::new()))),
+                busy_polls: Rc::new(RefCell::new(2)),
+            }
+        }
+
+        fn block(&self, argument: u32) -> usize {
+            if self.sdhc {
+                argument as usize
+            } else {
+                argument as usize / BLOCK_SIZE
+            }
+        }
+
+        fn respond(&self, bytes: &[u8]) {
+            let mut out = self.out.borrow_mut();
+            // One byte before the response
+            out.push_back(0xff);
+            out.extend(bytes);
+        }
+
+        fn command(&self, frame: &[u8]) {
+            let command = frame[0] & 0x3f;
+            let argument = u32::from_be_bytes(frame[1..5].try_into().unwrap());
+            if command == command::GO_IDLE_STATE || command == command::SEND_IF_COND {
+                assert_eq!(frame[5], crc7(&frame[..5]) << 1 | 1);
+            }
+            match command {
+                command::GO_IDLE_STATE | command::APP_CMD => self.respond(&[R1_IDLE]),
+                command::SEND_IF_COND if self.version2 => {
+                    let [.., high, low] = argument.to_be_bytes();
+                    self.respond(&[R1_IDLE, 0, 0, high, low]);
+                }
+                command::SD_SEND_OP_COND => {
+                    let mut busy_polls = self.busy_polls.borrow_mut();
+                    if *busy_polls > 0 {
+                        *busy_polls -= 1;
+                        self.respond(&[R1_IDLE]);
+                    } else {
+                        self.respond(&[0]);
+                    }
+                }
+                command::READ_OCR => {
+                    let ocr = 0x80ff_8000 | if self.sdhc { HCS } else { 0 };
+                    let mut response = vec![0];
+                    response.extend(ocr.to_be_bytes());
+                    self.respond(&response);
+                }
+                command::SET_BLOCKLEN if !self.sdhc => self.respond(&[0]),
+                command::SEND_CSD => {
+                    let mut response = vec![0, 0xff, DATA_TOKEN];
+                    response.extend(self.csd);
+                    response.extend([0, 0]);
+                    self.respond(&response);
+                }
+                command::READ_SINGLE_BLOCK => {
+                    match self.blocks.borrow().get(self.block(argument)) {
+                        Some(block) => {
+                            let mut response = vec![0, 0xff, 0xff, DATA_TOKEN];
+                            response.extend(block);
+                            response.extend([0, 0]);
+                            self.respond(&response);
+                        }
+                        // Address error
+                        None => self.respond(&[0x20]),
+                    }
+                }
+                command::WRITE_BLOCK => {
+                    self.respond(&[0]);
+                    *self.state.borrow_mut() = State::AwaitToken(self.block(argument));
+                }
+                _ => self.respond(&[R1_IDLE | R1_ILLEGAL_COMMAND]),
+            }
+        }
+
+        fn receive(&self, byte: u8) {
+            let mut state = self.state.borrow_mut();
+            match &mut *state {
+                State::Command(frame) => {
+                    if !frame.is_empty() || byte & 0xc0 == 0x40 {
+                        frame.push(byte);
+                    }
+                    if frame.len() == 6 {
+                        let frame = core::mem::take(frame);
+                        drop(state);
+                        self.command(&frame);
+                    }
+                }
+                State::AwaitToken(block) => {
+                    if byte == DATA_TOKEN {
+                        *state = State::Receive(*block, Vec::new());
+                    }
+                }
+                State::Receive(block, data) => {
+                    data.push(byte);
+                    if data.len() == BLOCK_SIZE + 2 {
+                        self.blocks.borrow_mut()[*block].copy_from_slice(&data[..BLOCK_SIZE]);
+                        // Accepted, then busy for a while
+                        self.out.borrow_mut().extend([0xe5, 0, 0, 0]);
+                        *state = State::Command(Vec::new());
+                    }
+                }
+            }
+        }
+    }
+
+    impl Transfer<u8> for Card {
+        type Error = Infallible;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+            for word in words.iter_mut() {
+                let out = self.out.borrow_mut().pop_front().unwrap_or(0xff);
+                self.receive(*word);
+                *word = out;
+            }
+            Ok(words)
+        }
+    }
+
+    impl Write<u8> for Card {
+        type Error = Infallible;
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+            self.transfer(&mut words.to_vec())?;
+            Ok(())
+        }
+    }
+
+    /// Version 2 CSD of a card with 2048 blocks
+    const SDHC_CSD: [u8; 16] = [0x40, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    /// Version 1 CSD of a card with 256 blocks
+    const SD1_CSD: [u8; 16] = [0, 0, 0, 0, 0, 0x09, 0, 0x0f, 0xc0, 0, 0, 0, 0, 0, 0, 0];
+
+    #[test]
+    fn command_crcs() {
+        assert_eq!(crc7(&[0x40, 0, 0, 0, 0]) << 1 | 1, 0x95);
+        assert_eq!(crc7(&[0x48, 0, 0, 0x01, 0xaa]) << 1 | 1, 0x87);
+    }
+
+    #[test]
+    fn reads_and_writes_sdhc_cards() {
+        let card = Card::new(true, true, SDHC_CSD);
+        let bus = SpiBus::new(card.clone());
+        let mut sd = SdCard::new(bus.device(Pin::default()), NoDelay);
+        assert_eq!(sd.init(), Ok(CardType::Sdhc));
+        assert_eq!(sd.num_blocks(), Ok(2048));
+
+        let mut block = [0_u8; BLOCK_SIZE];
+        block[0] = 0x55;
+        block[BLOCK_SIZE - 1] = 0xaa;
+        sd.write_block(5, &block).unwrap();
+        assert_eq!(card.blocks.borrow()[5], block);
+
+        let mut read = [0_u8; BLOCK_SIZE];
+        sd.read_block(5, &mut read).unwrap();
+        assert_eq!(read, block);
+    }
+
+    #[test]
+    fn addresses_version1_cards_in_bytes() {
+        let card = Card::new(false, false, SD1_CSD);
+        let bus = SpiBus::new(card.clone());
+        let mut sd = SdCard::new(bus.device(Pin::default()), NoDelay);
+        assert_eq!(sd.init(), Ok(CardType::Sd1));
+        assert_eq!(sd.num_blocks(), Ok(256));
+
+        let block = [7_u8; BLOCK_SIZE];
+        sd.write_block(3, &block).unwrap();
+        assert_eq!(card.blocks.borrow()[3], block);
+        assert_eq!(
+            sd.read_block(100, &mut [0; BLOCK_SIZE]),
+            Err(Error::Command {
+                command: command::READ_SINGLE_BLOCK,
+                response: 0x20
+            })
+        );
+        assert_eq!(
+            sd.read_block(1 << 23, &mut [0; BLOCK_SIZE]),
+            Err(Error::OutOfRange(1 << 23))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_csds() {
+        let mut csd = SD1_CSD;
+        csd[5] = 0x08;
+        assert_eq!(csd_blocks(&csd), Err(Error::InvalidCsd));
+        csd[5] = 0x0b;
+        assert_eq!(csd_blocks(&csd), Ok(1024));
+        assert_eq!(csd_blocks(&[0xc0; 16]), Err(Error::InvalidCsd));
+    }
+
+    #[test]
+    fn needs_a_card() {
+        let bus = SpiBus::new(SpiLog::default());
+        let mut sd = SdCard::new(bus.device(Pin::default()), NoDelay);
+        assert_eq!(
+            sd.read_block(0, &mut [0; BLOCK_SIZE]),
+            Err(Error::NotInitialized)
+        );
+        assert_eq!(sd.init(), Err(Error::NoCard));
+    }
+}
@@ -0,0 +1,532 @@
+//! Known wifi networks and the rom server entered on the setup page
+//!
+//! The `settings` partition holds one record: a magic and a version, the
+//! number of networks and for each its SSID and password after their
+//! lengths and its priority, then the rom server address after its
+//! length, the protocol of the rom server and a CRC of all of it. A record
+//! with a bad magic or CRC counts as no settings, so a board that was never
+//! set up falls back to its build variables. Records of version 1 hold a
+//! single network and are still read.
+
+use core::fmt;
+
+use embedded_storage::{ReadStorage, Storage};
+use udoo_link::crc16;
+
+use crate::dns::{Address, AddressError};
+use crate::http;
+use crate::partition::{Partition, PartitionTable};
+use crate::rom::Protocol;
+
+pub const LABEL: &str = "settings";
+/// Longest SSID of 802.11
+pub const MAX_SSID: usize = 32;
+/// Longest WPA2 passphrase
+pub const MAX_PASSWORD: usize = 63;
+/// Shortest WPA2 passphrase, open networks have none
+pub const MIN_PASSWORD: usize = 8;
+pub const MAX_ADDRESS: usize = 64;
+/// Networks that are remembered, adding another one forgets the one with
+/// the lowest priority
+pub const MAX_NETWORKS: usize = 4;
+/// Priority of a network entered without one
+pub const DEFAULT_PRIORITY: u8 = 1;
+
+const MAGIC: [u8; 4] = *b"UKWS";
+const VERSION: u8 = 2;
+/// The single network of the first version
+const VERSION_1: u8 = 1;
+/// The lengths of a network's SSID and password and its priority
+const NETWORK_SIZE: usize = 1 + MAX_SSID + 1 + MAX_PASSWORD + 1;
+/// The magic, the version, the number of networks, the networks, the rom
+/// server after its length, the protocol and the CRC
+const RECORD_SIZE: usize = 4 + 1 + 1 + MAX_NETWORKS * NETWORK_SIZE + 1 + MAX_ADDRESS + 1 + 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Reading or writing the esp32 flash failed
+    Storage,
+    /// The `settings` partition is missing from the partition table
+    MissingPartition,
+    /// The SSID is empty or longer than [`MAX_SSID`] bytes
+    Ssid,
+    /// The password is neither empty nor 8 to 63 characters
+    Password,
+    /// The priority is not a number from 0 to 255
+    Priority,
+    RomServer(AddressError),
+    /// The protocol is not `legacy`, `http` or `tftp`
+    Protocol,
+    /// A form field is not percent-encoded text or is too long
+    Form,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Storage => f.write_str("the flash cannot be read or written"),
+            Self::MissingPartition => f.write_str("the flash has no settings partition"),
+            Self::Ssid => f.write_str("the SSID has to be 1 to 32 bytes"),
+            Self::Password => f.write_str("the password has to be empty or 8 to 63 characters"),
+            Self::Priority => f.write_str("the priority has to be a number from 0 to 255"),
+            Self::RomServer(e) => write!(f, "the rom server cannot be used, {e}"),
+            Self::Protocol => f.write_str("the protocol is not legacy, http or tftp"),
+            Self::Form => f.write_str("the form is not valid or a field is too long"),
+        }
+    }
+}
+
+impl From<AddressError> for Error {
+    fn from(e: AddressError) -> Self {
+        Error::RomServer(e)
+    }
+}
+
+/// Text of up to `N` bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Text<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Text<N> {
+    fn new(text: &str) -> Option<Self> {
+        let mut bytes = [0; N];
+        bytes
+            .get_mut(..text.len())?
+            .copy_from_slice(text.as_bytes());
+        Some(Self {
+            bytes,
+            len: text.len(),
+        })
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+/// A network the esp32 may join, the higher the priority the sooner
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Network {
+    ssid: Text<MAX_SSID>,
+    password: Text<MAX_PASSWORD>,
+    priority: u8,
+}
+
+impl Network {
+    pub fn new(ssid: &str, password: &str, priority: u8) -> Result<Self, Error> {
+        let ssid = Some(ssid)
+            .filter(|ssid| !ssid.is_empty())
+            .and_then(Text::new)
+            .ok_or(Error::Ssid)?;
+        let password = Some(password)
+            .filter(|password| password.is_empty() || password.chars().count() >= MIN_PASSWORD)
+            .and_then(Text::new)
+            .ok_or(Error::Password)?;
+        Ok(Self {
+            ssid,
+            password,
+            priority,
+        })
+    }
+
+    pub fn ssid(&self) -> &str {
+        self.ssid.as_str()
+    }
+
+    pub fn password(&self) -> &str {
+        self.password.as_str()
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+}
+
+/// The network to join of the access points that were seen, given as SSID
+/// and signal strength in dBm
+///
+/// Of the known networks in sight the one with the highest priority wins,
+/// and of its access points the strongest. Returns the network and the
+/// index of the access point.
+pub fn choose<'k, 's>(
+    known: &'k [Network],
+    seen: impl IntoIterator<Item = (&'s str, i8)>,
+) -> Option<(&'k Network, usize)> {
+    seen.into_iter()
+        .enumerate()
+        .filter_map(|(index, (ssid, rssi))| {
+            let network = known.iter().find(|network| network.ssid() == ssid)?;
+            Some((network, index, rssi))
+        })
+        .max_by_key(|&(network, index, rssi)| {
+            // The first of equals is the one to keep
+            (network.priority, rssi, core::cmp::Reverse(index))
+        })
+        .map(|(network, index, _)| (network, index))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    networks: [Option<Network>; MAX_NETWORKS],
+    /// Empty when the rom server is left to the build or to mDNS
+    rom_server: Text<MAX_ADDRESS>,
+    protocol: Protocol,
+}
+
+impl Settings {
+    /// Settings without networks, `rom_server` is an empty string or
+    /// `host:port`
+    pub fn new(rom_server: &str, protocol: Protocol) -> Result<Self, Error> {
+        let rom_server = rom_server.trim();
+        if !rom_server.is_empty() {
+            Address::parse(rom_server)?;
+        }
+        let rom_server = Text::new(rom_server).ok_or(Error::RomServer(AddressError::BadHost))?;
+        Ok(Self {
+            networks: [None; MAX_NETWORKS],
+            rom_server,
+            protocol,
+        })
+    }
+
+    /// The settings of a submitted form with a single network, the fields
+    /// `ssid`, `password`, `priority`, `rom_server` and `protocol` in
+    /// `application/x-www-form-urlencoded`
+    ///
+    /// The networks of `saved` are kept, see [`Settings::add`].
+    pub fn from_form(body: &str, saved: Option<&Settings>) -> Result<Self, Error> {
+        let field = |name| {
+            body.split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|&(key, _)| key == name)
+                .map_or("", |(_, value)| value)
+        };
+        let mut ssid = [0; MAX_SSID];
+        let mut password = [0; MAX_PASSWORD];
+        let mut rom_server = [0; MAX_ADDRESS];
+        let decode = |value, out| http::percent_decode(value, out).map_err(|_| Error::Form);
+        let priority = match field("priority") {
+            "" => DEFAULT_PRIORITY,
+            priority => priority.parse().map_err(|_| Error::Priority)?,
+        };
+        let protocol = match field("protocol") {
+            "" => Protocol::Legacy,
+            protocol => protocol.parse().map_err(|_| Error::Protocol)?,
+        };
+        let network = Network::new(
+            decode(field("ssid"), &mut ssid)?,
+            decode(field("password"), &mut password)?,
+            priority,
+        )?;
+        let mut settings = Self::new(decode(field("rom_server"), &mut rom_server)?, protocol)?;
+        if let Some(saved) = saved {
+            settings.networks = saved.networks;
+        }
+        settings.add(network);
+        Ok(settings)
+    }
+
+    /// Remember a network, in place of one with the same SSID or, when
+    /// all are taken, of the one with the lowest priority
+    pub fn add(&mut self, network: Network) {
+        let slot = self
+            .networks
+            .iter()
+            .position(|n| n.is_some_and(|n| n.ssid() == network.ssid()))
+            .or_else(|| self.networks.iter().position(Option::is_none))
+            .or_else(|| {
+                (0..MAX_NETWORKS).min_by_key(|&i| self.networks[i].map_or(0, |n| n.priority))
+            });
+        if let Some(slot) = slot {
+            self.networks[slot] = Some(network);
+        }
+    }
+
+    /// Forget the network, returns whether it was known
+    pub fn remove(&mut self, ssid: &str) -> bool {
+        let mut removed = false;
+        for slot in &mut self.networks {
+            if slot.is_some_and(|n| n.ssid() == ssid) {
+                *slot = None;
+                removed = true;
+            }
+        }
+        removed
+    }
+
+    pub fn networks(&self) -> impl Iterator<Item = &Network> {
+        self.networks.iter().flatten()
+    }
+
+    /// The address of the rom server and the protocol it speaks, if one
+    /// was entered
+    pub fn rom_server(&self) -> Option<(&str, Protocol)> {
+        Some(self.rom_server.as_str())
+            .filter(|address| !address.is_empty())
+            .map(|address| (address, self.protocol))
+    }
+
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0; RECORD_SIZE];
+        let mut at = 0;
+        let mut put = |bytes: &[u8]| {
+            record[at..at + bytes.len()].copy_from_slice(bytes);
+            at += bytes.len();
+        };
+        put(&MAGIC);
+        put(&[VERSION, self.networks().count() as u8]);
+        for network in self.networks() {
+            for text in [network.ssid(), network.password()] {
+                put(&[text.len() as u8]);
+                put(text.as_bytes());
+            }
+            put(&[network.priority]);
+        }
+        put(&[self.rom_server.len as u8]);
+        put(self.rom_server.as_str().as_bytes());
+        put(&[match self.protocol {
+            Protocol::Legacy => 0,
+            Protocol::Http => 1,
+            Protocol::Tftp => 2,
+        }]);
+        let crc = crc16(&record[..at]);
+        record[at..at + 2].copy_from_slice(&crc.to_be_bytes());
+        record
+    }
+
+    fn decode(record: &[u8; RECORD_SIZE]) -> Option<Self> {
+        if record[..4] != MAGIC {
+            return None;
+        }
+        let mut reader = Reader { record, at: 5 };
+        let mut networks = [None; MAX_NETWORKS];
+        match record[4] {
+            VERSION_1 => {
+                let (ssid, password) = (reader.text()?, reader.text()?);
+                networks[0] = Some(Network::new(ssid, password, DEFAULT_PRIORITY).ok()?);
+            }
+            VERSION => {
+                let count = reader.byte()? as usize;
+                for network in networks.get_mut(..count)? {
+                    let (ssid, password) = (reader.text()?, reader.text()?);
+                    *network = Some(Network::new(ssid, password, reader.byte()?).ok()?);
+                }
+            }
+            _ => return None,
+        }
+        let rom_server = reader.text()?;
+        let protocol = match reader.byte()? {
+            0 => Protocol::Legacy,
+            1 => Protocol::Http,
+            2 => Protocol::Tftp,
+            _ => return None,
+        };
+        let end = reader.at;
+        let crc = u16::from_be_bytes([reader.byte()?, reader.byte()?]);
+        if crc16(&record[..end]) != crc {
+            return None;
+        }
+        let mut settings = Self::new(rom_server, protocol).ok()?;
+        settings.networks = networks;
+        Some(settings)
+    }
+}
+
+/// Reads the fields of a record in turn
+struct Reader<'r> {
+    record: &'r [u8],
+    at: usize,
+}
+
+impl<'r> Reader<'r> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.record.get(self.at)?;
+        self.at += 1;
+        Some(byte)
+    }
+
+    /// Text after its length
+    fn text(&mut self) -> Option<&'r str> {
+        let len = self.byte()? as usize;
+        let bytes = self.record.get(self.at..self.at + len)?;
+        self.at += len;
+        core::str::from_utf8(bytes).ok()
+    }
+}
+
+/// The settings partition
+pub struct Store<S> {
+    storage: S,
+    partition: Partition,
+}
+
+impl<S> Store<S>
+where
+    S: ReadStorage + Storage,
+{
+    pub fn new(mut storage: S) -> Result<Self, Error> {
+        let table = PartitionTable::read(&mut storage).map_err(|_| Error::Storage)?;
+        let partition = table.find(LABEL).ok_or(Error::MissingPartition)?;
+        Ok(Self { storage, partition })
+    }
+
+    /// The saved settings, `None` if there are none or they are damaged
+    pub fn load(&mut self) -> Result<Option<Settings>, Error> {
+        let mut record = [0; RECORD_SIZE];
+        self.storage
+            .read(self.partition.offset, &mut record)
+            .map_err(|_| Error::Storage)?;
+        Ok(Settings::decode(&record))
+    }
+
+    pub fn save(&mut self, settings: &Settings) -> Result<(), Error> {
+        self.storage
+            .write(self.partition.offset, &settings.encode())
+            .map_err(|_| Error::Storage)
+    }
+
+    /// Forget the settings, the build variables apply again
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.storage
+            .write(self.partition.offset, &[0; 4])
+            .map_err(|_| Error::Storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition::TYPE_DATA;
+    use crate::testing::RamFlash;
+
+    const OFFSET: u32 = 0x11000;
+
+    fn store() -> (Store<RamFlash>, RamFlash) {
+        let flash =
+            RamFlash::new(0x12000).with_partitions(&[(TYPE_DATA, 0x82, OFFSET, 0x1000, LABEL)]);
+        (Store::new(flash.clone()).unwrap(), flash)
+    }
+
+    fn ssids(settings: &Settings) -> Vec<&str> {
+        settings.networks().map(Network::ssid).collect()
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let (mut store, flash) = store();
+        assert_eq!(store.load(), Ok(None));
+
+        let mut settings = Settings::new("roms.lab:8000", Protocol::Http).unwrap();
+        settings.add(Network::new("lab", "correct horse", 3).unwrap());
+        settings.add(Network::new("guest", "", 0).unwrap());
+        store.save(&settings).unwrap();
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded, settings);
+        let lab = loaded.networks().next().unwrap();
+        assert_eq!(lab.ssid(), "lab");
+        assert_eq!(lab.password(), "correct horse");
+        assert_eq!(lab.priority(), 3);
+        assert_eq!(ssids(&loaded), ["lab", "guest"]);
+        assert_eq!(loaded.rom_server(), Some(("roms.lab:8000", Protocol::Http)));
+
+        // A damaged record is no record
+        let mut byte = flash.bytes(OFFSET + 7, 1);
+        byte[0] ^= 1;
+        flash.clone().write(OFFSET + 7, &byte).unwrap();
+        assert_eq!(store.load(), Ok(None));
+
+        store.save(&settings).unwrap();
+        store.clear().unwrap();
+        assert_eq!(store.load(), Ok(None));
+    }
+
+    #[test]
+    fn reads_the_first_version() {
+        let (mut store, flash) = store();
+        let mut record = b"UKWS\x01\x03lab\x08p@ssw0rd\x0d10.0.0.2:5000\x01".to_vec();
+        record.extend_from_slice(&crc16(&record).to_be_bytes());
+        flash.clone().write(OFFSET, &record).unwrap();
+
+        let loaded = store.load().unwrap().unwrap();
+        let network = loaded.networks().next().unwrap();
+        assert_eq!(
+            (network.ssid(), network.password(), network.priority()),
+            ("lab", "p@ssw0rd", DEFAULT_PRIORITY)
+        );
+        assert_eq!(loaded.rom_server(), Some(("10.0.0.2:5000", Protocol::Http)));
+    }
+
+    #[test]
+    fn keeps_a_few_networks() {
+        let mut settings = Settings::new("", Protocol::Legacy).unwrap();
+        for (ssid, priority) in [("a", 2), ("b", 0), ("c", 5), ("d", 1)] {
+            settings.add(Network::new(ssid, "", priority).unwrap());
+        }
+        // The same SSID is replaced, a new one takes the lowest priority's
+        // place
+        settings.add(Network::new("c", "", 4).unwrap());
+        settings.add(Network::new("e", "", 3).unwrap());
+        assert_eq!(ssids(&settings), ["a", "e", "c", "d"]);
+        assert_eq!(settings.networks().nth(2).unwrap().priority(), 4);
+
+        assert!(settings.remove("a"));
+        assert!(!settings.remove("a"));
+        assert_eq!(ssids(&settings), ["e", "c", "d"]);
+    }
+
+    #[test]
+    fn chooses_networks() {
+        let known = [
+            Network::new("home", "", 1).unwrap(),
+            Network::new("lab", "", 2).unwrap(),
+            Network::new("guest", "", 1).unwrap(),
+        ];
+        let seen = [("cafe", -30), ("home", -40), ("lab", -80), ("lab", -60)];
+        let (network, index) = choose(&known, seen).unwrap();
+        assert_eq!((network.ssid(), index), ("lab", 3));
+
+        // The strongest of equal priorities
+        let seen = [("home", -70), ("guest", -50), ("home", -50)];
+        let (network, index) = choose(&known, seen).unwrap();
+        assert_eq!((network.ssid(), index), ("guest", 1));
+
+        assert_eq!(choose(&known, [("cafe", -30)]), None);
+    }
+
+    #[test]
+    fn reads_forms() {
+        let form = "ssid=Lab+Wifi&password=p%40ssw0rd!&priority=3&rom_server=&protocol=legacy";
+        let settings = Settings::from_form(form, None).unwrap();
+        let network = settings.networks().next().unwrap();
+        assert_eq!(network.ssid(), "Lab Wifi");
+        assert_eq!(network.password(), "p@ssw0rd!");
+        assert_eq!(network.priority(), 3);
+        assert_eq!(settings.rom_server(), None);
+
+        // Another network is added to the saved ones
+        let open = Settings::from_form("ssid=guest&rom_server=10.0.0.2%3A5000", Some(&settings));
+        let open = open.unwrap();
+        assert_eq!(ssids(&open), ["Lab Wifi", "guest"]);
+        let guest = open.networks().nth(1).unwrap();
+        assert_eq!(guest.password(), "");
+        assert_eq!(guest.priority(), DEFAULT_PRIORITY);
+        assert_eq!(open.rom_server(), Some(("10.0.0.2:5000", Protocol::Legacy)));
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        let form = |body: &str| Settings::from_form(body, None);
+        assert_eq!(form("password=p%40ssw0rd!"), Err(Error::Ssid));
+        assert_eq!(form(&format!("ssid={}", "s".repeat(33))), Err(Error::Form));
+        assert_eq!(form("ssid=lab&password=short"), Err(Error::Password));
+        assert_eq!(form("ssid=lab&priority=high"), Err(Error::Priority));
+        assert_eq!(
+            form("ssid=lab&rom_server=10.0.0.2"),
+            Err(Error::RomServer(AddressError::NoPort))
+        );
+        assert_eq!(form("ssid=lab&protocol=ftp"), Err(Error::Protocol));
+        assert_eq!(form("ssid=%zz"), Err(Error::Form));
+    }
+}
@@ -0,0 +1,401 @@
+//! Command shell for a serial console
+//!
+//! [`Shell`] edits a line as it is typed and runs it once enter is
+//! pressed. Backspace, `Ctrl-U` (clear the line), `Ctrl-C` (drop the
+//! line) and the up arrow (the previous line) work like in a terminal.
+//!
+//! Commands come from the subsystems: each implements [`Commands`] for a
+//! handle to its state, listing its commands and running them. The program
+//! passes the handles of every subsystem it has to [`Shell::feed`], and
+//! `help` lists what they offer. Command names can be several words long,
+//! like `rom load`, whatever follows the name is handed to the command as
+//! [`Args`].
+
+use core::fmt::{self, Write};
+use core::str::{FromStr, SplitWhitespace};
+
+/// Longest line that can be typed
+pub const LINE_SIZE: usize = 64;
+
+const PROMPT: &str = "> ";
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const CTRL_C: u8 = 0x03;
+const CTRL_U: u8 = 0x15;
+const ESCAPE: u8 = 0x1b;
+
+/// Width of the usage column of `help`
+const USAGE_WIDTH: usize = 28;
+
+/// A command as listed by `help`
+pub struct Command {
+    /// One or more words
+    pub name: &'static str,
+    /// Arguments, like `<id>`
+    pub args: &'static str,
+    pub help: &'static str,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Missing or invalid arguments, the usage of the command is shown
+    Usage,
+    Failed(&'static str),
+}
+
+/// What a subsystem adds to the shell
+pub trait Commands {
+    fn commands(&self) -> &'static [Command];
+
+    /// Run one of [`Commands::commands`], `name` is its name
+    fn run(&mut self, name: &str, args: Args<'_>, out: &mut dyn Write) -> Result<(), Error>;
+}
+
+/// Words that follow the command name
+pub struct Args<'l>(SplitWhitespace<'l>);
+
+impl<'l> Args<'l> {
+    pub fn new(words: &'l str) -> Self {
+        Self(words.split_whitespace())
+    }
+
+    /// The next word as a `T`
+    pub fn parse<T: FromStr>(&mut self) -> Result<T, Error> {
+        self.0
+            .next()
+            .and_then(|word| word.parse().ok())
+            .ok_or(Error::Usage)
+    }
+}
+
+impl<'l> Iterator for Args<'l> {
+    type Item = &'l str;
+
+    fn next(&mut self) -> Option<&'l str> {
+        self.0.next()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    /// After an ESC
+    Started,
+    Sequence,
+}
+
+pub struct Shell {
+    line: [u8; LINE_SIZE],
+    len: usize,
+    previous: [u8; LINE_SIZE],
+    previous_len: usize,
+    escape: Escape,
+    /// A `\n` right after a `\r` ends the same line
+    after_cr: bool,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shell {
+    pub const fn new() -> Self {
+        Self {
+            line: [0; LINE_SIZE],
+            len: 0,
+            previous: [0; LINE_SIZE],
+            previous_len: 0,
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    pub fn prompt(&self, out: &mut dyn Write) {
+        _ = out.write_str(PROMPT);
+    }
+
+    /// Handle a byte typed on the console, echoing it to `out`
+    pub fn feed(&mut self, byte: u8, commands: &mut [&mut dyn Commands], out: &mut dyn Write) {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match (self.escape, byte) {
+            (Escape::None, ESCAPE) => self.escape = Escape::Started,
+            (Escape::Started, b'[') => self.escape = Escape::Sequence,
+            (Escape::Started, _) => self.escape = Escape::None,
+            (Escape::Sequence, b'A') => {
+                self.escape = Escape::None;
+                self.clear(out);
+                self.line = self.previous;
+                self.len = self.previous_len;
+                _ = out.write_str(self.text());
+            }
+            // Parameters of other sequences, like the other arrows
+            (Escape::Sequence, b'0'..=b'9' | b';') => {}
+            (Escape::Sequence, _) => self.escape = Escape::None,
+            (Escape::None, b'\n') if after_cr => {}
+            (Escape::None, b'\r' | b'\n') => {
+                _ = out.write_str("\r\n");
+                self.execute(commands, out);
+                self.len = 0;
+                self.prompt(out);
+            }
+            (Escape::None, BACKSPACE | DELETE) => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    _ = out.write_str("\x08 \x08");
+                }
+            }
+            (Escape::None, CTRL_U) => self.clear(out),
+            (Escape::None, CTRL_C) => {
+                _ = out.write_str("^C\r\n");
+                self.len = 0;
+                self.prompt(out);
+            }
+            (Escape::None, b' '..=b'~') => {
+                if self.len < LINE_SIZE {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                    _ = out.write_char(byte as char);
+                }
+            }
+            (Escape::None, _) => {}
+        }
+    }
+
+    fn execute(&mut self, commands: &mut [&mut dyn Commands], out: &mut dyn Write) {
+        if self.text().trim().is_empty() {
+            return;
+        }
+        self.previous = self.line;
+        self.previous_len = self.len;
+        let line = core::str::from_utf8(&self.previous[..self.previous_len]).unwrap_or_default();
+        run(line, commands, out);
+    }
+
+    /// The line typed so far
+    fn text(&self) -> &str {
+        // Only printable ascii is stored
+        core::str::from_utf8(&self.line[..self.len]).unwrap_or_default()
+    }
+
+    /// Erase the line on the terminal and in the buffer
+    fn clear(&mut self, out: &mut dyn Write) {
+        for _ in 0..self.len {
+            _ = out.write_str("\x08 \x08");
+        }
+        self.len = 0;
+    }
+}
+
+/// Run a command line that did not come from the console, like one sent
+/// over the network, writing what it prints to `out`
+pub fn run(line: &str, commands: &mut [&mut dyn Commands], out: &mut dyn Write) {
+    let line = line.trim();
+    if line == "help" {
+        help(commands, out);
+        return;
+    }
+    for group in commands.iter_mut() {
+        for command in group.commands() {
+            let Some(args) = strip_name(line, command.name) else {
+                continue;
+            };
+            match group.run(command.name, Args::new(args), out) {
+                Ok(()) => {}
+                Err(Error::Usage) => {
+                    _ = write!(out, "usage: {} {}\r\n", command.name, command.args);
+                }
+                Err(Error::Failed(reason)) => _ = write!(out, "error: {reason}\r\n"),
+            }
+            return;
+        }
+    }
+    _ = out.write_str("unknown command, try help\r\n");
+}
+
+/// The rest of `line` if it starts with the words of `name`
+fn strip_name<'l>(line: &'l str, name: &str) -> Option<&'l str> {
+    let mut rest = line;
+    for word in name.split_whitespace() {
+        rest = rest.trim_start().strip_prefix(word)?;
+        if !rest.is_empty() && !rest.starts_with(' ') {
+            return None;
+        }
+    }
+    Some(rest)
+}
+
+fn help(commands: &mut [&mut dyn Commands], out: &mut dyn Write) {
+    for group in commands.iter() {
+        for command in group.commands() {
+            let mut usage = Usage::default();
+            _ = write!(usage, "{} {}", command.name, command.args);
+            help_line(out, usage.text(), command.help);
+        }
+    }
+    help_line(out, "help", "Show this list");
+}
+
+/// A line of `help`, a long usage pushes the help text to the right
+fn help_line(out: &mut dyn Write, usage: &str, help: &str) {
+    let width = USAGE_WIDTH - 1;
+    _ = write!(out, "{usage:<width$} {help}\r\n");
+}
+
+/// Name and arguments of a command
+struct Usage {
+    text: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self {
+            text: [0; LINE_SIZE],
+            len: 0,
+        }
+    }
+}
+
+impl Usage {
+    fn text(&self) -> &str {
+        core::str::from_utf8(&self.text[..self.len]).unwrap_or_default()
+    }
+}
+
+impl Write for Usage {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.len < self.text.len() {
+                self.text[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Remembers what it was asked to run
+    #[derive(Default)]
+    struct Recorder {
+        runs: Vec<(String, Vec<String>)>,
+    }
+
+    impl Commands for Recorder {
+        fn commands(&self) -> &'static [Command] {
+            &[
+                Command {
+                    name: "rom list",
+                    args: "",
+                    help: "List the roms",
+                },
+                Command {
+                    name: "rom load",
+                    args: "<id>",
+                    help: "Load a rom",
+                },
+                Command {
+                    name: "fail",
+                    args: "",
+                    help: "Always fails",
+                },
+            ]
+        }
+
+        fn run(
+            &mut self,
+            name: &str,
+            mut args: Args<'_>,
+            out: &mut dyn Write,
+        ) -> Result<(), Error> {
+            match name {
+                "rom load" => {
+                    let id: u16 = args.parse()?;
+                    _ = write!(out, "loading {id}\r\n");
+                }
+                "fail" => return Err(Error::Failed("it broke")),
+                _ => {}
+            }
+            self.runs
+                .push((name.into(), args.map(String::from).collect()));
+            Ok(())
+        }
+    }
+
+    fn type_in(shell: &mut Shell, recorder: &mut Recorder, text: &[u8]) -> String {
+        let mut out = String::new();
+        for byte in text {
+            shell.feed(*byte, &mut [recorder], &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn runs_commands() {
+        let (mut shell, mut recorder) = (Shell::new(), Recorder::default());
+        let out = type_in(
+            &mut shell,
+            &mut recorder,
+            b"rom load 3\r\n rom  list extra\r",
+        );
+        assert_eq!(
+            recorder.runs,
+            [
+                (String::from("rom load"), Vec::new()),
+                (String::from("rom list"), Vec::from([String::from("extra")])),
+            ]
+        );
+        assert_eq!(out, "rom load 3\r\nloading 3\r\n>  rom  list extra\r\n> ");
+    }
+
+    #[test]
+    fn reports_errors() {
+        let (mut shell, mut recorder) = (Shell::new(), Recorder::default());
+        let out = type_in(&mut shell, &mut recorder, b"rom load x\rfail\rromlist\r");
+        assert!(out.contains("usage: rom load <id>\r\n"));
+        assert!(out.contains("error: it broke\r\n"));
+        assert!(out.contains("unknown command, try help\r\n"));
+        assert!(recorder.runs.is_empty());
+    }
+
+    #[test]
+    fn edits_lines() {
+        let (mut shell, mut recorder) = (Shell::new(), Recorder::default());
+        // A typo fixed with backspace, a line dropped with Ctrl-U and one
+        // dropped with Ctrl-C
+        type_in(&mut shell, &mut recorder, b"rom lisx\x08t\r");
+        type_in(&mut shell, &mut recorder, b"rom load 1\x15fail\x03");
+        assert_eq!(recorder.runs.len(), 1);
+        // Up arrow brings back the last line
+        let out = type_in(&mut shell, &mut recorder, b"xy\x1b[A\r");
+        assert!(out.starts_with("xy\x08 \x08\x08 \x08rom list\r\n"));
+        assert_eq!(recorder.runs.len(), 2);
+    }
+
+    #[test]
+    fn runs_lines() {
+        let mut recorder = Recorder::default();
+        let mut out = String::new();
+        run(" rom load 7\n", &mut [&mut recorder], &mut out);
+        assert_eq!(out, "loading 7\r\n");
+        assert_eq!(recorder.runs.len(), 1);
+    }
+
+    #[test]
+    fn lists_commands() {
+        let (mut shell, mut recorder) = (Shell::new(), Recorder::default());
+        let out = type_in(&mut shell, &mut recorder, b"help\r");
+        let lines: Vec<&str> = out.split("\r\n").collect();
+        assert_eq!(lines[1], "rom list                    List the roms");
+        assert_eq!(lines[2], "rom load <id>               Load a rom");
+        assert_eq!(lines[4], "help                        Show this list");
+    }
+}
@@ -0,0 +1,178 @@
+//! Several devices on one SPI bus
+//!
+//! The UEXT connector has one SPI bus and one chip select, but modules
+//! can be chained with chip selects on other pins. [`SpiBus`] owns the
+//! bus and hands out a [`Device`] per chip select. A device selects its
+//! chip only for the length of a transfer or a [`Device::transaction`],
+//! so drivers can take turns on the bus without knowing about each other.
+//!
+//! The bus lives in a `RefCell`, so all devices have to be used from the
+//! same context. Using the bus from within a transaction of another
+//! device panics.
+
+use core::cell::RefCell;
+
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::OutputPin;
+
+/// The bus returned an error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Error;
+
+/// Full duplex SPI bus shared by several devices
+pub struct SpiBus<B> {
+    bus: RefCell<B>,
+}
+
+impl<B> SpiBus<B>
+where
+    B: Transfer<u8> + Write<u8>,
+{
+    pub fn new(bus: B) -> Self {
+        Self {
+            bus: RefCell::new(bus),
+        }
+    }
+
+    /// A device that is selected by driving `cs` low, the chip is
+    /// deselected straight away
+    pub fn device<CS: OutputPin>(&self, mut cs: CS) -> Device<'_, B, CS> {
+        cs.set_high().ok();
+        Device { bus: &self.bus, cs }
+    }
+
+    pub fn free(self) -> B {
+        self.bus.into_inner()
+    }
+}
+
+/// One chip on a [`SpiBus`]
+pub struct Device<'a, B, CS> {
+    bus: &'a RefCell<B>,
+    cs: CS,
+}
+
+impl<'a, B, CS> Device<'a, B, CS>
+where
+    B: Transfer<u8> + Write<u8>,
+    CS: OutputPin,
+{
+    /// Run `f` with the chip selected for the whole time
+    pub fn transaction<R>(&mut self, f: impl FnOnce(&mut Bus<'_, B>) -> R) -> R {
+        let mut bus = self.bus.borrow_mut();
+        self.cs.set_low().ok();
+        let result = f(&mut Bus(&mut bus));
+        self.cs.set_high().ok();
+        result
+    }
+
+    /// Run `f` with the chip deselected, for clocking a chip with its
+    /// chip select high like SD cards need at power up
+    pub fn deselected<R>(&mut self, f: impl FnOnce(&mut Bus<'_, B>) -> R) -> R {
+        let mut bus = self.bus.borrow_mut();
+        f(&mut Bus(&mut bus))
+    }
+
+    /// Give back the chip select
+    pub fn free(self) -> CS {
+        self.cs
+    }
+}
+
+impl<'a, B, CS> Transfer<u8> for Device<'a, B, CS>
+where
+    B: Transfer<u8> + Write<u8>,
+    CS: OutputPin,
+{
+    type Error = Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Error> {
+        self.transaction(|bus| bus.transfer(words).map(|_| ()))?;
+        Ok(words)
+    }
+}
+
+impl<'a, B, CS> Write<u8> for Device<'a, B, CS>
+where
+    B: Transfer<u8> + Write<u8>,
+    CS: OutputPin,
+{
+    type Error = Error;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        self.transaction(|bus| bus.write(words))
+    }
+}
+
+/// The bus during a [`Device::transaction`]
+pub struct Bus<'b, B>(&'b mut B);
+
+impl<'b, B> Bus<'b, B>
+where
+    B: Transfer<u8> + Write<u8>,
+{
+    /// Send `words` and replace them with the bytes received
+    pub fn transfer(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        self.0.transfer(words).map(|_| ()).map_err(|_| Error)
+    }
+
+    pub fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        self.0.write(words).map_err(|_| Error)
+    }
+
+    /// Send a byte and return the byte received
+    pub fn exchange(&mut self, byte: u8) -> Result<u8, Error> {
+        let mut word = [byte];
+        self.transfer(&mut word)?;
+        Ok(word[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::testing::{Pin, SpiLog};
+
+    #[test]
+    fn selects_one_device_at_a_time() {
+        let log = SpiLog::default();
+        let bus = SpiBus::new(log.clone());
+        let (first_cs, second_cs) = (Pin::default(), Pin::default());
+        let mut first = bus.device(first_cs.clone());
+        let mut second = bus.device(second_cs.clone());
+
+        first.write(&[1, 2]).unwrap();
+        let mut words = [3];
+        second.transfer(&mut words).unwrap();
+        first.transaction(|bus| {
+            bus.write(&[4]).unwrap();
+            bus.exchange(5).unwrap();
+        });
+
+        assert_eq!(log.written(), [1, 2, 3, 4, 5]);
+        assert_eq!(first_cs.levels(), [true, false, true, false, true]);
+        assert_eq!(second_cs.levels(), [true, false, true]);
+    }
+
+    #[test]
+    fn clocks_without_selecting() {
+        let log = SpiLog::default();
+        let bus = SpiBus::new(log.clone());
+        let cs = Pin::default();
+        let mut device = bus.device(cs.clone());
+        device.deselected(|bus| bus.write(&[0xff; 3])).unwrap();
+        assert_eq!(log.written(), Vec::from([0xff; 3]));
+        assert_eq!(cs.levels(), [true]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn nested_transactions_panic() {
+        let bus = SpiBus::new(SpiLog::default());
+        let mut first = bus.device(Pin::default());
+        let mut second = bus.device(Pin::default());
+        first.transaction(|_| second.write(&[1]).ok());
+    }
+}
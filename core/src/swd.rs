@@ -0,0 +1,589 @@
+//! Bit-banged SWD and ADIv5 access to the rp2040
+//!
+//! The esp32 on the Udoo Key is wired to the SWD port of the rp2040
+//! (SWDIO on Gpio2, SWCLK on Gpio4). This module drives those two lines
+//! in software and implements enough of the ADIv5 debug port and MEM-AP
+//! to read and write rp2040 memory and halt or run its cores.
+//!
+//! The rp2040 uses multi-drop SWD, so every connection starts by waking
+//! the debug port from its dormant state and selecting a core with a
+//! `TARGETSEL` write.
+
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+/// `TARGETSEL` value for rp2040 core 0
+pub const RP2040_CORE0: u32 = 0x0100_2927;
+/// `TARGETSEL` value for rp2040 core 1
+pub const RP2040_CORE1: u32 = 0x1100_2927;
+/// `TARGETSEL` value for the rp2040 rescue debug port
+pub const RP2040_RESCUE: u32 = 0xf100_2927;
+
+/// Debug port register addresses
+pub mod dp {
+    /// Debug port identification register (read)
+    pub const DPIDR: u8 = 0x0;
+    /// Abort register (write)
+    pub const ABORT: u8 = 0x0;
+    /// Control and status register
+    pub const CTRL_STAT: u8 = 0x4;
+    /// Access port select register (write)
+    pub const SELECT: u8 = 0x8;
+    /// Read buffer for posted access port reads (read)
+    pub const RDBUFF: u8 = 0xc;
+    /// Multi-drop target selection register (write)
+    pub const TARGETSEL: u8 = 0xc;
+}
+
+/// MEM-AP register addresses
+pub mod ap {
+    /// Control and status word
+    pub const CSW: u8 = 0x0;
+    /// Transfer address register
+    pub const TAR: u8 = 0x4;
+    /// Data read/write register
+    pub const DRW: u8 = 0xc;
+    /// Identification register, lives in bank 0xf
+    pub const IDR: u8 = 0xfc;
+}
+
+/// Cortex-M debug registers used to control the rp2040 cores
+pub mod scs {
+    /// Application interrupt and reset control register
+    pub const AIRCR: u32 = 0xe000_ed0c;
+    /// Debug halting control and status register
+    pub const DHCSR: u32 = 0xe000_edf0;
+    /// Debug core register selector register
+    pub const DCRSR: u32 = 0xe000_edf4;
+    /// Debug core register data register
+    pub const DCRDR: u32 = 0xe000_edf8;
+    /// Debug exception and monitor control register
+    pub const DEMCR: u32 = 0xe000_edfc;
+
+    /// Key that has to accompany every write to `DHCSR`
+    pub const DHCSR_DBGKEY: u32 = 0xa05f << 16;
+    pub const DHCSR_C_DEBUGEN: u32 = 1 << 0;
+    pub const DHCSR_C_HALT: u32 = 1 << 1;
+    pub const DHCSR_C_MASKINTS: u32 = 1 << 3;
+    pub const DHCSR_S_REGRDY: u32 = 1 << 16;
+    pub const DHCSR_S_HALT: u32 = 1 << 17;
+    pub const DCRSR_REGWNR: u32 = 1 << 16;
+    pub const AIRCR_VECTKEY: u32 = 0x05fa << 16;
+    pub const AIRCR_SYSRESETREQ: u32 = 1 << 2;
+}
+
+/// Core registers as numbered by `DCRSR`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoreRegister {
+    R0 = 0,
+    R1 = 1,
+    R2 = 2,
+    R3 = 3,
+    R7 = 7,
+    Sp = 13,
+    Lr = 14,
+    Pc = 15,
+    Xpsr = 16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The target answered with a FAULT acknowledge
+    Fault,
+    /// The target kept answering WAIT
+    Wait,
+    /// The acknowledge was not a valid SWD response
+    Protocol(u8),
+    /// Read data failed the parity check
+    Parity,
+    /// A debug power up or halt request timed out
+    Timeout,
+}
+
+/// Number of times a transfer is retried after a WAIT acknowledge
+const WAIT_RETRIES: usize = 100;
+
+/// Sequence that moves an ADIv5.2 debug port from SWD to dormant state
+const SWD_TO_DORMANT: u16 = 0xe3bc;
+
+/// Selection alert sequence that wakes a dormant debug port
+const SELECTION_ALERT: [u8; 16] = [
+    0x92, 0xf3, 0x09, 0x62, 0x95, 0x2d, 0x85, 0x86, 0xe9, 0xaf, 0xdd, 0xe3, 0xa2, 0x0e, 0xbc, 0x19,
+];
+
+/// Activation code selecting the SWD protocol after the selection alert
+const SWD_ACTIVATION_CODE: u8 = 0x1a;
+
+/// Bit-banged SWD host
+pub struct Swd<DIO, CLK, D>
+where
+    DIO: InputPin + OutputPin,
+    CLK: OutputPin,
+    D: DelayUs<u32>,
+{
+    swdio: DIO,
+    swclk: CLK,
+    delay: D,
+    half_period_us: u32,
+    select: Option<u32>,
+}
+
+impl<DIO, CLK, D> Swd<DIO, CLK, D>
+where
+    DIO: InputPin + OutputPin,
+    CLK: OutputPin,
+    D: DelayUs<u32>,
+{
+    /// Create a new SWD host, `swdio` is expected to be an open
+    /// drain output with a pull up so the target can drive it
+    pub fn new(swdio: DIO, swclk: CLK, delay: D) -> Self {
+        Self {
+            swdio,
+            swclk,
+            delay,
+            half_period_us: 1,
+            select: None,
+        }
+    }
+
+    /// Set the length of half an SWCLK period in microseconds
+    pub fn set_half_period_us(&mut self, half_period_us: u32) {
+        self.half_period_us = half_period_us;
+    }
+
+    /// Release the pins and delay
+    pub fn free(self) -> (DIO, CLK, D) {
+        (self.swdio, self.swclk, self.delay)
+    }
+
+    fn half_period(&mut self) {
+        if self.half_period_us > 0 {
+            self.delay.delay_us(self.half_period_us);
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if bit {
+            self.swdio.set_high().ok();
+        } else {
+            self.swdio.set_low().ok();
+        }
+        self.swclk.set_low().ok();
+        self.half_period();
+        self.swclk.set_high().ok();
+        self.half_period();
+    }
+
+    fn read_bit(&mut self) -> bool {
+        self.swclk.set_low().ok();
+        self.half_period();
+        let bit = self.swdio.is_high().unwrap_or(false);
+        self.swclk.set_high().ok();
+        self.half_period();
+        bit
+    }
+
+    /// Release SWDIO and clock a turnaround cycle
+    fn turnaround(&mut self) {
+        self.swdio.set_high().ok();
+        self.swclk.set_low().ok();
+        self.half_period();
+        self.swclk.set_high().ok();
+        self.half_period();
+    }
+
+    /// Clock out `count` bits of `value` starting with the least significant bit
+    pub fn write_bits(&mut self, value: u32, count: usize) {
+        for i in 0..count {
+            self.write_bit(value >> i & 1 == 1);
+        }
+    }
+
+    /// Release SWDIO and clock in `count` bits, the first bit read ends
+    /// up as the least significant bit
+    pub fn read_bits(&mut self, count: usize) -> u32 {
+        self.swdio.set_high().ok();
+        let mut value = 0;
+        for i in 0..count {
+            if self.read_bit() {
+                value |= 1 << i;
+            }
+        }
+        value
+    }
+
+    /// Clock out a raw bit sequence, `bytes` are sent least significant bit first
+    pub fn write_sequence(&mut self, bytes: &[u8], count: usize) {
+        for i in 0..count {
+            self.write_bit(bytes[i / 8] >> (i % 8) & 1 == 1);
+        }
+    }
+
+    /// Hold SWDIO high for more than 50 clocks followed by idle cycles
+    pub fn line_reset(&mut self) {
+        self.write_sequence(&[0xff; 7], 56);
+        self.write_bits(0, 2);
+    }
+
+    /// Wake the debug port of a multi-drop target and put it in SWD mode
+    pub fn dormant_to_swd(&mut self) {
+        self.line_reset();
+        self.write_bits(SWD_TO_DORMANT as u32, 16);
+        self.write_bits(0xff, 8);
+        self.write_sequence(&SELECTION_ALERT, 128);
+        self.write_bits(0, 4);
+        self.write_bits(SWD_ACTIVATION_CODE as u32, 8);
+        self.line_reset();
+    }
+
+    fn request(&mut self, ap: bool, read: bool, addr: u8) -> u32 {
+        let a2 = addr >> 2 & 1 == 1;
+        let a3 = addr >> 3 & 1 == 1;
+        let parity = ap ^ read ^ a2 ^ a3;
+        1 | (ap as u32) << 1
+            | (read as u32) << 2
+            | (a2 as u32) << 3
+            | (a3 as u32) << 4
+            | (parity as u32) << 5
+            | 1 << 7
+    }
+
+    /// A single DP or AP register transfer, `addr` is the register
+    /// address within the selected bank
+    ///
+    /// AP reads are posted, the value returned belongs to the previous
+    /// AP read. Prefer [`Swd::read_ap`] and [`Swd::write_ap`], this is for
+    /// callers that manage `SELECT` themselves.
+    pub fn transfer(&mut self, ap: bool, read: bool, addr: u8, value: u32) -> Result<u32, Error> {
+        if !ap && !read && addr == dp::SELECT {
+            self.select = None;
+        }
+        let request = self.request(ap, read, addr);
+        for _ in 0..WAIT_RETRIES {
+            self.write_bits(request, 8);
+            self.turnaround();
+            let ack = self.read_bits(3) as u8;
+            match ack {
+                0b001 => {
+                    let result = if read {
+                        let data = self.read_bits(32);
+                        let parity = self.read_bit();
+                        self.turnaround();
+                        if (data.count_ones() & 1 == 1) != parity {
+                            return Err(Error::Parity);
+                        }
+                        data
+                    } else {
+                        self.turnaround();
+                        self.write_bits(value, 32);
+                        self.write_bit(value.count_ones() & 1 == 1);
+                        0
+                    };
+                    self.write_bits(0, 2);
+                    return Ok(result);
+                }
+                0b010 => {
+                    self.turnaround();
+                    self.write_bits(0, 2);
+                }
+                0b100 => {
+                    self.turnaround();
+                    self.write_bits(0, 2);
+                    return Err(Error::Fault);
+                }
+                ack => {
+                    // Let the target finish whatever it thinks it is doing
+                    self.read_bits(32);
+                    self.read_bit();
+                    self.turnaround();
+                    self.write_bits(0, 2);
+                    return Err(Error::Protocol(ack));
+                }
+            }
+        }
+        Err(Error::Wait)
+    }
+
+    /// Select a multi-drop target, the target does not acknowledge this write
+    pub fn target_select(&mut self, target: u32) {
+        let request = self.request(false, false, dp::TARGETSEL);
+        self.write_bits(request, 8);
+        // Turnaround, acknowledge and turnaround are left undriven
+        self.swdio.set_high().ok();
+        self.read_bits(5);
+        self.write_bits(target, 32);
+        self.write_bit(target.count_ones() & 1 == 1);
+        self.write_bits(0, 2);
+        self.select = None;
+    }
+
+    pub fn read_dp(&mut self, addr: u8) -> Result<u32, Error> {
+        self.transfer(false, true, addr, 0)
+    }
+
+    pub fn write_dp(&mut self, addr: u8, value: u32) -> Result<(), Error> {
+        self.transfer(false, false, addr, value).map(|_| ())
+    }
+
+    fn select_ap(&mut self, apsel: u8, addr: u8) -> Result<(), Error> {
+        let select = (apsel as u32) << 24 | (addr as u32 & 0xf0);
+        if self.select != Some(select) {
+            self.write_dp(dp::SELECT, select)?;
+            self.select = Some(select);
+        }
+        Ok(())
+    }
+
+    /// Read an access port register, AP reads are posted so
+    /// the result is collected from `RDBUFF`
+    pub fn read_ap(&mut self, apsel: u8, addr: u8) -> Result<u32, Error> {
+        self.select_ap(apsel, addr)?;
+        self.transfer(true, true, addr & 0xc, 0)?;
+        self.read_dp(dp::RDBUFF)
+    }
+
+    pub fn write_ap(&mut self, apsel: u8, addr: u8, value: u32) -> Result<(), Error> {
+        self.select_ap(apsel, addr)?;
+        self.transfer(true, false, addr & 0xc, value).map(|_| ())
+    }
+
+    /// Clear sticky errors in the debug port
+    pub fn clear_errors(&mut self) -> Result<(), Error> {
+        self.write_dp(dp::ABORT, 0x1e)
+    }
+
+    /// Wake the rp2040 debug port, select `target` and power up the debug domain
+    ///
+    /// Returns the value of `DPIDR`
+    pub fn connect(&mut self, target: u32) -> Result<u32, Error> {
+        self.dormant_to_swd();
+        self.target_select(target);
+        let idcode = self.read_dp(dp::DPIDR)?;
+        self.clear_errors()?;
+        self.write_dp(dp::SELECT, 0)?;
+        self.select = Some(0);
+        // CSYSPWRUPREQ | CDBGPWRUPREQ
+        self.write_dp(dp::CTRL_STAT, 0x5000_0000)?;
+        for _ in 0..100 {
+            // CSYSPWRUPACK | CDBGPWRUPACK
+            if self.read_dp(dp::CTRL_STAT)? & 0xa000_0000 == 0xa000_0000 {
+                return Ok(idcode);
+            }
+            self.delay.delay_us(100);
+        }
+        Err(Error::Timeout)
+    }
+
+    pub fn delay_us(&mut self, us: u32) {
+        self.delay.delay_us(us);
+    }
+}
+
+/// Access to target memory through MEM-AP 0 of a connected debug port
+pub struct MemAp<'a, DIO, CLK, D>
+where
+    DIO: InputPin + OutputPin,
+    CLK: OutputPin,
+    D: DelayUs<u32>,
+{
+    swd: &'a mut Swd<DIO, CLK, D>,
+}
+
+/// 32 bit accesses with single address increment
+const CSW_WORD_INCREMENT: u32 = 0xa200_0012;
+
+impl<'a, DIO, CLK, D> MemAp<'a, DIO, CLK, D>
+where
+    DIO: InputPin + OutputPin,
+    CLK: OutputPin,
+    D: DelayUs<u32>,
+{
+    pub fn new(swd: &'a mut Swd<DIO, CLK, D>) -> Result<Self, Error> {
+        swd.write_ap(0, ap::CSW, CSW_WORD_INCREMENT)?;
+        Ok(Self { swd })
+    }
+
+    pub fn read_word(&mut self, addr: u32) -> Result<u32, Error> {
+        self.swd.write_ap(0, ap::TAR, addr)?;
+        self.swd.read_ap(0, ap::DRW)
+    }
+
+    pub fn write_word(&mut self, addr: u32, value: u32) -> Result<(), Error> {
+        self.swd.write_ap(0, ap::TAR, addr)?;
+        self.swd.write_ap(0, ap::DRW, value)
+    }
+
+    /// Read a halfword, `addr` must be 2 byte aligned
+    pub fn read_halfword(&mut self, addr: u32) -> Result<u16, Error> {
+        let word = self.read_word(addr & !0x3)?;
+        Ok((word >> ((addr & 0x2) * 8)) as u16)
+    }
+
+    /// Write `data` to word aligned `addr`, the TAR auto increment only
+    /// covers 1KB so the address is written again at every boundary
+    pub fn write_block(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        for (i, chunk) in data.chunks(4).enumerate() {
+            let address = addr + i as u32 * 4;
+            if i == 0 || address & 0x3ff == 0 {
+                self.swd.write_ap(0, ap::TAR, address)?;
+            }
+            let mut word = [0xff; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.swd.write_ap(0, ap::DRW, u32::from_le_bytes(word))?;
+        }
+        Ok(())
+    }
+
+    /// Fill `data` from word aligned `addr`
+    pub fn read_block(&mut self, addr: u32, data: &mut [u8]) -> Result<(), Error> {
+        for (i, chunk) in data.chunks_mut(4).enumerate() {
+            let address = addr + i as u32 * 4;
+            if i == 0 || address & 0x3ff == 0 {
+                self.swd.write_ap(0, ap::TAR, address)?;
+            }
+            let word = self.swd.read_ap(0, ap::DRW)?.to_le_bytes();
+            chunk.copy_from_slice(&word[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    pub fn halt(&mut self) -> Result<(), Error> {
+        self.write_word(
+            scs::DHCSR,
+            scs::DHCSR_DBGKEY | scs::DHCSR_C_DEBUGEN | scs::DHCSR_C_HALT | scs::DHCSR_C_MASKINTS,
+        )?;
+        self.wait_for_halt(100)
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        self.write_word(
+            scs::DHCSR,
+            scs::DHCSR_DBGKEY | scs::DHCSR_C_DEBUGEN | scs::DHCSR_C_MASKINTS,
+        )
+    }
+
+    pub fn is_halted(&mut self) -> Result<bool, Error> {
+        Ok(self.read_word(scs::DHCSR)? & scs::DHCSR_S_HALT != 0)
+    }
+
+    /// Poll `DHCSR` until the core halts, checking every millisecond
+    pub fn wait_for_halt(&mut self, timeout_ms: u32) -> Result<(), Error> {
+        for _ in 0..timeout_ms {
+            if self.is_halted()? {
+                return Ok(());
+            }
+            self.swd.delay_us(1000);
+        }
+        Err(Error::Timeout)
+    }
+
+    fn wait_for_register(&mut self) -> Result<(), Error> {
+        for _ in 0..100 {
+            if self.read_word(scs::DHCSR)? & scs::DHCSR_S_REGRDY != 0 {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    pub fn read_register(&mut self, register: CoreRegister) -> Result<u32, Error> {
+        self.write_word(scs::DCRSR, register as u32)?;
+        self.wait_for_register()?;
+        self.read_word(scs::DCRDR)
+    }
+
+    pub fn write_register(&mut self, register: CoreRegister, value: u32) -> Result<(), Error> {
+        self.write_word(scs::DCRDR, value)?;
+        self.write_word(scs::DCRSR, register as u32 | scs::DCRSR_REGWNR)?;
+        self.wait_for_register()
+    }
+
+    /// Request a system reset through `AIRCR`
+    pub fn system_reset(&mut self) -> Result<(), Error> {
+        self.write_word(scs::AIRCR, scs::AIRCR_VECTKEY | scs::AIRCR_SYSRESETREQ)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{NoDelay, SwdPin, SwdWire, SWD_DPIDR};
+
+    fn swd(wire: &SwdWire) -> Swd<SwdPin, SwdPin, NoDelay> {
+        Swd::new(wire.swdio(), wire.swclk(), NoDelay)
+    }
+
+    #[test]
+    fn encodes_requests() {
+        let wire = SwdWire::default();
+        let mut swd = swd(&wire);
+        assert_eq!(swd.request(false, true, dp::DPIDR), 0xa5);
+        assert_eq!(swd.request(false, false, dp::ABORT), 0x81);
+        assert_eq!(swd.request(false, false, dp::SELECT), 0xb1);
+        assert_eq!(swd.request(false, true, dp::RDBUFF), 0xbd);
+        assert_eq!(swd.request(false, false, dp::TARGETSEL), 0x99);
+        assert_eq!(swd.request(true, false, ap::TAR), 0x8b);
+        assert_eq!(swd.request(true, true, ap::DRW), 0x9f);
+        assert_eq!(swd.request(true, false, ap::DRW), 0xbb);
+    }
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let wire = SwdWire::default();
+        let mut swd = swd(&wire);
+        assert_eq!(swd.read_dp(dp::DPIDR), Ok(SWD_DPIDR));
+
+        let mut mem = MemAp::new(&mut swd).unwrap();
+        mem.write_word(0x2000_0100, 0x1234_5678).unwrap();
+        assert_eq!(wire.word(0x2000_0100), 0x1234_5678);
+        assert_eq!(mem.read_word(0x2000_0100), Ok(0x1234_5678));
+        assert_eq!(mem.read_halfword(0x2000_0102), Ok(0x1234));
+        // SELECT is written once for bank 0
+        let selects = wire.requests().iter().filter(|&&r| r == 0xb1).count();
+        assert_eq!(selects, 1);
+        assert_eq!(swd.read_ap(0, ap::IDR), Ok(0x0477_0031));
+
+        // Across a 1KB boundary the address is written again
+        let mut mem = MemAp::new(&mut swd).unwrap();
+        let data: Vec<u8> = (0..16).collect();
+        mem.write_block(0x2000_03f8, &data).unwrap();
+        assert_eq!(wire.word(0x2000_0400), 0x0b0a_0908);
+        let mut read = [0; 16];
+        mem.read_block(0x2000_03f8, &mut read).unwrap();
+        assert_eq!(read[..], data[..]);
+    }
+
+    #[test]
+    fn retries_after_wait() {
+        let wire = SwdWire::default();
+        let mut swd = swd(&wire);
+        wire.wait(3);
+        assert_eq!(swd.read_dp(dp::DPIDR), Ok(SWD_DPIDR));
+        assert_eq!(wire.requests(), [0xa5; 4]);
+
+        wire.wait(WAIT_RETRIES);
+        assert_eq!(swd.read_dp(dp::DPIDR), Err(Error::Wait));
+        assert_eq!(swd.read_dp(dp::DPIDR), Ok(SWD_DPIDR));
+    }
+
+    #[test]
+    fn maps_errors() {
+        let wire = SwdWire::default();
+        let mut swd = swd(&wire);
+        wire.fault(true);
+        assert_eq!(swd.write_dp(dp::CTRL_STAT, 0), Err(Error::Fault));
+        wire.fault(false);
+
+        wire.bad_parity(true);
+        assert_eq!(swd.read_dp(dp::DPIDR), Err(Error::Parity));
+        wire.bad_parity(false);
+
+        // Nobody drives the acknowledge, the pull up reads all ones
+        wire.silent(true);
+        assert_eq!(swd.read_dp(dp::DPIDR), Err(Error::Protocol(0b111)));
+        wire.silent(false);
+
+        // The line is back in sync
+        assert_eq!(swd.read_dp(dp::DPIDR), Ok(SWD_DPIDR));
+    }
+}
@@ -0,0 +1,809 @@
+//! Helpers for the tests of this crate
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use embedded_hal::blocking::spi;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal::serial::{Read as SerialRead, Write as SerialWrite};
+use embedded_storage::{ReadStorage, Storage};
+
+use crate::image::{
+    UF2_BLOCK_SIZE, UF2_FAMILY_RP2040, UF2_MAGIC_END, UF2_MAGIC_START0, UF2_MAGIC_START1,
+};
+use crate::partition::PARTITION_TABLE_OFFSET;
+
+/// Flash held in memory, clones share the same bytes so a test can look
+/// at the flash while something else owns it
+#[derive(Clone)]
+pub struct RamFlash {
+    bytes: Rc<RefCell<Vec<u8>>>,
+}
+
+impl RamFlash {
+    /// Erased flash of `size` bytes
+    pub fn new(size: usize) -> Self {
+        Self {
+            bytes: Rc::new(RefCell::new(vec![0xff; size])),
+        }
+    }
+
+    pub fn bytes(&self, offset: u32, len: usize) -> Vec<u8> {
+        let offset = offset as usize;
+        self.bytes.borrow()[offset..offset + len].to_vec()
+    }
+
+    /// Write a partition table with `(kind, subtype, offset, size, label)`
+    /// entries
+    pub fn with_partitions(self, partitions: &[(u8, u8, u32, u32, &str)]) -> Self {
+        for (index, (kind, subtype, offset, size, label)) in partitions.iter().enumerate() {
+            let mut entry = [0_u8; 32];
+            entry[0..2].copy_from_slice(&[0xaa, 0x50]);
+            entry[2] = *kind;
+            entry[3] = *subtype;
+            entry[4..8].copy_from_slice(&offset.to_le_bytes());
+            entry[8..12].copy_from_slice(&size.to_le_bytes());
+            entry[12..12 + label.len()].copy_from_slice(label.as_bytes());
+            let at = PARTITION_TABLE_OFFSET as usize + index * entry.len();
+            self.bytes.borrow_mut()[at..at + entry.len()].copy_from_slice(&entry);
+        }
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct OutOfBounds;
+
+impl ReadStorage for RamFlash {
+    type Error = OutOfBounds;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        let flash = self.bytes.borrow();
+        let source = flash.get(offset..offset + bytes.len()).ok_or(OutOfBounds)?;
+        bytes.copy_from_slice(source);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.bytes.borrow().len()
+    }
+}
+
+impl Storage for RamFlash {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        let mut flash = self.bytes.borrow_mut();
+        flash
+            .get_mut(offset..offset + bytes.len())
+            .ok_or(OutOfBounds)?
+            .copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// A UF2 block for the rp2040 with `data` at `address`
+pub fn uf2_block(flags: u32, address: u32, data: &[u8]) -> Vec<u8> {
+    let mut block = vec![0; UF2_BLOCK_SIZE];
+    let mut put =
+        |offset: usize, value: u32| block[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    put(0, UF2_MAGIC_START0);
+    put(4, UF2_MAGIC_START1);
+    put(8, flags);
+    put(12, address);
+    put(16, data.len() as u32);
+    put(28, UF2_FAMILY_RP2040);
+    put(508, UF2_MAGIC_END);
+    block[32..32 + data.len()].copy_from_slice(data);
+    block
+}
+
+/// Pin that remembers every level it was driven to, clones share the log.
+/// Reading it returns the last level driven.
+#[derive(Clone, Default)]
+pub struct Pin {
+    levels: Rc<RefCell<Vec<bool>>>,
+}
+
+impl Pin {
+    pub fn levels(&self) -> Vec<bool> {
+        self.levels.borrow().clone()
+    }
+
+    pub fn is_high(&self) -> bool {
+        self.levels.borrow().last().copied().unwrap_or(false)
+    }
+}
+
+impl OutputPin for Pin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.levels.borrow_mut().push(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.levels.borrow_mut().push(true);
+        Ok(())
+    }
+}
+
+impl InputPin for Pin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_high(self))
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(!Pin::is_high(self))
+    }
+}
+
+/// Delay that returns straight away
+pub struct NoDelay;
+
+impl DelayUs<u32> for NoDelay {
+    fn delay_us(&mut self, _us: u32) {}
+}
+
+impl DelayMs<u32> for NoDelay {
+    fn delay_ms(&mut self, _ms: u32) {}
+}
+
+/// Serial port with queued received bytes, clones share both directions
+#[derive(Clone, Default)]
+pub struct Serial {
+    pub rx: Rc<RefCell<VecDeque<u8>>>,
+    pub tx: Rc<RefCell<Vec<u8>>>,
+}
+
+impl SerialRead<u8> for Serial {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        self.rx
+            .borrow_mut()
+            .pop_front()
+            .ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl SerialWrite<u8> for Serial {
+    type Error = Infallible;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.tx.borrow_mut().push(word);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// SPI bus that records what was written and reads back 0xff, clones
+/// share the log
+#[derive(Clone, Default)]
+pub struct SpiLog {
+    written: Rc<RefCell<Vec<u8>>>,
+}
+
+impl SpiLog {
+    pub fn written(&self) -> Vec<u8> {
+        self.written.borrow().clone()
+    }
+}
+
+impl spi::Transfer<u8> for SpiLog {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        self.written.borrow_mut().extend_from_slice(words);
+        words.fill(0xff);
+        Ok(words)
+    }
+}
+
+impl spi::Write<u8> for SpiLog {
+    type Error = Infallible;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.written.borrow_mut().extend_from_slice(words);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum I2cPhase {
+    Idle,
+    Address,
+    AddressAck,
+    Write,
+    WriteAck,
+    Read,
+    ReadAck,
+}
+
+/// An I2C device with 16 registers, written after a register number and
+/// read from the last register number
+struct I2cDevice {
+    address: u8,
+    registers: [u8; 16],
+    register: usize,
+    phase: I2cPhase,
+    byte: u8,
+    bits: u8,
+    read: bool,
+    first_write: bool,
+    master_ack: bool,
+    holds_sda: bool,
+    /// Clocks until a device that was stuck lets go of SDA
+    stuck_clocks: u32,
+    /// Reads of SCL that the device keeps it low for after a release
+    stretch: u32,
+    stretching: u32,
+    holds_scl: bool,
+}
+
+struct I2cLines {
+    master_scl: bool,
+    master_sda: bool,
+    scl: bool,
+    sda: bool,
+    device: I2cDevice,
+    stops: usize,
+}
+
+impl I2cLines {
+    fn scl_line(&self) -> bool {
+        self.master_scl && self.device.stretching == 0 && !self.device.holds_scl
+    }
+
+    fn sda_line(&self) -> bool {
+        self.master_sda && !self.device.holds_sda
+    }
+
+    fn update(&mut self) {
+        let (scl, sda) = (self.scl_line(), self.sda_line());
+        if scl && self.scl {
+            if self.sda && !sda {
+                self.device.start();
+            } else if !self.sda && sda {
+                self.device.phase = I2cPhase::Idle;
+                self.stops += 1;
+            }
+        } else if scl && !self.scl {
+            self.device.rise(sda);
+        } else if !scl && self.scl {
+            self.device.fall();
+        }
+        self.scl = self.scl_line();
+        self.sda = self.sda_line();
+    }
+}
+
+impl I2cDevice {
+    fn start(&mut self) {
+        self.phase = I2cPhase::Address;
+        self.byte = 0;
+        self.bits = 0;
+    }
+
+    fn rise(&mut self, sda: bool) {
+        match self.phase {
+            I2cPhase::Address | I2cPhase::Write => {
+                self.byte = self.byte << 1 | sda as u8;
+                self.bits += 1;
+            }
+            I2cPhase::ReadAck => self.master_ack = !sda,
+            _ => {}
+        }
+    }
+
+    fn fall(&mut self) {
+        if self.stuck_clocks > 0 {
+            self.stuck_clocks -= 1;
+            self.holds_sda = self.stuck_clocks > 0;
+            return;
+        }
+        match self.phase {
+            I2cPhase::Address if self.bits == 8 => {
+                if self.byte >> 1 == self.address {
+                    self.read = self.byte & 1 == 1;
+                    self.holds_sda = true;
+                    self.phase = I2cPhase::AddressAck;
+                } else {
+                    self.phase = I2cPhase::Idle;
+                }
+            }
+            I2cPhase::AddressAck | I2cPhase::WriteAck if !self.read => {
+                if self.phase == I2cPhase::AddressAck {
+                    self.first_write = true;
+                }
+                self.holds_sda = false;
+                self.phase = I2cPhase::Write;
+                self.byte = 0;
+                self.bits = 0;
+            }
+            I2cPhase::Write if self.bits == 8 => {
+                if self.first_write {
+                    self.register = self.byte as usize % self.registers.len();
+                    self.first_write = false;
+                } else {
+                    self.registers[self.register] = self.byte;
+                    self.register = (self.register + 1) % self.registers.len();
+                }
+                self.holds_sda = true;
+                self.phase = I2cPhase::WriteAck;
+            }
+            I2cPhase::AddressAck => self.send_byte(),
+            I2cPhase::ReadAck if self.master_ack => self.send_byte(),
+            I2cPhase::ReadAck => {
+                self.holds_sda = false;
+                self.phase = I2cPhase::Idle;
+            }
+            I2cPhase::Read => {
+                self.bits += 1;
+                if self.bits == 8 {
+                    self.holds_sda = false;
+                    self.register = (self.register + 1) % self.registers.len();
+                    self.phase = I2cPhase::ReadAck;
+                } else {
+                    self.holds_sda = self.byte >> (7 - self.bits) & 1 == 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn send_byte(&mut self) {
+        self.byte = self.registers[self.register];
+        self.bits = 0;
+        self.holds_sda = self.byte & 0x80 == 0;
+        self.phase = I2cPhase::Read;
+    }
+}
+
+/// Open drain SCL and SDA lines with pull ups and an [`I2cDevice`] on
+/// them, clones share the lines
+#[derive(Clone)]
+pub struct I2cWire {
+    lines: Rc<RefCell<I2cLines>>,
+}
+
+impl I2cWire {
+    pub fn new(address: u8) -> Self {
+        let device = I2cDevice {
+            address,
+            registers: [0; 16],
+            register: 0,
+            phase: I2cPhase::Idle,
+            byte: 0,
+            bits: 0,
+            read: false,
+            first_write: false,
+            master_ack: false,
+            holds_sda: false,
+            stuck_clocks: 0,
+            stretch: 0,
+            stretching: 0,
+            holds_scl: false,
+        };
+        Self {
+            lines: Rc::new(RefCell::new(I2cLines {
+                master_scl: true,
+                master_sda: true,
+                scl: true,
+                sda: true,
+                device,
+                stops: 0,
+            })),
+        }
+    }
+
+    pub fn scl(&self) -> I2cPin {
+        I2cPin {
+            lines: self.lines.clone(),
+            scl: true,
+        }
+    }
+
+    pub fn sda(&self) -> I2cPin {
+        I2cPin {
+            lines: self.lines.clone(),
+            scl: false,
+        }
+    }
+
+    pub fn registers(&self) -> [u8; 16] {
+        self.lines.borrow().device.registers
+    }
+
+    /// Stretch every clock for `reads` reads of SCL
+    pub fn stretch(&self, reads: u32) {
+        let mut lines = self.lines.borrow_mut();
+        lines.device.stretch = reads;
+        lines.device.stretching = 0;
+        lines.update();
+    }
+
+    pub fn hold_scl(&self, hold: bool) {
+        let mut lines = self.lines.borrow_mut();
+        lines.device.holds_scl = hold;
+        lines.update();
+    }
+
+    /// Hold SDA low for `clocks` clocks, like a device that was reset in
+    /// the middle of a read
+    pub fn hold_sda(&self, clocks: u32) {
+        let mut lines = self.lines.borrow_mut();
+        lines.device.holds_sda = clocks > 0;
+        lines.device.stuck_clocks = clocks;
+        lines.update();
+    }
+
+    pub fn is_idle(&self) -> bool {
+        let lines = self.lines.borrow();
+        lines.scl && lines.sda
+    }
+
+    /// Stop conditions seen so far
+    pub fn stops(&self) -> usize {
+        self.lines.borrow().stops
+    }
+}
+
+/// One line of an [`I2cWire`]
+pub struct I2cPin {
+    lines: Rc<RefCell<I2cLines>>,
+    scl: bool,
+}
+
+impl I2cPin {
+    fn drive(&mut self, high: bool) {
+        let mut lines = self.lines.borrow_mut();
+        if self.scl {
+            if high && !lines.master_scl {
+                lines.device.stretching = lines.device.stretch;
+            }
+            lines.master_scl = high;
+        } else {
+            lines.master_sda = high;
+        }
+        lines.update();
+    }
+}
+
+impl OutputPin for I2cPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.drive(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.drive(true);
+        Ok(())
+    }
+}
+
+impl InputPin for I2cPin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        let mut lines = self.lines.borrow_mut();
+        if self.scl {
+            if lines.device.stretching > 0 {
+                lines.device.stretching -= 1;
+                lines.update();
+            }
+            Ok(lines.scl)
+        } else {
+            Ok(lines.sda)
+        }
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+/// Answer of the [`SwdWire`] target to a request
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SwdSlot {
+    /// Nobody drives the line
+    Turnaround,
+    /// The target drives the line
+    Drive(bool),
+    /// The host drives a bit of write data
+    Sample,
+}
+
+/// `DPIDR` of the [`SwdWire`] target, the one of the rp2040
+pub const SWD_DPIDR: u32 = 0x0bc1_2477;
+
+/// A debug port with a MEM-AP on word addressed memory
+///
+/// A request has to follow a low line, so line resets and the reads
+/// after a protocol error are not taken for one. `DHCSR`
+/// always reads as a halted core with a register ready.
+#[derive(Default)]
+struct SwdTarget {
+    host_swdio: bool,
+    /// The line has to go low before the next request
+    needs_idle: bool,
+    request: u8,
+    request_bits: u32,
+    slots: VecDeque<SwdSlot>,
+    current: Option<SwdSlot>,
+    /// The register a write goes to, with its data so far
+    write: Option<(bool, u8)>,
+    data: u64,
+    data_bits: u32,
+    select: u32,
+    csw: u32,
+    tar: u32,
+    rdbuff: u32,
+    memory: HashMap<u32, u32>,
+    requests: Vec<u8>,
+    waits: usize,
+    fault: bool,
+    bad_parity: bool,
+    silent: bool,
+}
+
+impl SwdTarget {
+    fn swdio(&self) -> bool {
+        match self.current {
+            Some(SwdSlot::Drive(bit)) => bit,
+            _ => self.host_swdio,
+        }
+    }
+
+    fn fall(&mut self) {
+        self.current = self.slots.pop_front();
+    }
+
+    fn rise(&mut self) {
+        let bit = self.host_swdio;
+        match self.current {
+            Some(SwdSlot::Sample) => {
+                self.data |= u64::from(bit) << self.data_bits;
+                self.data_bits += 1;
+                if self.data_bits == 33 {
+                    if let Some((ap, addr)) = self.write.take() {
+                        self.write_register(ap, addr, self.data as u32);
+                    }
+                }
+            }
+            Some(_) => {}
+            None => self.request_bit(bit),
+        }
+    }
+
+    fn request_bit(&mut self, bit: bool) {
+        if self.request_bits == 0 && (!bit || self.needs_idle) {
+            self.needs_idle = bit;
+            return;
+        }
+        self.request |= u8::from(bit) << self.request_bits;
+        self.request_bits += 1;
+        if self.request_bits < 8 {
+            return;
+        }
+        let request = self.request;
+        self.request = 0;
+        self.request_bits = 0;
+        self.needs_idle = true;
+        let parity = (request >> 1 & 0x0f).count_ones() & 1 == 1;
+        if request & 0x40 == 0 && request & 0x80 != 0 && parity == (request & 0x20 != 0) {
+            self.answer(request);
+        }
+    }
+
+    fn answer(&mut self, request: u8) {
+        let ap = request & 0x02 != 0;
+        let read = request & 0x04 != 0;
+        let addr = (request >> 1) & 0x0c;
+        self.requests.push(request);
+        self.slots.push_back(SwdSlot::Turnaround);
+        let ack = if self.silent {
+            self.slots.extend([SwdSlot::Turnaround; 3]);
+            return;
+        } else if self.waits > 0 {
+            self.waits -= 1;
+            0b010
+        } else if self.fault {
+            0b100
+        } else {
+            0b001
+        };
+        self.slots
+            .extend((0..3).map(|i| SwdSlot::Drive(ack >> i & 1 == 1)));
+        if ack != 0b001 {
+            self.slots.push_back(SwdSlot::Turnaround);
+        } else if read {
+            let value = self.read_register(ap, addr);
+            self.slots
+                .extend((0..32).map(|i| SwdSlot::Drive(value >> i & 1 == 1)));
+            let parity = value.count_ones() & 1 == 1;
+            self.slots
+                .push_back(SwdSlot::Drive(parity != self.bad_parity));
+            self.slots.push_back(SwdSlot::Turnaround);
+        } else {
+            self.slots.push_back(SwdSlot::Turnaround);
+            self.slots.extend([SwdSlot::Sample; 33]);
+            self.write = Some((ap, addr));
+            self.data = 0;
+            self.data_bits = 0;
+        }
+    }
+
+    /// AP reads are posted, they answer the previous one
+    fn read_register(&mut self, ap: bool, addr: u8) -> u32 {
+        if !ap {
+            return match addr {
+                0x0 => SWD_DPIDR,
+                // Both power domains are up
+                0x4 => 0xf000_0000,
+                0x8 => self.select,
+                _ => self.rdbuff,
+            };
+        }
+        let value = match addr | (self.select & 0xf0) as u8 {
+            0x00 => self.csw,
+            0x04 => self.tar,
+            0x0c => {
+                let word = self.word(self.tar);
+                self.increment();
+                word
+            }
+            0xfc => 0x0477_0031,
+            _ => 0,
+        };
+        core::mem::replace(&mut self.rdbuff, value)
+    }
+
+    fn write_register(&mut self, ap: bool, addr: u8, value: u32) {
+        match (ap, addr | if ap { (self.select & 0xf0) as u8 } else { 0 }) {
+            (false, 0x8) => self.select = value,
+            (false, _) => {}
+            (true, 0x00) => self.csw = value,
+            (true, 0x04) => self.tar = value,
+            (true, 0x0c) => {
+                self.memory.insert(self.tar & !0x3, value);
+                self.increment();
+            }
+            _ => {}
+        }
+    }
+
+    fn word(&self, address: u32) -> u32 {
+        if address == 0xe000_edf0 {
+            // S_HALT | S_REGRDY
+            return 0x3_0000;
+        }
+        self.memory.get(&(address & !0x3)).copied().unwrap_or(0)
+    }
+
+    fn increment(&mut self) {
+        if self.csw & 0x30 == 0x10 {
+            self.tar = self.tar.wrapping_add(4);
+        }
+    }
+}
+
+/// SWDIO and SWCLK lines with an SWD target on them, clones share the
+/// lines
+#[derive(Clone, Default)]
+pub struct SwdWire {
+    target: Rc<RefCell<SwdTarget>>,
+}
+
+impl SwdWire {
+    pub fn swdio(&self) -> SwdPin {
+        SwdPin {
+            target: self.target.clone(),
+            clock: false,
+        }
+    }
+
+    pub fn swclk(&self) -> SwdPin {
+        SwdPin {
+            target: self.target.clone(),
+            clock: true,
+        }
+    }
+
+    /// Requests the target answered, the WAITs and FAULTs included
+    pub fn requests(&self) -> Vec<u8> {
+        self.target.borrow().requests.clone()
+    }
+
+    /// Answer WAIT to the next `count` requests
+    pub fn wait(&self, count: usize) {
+        self.target.borrow_mut().waits = count;
+    }
+
+    /// Answer FAULT to the requests
+    pub fn fault(&self, fault: bool) {
+        self.target.borrow_mut().fault = fault;
+    }
+
+    /// Send read data with the wrong parity
+    pub fn bad_parity(&self, bad: bool) {
+        self.target.borrow_mut().bad_parity = bad;
+    }
+
+    /// Leave the acknowledge undriven, like a target that is not there
+    pub fn silent(&self, silent: bool) {
+        self.target.borrow_mut().silent = silent;
+    }
+
+    pub fn word(&self, address: u32) -> u32 {
+        self.target.borrow().word(address)
+    }
+
+    /// Put `bytes` into the memory of the target
+    pub fn write_bytes(&self, address: u32, bytes: &[u8]) {
+        let mut target = self.target.borrow_mut();
+        for (i, byte) in bytes.iter().enumerate() {
+            let address = address + i as u32;
+            let word = target.memory.entry(address & !0x3).or_default();
+            let shift = (address & 0x3) * 8;
+            *word = *word & !(0xff << shift) | u32::from(*byte) << shift;
+        }
+    }
+}
+
+/// One line of an [`SwdWire`]
+pub struct SwdPin {
+    target: Rc<RefCell<SwdTarget>>,
+    clock: bool,
+}
+
+impl SwdPin {
+    fn drive(&mut self, high: bool) {
+        let mut target = self.target.borrow_mut();
+        if !self.clock {
+            target.host_swdio = high;
+        } else if high {
+            target.rise();
+        } else {
+            target.fall();
+        }
+    }
+}
+
+impl OutputPin for SwdPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.drive(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.drive(true);
+        Ok(())
+    }
+}
+
+impl InputPin for SwdPin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.target.borrow().swdio())
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        self.is_high().map(|high| !high)
+    }
+}
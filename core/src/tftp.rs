@@ -0,0 +1,230 @@
+//! Reading files from a TFTP server (RFC 1350)
+//!
+//! Lab machines often run a TFTP server already, so the esp32 can get its
+//! roms from one. The server lists them in [`LIST_FILE`], one file name
+//! per line, and rom `n` is the file on line `n`, see [`roms`]. A
+//! [`Download`] follows a transfer in octet mode block by block, sending
+//! the packets, waiting for the answers and sending the last packet again
+//! when none comes is up to the caller.
+
+/// Port of the server, it answers from a port of its own
+pub const PORT: u16 = 69;
+/// The list of roms on the server
+pub const LIST_FILE: &str = "roms.txt";
+pub const BLOCK_SIZE: usize = 512;
+/// Largest packet, data with a full block
+pub const MAX_PACKET: usize = 4 + BLOCK_SIZE;
+
+/// Error code of a missing file
+pub const NOT_FOUND: u16 = 1;
+/// Error code of a file that does not fit
+pub const DISK_FULL: u16 = 3;
+/// Error code of a packet from outside the transfer
+pub const UNKNOWN_TRANSFER: u16 = 5;
+
+const READ_REQUEST: u16 = 1;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+const MODE: &str = "octet";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The packet is too short or neither data nor an error
+    Malformed,
+    /// The file or the packet does not fit into the buffer
+    TooLarge,
+    /// The file name is empty or contains a NUL
+    Name,
+    /// The server ended the transfer with this error code, like
+    /// [`NOT_FOUND`]
+    Server(u16),
+}
+
+/// A read request for `file` in octet mode
+pub fn read_request(file: &str, out: &mut [u8]) -> Result<usize, Error> {
+    if file.is_empty() || file.contains('\0') {
+        return Err(Error::Name);
+    }
+    let len = 2 + file.len() + 1 + MODE.len() + 1;
+    let out = out.get_mut(..len).ok_or(Error::TooLarge)?;
+    out[..2].copy_from_slice(&READ_REQUEST.to_be_bytes());
+    let (name, mode) = out[2..].split_at_mut(file.len() + 1);
+    name[..file.len()].copy_from_slice(file.as_bytes());
+    name[file.len()] = 0;
+    mode[..MODE.len()].copy_from_slice(MODE.as_bytes());
+    mode[MODE.len()] = 0;
+    Ok(len)
+}
+
+/// An error packet, which ends a transfer
+pub fn error(code: u16, message: &str, out: &mut [u8]) -> Result<usize, Error> {
+    let len = 4 + message.len() + 1;
+    let out = out.get_mut(..len).ok_or(Error::TooLarge)?;
+    out[..2].copy_from_slice(&ERROR.to_be_bytes());
+    out[2..4].copy_from_slice(&code.to_be_bytes());
+    out[4..len - 1].copy_from_slice(message.as_bytes());
+    out[len - 1] = 0;
+    Ok(len)
+}
+
+/// The rom files of a [`LIST_FILE`], rom `n` is the `n`th
+///
+/// Empty lines and lines starting with `#` are skipped.
+pub fn roms(list: &str) -> impl Iterator<Item = &str> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// A file arriving from the server
+pub struct Download<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+    /// The last block that arrived, 0 before the first
+    block: u16,
+    done: bool,
+}
+
+impl<'b> Download<'b> {
+    pub fn new(buffer: &'b mut [u8]) -> Self {
+        Self {
+            buffer,
+            len: 0,
+            block: 0,
+            done: false,
+        }
+    }
+
+    /// Take a packet from the server, returns the ack to send for it
+    ///
+    /// The next block is kept and acknowledged. The block before it is
+    /// acknowledged again, since the server sends it again when the ack
+    /// was lost, and others are ignored. A block shorter than
+    /// [`BLOCK_SIZE`] ends the file.
+    pub fn receive(&mut self, packet: &[u8]) -> Result<Option<[u8; 4]>, Error> {
+        if packet.len() < 4 {
+            return Err(Error::Malformed);
+        }
+        let opcode = u16::from_be_bytes([packet[0], packet[1]]);
+        let number = u16::from_be_bytes([packet[2], packet[3]]);
+        let data = &packet[4..];
+        match opcode {
+            ERROR => Err(Error::Server(number)),
+            DATA if data.len() > BLOCK_SIZE => Err(Error::Malformed),
+            DATA if number == self.block.wrapping_add(1) && !self.done => {
+                let end = self.len + data.len();
+                self.buffer
+                    .get_mut(self.len..end)
+                    .ok_or(Error::TooLarge)?
+                    .copy_from_slice(data);
+                self.len = end;
+                self.block = number;
+                self.done = data.len() < BLOCK_SIZE;
+                Ok(Some(ack(number)))
+            }
+            DATA if number == self.block && self.block != 0 => Ok(Some(ack(number))),
+            DATA => Ok(None),
+            _ => Err(Error::Malformed),
+        }
+    }
+
+    /// The last block arrived
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Bytes that arrived so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+fn ack(block: u16) -> [u8; 4] {
+    let [high, low] = block.to_be_bytes();
+    let [op_high, op_low] = ACK.to_be_bytes();
+    [op_high, op_low, high, low]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(block: u16, bytes: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0, 3];
+        packet.extend_from_slice(&block.to_be_bytes());
+        packet.extend_from_slice(bytes);
+        packet
+    }
+
+    #[test]
+    fn writes_requests() {
+        let mut out = [0; MAX_PACKET];
+        let len = read_request("roms/PONG", &mut out).unwrap();
+        assert_eq!(&out[..len], b"\x00\x01roms/PONG\x00octet\x00");
+        assert_eq!(read_request("", &mut out), Err(Error::Name));
+        assert_eq!(read_request("a\0b", &mut out), Err(Error::Name));
+        assert_eq!(read_request("PONG", &mut out[..10]), Err(Error::TooLarge));
+
+        let len = error(DISK_FULL, "too large", &mut out).unwrap();
+        assert_eq!(&out[..len], b"\x00\x05\x00\x03too large\x00");
+    }
+
+    #[test]
+    fn downloads_files() {
+        let mut buffer = [0; 1200];
+        let mut download = Download::new(&mut buffer);
+        let first = data(1, &[0xaa; BLOCK_SIZE]);
+        assert_eq!(download.receive(&first), Ok(Some([0, 4, 0, 1])));
+        // The ack was lost
+        assert_eq!(download.receive(&first), Ok(Some([0, 4, 0, 1])));
+        // Out of order
+        assert_eq!(download.receive(&data(3, &[0xcc; 10])), Ok(None));
+        assert!(!download.is_done());
+
+        assert_eq!(
+            download.receive(&data(2, &[0xbb; 100])),
+            Ok(Some([0, 4, 0, 2]))
+        );
+        assert!(download.is_done());
+        assert_eq!(download.len(), BLOCK_SIZE + 100);
+        assert_eq!(download.receive(&data(3, &[0xcc; 10])), Ok(None));
+        assert_eq!(buffer[BLOCK_SIZE - 1..BLOCK_SIZE + 1], [0xaa, 0xbb]);
+
+        // A file of whole blocks ends with an empty one
+        let mut buffer = [0; BLOCK_SIZE];
+        let mut download = Download::new(&mut buffer);
+        download.receive(&data(1, &[1; BLOCK_SIZE])).unwrap();
+        assert!(!download.is_done());
+        assert_eq!(download.receive(&data(2, &[])), Ok(Some([0, 4, 0, 2])));
+        assert!(download.is_done());
+    }
+
+    #[test]
+    fn stops_on_errors() {
+        let mut buffer = [0; 600];
+        let mut download = Download::new(&mut buffer);
+        assert_eq!(
+            download.receive(b"\x00\x05\x00\x01File not found\x00"),
+            Err(Error::Server(NOT_FOUND))
+        );
+        assert_eq!(download.receive(&[0, 3, 0]), Err(Error::Malformed));
+        assert_eq!(download.receive(&[0, 4, 0, 1]), Err(Error::Malformed));
+        download.receive(&data(1, &[0; BLOCK_SIZE])).unwrap();
+        assert_eq!(
+            download.receive(&data(2, &[0; BLOCK_SIZE])),
+            Err(Error::TooLarge)
+        );
+    }
+
+    #[test]
+    fn lists_roms() {
+        let list = "# lab roms\nPONG\n\n  games/TETRIS.ch8 \r\nINVADERS\n";
+        let roms: Vec<_> = roms(list).collect();
+        assert_eq!(roms, ["PONG", "games/TETRIS.ch8", "INVADERS"]);
+    }
+}
@@ -0,0 +1,274 @@
+//! The server end of a WebSocket (RFC 6455)
+//!
+//! A browser asks for a WebSocket with an HTTP request that carries a
+//! `Sec-WebSocket-Key`, [`accept`] answers it and from then on both ends
+//! exchange frames over the connection. Only whole messages are
+//! supported, a browser does not split the short messages it sends here.
+
+use core::fmt::Write as _;
+
+use embedded_io::blocking::{Read, Write};
+use sha1::{Digest, Sha1};
+
+use crate::http::{self, Writer};
+
+/// Appended to the key of the client before hashing
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Length of an accept key, 20 bytes of SHA-1 in base64
+pub const ACCEPT_KEY_SIZE: usize = 28;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Reading or writing the stream failed, or the client went away
+    Io,
+    /// The frame breaks the protocol, such as an unmasked frame from
+    /// the client or a message in several frames
+    Protocol,
+    /// The payload does not fit into the buffer
+    TooLarge,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xa,
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x1 => Ok(Self::Text),
+            0x2 => Ok(Self::Binary),
+            0x8 => Ok(Self::Close),
+            0x9 => Ok(Self::Ping),
+            0xa => Ok(Self::Pong),
+            _ => Err(Error::Protocol),
+        }
+    }
+}
+
+/// The `Sec-WebSocket-Accept` answer to the `Sec-WebSocket-Key` of a
+/// client
+pub fn accept_key(key: &str) -> [u8; ACCEPT_KEY_SIZE] {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.trim().as_bytes());
+    sha1.update(GUID.as_bytes());
+    let digest: [u8; 20] = sha1.finalize().into();
+    base64(&digest)
+}
+
+/// Standard base64 with padding of 20 bytes
+fn base64(bytes: &[u8; 20]) -> [u8; ACCEPT_KEY_SIZE] {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = [b'='; ACCEPT_KEY_SIZE];
+    for (group, chunk) in bytes.chunks(3).enumerate() {
+        let mut triple = [0_u8; 3];
+        triple[..chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from_be_bytes([0, triple[0], triple[1], triple[2]]);
+        for digit in 0..=chunk.len() {
+            let index = (bits >> (18 - 6 * digit)) & 0x3f;
+            out[4 * group + digit] = ALPHABET[index as usize];
+        }
+    }
+    out
+}
+
+/// Answer a request for a WebSocket with the key of the client
+pub fn accept<S: Write>(stream: &mut S, key: &str) -> Result<(), http::Error> {
+    let accept = accept_key(key);
+    // Base64 is ASCII
+    let accept = core::str::from_utf8(&accept).unwrap_or_default();
+    write!(
+        Writer(&mut *stream),
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+         Connection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+    )
+    .map_err(|_| http::Error::Io)?;
+    stream.flush().map_err(|_| http::Error::Io)
+}
+
+/// Send a message in one frame, servers do not mask their frames
+pub fn write<S: Write>(stream: &mut S, opcode: Opcode, payload: &[u8]) -> Result<(), Error> {
+    let mut head = [0_u8; 10];
+    head[0] = 0x80 | opcode as u8;
+    let len = match payload.len() {
+        len @ 0..=125 => {
+            head[1] = len as u8;
+            2
+        }
+        len @ 126..=0xffff => {
+            head[1] = 126;
+            head[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            4
+        }
+        len => {
+            head[1] = 127;
+            head[2..10].copy_from_slice(&(len as u64).to_be_bytes());
+            10
+        }
+    };
+    stream.write_all(&head[..len]).map_err(|_| Error::Io)?;
+    stream.write_all(payload).map_err(|_| Error::Io)?;
+    stream.flush().map_err(|_| Error::Io)
+}
+
+/// Read a frame of the client into `payload`, returns its opcode and the
+/// payload length
+///
+/// Pings are not answered here, see [`write`].
+pub fn read<S: Read>(stream: &mut S, payload: &mut [u8]) -> Result<(Opcode, usize), Error> {
+    let mut head = [0_u8; 2];
+    stream.read_exact(&mut head).map_err(|_| Error::Io)?;
+    // Neither fragments nor extensions
+    if head[0] & 0xf0 != 0x80 || head[1] & 0x80 == 0 {
+        return Err(Error::Protocol);
+    }
+    let opcode = Opcode::try_from(head[0] & 0x0f)?;
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0_u8; 2];
+            stream.read_exact(&mut len).map_err(|_| Error::Io)?;
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0_u8; 8];
+            stream.read_exact(&mut len).map_err(|_| Error::Io)?;
+            usize::try_from(u64::from_be_bytes(len)).map_err(|_| Error::TooLarge)?
+        }
+        len => len as usize,
+    };
+    let mut mask = [0_u8; 4];
+    stream.read_exact(&mut mask).map_err(|_| Error::Io)?;
+    let payload = payload.get_mut(..len).ok_or(Error::TooLarge)?;
+    stream.read_exact(payload).map_err(|_| Error::Io)?;
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+    Ok((opcode, len))
+}
+
+/// The size of the frame at the start of `bytes`, once enough of its
+/// head is there to tell
+///
+/// A frame can be passed to [`read`] once all of it has arrived, so that
+/// reading it does not wait for the client.
+pub fn frame_size(bytes: &[u8]) -> Option<usize> {
+    let head = *bytes.get(1)?;
+    let (len, offset) = match head & 0x7f {
+        126 => (
+            u16::from_be_bytes(bytes.get(2..4)?.try_into().ok()?) as u64,
+            4,
+        ),
+        127 => (u64::from_be_bytes(bytes.get(2..10)?.try_into().ok()?), 10),
+        len => (len as u64, 2),
+    };
+    let mask = if head & 0x80 != 0 { 4 } else { 0 };
+    let len = usize::try_from(len).unwrap_or(usize::MAX);
+    Some(len.saturating_add(offset + mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// What `send` writes to a stream
+    fn sent(send: impl FnOnce(&mut &mut [u8])) -> Vec<u8> {
+        let mut buffer = [0; 512];
+        let mut stream = &mut buffer[..];
+        send(&mut stream);
+        let len = 512 - stream.len();
+        buffer[..len].to_vec()
+    }
+
+    #[test]
+    fn accepts_keys() {
+        // The example of RFC 6455
+        assert_eq!(
+            &accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(
+            sent(|stream| accept(stream, " dGhlIHNhbXBsZSBub25jZQ==").unwrap()),
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn writes_frames() {
+        assert_eq!(
+            sent(|stream| write(stream, Opcode::Text, b"Hello").unwrap()),
+            b"\x81\x05Hello"
+        );
+        let long = sent(|stream| write(stream, Opcode::Binary, &[7; 256]).unwrap());
+        assert_eq!(long[..4], [0x82, 126, 0x01, 0x00]);
+        assert_eq!(long.len(), 4 + 256);
+    }
+
+    #[test]
+    fn reads_masked_frames() {
+        // The masked "Hello" of RFC 6455
+        let mut stream: &[u8] = b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58\x88\x80\0\0\0\0";
+        let mut payload = [0; 8];
+        assert_eq!(read(&mut stream, &mut payload), Ok((Opcode::Text, 5)));
+        assert_eq!(&payload[..5], b"Hello");
+        assert_eq!(read(&mut stream, &mut payload), Ok((Opcode::Close, 0)));
+
+        let mut long = vec![0x82, 0xfe, 0x00, 0x80, 0, 0, 0, 0];
+        long.extend_from_slice(&[0x55; 0x80]);
+        let mut payload = [0; 0x80];
+        assert_eq!(
+            read(&mut &long[..], &mut payload),
+            Ok((Opcode::Binary, 0x80))
+        );
+        assert_eq!(read(&mut &long[..], &mut [0; 8]), Err(Error::TooLarge));
+    }
+
+    #[test]
+    fn rejects_frames() {
+        let mut payload = [0; 8];
+        // Not masked
+        assert_eq!(
+            read(&mut &b"\x82\x02\x00\x01"[..], &mut payload),
+            Err(Error::Protocol)
+        );
+        // First of several frames
+        assert_eq!(
+            read(&mut &b"\x02\x80\0\0\0\0"[..], &mut payload),
+            Err(Error::Protocol)
+        );
+        assert_eq!(
+            read(&mut &b"\x83\x80\0\0\0\0"[..], &mut payload),
+            Err(Error::Protocol)
+        );
+        // The client went away
+        assert_eq!(read(&mut &b"\x82"[..], &mut payload), Err(Error::Io));
+    }
+
+    #[test]
+    fn sizes_frames() {
+        assert_eq!(frame_size(b""), None);
+        assert_eq!(frame_size(b"\x82"), None);
+        assert_eq!(frame_size(b"\x82\x82"), Some(8));
+        assert_eq!(frame_size(b"\x82\x02"), Some(4));
+        assert_eq!(frame_size(b"\x82\xfe\x01"), None);
+        assert_eq!(frame_size(b"\x82\xfe\x01\x00"), Some(4 + 4 + 256));
+        assert_eq!(frame_size(b"\x82\xff\0\0\0\0\0\0\x01"), None);
+        assert_eq!(
+            frame_size(b"\x82\xff\0\0\0\0\0\0\x01\x00"),
+            Some(10 + 4 + 256)
+        );
+        assert_eq!(
+            frame_size(b"\x82\xff\xff\xff\xff\xff\xff\xff\xff\xff"),
+            Some(usize::MAX)
+        );
+    }
+}
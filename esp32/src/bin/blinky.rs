@@ -2,20 +2,53 @@
 #![no_main]
 
 use esp32_hal::{
-    clock::ClockControl, gpio::IO, peripherals::Peripherals, prelude::*, timer::TimerGroup, Delay,
-    Rtc,
+    clock::{ClockControl, Clocks},
+    prelude::*,
+    timer::TimerGroup,
+    Delay, Rtc,
 };
 use esp_backtrace as _;
+use log::info;
+use static_cell::StaticCell;
+use udoo_core::led::{Color, Pattern};
+use udoo_esp32::{crash, logger, status};
+use udoo_key_bsp::Board;
+
+/// Every status pattern, each one is shown for a few seconds
+const PATTERNS: [Pattern; 10] = [
+    Pattern::Boot,
+    Pattern::WifiConnecting,
+    Pattern::Transfer,
+    Pattern::Heartbeat,
+    Pattern::Breathing,
+    Pattern::Error(2),
+    Pattern::Error(4),
+    Pattern::Level(16),
+    Pattern::Level(128),
+    Pattern::Fade {
+        from: 255,
+        to: 0,
+        ms: 4000,
+    },
+];
+
+static CLOCKS: StaticCell<Clocks<'static>> = StaticCell::new();
 
 #[entry]
 fn main() -> ! {
-    let peripherals = Peripherals::take();
+    logger::init(log::LevelFilter::Info);
+    crash::report();
+
+    let Board {
+        leds, peripherals, ..
+    } = Board::take();
     let mut system = peripherals.DPORT.split();
-    let clocks = ClockControl::boot_defaults(system.clock_control).freeze();
+    // The led driver keeps a reference to the clocks
+    let clocks = &*CLOCKS.init(ClockControl::boot_defaults(system.clock_control).freeze());
 
     let timer_group0 = TimerGroup::new(
         peripherals.TIMG0,
-        &clocks,
+        clocks,
         &mut system.peripheral_clock_control,
     );
     let mut wdt = timer_group0.wdt;
@@ -25,18 +58,28 @@ fn main() -> ! {
     wdt.disable();
     rtc.rwdt.disable();
 
-    let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
-    let mut yellow_led = io.pins.gpio33.into_push_pull_output();
-    let mut blue_led = io.pins.gpio32.into_push_pull_output();
-
-    yellow_led.set_high().unwrap();
-    blue_led.set_high().unwrap();
+    status::start(
+        leds,
+        peripherals.LEDC,
+        clocks,
+        &mut system.peripheral_clock_control,
+        timer_group0.timer1,
+    );
 
-    let mut delay = Delay::new(&clocks);
+    let mut delay = Delay::new(clocks);
 
     loop {
-        yellow_led.toggle().unwrap();
-        blue_led.toggle().unwrap();
-        delay.delay_ms(500u32);
+        for pattern in PATTERNS {
+            // The blue led runs a pattern behind the yellow one
+            info!("{pattern:?}");
+            status::show(Color::Yellow, pattern);
+            delay.delay_ms(5000u32);
+            status::show(Color::Blue, pattern);
+        }
     }
 }
+
+#[interrupt]
+fn TG0_T1_LEVEL() {
+    status::on_timer();
+}
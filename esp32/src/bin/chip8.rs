@@ -1,47 +1,96 @@
+//! Loads a rom from the rom server into the rp2040 and keeps the rp2040
+//! answering.
+//!
+//! The UEXT serial port (TXD Gpio13, RXD Gpio26, 115200 baud) shows the
+//! log, including the rp2040 watchdog events, and takes commands, type
+//! `help` for the list. With `LOG_ADDRESS=ip:port` set at build time the
+//! log goes to a TCP server instead, like `nc -l 5001`.
+//!
+//! The rp2040 can send its defmt log over the link, see its `link-log`
+//! feature. With `DEFMT_ADDRESS=ip:port` the log is passed on to
+//! `udoo-log-server`, which decodes it.
+//!
+//! Roms come from `rom_server.py` at `ADDRESS`, or with
+//! `HTTP_ADDRESS=ip:port` set from the catalogue of a web server, or with
+//! `TFTP_ADDRESS=ip:port` from a TFTP server. The `rom protocol` command
+//! switches between them. Built without any, the board looks for a rom
+//! server with mDNS. It answers for `udoo-key-<mac>.local` either way.
+//!
+//! A web server on port 80 takes roms uploaded from a browser and
+//! launches them, and shows the chip8 screen and keypad of the rp2040
+//! through a WebSocket on port 81, see `udoo_esp32::web`.
+//!
+//! Built without `SSID` and `PASSWORD`, or when the network does not
+//! answer, the board opens the setup access point to enter them and the
+//! rom server, see `udoo_esp32::setup`. Of the saved networks and the
+//! one of the build variables it joins the one in sight with the highest
+//! priority.
+//!
+//! With `MQTT_ADDRESS=host:port` set the board publishes its state to an
+//! MQTT broker and runs the commands sent to it, see `udoo_esp32::mqtt`.
+//! `MQTT_USER` and `MQTT_PASSWORD` log in to a broker that asks for them.
+//!
+//! A build installed with `esp32_ota` is on trial until it joined the
+//! network and reached the main loop, otherwise the RTC watchdog resets
+//! the esp32 and the previous image boots again.
 #![no_std]
 #![no_main]
 
+use core::fmt::{self, Write as _};
+
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::digital::v2::OutputPin;
 use embedded_hal::serial::{Read as SerialRead, Write as SerialWrite};
 use embedded_io::blocking::*;
 use embedded_svc::ipv4::Interface;
-use embedded_svc::wifi::{ClientConfiguration, Configuration, Wifi};
+use embedded_svc::wifi::Wifi;
 
-use esp32_hal::clock::{ClockControl, CpuClock};
+use esp32_hal::clock::{ClockControl, Clocks, CpuClock};
+use esp32_hal::reset::software_reset;
 use esp32_hal::Rng;
-use esp32_hal::{
-    peripherals::Peripherals,
-    prelude::*,
-    uart::{
-        config::{Config, DataBits, Parity, StopBits},
-        TxRxPins,
-    },
-    Rtc, Uart, IO,
-};
+use esp32_hal::{prelude::*, timer::TimerGroup, Delay, Rtc};
 use esp_backtrace as _;
-use esp_println::logger::init_logger;
-use esp_println::{print, println};
+use esp_storage::FlashStorage;
 use esp_wifi::wifi::utils::create_network_interface;
-use esp_wifi::wifi::WifiMode;
-use esp_wifi::wifi_interface::{IoError, Socket, WifiStack};
+use esp_wifi::wifi::{get_sta_mac, WifiMode};
+use esp_wifi::wifi_interface::{IoError, Socket, UdpSocket, WifiStack};
 use esp_wifi::{current_millis, initialize, EspWifiInitFor};
+use log::{debug, error, info, warn};
 use smoltcp::iface::SocketStorage;
+use smoltcp::socket::udp::PacketMetadata;
 use smoltcp::wire::IpAddress;
-use smoltcp::wire::Ipv4Address;
-
-const SSID: &str = env!("SSID");
-const PASSWORD: &str = env!("PASSWORD");
-const RP_SERIAL_CONFIG: Config = Config {
-    baudrate: 9600,
-    data_bits: DataBits::DataBits8,
-    parity: Parity::ParityNone,
-    stop_bits: StopBits::STOP1,
-};
-
-#[repr(u8)]
-enum Command {
-    RequestRomList = 0x1,
-    RequestRom = 0x2,
-}
+use static_cell::StaticCell;
+use udoo_core::esp_ota::{Boot, EspOta};
+use udoo_core::led::{self, Color, Pattern};
+use udoo_core::logger::Sink;
+use udoo_core::rom::{Protocol, Request, RomCache, MAX_ROM_SIZE};
+use udoo_core::rp_control::{Event, RpControl, Watchdog};
+use udoo_core::rp_link::RpLink;
+use udoo_core::settings::{self, Network, Settings};
+use udoo_core::shell::{self, Args, Command, Shell};
+use udoo_core::{catalogue, http};
+use udoo_esp32::mdns::Mdns;
+use udoo_esp32::{crash, dns, i2c, logger, mqtt, setup, status, tftp, web, wifi};
+use udoo_key_bsp::Board;
+use udoo_link::{Message, ROM_CHUNK};
+
+const SSID: Option<&str> = option_env!("SSID");
+const PASSWORD: Option<&str> = option_env!("PASSWORD");
+const UEXT_BAUDRATE: u32 = 115200;
+const HEARTBEAT_INTERVAL_MS: u64 = 1000;
+const HEARTBEAT_TIMEOUT_MS: u64 = 5 * 1000;
+/// Largest rom list of a web server
+const LIST_SIZE: usize = 1024;
+/// Time to look for a rom server with mDNS
+const DISCOVERY_TIMEOUT_MS: u64 = 5 * 1000;
+/// Time to join the network before the setup access point opens
+const CONNECT_TIMEOUT_MS: u64 = 30 * 1000;
+/// How long a trial boot gets to reach the main loop before it is
+/// rolled back, joining the network can take `CONNECT_TIMEOUT_MS`
+const TRIAL_TIMEOUT_S: u64 = 120;
+
+static CLOCKS: StaticCell<Clocks<'static>> = StaticCell::new();
+static ROM_CACHE: StaticCell<RomCache<{ web::CACHE_SIZE }>> = StaticCell::new();
 
 #[derive(Clone, Copy, Debug)]
 struct RomInfo<const S: usize> {
@@ -57,36 +106,185 @@ impl<const S: usize> RomInfo<S> {
         }
     }
 
+    /// A long name is cut off between characters
+    fn with_name(rom_id: u16, name: &str) -> Self {
+        let mut info = Self::new();
+        info.rom_id = rom_id;
+        let mut len = name.len().min(S);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        info.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        info
+    }
+
     fn name(&self) -> &str {
         core::str::from_utf8(&self.name).unwrap()
     }
 }
 
+const UNREACHABLE: shell::Error = shell::Error::Failed("the rom server is unreachable");
+const NO_SUCH_ROM: shell::Error = shell::Error::Failed("the server has no such rom");
+const NO_SERVER: shell::Error = shell::Error::Failed("no server for the protocol was set or found");
+
+/// The console message for a failed transfer from the TFTP server
+fn tftp_error(e: tftp::Error) -> shell::Error {
+    warn!("TFTP transfer failed: {e}");
+    match e {
+        tftp::Error::Io | tftp::Error::Timeout => UNREACHABLE,
+        tftp::Error::Tftp(udoo_core::tftp::Error::Server(udoo_core::tftp::NOT_FOUND)) => {
+            NO_SUCH_ROM
+        }
+        tftp::Error::Tftp(udoo_core::tftp::Error::TooLarge) => {
+            shell::Error::Failed("the file is too large")
+        }
+        _ => shell::Error::Failed("the server sent a bad response"),
+    }
+}
+
+/// The rom servers that were set or found
+#[derive(Clone, Copy, Default)]
+struct Servers {
+    /// `rom_server.py`
+    legacy: Option<(IpAddress, u16)>,
+    http: Option<(IpAddress, u16)>,
+    tftp: Option<(IpAddress, u16)>,
+}
+
+impl Servers {
+    fn get(&self, protocol: Protocol) -> Option<(IpAddress, u16)> {
+        match protocol {
+            Protocol::Legacy => self.legacy,
+            Protocol::Http => self.http,
+            Protocol::Tftp => self.tftp,
+        }
+    }
+
+    fn set(&mut self, protocol: Protocol, address: Option<(IpAddress, u16)>) {
+        match protocol {
+            Protocol::Legacy => self.legacy = address,
+            Protocol::Http => self.http = address,
+            Protocol::Tftp => self.tftp = address,
+        }
+    }
+
+    /// The protocol to start with, the web server comes first and TFTP
+    /// last
+    fn protocol(&self) -> Protocol {
+        [Protocol::Http, Protocol::Legacy, Protocol::Tftp]
+            .into_iter()
+            .find(|&protocol| self.get(protocol).is_some())
+            .unwrap_or(Protocol::Legacy)
+    }
+}
+
+/// The console message for a failed request to the web server
+fn http_error(e: http::Error) -> shell::Error {
+    warn!("HTTP request failed: {e:?}");
+    match e {
+        http::Error::Io => UNREACHABLE,
+        http::Error::Status(404) => NO_SUCH_ROM,
+        http::Error::TooLarge => shell::Error::Failed("the response is too large"),
+        _ => shell::Error::Failed("the server sent a bad response"),
+    }
+}
+
 struct RomGetter<'a, UART, const R: usize, const N: usize>
 where
     UART: SerialRead<u8> + SerialWrite<u8>,
 {
     rom_buffer: [u8; 4096],
     rom_size: usize,
+    /// The rom of the server in the buffer, `None` for an uploaded one
+    rom_id: Option<u16>,
     pub roms: [Option<RomInfo<N>>; R],
     pub socket: Socket<'a, 'a>,
-    uart: UART,
+    /// For the TFTP server, bound to `tftp::LOCAL_PORT`
+    tftp_socket: UdpSocket<'a, 'a>,
+    servers: Servers,
+    protocol: Protocol,
+    link: RpLink<UART>,
 }
 
 impl<'a, UART, const R: usize, const N: usize> RomGetter<'a, UART, R, N>
 where
     UART: SerialRead<u8> + SerialWrite<u8>,
 {
-    fn new(uart: UART, socket: Socket<'a, 'a>) -> Self {
+    /// Roms come from the web server if there is one
+    fn new(
+        uart: UART,
+        socket: Socket<'a, 'a>,
+        tftp_socket: UdpSocket<'a, 'a>,
+        servers: Servers,
+    ) -> Self {
         Self {
             rom_buffer: [0; 4096],
             rom_size: 0,
+            rom_id: None,
             roms: [None; R],
             socket,
-            uart,
+            tftp_socket,
+            servers,
+            protocol: servers.protocol(),
+            link: RpLink::new(uart),
         }
     }
 
+    /// Connect to the server of the current protocol, both answer one
+    /// request per connection
+    fn connect(&mut self) -> Result<(), shell::Error> {
+        let (ip, port) = self.servers.get(self.protocol).ok_or(NO_SERVER)?;
+        self.socket.work();
+        self.socket.open(ip, port).map_err(|_| UNREACHABLE)
+    }
+
+    /// `ip:port` of the web server for the `Host` header
+    fn http_host(&self) -> heapless::String<21> {
+        let mut host = heapless::String::new();
+        if let Some((ip, port)) = self.servers.http {
+            _ = write!(host, "{ip}:{port}");
+        }
+        host
+    }
+
+    /// Get the list of roms with the current protocol
+    fn fetch_rom_list(&mut self) -> Result<(), shell::Error> {
+        // Over UDP, without a connection
+        if self.protocol == Protocol::Tftp {
+            return self.get_rom_list_tftp();
+        }
+        self.connect()?;
+        let result = match self.protocol {
+            Protocol::Legacy => {
+                self.get_rom_list();
+                Ok(())
+            }
+            _ => self.get_rom_list_http(),
+        };
+        self.socket.disconnect();
+        result
+    }
+
+    /// Get a rom with the current protocol
+    fn fetch_rom(&mut self, rom_id: u16) -> Result<(), shell::Error> {
+        if self.protocol == Protocol::Tftp {
+            self.get_rom_tftp(rom_id)?;
+        } else {
+            self.connect()?;
+            let result = match self.protocol {
+                Protocol::Legacy => self.get_rom(rom_id),
+                _ => self.get_rom_http(rom_id),
+            };
+            self.socket.disconnect();
+            result?;
+        }
+        if self.rom_size == 0 {
+            return Err(NO_SUCH_ROM);
+        }
+        self.rom_id = Some(rom_id);
+        Ok(())
+    }
+
     fn read(&mut self, buffer: &mut [u8]) -> Result<usize, IoError> {
         self.socket.work();
         self.socket.read(buffer)
@@ -101,7 +299,8 @@ where
     /// socket server
     fn get_rom_list(&mut self) {
         let mut num_roms = [0_u8; 2];
-        _ = self.write(&[0x0, 0x0, 0x0, Command::RequestRomList as u8]);
+        self.roms = [None; R];
+        _ = self.write(&Request::RomList.to_bytes());
         while let Ok(len) = self.read(&mut num_roms) {
             if len > 0 {
                 break;
@@ -127,14 +326,11 @@ where
                 match rom_info.name.get(0..rom_name_size) {
                     Some(slice) => match self.read(&mut rom_info.name[0..rom_name_size]) {
                         Ok(len) => {
-                            println!(
-                                "rom_name_size for {:?} is :{}\n\r",
-                                rom_id, rom_name_size
-                            );
+                            debug!("rom_name_size for {:?} is :{}", rom_id, rom_name_size);
                         }
-                        Err(e) => println!("get_rom_list error: {e:?}\n\r"),
+                        Err(e) => error!("get_rom_list error: {e:?}"),
                     },
-                    None => println!("Invalid slice range\n\r"),
+                    None => error!("Invalid slice range"),
                 }
                 self.roms[i] = Some(rom_info);
             }
@@ -142,113 +338,378 @@ where
     }
 
     /// Get a rom from the socket server
-    fn get_rom(&mut self, rom_id: u16) {
-        println!("get_rom called\n\r");
+    ///
+    /// The size comes first and the rom follows in as many reads as it
+    /// takes.
+    fn get_rom(&mut self, rom_id: u16) -> Result<(), shell::Error> {
+        debug!("get_rom called");
         let mut rom_size: [u8; 2] = [0; 2];
-        _ = self.write(&[
-            (rom_id >> 8) as u8,
-            (rom_id & 0xff) as u8,
-            0x0,
-            Command::RequestRom as u8,
-        ]);
-        loop {
-            match self.read(&mut rom_size) {
-                Ok(len) if len > 0 => {
-                    println!("get_rom length: {}\n\r", len);
-                    let rom_size = ((rom_size[0] as usize) << 8 | rom_size[1] as usize) as usize;
-                    self.rom_size = rom_size;
-                    self.socket.work();
-                    _ = self.socket.read(&mut self.rom_buffer[0..self.rom_size]);
-                    break;
+        self.rom_size = 0;
+        _ = self.write(&Request::Rom(rom_id).to_bytes());
+        self.socket.work();
+        self.socket
+            .read_exact(&mut rom_size)
+            .map_err(|_| UNREACHABLE)?;
+        let rom_size = u16::from_be_bytes(rom_size) as usize;
+        debug!("get_rom length: {rom_size}");
+        // The rp2040 takes no more, and the buffer holds that much
+        if rom_size > MAX_ROM_SIZE {
+            return Err(shell::Error::Failed("the rom is too large"));
+        }
+        self.socket
+            .read_exact(&mut self.rom_buffer[..rom_size])
+            .map_err(|_| UNREACHABLE)?;
+        self.rom_size = rom_size;
+        Ok(())
+    }
+
+    /// Get the list of roms from the web server
+    fn get_rom_list_http(&mut self) -> Result<(), shell::Error> {
+        let mut list = [0_u8; LIST_SIZE];
+        self.roms = [None; R];
+        let host = self.http_host();
+        let len = http::get(
+            &mut self.socket,
+            &host,
+            format_args!("{}", catalogue::LIST_PATH),
+            &mut list,
+        )
+        .map_err(|e| match e {
+            http::Error::Status(_) => shell::Error::Failed("the server has no rom list"),
+            e => http_error(e),
+        })?;
+        let roms = &mut self.roms;
+        let mut index = 0;
+        catalogue::parse(&list[..len], |rom_id, name| {
+            if let Some(rom) = roms.get_mut(index) {
+                *rom = Some(RomInfo::with_name(rom_id, name));
+            }
+            index += 1;
+        })
+        .map_err(|e| {
+            warn!("Bad rom list: {e:?}");
+            shell::Error::Failed("the rom list is not valid")
+        })?;
+        Ok(())
+    }
+
+    /// Get a rom from the web server
+    fn get_rom_http(&mut self, rom_id: u16) -> Result<(), shell::Error> {
+        self.rom_size = 0;
+        let host = self.http_host();
+        self.rom_size = http::get(
+            &mut self.socket,
+            &host,
+            format_args!("{}/{rom_id}", catalogue::LIST_PATH),
+            &mut self.rom_buffer,
+        )
+        .map_err(http_error)?;
+        Ok(())
+    }
+
+    /// Get the rom list of the TFTP server into `list`, returns it as
+    /// text
+    fn get_tftp_list<'l>(&mut self, list: &'l mut [u8]) -> Result<&'l str, shell::Error> {
+        let server = self.servers.tftp.ok_or(NO_SERVER)?;
+        let len = tftp::get(
+            &mut self.tftp_socket,
+            server,
+            udoo_core::tftp::LIST_FILE,
+            list,
+        )
+        .map_err(|e| match e {
+            tftp::Error::Tftp(udoo_core::tftp::Error::Server(udoo_core::tftp::NOT_FOUND)) => {
+                shell::Error::Failed("the server has no rom list")
+            }
+            e => tftp_error(e),
+        })?;
+        core::str::from_utf8(&list[..len])
+            .map_err(|_| shell::Error::Failed("the rom list is not valid"))
+    }
+
+    /// Get the list of roms from the TFTP server
+    fn get_rom_list_tftp(&mut self) -> Result<(), shell::Error> {
+        let mut list = [0_u8; LIST_SIZE];
+        self.roms = [None; R];
+        let list = self.get_tftp_list(&mut list)?;
+        let roms = udoo_core::tftp::roms(list).enumerate();
+        for (rom, (rom_id, name)) in self.roms.iter_mut().zip(roms) {
+            *rom = Some(RomInfo::with_name(rom_id as u16, name));
+        }
+        Ok(())
+    }
+
+    /// Get a rom from the TFTP server, the file on its line of the list
+    fn get_rom_tftp(&mut self, rom_id: u16) -> Result<(), shell::Error> {
+        self.rom_size = 0;
+        let mut list = [0_u8; LIST_SIZE];
+        let list = self.get_tftp_list(&mut list)?;
+        let file = udoo_core::tftp::roms(list)
+            .nth(rom_id.into())
+            .ok_or(NO_SUCH_ROM)?;
+        let server = self.servers.tftp.ok_or(NO_SERVER)?;
+        self.rom_size = tftp::get(&mut self.tftp_socket, server, file, &mut self.rom_buffer)
+            .map_err(tftp_error)?;
+        Ok(())
+    }
+
+    /// Send a rom to the rp2040
+    fn send_rom(&mut self) {
+        status::show(Color::Yellow, Pattern::Transfer);
+        let rom = &self.rom_buffer[0..self.rom_size];
+        _ = self.link.send(&Message::RomBegin(rom.len() as u16));
+        for (idx, chunk) in rom.chunks(ROM_CHUNK).enumerate() {
+            _ = self.link.send(&Message::RomData {
+                offset: (idx * ROM_CHUNK) as u16,
+                data: chunk,
+            });
+        }
+        _ = self.link.send(&Message::RomEnd);
+        status::show(Color::Yellow, Pattern::Heartbeat);
+    }
+}
+
+impl<'a, UART, const R: usize, const N: usize> shell::Commands for RomGetter<'a, UART, R, N>
+where
+    UART: SerialRead<u8> + SerialWrite<u8>,
+{
+    fn commands(&self) -> &'static [Command] {
+        &[
+            Command {
+                name: "rom list",
+                args: "",
+                help: "List the roms of the server",
+            },
+            Command {
+                name: "rom load",
+                args: "<id>",
+                help: "Load a rom into the rp2040",
+            },
+            Command {
+                name: "rom protocol",
+                args: "<legacy|http|tftp>",
+                help: "Get roms from rom_server.py, a web server or a TFTP server",
+            },
+        ]
+    }
+
+    fn run(
+        &mut self,
+        name: &str,
+        mut args: Args<'_>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), shell::Error> {
+        match name {
+            "rom list" => {
+                self.fetch_rom_list()?;
+                for rom in self.roms.iter().flatten() {
+                    let name = rom.name().trim_end_matches('\0');
+                    _ = write!(out, "{}: {}\r\n", rom.rom_id, name);
                 }
-                Err(e) => {
-                    println!("get_rom error: {e:?}\n\r");
-                    break;
+            }
+            "rom load" => {
+                let rom_id = args.parse()?;
+                self.fetch_rom(rom_id)?;
+                self.send_rom();
+                _ = write!(out, "sent {} bytes\r\n", self.rom_size);
+            }
+            _ => {
+                let protocol = args.parse()?;
+                if self.servers.get(protocol).is_none() {
+                    return Err(NO_SERVER);
                 }
-                _ => {}
+                self.protocol = protocol;
+                _ = write!(out, "roms come from the {protocol} server\r\n");
             }
         }
+        Ok(())
     }
+}
 
-    /// Send a rom to the rp2040
-    fn send_rom(&self) {}
+/// What the web server can do with the board
+struct Web<'a, 's, UART, P, D, const R: usize, const N: usize>
+where
+    UART: SerialRead<u8> + SerialWrite<u8>,
+{
+    rom_getter: &'a mut RomGetter<'s, UART, R, N>,
+    rp_control: &'a mut RpControl<P, D>,
+    watchdog: &'a Watchdog,
+}
+
+impl<'a, 's, UART, P, D, const R: usize, const N: usize> web::Control
+    for Web<'a, 's, UART, P, D, R, N>
+where
+    UART: SerialRead<u8> + SerialWrite<u8>,
+    P: OutputPin,
+    D: DelayMs<u32>,
+{
+    fn launch(&mut self, rom: &[u8]) {
+        // Kept for when the rp2040 has to get it again
+        self.rom_getter.rom_buffer[..rom.len()].copy_from_slice(rom);
+        self.rom_getter.rom_size = rom.len();
+        self.rom_getter.rom_id = None;
+        self.rom_getter.send_rom();
+        info!("Launched a rom of {} bytes for a web client", rom.len());
+    }
+
+    fn reset_rp2040(&mut self) {
+        self.rp_control.reset_rp2040();
+    }
+
+    fn status(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(
+            out,
+            "\"uptime_ms\": {}, \"rp2040\": \"{}\", \"rom_size\": {}, \"protocol\": \"{}\"",
+            current_millis(),
+            rp2040_state(self.rp_control, self.watchdog),
+            self.rom_getter.rom_size,
+            self.rom_getter.protocol,
+        )
+    }
+}
+
+/// How the rp2040 is doing, for the web page and the MQTT broker
+fn rp2040_state<P, D>(rp_control: &RpControl<P, D>, watchdog: &Watchdog) -> &'static str
+where
+    P: OutputPin,
+    D: DelayMs<u32>,
+{
+    if rp_control.is_held() {
+        "held in reset"
+    } else if watchdog.is_alive() {
+        "answering"
+    } else {
+        "silent"
+    }
 }
 
 #[entry]
 fn main() -> ! {
-    init_logger(log::LevelFilter::Info);
-
-    let peripherals = Peripherals::take();
+    logger::init(log::LevelFilter::Info);
+    crash::report();
+
+    let Board {
+        leds,
+        rp_link,
+        uext,
+        rp_reset,
+        peripherals,
+        ..
+    } = Board::take();
 
     let system = peripherals.DPORT.split();
     let mut peripheral_clock_control = system.peripheral_clock_control;
-    let clocks = ClockControl::configure(system.clock_control, CpuClock::Clock240MHz).freeze();
+    // The led driver keeps a reference to the clocks
+    let clocks = &*CLOCKS
+        .init(ClockControl::configure(system.clock_control, CpuClock::Clock240MHz).freeze());
     let mut rtc = Rtc::new(peripherals.RTC_CNTL);
     rtc.rwdt.disable();
 
-    let timer = esp32_hal::timer::TimerGroup::new(
-        peripherals.TIMG1,
-        &clocks,
+    let mut ota = EspOta::new(FlashStorage::new())
+        .map_err(|e| error!("The ota partitions cannot be read: {e:?}"))
+        .ok();
+    match ota.as_mut().map(EspOta::boot) {
+        Some(Ok(Boot::Trial)) => {
+            warn!("Trial boot, rolling back unless running within {TRIAL_TIMEOUT_S}s");
+            rtc.rwdt.start(TRIAL_TIMEOUT_S.secs());
+        }
+        Some(Ok(Boot::RolledBack)) => {
+            warn!("The new image was not confirmed, rolling back");
+            software_reset();
+        }
+        Some(Ok(Boot::Confirmed)) | None => {}
+        Some(Err(e)) => error!("Reading the ota state failed: {e:?}"),
+    }
+
+    // Yellow shows the rp2040, blue the wifi connection
+    let timer_group0 = TimerGroup::new(peripherals.TIMG0, clocks, &mut peripheral_clock_control);
+    status::start(
+        leds,
+        peripherals.LEDC,
+        clocks,
         &mut peripheral_clock_control,
-    )
-    .timer0;
+        timer_group0.timer1,
+    );
+    status::show(Color::Blue, Pattern::Boot);
+
+    let timer =
+        esp32_hal::timer::TimerGroup::new(peripherals.TIMG1, clocks, &mut peripheral_clock_control)
+            .timer0;
     let init = initialize(
         EspWifiInitFor::Wifi,
         timer,
         Rng::new(peripherals.RNG),
         system.radio_clock_control,
-        &clocks,
+        clocks,
     )
     .unwrap();
 
-    let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
-
-    let pins = TxRxPins::new_tx_rx(
-        io.pins.gpio19.into_push_pull_output(),
-        io.pins.gpio22.into_floating_input(),
-    );
-
-    let rp_serial = Uart::new_with_config(
-        peripherals.UART1,
-        Some(RP_SERIAL_CONFIG),
-        Some(pins),
-        &clocks,
-        &mut peripheral_clock_control,
-    );
-
-    let local_address = core::env!("ADDRESS");
-    let mut parts = local_address.split(':');
-    let ip = parts.next().unwrap();
-    let port = parts.next().unwrap().parse::<u16>().unwrap();
-    let mut local_ip: [u8; 4] = [0; 4];
-    let mut index = 0;
-    for part in ip.split('.') {
-        local_ip[index] = part.parse::<u8>().unwrap();
-        index += 1;
+    let rp_serial = rp_link.into_uart(clocks, &mut peripheral_clock_control);
+
+    // Console on the UEXT connector for rp2040 watchdog events and
+    // commands
+    let i2c_bus = i2c::bus(uext.i2c, clocks);
+    let mut uext = uext
+        .uart
+        .into_uart(UEXT_BAUDRATE, clocks, &mut peripheral_clock_control);
+    logger::set_sink(Sink::Uext);
+    let mut rp_control = RpControl::new(rp_reset, Delay::new(clocks));
+
+    // The settings from the setup page take the place of the build
+    // variables
+    let mut store = settings::Store::new(FlashStorage::new())
+        .map_err(|e| error!("The settings cannot be read: {e}"))
+        .ok();
+    let stored: Option<Settings> = store.as_mut().and_then(|store| {
+        store
+            .load()
+            .map_err(|e| error!("The settings cannot be read: {e}"))
+            .ok()
+            .flatten()
+    });
+    // The build variables are the last resort
+    let mut known: heapless::Vec<Network, { settings::MAX_NETWORKS + 1 }> = stored
+        .iter()
+        .flat_map(Settings::networks)
+        .copied()
+        .collect();
+    if let Some((ssid, password)) = SSID.zip(PASSWORD) {
+        match Network::new(ssid, password, 0) {
+            Ok(network) => _ = known.push(network),
+            Err(e) => error!("SSID={ssid} cannot be used: {e}"),
+        }
     }
 
     let (wifi, _) = peripherals.RADIO.split();
-    let mut socket_set_entries: [SocketStorage; 3] = Default::default();
+    // The rom, mDNS, TFTP, log, defmt, web, live and MQTT sockets, the
+    // DHCP client of esp-wifi and one for looking up the names of servers
+    let mut socket_set_entries: [SocketStorage; 10] = Default::default();
+    // Asked for once, so the next start joins the network again
+    let open_setup = setup::requested() || known.is_empty();
+    let mode = if open_setup {
+        WifiMode::Ap
+    } else {
+        WifiMode::Sta
+    };
     let (iface, device, mut controller, sockets) =
-        create_network_interface(&init, wifi, WifiMode::Sta, &mut socket_set_entries);
+        create_network_interface(&init, wifi, mode, &mut socket_set_entries);
     let wifi_stack = WifiStack::new(iface, device, sockets, current_millis);
 
-    let client_config = Configuration::Client(ClientConfiguration {
-        ssid: SSID.into(),
-        password: PASSWORD.into(),
-        ..Default::default()
-    });
-    let res = controller.set_configuration(&client_config);
-    println!("wifi_set_configuration returned {:?}\n\r", res);
-
-    controller.start().unwrap();
-    println!("is wifi started: {:?}\n\r", controller.is_started());
-
-    //println!("capabilities: {:?}\n\r", controller.get_capabilities());
-
-    // wait to get connected
-    println!("wifi_connect {:?}\n\r", controller.connect());
-    println!("Wait to get connected\n\r");
+    if open_setup {
+        status::show(Color::Blue, Pattern::Breathing);
+        setup::run(&mut controller, &wifi_stack, store);
+    }
+    status::show(Color::Blue, Pattern::WifiConnecting);
+    let Some(network) = wifi::configure_known(&mut controller, &known) else {
+        setup::restart();
+    };
+    let ssid = network.ssid();
+
+    // wait to get connected, a network that never answers leads to the
+    // setup access point
+    info!("wifi_connect {:?}", controller.connect());
+    info!("Wait to get connected to {ssid}");
+    let deadline = current_millis() + CONNECT_TIMEOUT_MS;
     loop {
         let res = controller.is_connected();
         match res {
@@ -258,70 +719,296 @@ fn main() -> ! {
                 }
             }
             Err(err) => {
-                println!("Error: {:?}\n\r", err);
-                println!("Retrying...\n\r");
+                warn!("Connecting failed, retrying: {:?}", err);
                 _ = controller.connect();
             }
         }
+        if current_millis() > deadline {
+            error!("{ssid} did not answer, opening the setup access point");
+            setup::restart();
+        }
     }
-    println!("is_connected: {:?}\n\r", controller.is_connected());
+    info!("is_connected: {:?}", controller.is_connected());
 
-    // wait for getting an ip address
-    println!("Wait to get an ip address\n\r");
-    loop {
-        wifi_stack.work();
+    wifi::wait_for_ip(&wifi_stack);
+    status::show(Color::Blue, Pattern::Heartbeat);
 
-        if wifi_stack.is_iface_up() {
-            println!("got ip {:?}\n\r", wifi_stack.get_ip_info());
-            break;
+    let mut rx_buffer = [0u8; 1536];
+    let mut tx_buffer = [0u8; 1536];
+    let socket = wifi_stack.get_socket(&mut rx_buffer, &mut tx_buffer);
+
+    // The board answers for its name, and finds a rom server when none
+    // was given
+    let mut mdns_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut mdns_rx_buffer = [0u8; 1024];
+    let mut mdns_tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut mdns_tx_buffer = [0u8; 1024];
+    let mdns_socket = wifi_stack.get_udp_socket(
+        &mut mdns_rx_meta,
+        &mut mdns_rx_buffer,
+        &mut mdns_tx_meta,
+        &mut mdns_tx_buffer,
+    );
+    let mut mac = [0u8; 6];
+    get_sta_mac(&mut mac);
+    let ip = wifi_stack.get_ip_info().map(|info| info.ip.octets());
+    let mut mdns = Mdns::new(mdns_socket, mac, ip.unwrap_or_default());
+    let mut servers = Servers::default();
+    match stored.as_ref().and_then(Settings::rom_server) {
+        Some((address, protocol)) => servers.set(
+            protocol,
+            dns::resolve_variable(&wifi_stack, "rom_server", Some(address)),
+        ),
+        None => {
+            servers.legacy = dns::resolve_variable(&wifi_stack, "ADDRESS", option_env!("ADDRESS"));
+            servers.http =
+                dns::resolve_variable(&wifi_stack, "HTTP_ADDRESS", option_env!("HTTP_ADDRESS"));
+            servers.tftp =
+                dns::resolve_variable(&wifi_stack, "TFTP_ADDRESS", option_env!("TFTP_ADDRESS"));
+        }
+    }
+    if servers.legacy.is_none() && servers.http.is_none() && servers.tftp.is_none() {
+        match mdns.find_rom_server(DISCOVERY_TIMEOUT_MS) {
+            Some((address, protocol)) => servers.set(protocol, Some(address)),
+            None => warn!("No rom server answered over mDNS"),
         }
     }
 
-    let mut rx_buffer = [0u8; 1536];
-    let mut tx_buffer = [0u8; 1536];
-    let mut socket = wifi_stack.get_socket(&mut rx_buffer, &mut tx_buffer);
-
-    socket.work();
-    socket
-        .open(
-            IpAddress::Ipv4(Ipv4Address::new(
-                local_ip[0],
-                local_ip[1],
-                local_ip[2],
-                local_ip[3],
-            )),
-            port,
-        )
-        .unwrap();
+    // Roms from a TFTP server come over UDP
+    let mut tftp_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tftp_rx_buffer = [0u8; 2 * udoo_core::tftp::MAX_PACKET];
+    let mut tftp_tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tftp_tx_buffer = [0u8; 256];
+    let mut tftp_socket = wifi_stack.get_udp_socket(
+        &mut tftp_rx_meta,
+        &mut tftp_rx_buffer,
+        &mut tftp_tx_meta,
+        &mut tftp_tx_buffer,
+    );
+    if let Err(e) = tftp_socket.bind(tftp::LOCAL_PORT) {
+        warn!("Binding the TFTP port failed: {e:?}");
+    }
+
+    // Records go to a log server instead of the console when
+    // LOG_ADDRESS is set
+    let mut log_rx_buffer = [0u8; 64];
+    let mut log_tx_buffer = [0u8; 1536];
+    let mut log_socket = wifi_stack.get_socket(&mut log_rx_buffer, &mut log_tx_buffer);
+    if let Some((ip, port)) =
+        dns::resolve_variable(&wifi_stack, "LOG_ADDRESS", option_env!("LOG_ADDRESS"))
+    {
+        log_socket.work();
+        match log_socket.open(ip, port) {
+            Ok(()) => logger::set_sink(Sink::Network),
+            Err(e) => warn!("Connecting to the log server failed: {e:?}"),
+        }
+    }
+    // The report at boot only went to USB
+    crash::report();
+
+    // The defmt log of the rp2040 goes to a log server when DEFMT_ADDRESS
+    // is set
+    let mut defmt_rx_buffer = [0u8; 64];
+    let mut defmt_tx_buffer = [0u8; 1024];
+    let mut defmt_socket = wifi_stack.get_socket(&mut defmt_rx_buffer, &mut defmt_tx_buffer);
+    let mut defmt_connected = false;
+    if let Some((ip, port)) =
+        dns::resolve_variable(&wifi_stack, "DEFMT_ADDRESS", option_env!("DEFMT_ADDRESS"))
+    {
+        defmt_socket.work();
+        match defmt_socket.open(ip, port) {
+            Ok(()) => defmt_connected = true,
+            Err(e) => warn!("Connecting to the defmt log server failed: {e:?}"),
+        }
+    }
 
-    let mut rom_getter: RomGetter<_, 8, 32> = RomGetter::new(rp_serial, socket);
+    // Browsers upload and launch roms
+    let mut web_rx_buffer = [0u8; 1536];
+    let mut web_tx_buffer = [0u8; 1536];
+    let web_socket = wifi_stack.get_socket(&mut web_rx_buffer, &mut web_tx_buffer);
+    let mut web_server = web::Server::new(web_socket, web::PORT, ROM_CACHE.init(RomCache::new()));
+
+    // Browsers show the screen of the rp2040 and press its keys
+    let mut live_rx_buffer = [0u8; 256];
+    let mut live_tx_buffer = [0u8; 1536];
+    let live_socket = wifi_stack.get_socket(&mut live_rx_buffer, &mut live_tx_buffer);
+    let mut live = web::Live::new(live_socket, web::LIVE_PORT);
+
+    // The state goes to an MQTT broker and commands come from it when
+    // MQTT_ADDRESS is set
+    let mut mqtt_rx_buffer = [0u8; 1024];
+    let mut mqtt_tx_buffer = [0u8; 1024];
+    let mqtt_socket = wifi_stack.get_socket(&mut mqtt_rx_buffer, &mut mqtt_tx_buffer);
+    let mut mqtt_client =
+        dns::resolve_variable(&wifi_stack, "MQTT_ADDRESS", option_env!("MQTT_ADDRESS")).map(
+            |broker| {
+                let user = option_env!("MQTT_USER").zip(option_env!("MQTT_PASSWORD"));
+                mqtt::Client::new(mqtt_socket, broker, mdns.name(), user)
+            },
+        );
+
+    let mut rom_getter: RomGetter<_, 8, 32> =
+        RomGetter::new(rp_serial, socket, tftp_socket, servers);
+    info!("Getting roms over {}", rom_getter.protocol);
 
     //rom_getter.get_rom_list();
     //for rom in rom_getter.roms {
     //    if rom.is_some() {
     //        let rom = rom.unwrap();
-    //        println!("{}: {}\n\r", rom.rom_id, rom.name());
+    //        info!("{}: {}", rom.rom_id, rom.name());
     //    }
     //}
 
-    rom_getter.get_rom(1);
-
-    for (idx, byte) in rom_getter.rom_buffer[0..rom_getter.rom_size]
-        .iter()
-        .enumerate()
-    {
-        if idx % 17 == 0 {
-            println!("\n\r");
-        } else {
-            print!("{byte:02x} ");
+    status::show(Color::Yellow, Pattern::Transfer);
+    match rom_getter.fetch_rom(1) {
+        Ok(()) => {
+            for (idx, row) in rom_getter.rom_buffer[0..rom_getter.rom_size]
+                .chunks(16)
+                .enumerate()
+            {
+                debug!("{:04x}: {:02x?}", idx * 16, row);
+            }
+            rom_getter.send_rom();
+        }
+        Err(e) => {
+            error!("Getting the first rom failed: {e:?}");
+            status::show(Color::Yellow, Pattern::Error(2));
         }
     }
-
-    rom_getter.socket.disconnect();
+    crash::healthy();
 
     let wait_end = current_millis() + 5 * 1000;
     while current_millis() < wait_end {
         rom_getter.socket.work();
     }
-    loop {}
+
+    // Keep the rp2040 answering, it gets the rom again once it is back
+    // from a power cycle
+    let mut watchdog = Watchdog::new(
+        HEARTBEAT_INTERVAL_MS,
+        HEARTBEAT_TIMEOUT_MS,
+        current_millis(),
+    );
+    let mut resend_rom = false;
+    let mut shell = Shell::new();
+    shell.prompt(&mut uext);
+    // Joining the network and reaching the main loop is what this program
+    // needs to work
+    if let Some(ota) = ota.as_mut() {
+        if ota.mark_valid().is_ok() {
+            rtc.rwdt.disable();
+        }
+    }
+    loop {
+        let now = current_millis();
+        let event = match rom_getter.link.poll() {
+            Some(Ok(Message::Pong(seq))) => watchdog.pong(seq, now),
+            // The rp2040 can drive the yellow and blue leds
+            Some(Ok(message @ Message::Led { .. })) => {
+                if let Some((color, pattern)) = led::parse_message(&message) {
+                    status::show(color, pattern);
+                }
+                None
+            }
+            // The host decodes the rp2040 log, it is passed on untouched
+            Some(Ok(Message::Log(bytes))) => {
+                if defmt_connected {
+                    defmt_socket.work();
+                    if let Err(e) = defmt_socket.write_all(bytes) {
+                        warn!("Lost the defmt log server: {e:?}");
+                        defmt_connected = false;
+                    }
+                }
+                None
+            }
+            Some(Ok(Message::Screen { row, pixels })) => {
+                live.apply(row, pixels);
+                None
+            }
+            _ => watchdog.poll(now, &mut rom_getter.link, &mut rp_control),
+        };
+        match event {
+            Some(Event::PowerCycled { .. }) => {
+                status::show(Color::Yellow, Pattern::Error(1));
+                resend_rom = true;
+            }
+            Some(Event::Alive) if resend_rom => {
+                rom_getter.send_rom();
+                resend_rom = false;
+            }
+            _ => {}
+        }
+        match event {
+            Some(event @ Event::PowerCycled { .. }) => warn!("{event}"),
+            Some(event) => info!("{event}"),
+            None => {}
+        }
+        _ = logger::send(Sink::Uext, &mut uext);
+        _ = logger::send(Sink::Network, &mut logger::Connection(&mut log_socket));
+        mdns.poll();
+
+        web_server.poll(
+            now,
+            &mut Web {
+                rom_getter: &mut rom_getter,
+                rp_control: &mut rp_control,
+                watchdog: &watchdog,
+            },
+        );
+        if let Some(keys) = live.poll(now) {
+            _ = rom_getter.link.send(&Message::Keys(keys));
+        }
+        if let Some(client) = mqtt_client.as_mut() {
+            let command = client.poll(now, || mqtt::Telemetry {
+                rom_id: rom_getter.rom_id,
+                rom_size: rom_getter.rom_size,
+                rp2040: rp2040_state(&rp_control, &watchdog),
+                rssi: wifi::rssi(),
+            });
+            if let Some(command) = command {
+                info!("Running {command} for the MQTT broker");
+                let mut response: heapless::String<{ mqtt::RESPONSE_SIZE }> =
+                    heapless::String::new();
+                shell::run(
+                    &command,
+                    &mut [
+                        &mut rom_getter,
+                        &mut rp_control,
+                        &mut led::Commands(status::show),
+                    ],
+                    &mut response,
+                );
+                client.respond(&response);
+            }
+        }
+
+        // The green led is on the rp2040
+        if let Some(pattern) = status::take_green() {
+            _ = rom_getter.link.send(&led::message(Color::Green, pattern));
+        }
+        if let Ok(byte) = uext.read() {
+            let mut wifi = wifi::Commands {
+                controller: &mut controller,
+                stack: &wifi_stack,
+                known: &known,
+            };
+            shell.feed(
+                byte,
+                &mut [
+                    &mut rom_getter,
+                    &mut rp_control,
+                    &mut led::Commands(status::show),
+                    &mut logger::Commands,
+                    &mut wifi,
+                    &mut &i2c_bus,
+                ],
+                &mut uext,
+            );
+        }
+    }
+}
+
+#[interrupt]
+fn TG0_T1_LEVEL() {
+    status::on_timer();
 }
@@ -0,0 +1,119 @@
+//! Wireless debug probe for the rp2040
+//!
+//! The esp32 joins the wifi network and serves CMSIS-DAP over TCP on port
+//! 4441, driving the rp2040 SWD port over the on-board wiring. OpenOCD
+//! connects to it with its `tcp` CMSIS-DAP backend, see `rp2040/README.md`.
+#![no_std]
+#![no_main]
+
+use embedded_io::blocking::{Read, Write};
+use esp32_hal::clock::{ClockControl, CpuClock};
+use esp32_hal::Rng;
+use esp32_hal::{prelude::*, Delay, Rtc};
+use esp_backtrace as _;
+use esp_wifi::wifi::utils::create_network_interface;
+use esp_wifi::wifi::WifiMode;
+use esp_wifi::wifi_interface::WifiStack;
+use esp_wifi::{current_millis, initialize, EspWifiInitFor};
+use log::{error, info};
+use smoltcp::iface::SocketStorage;
+
+use udoo_core::dap::{self, Dap, PACKET_SIZE, TCP_HEADER_SIZE, TCP_PORT};
+use udoo_core::rp_control::RpControl;
+use udoo_core::swd::Swd;
+use udoo_esp32::{crash, logger, wifi};
+use udoo_key_bsp::Board;
+
+const SSID: &str = env!("SSID");
+const PASSWORD: &str = env!("PASSWORD");
+
+#[entry]
+fn main() -> ! {
+    logger::init(log::LevelFilter::Info);
+    crash::report();
+
+    let Board {
+        swd: mut swd_pins,
+        rp_reset,
+        peripherals,
+        ..
+    } = Board::take();
+
+    let system = peripherals.DPORT.split();
+    let mut peripheral_clock_control = system.peripheral_clock_control;
+    let clocks = ClockControl::configure(system.clock_control, CpuClock::Clock240MHz).freeze();
+    let mut rtc = Rtc::new(peripherals.RTC_CNTL);
+    rtc.rwdt.disable();
+
+    let timer = esp32_hal::timer::TimerGroup::new(
+        peripherals.TIMG1,
+        &clocks,
+        &mut peripheral_clock_control,
+    )
+    .timer0;
+    let init = initialize(
+        EspWifiInitFor::Wifi,
+        timer,
+        Rng::new(peripherals.RNG),
+        system.radio_clock_control,
+        &clocks,
+    )
+    .unwrap();
+
+    // Route the rp2040 SWD port to the esp32 instead of the external header
+    swd_pins.select_esp32();
+    let rp_control = RpControl::new(rp_reset, Delay::new(&clocks));
+    let swd = Swd::new(swd_pins.swdio, swd_pins.swclk, Delay::new(&clocks));
+    let mut dap = Dap::new(swd, rp_control);
+
+    let (wifi, _) = peripherals.RADIO.split();
+    let mut socket_set_entries: [SocketStorage; 3] = Default::default();
+    let (iface, device, mut controller, sockets) =
+        create_network_interface(&init, wifi, WifiMode::Sta, &mut socket_set_entries);
+    let wifi_stack = WifiStack::new(iface, device, sockets, current_millis);
+
+    wifi::connect(&mut controller, SSID, PASSWORD);
+    wifi::wait_for_ip(&wifi_stack);
+
+    let mut rx_buffer = [0u8; 1536];
+    let mut tx_buffer = [0u8; 1536];
+    let mut socket = wifi_stack.get_socket(&mut rx_buffer, &mut tx_buffer);
+
+    let mut request = [0_u8; PACKET_SIZE];
+    let mut response = [0_u8; PACKET_SIZE];
+    loop {
+        info!("Waiting for a debugger on port {TCP_PORT}");
+        if let Err(e) = socket.listen(TCP_PORT) {
+            error!("listen failed: {e:?}");
+            continue;
+        }
+        info!("Debugger connected");
+
+        loop {
+            let mut header = [0_u8; TCP_HEADER_SIZE];
+            if socket.read_exact(&mut header).is_err() {
+                break;
+            }
+            let Some(len) = dap::parse_tcp_header(&header) else {
+                error!("Invalid CMSIS-DAP frame");
+                break;
+            };
+            if socket.read_exact(&mut request[..len]).is_err() {
+                break;
+            }
+            let answer = dap.process(&request[..len], &mut response);
+            if answer == 0 {
+                continue;
+            }
+            if socket.write_all(&dap::tcp_header(answer)).is_err()
+                || socket.write_all(&response[..answer]).is_err()
+                || socket.flush().is_err()
+            {
+                break;
+            }
+        }
+
+        info!("Debugger disconnected");
+        socket.disconnect();
+    }
+}
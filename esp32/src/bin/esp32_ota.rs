@@ -0,0 +1,159 @@
+//! Over the air update of the esp32 firmware
+//!
+//! The esp32 downloads a signed esp32 image from the rom server into the
+//! app partition it is not running from, checks it and reboots into it.
+//! A freshly updated image is on trial until it connected to the wifi
+//! network, the RTC watchdog resets the esp32 when that takes too long
+//! and the next boot rolls back to the previous image.
+//!
+//! Other programs can take part in updates the same way by calling
+//! `EspOta::boot` at startup and `EspOta::mark_valid` once they work.
+#![no_std]
+#![no_main]
+
+use embedded_io::blocking::Write;
+use esp32_hal::clock::{ClockControl, CpuClock};
+use esp32_hal::reset::software_reset;
+use esp32_hal::Rng;
+use esp32_hal::{prelude::*, Rtc};
+use esp_backtrace as _;
+use esp_storage::FlashStorage;
+use esp_wifi::wifi::utils::create_network_interface;
+use esp_wifi::wifi::WifiMode;
+use esp_wifi::wifi_interface::WifiStack;
+use esp_wifi::{current_millis, initialize, EspWifiInitFor};
+use log::{error, info, warn};
+use smoltcp::iface::SocketStorage;
+
+use udoo_core::esp_ota::{Boot, EspOta};
+use udoo_core::ota::{self, Target};
+use udoo_core::rom::Request;
+use udoo_esp32::{crash, dns, logger, wifi};
+use udoo_key_bsp::Board;
+
+const SSID: &str = env!("SSID");
+const PASSWORD: &str = env!("PASSWORD");
+const ADDRESS: &str = env!("ADDRESS");
+/// Public key printed by `pack_firmware.py keygen`
+const OTA_PUBLIC_KEY: &str = env!("OTA_PUBLIC_KEY");
+
+/// How long a trial boot gets to connect before it is rolled back
+const TRIAL_TIMEOUT_S: u64 = 60;
+
+/// Version of this build, only newer images are installed
+fn firmware_version() -> u32 {
+    option_env!("FIRMWARE_VERSION")
+        .and_then(|version| version.parse().ok())
+        .unwrap_or(0)
+}
+
+#[entry]
+fn main() -> ! {
+    logger::init(log::LevelFilter::Info);
+    crash::report();
+
+    let Board { peripherals, .. } = Board::take();
+
+    let system = peripherals.DPORT.split();
+    let mut peripheral_clock_control = system.peripheral_clock_control;
+    let clocks = ClockControl::configure(system.clock_control, CpuClock::Clock240MHz).freeze();
+    let mut rtc = Rtc::new(peripherals.RTC_CNTL);
+    rtc.rwdt.disable();
+
+    let mut ota = match EspOta::new(FlashStorage::new()) {
+        Ok(ota) => ota,
+        Err(e) => {
+            error!("ota partitions unavailable: {e:?}");
+            loop {}
+        }
+    };
+    let version = firmware_version();
+    info!(
+        "Running version {} from {:?} ({:?})",
+        version,
+        ota.running(),
+        ota.state()
+    );
+    match ota.boot() {
+        Ok(Boot::Trial) => {
+            warn!("Trial boot, rolling back unless connected within {TRIAL_TIMEOUT_S}s");
+            rtc.rwdt.start(TRIAL_TIMEOUT_S.secs());
+        }
+        Ok(Boot::RolledBack) => {
+            warn!("The new image was not confirmed, rolling back");
+            software_reset();
+        }
+        Ok(Boot::Confirmed) => {}
+        Err(e) => error!("Reading the ota state failed: {e:?}"),
+    }
+
+    let Some(public_key) = ota::parse_public_key(OTA_PUBLIC_KEY) else {
+        error!("OTA_PUBLIC_KEY has to be 64 hex digits");
+        loop {}
+    };
+
+    let timer = esp32_hal::timer::TimerGroup::new(
+        peripherals.TIMG1,
+        &clocks,
+        &mut peripheral_clock_control,
+    )
+    .timer0;
+    let init = initialize(
+        EspWifiInitFor::Wifi,
+        timer,
+        Rng::new(peripherals.RNG),
+        system.radio_clock_control,
+        &clocks,
+    )
+    .unwrap();
+
+    let (wifi, _) = peripherals.RADIO.split();
+    let mut socket_set_entries: [SocketStorage; 3] = Default::default();
+    let (iface, device, mut controller, sockets) =
+        create_network_interface(&init, wifi, WifiMode::Sta, &mut socket_set_entries);
+    let wifi_stack = WifiStack::new(iface, device, sockets, current_millis);
+
+    wifi::connect(&mut controller, SSID, PASSWORD);
+    wifi::wait_for_ip(&wifi_stack);
+
+    // Reaching the network is what this program needs to work
+    if ota.mark_valid().is_ok() {
+        rtc.rwdt.disable();
+    }
+    crash::healthy();
+
+    let (address, port) = match dns::resolve(&wifi_stack, ADDRESS) {
+        Ok(address) => address,
+        Err(e) => {
+            error!("ADDRESS={ADDRESS} cannot be used: {e}");
+            loop {}
+        }
+    };
+
+    let mut rx_buffer = [0u8; 1536];
+    let mut tx_buffer = [0u8; 1536];
+    let mut socket = wifi_stack.get_socket(&mut rx_buffer, &mut tx_buffer);
+
+    socket.work();
+    if let Err(e) = socket.open(address, port) {
+        error!("The rom server at {address}:{port} cannot be reached: {e:?}");
+        loop {}
+    }
+    _ = socket.write_all(&Request::Firmware(Target::Esp32).to_bytes());
+
+    let staged = ota.stage(&mut socket, &public_key, version);
+    socket.disconnect();
+
+    match staged.and_then(|(slot, header)| ota.activate(slot).map(|_| (slot, header))) {
+        Ok((slot, header)) => {
+            info!(
+                "Installed version {} ({} bytes) in {:?}, restarting",
+                header.version, header.length, slot
+            );
+            software_reset();
+        }
+        Err(e) => warn!("No update installed: {e:?}"),
+    }
+
+    loop {}
+}
@@ -0,0 +1,57 @@
+//! Console for the I2C bus on the UEXT connector
+//!
+//! Type commands into the UEXT serial port (TXD Gpio13, RXD Gpio26) at
+//! 115200 baud:
+//!
+//! - `i2c scan` lists the addresses that answer
+//! - `i2c recover` clocks the bus free when a device holds SDA low
+#![no_std]
+#![no_main]
+
+use core::fmt::Write;
+
+use esp32_hal::{clock::ClockControl, prelude::*, timer::TimerGroup, Rtc};
+use esp_backtrace as _;
+use udoo_core::shell::Shell;
+use udoo_esp32::{crash, i2c, logger};
+use udoo_key_bsp::Board;
+
+const UEXT_BAUDRATE: u32 = 115200;
+
+#[entry]
+fn main() -> ! {
+    logger::init(log::LevelFilter::Info);
+    crash::report();
+
+    let Board {
+        uext, peripherals, ..
+    } = Board::take();
+    let mut system = peripherals.DPORT.split();
+    let clocks = ClockControl::boot_defaults(system.clock_control).freeze();
+
+    let timer_group0 = TimerGroup::new(
+        peripherals.TIMG0,
+        &clocks,
+        &mut system.peripheral_clock_control,
+    );
+    let mut wdt = timer_group0.wdt;
+    let mut rtc = Rtc::new(peripherals.RTC_CNTL);
+
+    // Disable MWDT and RWDT (Watchdog) flash boot protection
+    wdt.disable();
+    rtc.rwdt.disable();
+
+    let mut console =
+        uext.uart
+            .into_uart(UEXT_BAUDRATE, &clocks, &mut system.peripheral_clock_control);
+    let bus = i2c::bus(uext.i2c, &clocks);
+
+    let mut shell = Shell::new();
+    _ = write!(console, "I2C console, type help for the commands\r\n");
+    shell.prompt(&mut console);
+    loop {
+        if let Ok(byte) = console.read() {
+            shell.feed(byte, &mut [&mut &bus], &mut console);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Over the air update of the rp2040 firmware
+//!
+//! The esp32 downloads a packed rp2040 image from the rom server, stages it
+//! in its own flash and programs it into the rp2040 over SWD. After the
+//! rp2040 is reset it has to answer heartbeat pings on the serial
+//! connection, otherwise the previously staged image is programmed back.
+//!
+//! The rp2040 firmware has to answer `Ping` messages from `udoo-link`, like
+//! the rp2040 chip8 program does.
+#![no_std]
+#![no_main]
+
+use embedded_hal::serial::{Read as SerialRead, Write as SerialWrite};
+use embedded_io::blocking::Write;
+use esp32_hal::clock::{ClockControl, CpuClock};
+use esp32_hal::Rng;
+use esp32_hal::{prelude::*, Delay, Rtc};
+use esp_backtrace as _;
+use esp_storage::FlashStorage;
+use esp_wifi::wifi::utils::create_network_interface;
+use esp_wifi::wifi::WifiMode;
+use esp_wifi::wifi_interface::WifiStack;
+use esp_wifi::{current_millis, initialize, EspWifiInitFor};
+use log::{error, info, warn};
+use smoltcp::iface::SocketStorage;
+
+use udoo_core::ota::Target;
+use udoo_core::rom::Request;
+use udoo_core::rp_control::RpControl;
+use udoo_core::rp_link::RpLink;
+use udoo_core::rp_ota::RpOta;
+use udoo_core::swd::Swd;
+use udoo_esp32::{crash, dns, logger, wifi};
+use udoo_key_bsp::Board;
+use udoo_link::Message;
+
+const SSID: &str = env!("SSID");
+const PASSWORD: &str = env!("PASSWORD");
+const ADDRESS: &str = env!("ADDRESS");
+
+/// How long the rp2040 gets to answer a ping after booting
+const HEARTBEAT_TIMEOUT_MS: u64 = 10 * 1000;
+const PING_INTERVAL_MS: u64 = 500;
+
+/// Ping the rp2040 until it answers or the timeout runs out
+fn heartbeat<UART>(link: &mut RpLink<UART>, timeout_ms: u64) -> bool
+where
+    UART: SerialRead<u8> + SerialWrite<u8>,
+{
+    let end = current_millis() + timeout_ms;
+    let mut seq = 0_u16;
+    let mut next_ping = 0;
+    while current_millis() < end {
+        if current_millis() >= next_ping {
+            seq = seq.wrapping_add(1);
+            _ = link.send(&Message::Ping(seq));
+            next_ping = current_millis() + PING_INTERVAL_MS;
+        }
+        if let Some(Ok(Message::Pong(pong))) = link.poll() {
+            if pong == seq {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+#[entry]
+fn main() -> ! {
+    logger::init(log::LevelFilter::Info);
+    crash::report();
+
+    let Board {
+        rp_link,
+        swd: mut swd_pins,
+        rp_reset,
+        peripherals,
+        ..
+    } = Board::take();
+
+    let system = peripherals.DPORT.split();
+    let mut peripheral_clock_control = system.peripheral_clock_control;
+    let clocks = ClockControl::configure(system.clock_control, CpuClock::Clock240MHz).freeze();
+    let mut rtc = Rtc::new(peripherals.RTC_CNTL);
+    rtc.rwdt.disable();
+
+    let timer = esp32_hal::timer::TimerGroup::new(
+        peripherals.TIMG1,
+        &clocks,
+        &mut peripheral_clock_control,
+    )
+    .timer0;
+    let init = initialize(
+        EspWifiInitFor::Wifi,
+        timer,
+        Rng::new(peripherals.RNG),
+        system.radio_clock_control,
+        &clocks,
+    )
+    .unwrap();
+
+    let rp_serial = rp_link.into_uart(&clocks, &mut peripheral_clock_control);
+    let mut link = RpLink::new(rp_serial);
+
+    // Route the rp2040 SWD port to the esp32 instead of the external header
+    swd_pins.select_esp32();
+    let mut rp_control = RpControl::new(rp_reset, Delay::new(&clocks));
+    let mut swd = Swd::new(swd_pins.swdio, swd_pins.swclk, Delay::new(&clocks));
+
+    let mut ota = match RpOta::new(FlashStorage::new()) {
+        Ok(ota) => ota,
+        Err(e) => {
+            error!("rp2040 staging partitions unavailable: {e:?}");
+            loop {}
+        }
+    };
+    info!("Active rp2040 slot: {:?}", ota.active());
+
+    let (wifi, _) = peripherals.RADIO.split();
+    let mut socket_set_entries: [SocketStorage; 3] = Default::default();
+    let (iface, device, mut controller, sockets) =
+        create_network_interface(&init, wifi, WifiMode::Sta, &mut socket_set_entries);
+    let wifi_stack = WifiStack::new(iface, device, sockets, current_millis);
+
+    wifi::connect(&mut controller, SSID, PASSWORD);
+    wifi::wait_for_ip(&wifi_stack);
+    let (address, port) = match dns::resolve(&wifi_stack, ADDRESS) {
+        Ok(address) => address,
+        Err(e) => {
+            error!("ADDRESS={ADDRESS} cannot be used: {e}");
+            loop {}
+        }
+    };
+
+    let mut rx_buffer = [0u8; 1536];
+    let mut tx_buffer = [0u8; 1536];
+    let mut socket = wifi_stack.get_socket(&mut rx_buffer, &mut tx_buffer);
+
+    socket.work();
+    if let Err(e) = socket.open(address, port) {
+        error!("The rom server at {address}:{port} cannot be reached: {e:?}");
+        loop {}
+    }
+    _ = socket.write_all(&Request::Firmware(Target::Rp2040).to_bytes());
+
+    let staged = ota.stage(&mut socket);
+    socket.disconnect();
+
+    let (slot, header) = match staged {
+        Ok(staged) => staged,
+        Err(e) => {
+            error!("Staging the rp2040 image failed: {e:?}");
+            loop {}
+        }
+    };
+    info!(
+        "Staged rp2040 version {} ({} bytes) in slot {:?}",
+        header.version, header.length, slot
+    );
+
+    let programmed = ota.program(slot, &mut swd);
+    rp_control.reset_rp2040();
+    match programmed {
+        Ok(_) if heartbeat(&mut link, HEARTBEAT_TIMEOUT_MS) => {
+            _ = ota.commit(slot);
+            info!("rp2040 runs version {}", header.version);
+        }
+        result => {
+            error!("rp2040 update failed: {:?}", result.err());
+            match ota.active() {
+                Some(previous) => {
+                    let rollback = ota.program(previous, &mut swd);
+                    rp_control.reset_rp2040();
+                    match rollback {
+                        Ok(header) if heartbeat(&mut link, HEARTBEAT_TIMEOUT_MS) => {
+                            info!("Rolled back to version {}", header.version)
+                        }
+                        result => error!("Rollback failed: {:?}", result.err()),
+                    }
+                }
+                None => warn!("No previous rp2040 image to roll back to"),
+            }
+        }
+    }
+
+    loop {}
+}
@@ -0,0 +1,90 @@
+//! Reads an SD card on the UEXT connector, for example an Olimex
+//! MOD-SDMMC. The card sits on the shared UEXT SPI bus with the UEXT chip
+//! select (Gpio15), more modules can be added with `bus.device` and a
+//! chip select on another pin.
+//!
+//! The card type, its size and the partition table of its first block
+//! are printed. Nothing is written to the card.
+#![no_std]
+#![no_main]
+
+use esp32_hal::{clock::ClockControl, prelude::*, timer::TimerGroup, Delay, Rtc};
+use esp_backtrace as _;
+use log::{error, info, warn};
+use udoo_core::sd::{SdCard, BLOCK_SIZE};
+use udoo_core::spi_bus::SpiBus;
+use udoo_esp32::{crash, logger};
+use udoo_key_bsp::Board;
+
+/// SD cards have to be initialised at 400 kHz or less, the bus is kept
+/// at that speed so it suits every module
+const SPI_FREQUENCY_KHZ: u32 = 400;
+
+/// Master boot record partition entries
+const PARTITION_TABLE: usize = 0x1be;
+const PARTITION_ENTRY_SIZE: usize = 16;
+
+#[entry]
+fn main() -> ! {
+    logger::init(log::LevelFilter::Info);
+    crash::report();
+
+    let Board {
+        uext, peripherals, ..
+    } = Board::take();
+    let mut system = peripherals.DPORT.split();
+    let clocks = ClockControl::boot_defaults(system.clock_control).freeze();
+
+    let timer_group0 = TimerGroup::new(
+        peripherals.TIMG0,
+        &clocks,
+        &mut system.peripheral_clock_control,
+    );
+    let mut wdt = timer_group0.wdt;
+    let mut rtc = Rtc::new(peripherals.RTC_CNTL);
+
+    // Disable MWDT and RWDT (Watchdog) flash boot protection
+    wdt.disable();
+    rtc.rwdt.disable();
+
+    let (spi, cs) = uext.spi.into_spi(
+        SPI_FREQUENCY_KHZ.kHz(),
+        &clocks,
+        &mut system.peripheral_clock_control,
+    );
+    let bus = SpiBus::new(spi);
+    let mut card = SdCard::new(bus.device(cs), Delay::new(&clocks));
+
+    match card.init() {
+        Ok(card_type) => info!("Found a {card_type:?} card"),
+        Err(e) => {
+            warn!("No SD card: {e:?}");
+            loop {}
+        }
+    }
+    match card.num_blocks() {
+        Ok(blocks) => info!("{} MiB", blocks / (1024 * 1024 / BLOCK_SIZE as u32)),
+        Err(e) => error!("Reading the card size failed: {e:?}"),
+    }
+
+    let mut block = [0_u8; BLOCK_SIZE];
+    match card.read_block(0, &mut block) {
+        Ok(()) if block[BLOCK_SIZE - 2..] == [0x55, 0xaa] => {
+            for (index, entry) in block[PARTITION_TABLE..BLOCK_SIZE - 2]
+                .chunks(PARTITION_ENTRY_SIZE)
+                .enumerate()
+            {
+                let kind = entry[4];
+                let start = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+                let blocks = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+                if kind != 0 {
+                    info!("Partition {index}: type {kind:02x}, block {start}, {blocks} blocks");
+                }
+            }
+        }
+        Ok(()) => info!("No partition table"),
+        Err(e) => error!("Reading block 0 failed: {e:?}"),
+    }
+
+    loop {}
+}
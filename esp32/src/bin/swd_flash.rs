@@ -0,0 +1,83 @@
+//! Flash the rp2040 from the esp32 over the on-board SWD wiring
+//!
+//! The rp2040 image is embedded into this program at build time from the
+//! path in the `RP2040_IMAGE` environment variable, either a UF2 or an ELF
+//! file. On start up the esp32 selects the internal SWD connection, halts
+//! the rp2040, programs and verifies the image and resets the rp2040 so it
+//! boots the new firmware.
+#![no_std]
+#![no_main]
+
+use esp32_hal::{
+    clock::{ClockControl, CpuClock},
+    prelude::*,
+    timer::TimerGroup,
+    Delay, Rtc,
+};
+use esp_backtrace as _;
+use log::{error, info};
+use udoo_esp32::{crash, logger};
+use udoo_key_bsp::Board;
+
+use udoo_core::flasher::Flasher;
+use udoo_core::image::Image;
+use udoo_core::rp_control::RpControl;
+use udoo_core::swd::Swd;
+
+static RP2040_IMAGE: &[u8] = include_bytes!(env!("RP2040_IMAGE"));
+
+#[entry]
+fn main() -> ! {
+    logger::init(log::LevelFilter::Info);
+    crash::report();
+
+    let Board {
+        swd: mut swd_pins,
+        rp_reset,
+        peripherals,
+        ..
+    } = Board::take();
+    let mut system = peripherals.DPORT.split();
+    let clocks = ClockControl::configure(system.clock_control, CpuClock::Clock240MHz).freeze();
+
+    let timer_group0 = TimerGroup::new(
+        peripherals.TIMG0,
+        &clocks,
+        &mut system.peripheral_clock_control,
+    );
+    let mut wdt = timer_group0.wdt;
+    let mut rtc = Rtc::new(peripherals.RTC_CNTL);
+
+    // Disable MWDT and RWDT (Watchdog) flash boot protection
+    wdt.disable();
+    rtc.rwdt.disable();
+
+    // Route the rp2040 SWD port to the esp32 instead of the external header
+    swd_pins.select_esp32();
+    let mut rp_control = RpControl::new(rp_reset, Delay::new(&clocks));
+
+    let image = match Image::parse(RP2040_IMAGE) {
+        Ok(image) => image,
+        Err(e) => {
+            error!("Invalid rp2040 image: {e:?}");
+            loop {}
+        }
+    };
+    info!("Flashing {} bytes to the rp2040", RP2040_IMAGE.len());
+
+    let mut swd = Swd::new(swd_pins.swdio, swd_pins.swclk, Delay::new(&clocks));
+    let result = Flasher::new(&mut swd).and_then(|mut flasher| {
+        flasher.program(&image)?;
+        flasher.release()
+    });
+
+    match result {
+        Ok(()) => info!("rp2040 programmed and verified"),
+        Err(e) => error!("Flashing the rp2040 failed: {e:?}"),
+    }
+
+    // Reset the rp2040 to boot the new firmware
+    rp_control.reset_rp2040();
+
+    loop {}
+}
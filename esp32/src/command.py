@@ -10,6 +10,8 @@ class Command(Enum):
     REQUEST_ROM_LIST = 0x1
     # Request a specific rom by id
     REQUEST_ROM = 0x2
+    # Request a packed firmware image by target (0 rp2040, 1 esp32)
+    REQUEST_FIRMWARE = 0x3
 
 
 def command_from_int(value: int) -> Optional[Command]:
@@ -19,4 +21,6 @@ def command_from_int(value: int) -> Optional[Command]:
         command = Command.REQUEST_ROM_LIST
     elif value == Command.REQUEST_ROM.value:
         command = Command.REQUEST_ROM
+    elif value == Command.REQUEST_FIRMWARE.value:
+        command = Command.REQUEST_FIRMWARE
     return command
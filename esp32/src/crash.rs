@@ -0,0 +1,75 @@
+//! Crash reports of the esp32
+//!
+//! The panic handler keeps the message, the backtrace and the uptime in
+//! RTC fast memory, which keeps its contents over a reset, and restarts
+//! the chip. [`report`] logs the crash on the next boot, and again once
+//! the program picked the console or the network for its log, so it
+//! reaches them like the rest of the log. Crashes in a row are counted
+//! until the program calls [`healthy`].
+
+use core::cell::Cell;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+
+use critical_section::Mutex;
+use esp32_hal::{macros::ram, reset::software_reset};
+use esp_println::println;
+use log::{error, warn};
+use udoo_core::crash::{Boot, CrashArea, CrashRecord, BACKTRACE_SIZE};
+
+use crate::logger;
+
+#[ram(rtc_fast, uninitialized)]
+static mut CRASH: CrashArea = CrashArea::new();
+
+/// What the first [`report`] took from the crash area
+static BOOT: Mutex<Cell<Option<Boot>>> = Mutex::new(Cell::new(None));
+
+fn with_area<R>(f: impl FnOnce(&mut CrashArea) -> R) -> R {
+    // Safety: the area is only touched inside a critical section
+    critical_section::with(|_| f(unsafe { &mut *addr_of_mut!(CRASH) }))
+}
+
+/// Log the crash of the last run, call it after `logger::init` and again
+/// once the program chose where its log goes, as the records printed to
+/// USB are not sent on to the next sink
+pub fn report() {
+    let boot = match critical_section::with(|cs| BOOT.borrow(cs).get()) {
+        Some(boot) => boot,
+        None => {
+            let boot = with_area(CrashArea::boot);
+            critical_section::with(|cs| BOOT.borrow(cs).set(Some(boot)));
+            boot
+        }
+    };
+    if let Some(crash) = boot.crash {
+        error!("The last run crashed: {crash}");
+    }
+    if boot.failures > 0 {
+        warn!("{} crashes since the last healthy start", boot.failures);
+    }
+}
+
+/// The program works, the crash counter starts over
+pub fn healthy() {
+    with_area(CrashArea::healthy);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut backtrace = [0_u32; BACKTRACE_SIZE + 1];
+    for (address, found) in backtrace.iter_mut().zip(esp_backtrace::arch::backtrace()) {
+        *address = found.unwrap_or(0) as u32;
+    }
+    // The innermost return address stands in for the pc
+    let record = CrashRecord::new(
+        logger::uptime_ms(),
+        backtrace[0],
+        &backtrace[1..],
+        format_args!("{info}"),
+    );
+    with_area(|area| area.record(record));
+    println!("{record}");
+    software_reset();
+    loop {}
+}
@@ -0,0 +1,129 @@
+//! Server addresses with names, looked up at the DNS servers of the lease
+//!
+//! The `ADDRESS` build variables take `host:port`, where the host is an
+//! IPv4 address or a name, see `udoo_core::dns::Address`.
+
+use core::fmt;
+
+use embedded_svc::ipv4::Interface;
+use esp_wifi::current_millis;
+use esp_wifi::wifi_interface::WifiStack;
+use log::{error, info, warn};
+use smoltcp::socket::udp::PacketMetadata;
+use smoltcp::wire::{IpAddress, Ipv4Address};
+use udoo_core::dns::{self, Address, AddressError, Host, MAX_MESSAGE};
+
+/// Questions sent to each server before giving up
+const ATTEMPTS: usize = 3;
+/// Time to wait for an answer before asking again
+const TIMEOUT_MS: u64 = 1000;
+/// First of the ports the questions are sent from, each lookup takes
+/// one of its own so late answers to an earlier one do not reach it
+const FIRST_LOCAL_PORT: u16 = 50000;
+/// Number of ports after [`FIRST_LOCAL_PORT`]
+const LOCAL_PORTS: u16 = 10000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    Address(AddressError),
+    /// The lease names no DNS server
+    NoServer,
+    /// No DNS server answered
+    Timeout,
+    Dns(dns::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address(e) => write!(f, "{e}"),
+            Self::NoServer => write!(f, "the network has no DNS server to look the name up"),
+            Self::Timeout => write!(f, "no DNS server answered"),
+            Self::Dns(dns::Error::NotFound) => write!(f, "the name has no IPv4 address"),
+            Self::Dns(dns::Error::TooLarge) => write!(f, "the name is too long"),
+            Self::Dns(dns::Error::Malformed) => write!(f, "the DNS server sent a bad answer"),
+            Self::Dns(dns::Error::Server(code)) => {
+                write!(f, "the DNS server failed with code {code}")
+            }
+        }
+    }
+}
+
+/// The IP address and port of a `host:port` server address
+pub fn resolve(stack: &WifiStack<'_>, address: &str) -> Result<(IpAddress, u16), Error> {
+    let address = Address::parse(address).map_err(Error::Address)?;
+    let ip = match address.host {
+        Host::Ipv4(ip) => ip,
+        Host::Name(name) => {
+            let ip = look_up(stack, name)?;
+            info!("{name} is {}", Ipv4Address(ip));
+            ip
+        }
+    };
+    Ok((IpAddress::Ipv4(Ipv4Address(ip)), address.port))
+}
+
+/// [`resolve`] the address of a build variable if it is set, logs why it
+/// cannot be used
+pub fn resolve_variable(
+    stack: &WifiStack<'_>,
+    variable: &str,
+    address: Option<&str>,
+) -> Option<(IpAddress, u16)> {
+    let address = address?;
+    resolve(stack, address)
+        .map_err(|e| error!("{variable}={address} cannot be used: {e}"))
+        .ok()
+}
+
+/// Ask the DNS servers of the lease for the address of `name`, each in
+/// turn until one answers
+fn look_up(stack: &WifiStack<'_>, name: &str) -> Result<[u8; 4], Error> {
+    let info = stack.get_ip_info().map_err(|_| Error::NoServer)?;
+    if info.dns.is_none() && info.secondary_dns.is_none() {
+        return Err(Error::NoServer);
+    }
+    let id = current_millis() as u16;
+    let mut query = [0u8; MAX_MESSAGE];
+    let query_len = dns::query(id, name, &mut query).map_err(Error::Dns)?;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; MAX_MESSAGE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0u8; MAX_MESSAGE];
+    let mut socket =
+        stack.get_udp_socket(&mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = socket.bind(FIRST_LOCAL_PORT + id % LOCAL_PORTS) {
+        warn!("Binding the DNS port failed: {e:?}");
+    }
+    let mut packet = [0u8; MAX_MESSAGE];
+    for _ in 0..ATTEMPTS {
+        for server in [info.dns, info.secondary_dns].into_iter().flatten() {
+            let server = IpAddress::Ipv4(Ipv4Address(server.octets()));
+            socket.work();
+            if let Err(e) = socket.send(server, dns::PORT, &query[..query_len]) {
+                warn!("Asking {server} for {name} failed: {e:?}");
+                continue;
+            }
+            let end = current_millis() + TIMEOUT_MS;
+            while current_millis() < end {
+                socket.work();
+                let Ok((len, from, port)) = socket.receive(&mut packet) else {
+                    continue;
+                };
+                // Anyone on the network can send to the port
+                if IpAddress::Ipv4(Ipv4Address(from)) != server || port != dns::PORT {
+                    continue;
+                }
+                match dns::parse_response(&packet[..len], id) {
+                    Ok(Some(ip)) => return Ok(ip),
+                    Ok(None) => {}
+                    Err(dns::Error::Malformed) => warn!("{server} sent a bad answer for {name}"),
+                    // The server knows the name has no address
+                    Err(e) => return Err(Error::Dns(e)),
+                }
+            }
+        }
+    }
+    Err(Error::Timeout)
+}
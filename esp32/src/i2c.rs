@@ -0,0 +1,24 @@
+//! The I2C bus on the UEXT connector
+//!
+//! The bus is bit-banged on Gpio21 (SCL) and Gpio18 (SDA) by
+//! `udoo_core::i2c::SoftI2c`, which follows clock stretching and frees a
+//! stuck bus. Drivers get their handle from [`Bus::acquire`], and a
+//! reference to the bus adds the `i2c scan` and `i2c recover` commands to
+//! a console shell.
+
+use esp32_hal::{
+    clock::Clocks,
+    gpio::{Gpio18, Gpio21, OpenDrain, Output},
+    Delay,
+};
+use udoo_core::i2c::SoftI2c;
+use udoo_core::i2c_bus::I2cBus;
+use udoo_key_bsp::UextI2c;
+
+pub type Bus = I2cBus<SoftI2c<Gpio21<Output<OpenDrain>>, Gpio18<Output<OpenDrain>>, Delay>>;
+
+/// Set up the bus at 100 kHz
+pub fn bus(pins: UextI2c, clocks: &Clocks) -> Bus {
+    let (scl, sda) = pins.into_open_drain();
+    I2cBus::new(SoftI2c::new(scl, sda, Delay::new(clocks)))
+}
@@ -0,0 +1,16 @@
+//! Code shared between the esp32 programs
+//!
+//! Everything that does not need the esp32 itself is in `udoo-core`.
+#![no_std]
+
+pub mod crash;
+pub mod dns;
+pub mod i2c;
+pub mod logger;
+pub mod mdns;
+pub mod mqtt;
+pub mod setup;
+pub mod status;
+pub mod tftp;
+pub mod web;
+pub mod wifi;
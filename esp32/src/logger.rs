@@ -0,0 +1,198 @@
+//! `log` backend of the esp32 programs
+//!
+//! [`init`] installs it, after that the `log` macros write timestamped
+//! records to the selected [`Sink`], the USB serial port to start with.
+//! The UEXT serial port and the network connection belong to the program,
+//! so records for them wait in the history until the program passes the
+//! port or connection to [`send`]. The `log` console [`Commands`] change
+//! the level and the sink at runtime.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+
+use critical_section::Mutex;
+use embedded_io::blocking::Write as _;
+use esp32_hal::peripherals::RTC_CNTL;
+use esp_println::Printer;
+use esp_wifi::wifi_interface::Socket;
+use log::{LevelFilter, Log, Metadata, Record};
+use udoo_core::logger::{write_record, History, Sink};
+use udoo_core::shell::{self, Args, Command};
+
+/// Bytes of records kept for `log show` and for sinks that are not
+/// connected
+const HISTORY_SIZE: usize = 4096;
+
+/// The RTC slow clock runs from the 150 kHz RC oscillator, timestamps
+/// are off by a few percent
+const SLOW_CLOCK_HZ: u64 = 150_000;
+
+/// Bytes copied out of the history at a time, records are written to the
+/// sinks outside of the critical section
+const CHUNK_SIZE: usize = 128;
+
+struct State {
+    sink: Sink,
+    history: History<HISTORY_SIZE>,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    sink: Sink::Usb,
+    history: History::new(),
+}));
+
+static LOGGER: Logger = Logger;
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = uptime_ms();
+        critical_section::with(|cs| {
+            let mut state = STATE.borrow_ref_mut(cs);
+            let (level, target, args) = (record.level(), record.target(), *record.args());
+            _ = write_record(&mut state.history, now, level, target, args);
+            match state.sink {
+                Sink::Usb => {
+                    _ = write_record(&mut Printer, now, level, target, args);
+                    state.history.mark_sent();
+                }
+                Sink::Memory => state.history.mark_sent(),
+                Sink::Uext | Sink::Network => {}
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger, records above `level` are dropped
+pub fn init(level: LevelFilter) {
+    _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
+
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Send the next records to `sink`, the ones that were not sent anywhere
+/// yet go along
+pub fn set_sink(sink: Sink) {
+    critical_section::with(|cs| STATE.borrow_ref_mut(cs).sink = sink);
+}
+
+pub fn sink() -> Sink {
+    critical_section::with(|cs| STATE.borrow_ref(cs).sink)
+}
+
+/// Write the records that wait for `sink` to `out`, does nothing when
+/// another sink is selected
+pub fn send(sink: Sink, out: &mut dyn Write) -> fmt::Result {
+    let mut chunk = [0; CHUNK_SIZE];
+    loop {
+        let len = critical_section::with(|cs| {
+            let mut state = STATE.borrow_ref_mut(cs);
+            if state.sink == sink {
+                state.history.take_unsent(&mut chunk)
+            } else {
+                0
+            }
+        });
+        if len == 0 {
+            return Ok(());
+        }
+        // The history only holds ascii
+        out.write_str(core::str::from_utf8(&chunk[..len]).unwrap_or_default())?;
+    }
+}
+
+/// Milliseconds since the chip started, from the RTC timer
+pub fn uptime_ms() -> u64 {
+    // Safety: the timer registers are only read, and the update bit only
+    // latches the counter
+    let rtc = unsafe { &*RTC_CNTL::PTR };
+    rtc.time_update.write(|w| w.time_update().set_bit());
+    while rtc.time_update.read().time_valid().bit_is_clear() {}
+    let ticks =
+        (rtc.time1.read().time_hi().bits() as u64) << 32 | rtc.time0.read().time_lo().bits() as u64;
+    ticks * 1000 / SLOW_CLOCK_HZ
+}
+
+/// A TCP connection to a log server as a sink, for [`send`]
+pub struct Connection<'s, 'a>(pub &'s mut Socket<'a, 'a>);
+
+impl<'s, 'a> Write for Connection<'s, 'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.work();
+        self.0.write_all(s.as_bytes()).map_err(|_| fmt::Error)?;
+        self.0.flush().map_err(|_| fmt::Error)
+    }
+}
+
+/// The `log` console commands
+pub struct Commands;
+
+impl shell::Commands for Commands {
+    fn commands(&self) -> &'static [Command] {
+        &[
+            Command {
+                name: "log level",
+                args: "<level>",
+                help: "Drop records above off, error, warn, info, debug or trace",
+            },
+            Command {
+                name: "log sink",
+                args: "<sink>",
+                help: "Send records to usb, uext, memory or network",
+            },
+            Command {
+                name: "log show",
+                args: "",
+                help: "Show the last records",
+            },
+        ]
+    }
+
+    fn run(
+        &mut self,
+        name: &str,
+        mut args: Args<'_>,
+        out: &mut dyn Write,
+    ) -> Result<(), shell::Error> {
+        match name {
+            "log level" => {
+                let level: LevelFilter = args.parse()?;
+                set_level(level);
+                _ = write!(out, "log level {level}\r\n");
+            }
+            "log sink" => {
+                let sink: Sink = args.parse()?;
+                set_sink(sink);
+                _ = write!(out, "logging to {sink}\r\n");
+            }
+            _ => {
+                let mut chunk = [0; CHUNK_SIZE];
+                let mut offset = 0;
+                loop {
+                    let len = critical_section::with(|cs| {
+                        STATE.borrow_ref(cs).history.copy(offset, &mut chunk)
+                    });
+                    if len == 0 {
+                        break;
+                    }
+                    _ = out.write_str(core::str::from_utf8(&chunk[..len]).unwrap_or_default());
+                    offset += len;
+                }
+            }
+        }
+        Ok(())
+    }
+}
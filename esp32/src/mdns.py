@@ -0,0 +1,134 @@
+"""
+Announces a rom server on the LAN with multicast DNS
+
+The esp32 finds a rom server by asking for the instances of
+_chip8rom._tcp.local when it is built without ADDRESS. The announcer
+answers with the address and port of the server and, in a TXT record,
+the protocol it speaks: "legacy" for rom_server.py and "http" for
+rom_web_server.py. It also announces the server when it starts.
+"""
+import socket
+import struct
+import threading
+from typing import Optional, Tuple
+
+GROUP = "224.0.0.251"
+PORT = 5353
+SERVICE = "_chip8rom._tcp.local"
+TTL = 120
+
+TYPE_A = 1
+TYPE_PTR = 12
+TYPE_TXT = 16
+TYPE_SRV = 33
+TYPE_ANY = 255
+CLASS_IN = 1
+CACHE_FLUSH = 0x8000
+
+
+def encode_name(name: str) -> bytes:
+    """A dotted name as labels, without compression"""
+    encoded = b""
+    for label in name.split("."):
+        encoded += bytes([len(label.encode())]) + label.encode()
+    return encoded + b"\0"
+
+
+def read_name(packet: bytes, offset: int) -> Tuple[str, int]:
+    """The name at offset and where it ends, following compression"""
+    labels = []
+    end = None
+    for _ in range(64):
+        length = packet[offset]
+        if length == 0:
+            return ".".join(labels), end if end is not None else offset + 1
+        if length >= 0xC0:
+            if end is None:
+                end = offset + 2
+            offset = struct.unpack_from("!H", packet, offset)[0] & 0x3FFF
+        else:
+            labels.append(packet[offset + 1 : offset + 1 + length].decode())
+            offset += 1 + length
+    raise ValueError("name loops")
+
+
+def record(name: str, kind: int, data: bytes, flush: bool = False) -> bytes:
+    """A resource record"""
+    klass = CLASS_IN | (CACHE_FLUSH if flush else 0)
+    return encode_name(name) + struct.pack("!HHIH", kind, klass, TTL, len(data)) + data
+
+
+def local_address() -> str:
+    """The address of the interface that reaches the LAN"""
+    with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as sock:
+        sock.connect((GROUP, PORT))
+        return sock.getsockname()[0]
+
+
+class Announcer:
+    """Answers mDNS questions for the rom server service"""
+
+    def __init__(self, port: int, protocol: str, address: Optional[str] = None):
+        self.host = socket.gethostname().split(".")[0] + ".local"
+        self.instance = f"{socket.gethostname().split('.')[0]}-{protocol}.{SERVICE}"
+        self.port = port
+        self.protocol = protocol
+        self.address = address or local_address()
+
+    def response(self) -> bytes:
+        """The PTR answer with the SRV, TXT and A records of the server"""
+        txt = f"protocol={self.protocol}".encode()
+        records = [
+            record(SERVICE, TYPE_PTR, encode_name(self.instance)),
+            record(
+                self.instance,
+                TYPE_SRV,
+                struct.pack("!HHH", 0, 0, self.port) + encode_name(self.host),
+                flush=True,
+            ),
+            record(self.instance, TYPE_TXT, bytes([len(txt)]) + txt, flush=True),
+            record(self.host, TYPE_A, socket.inet_aton(self.address), flush=True),
+        ]
+        header = struct.pack("!HHHHHH", 0, 0x8400, 0, 1, 0, len(records) - 1)
+        return header + b"".join(records)
+
+    def asked(self, packet: bytes) -> bool:
+        """The packet is a query for the service"""
+        _, flags, questions = struct.unpack_from("!HHH", packet)
+        if flags & 0x8000:
+            return False
+        offset = 12
+        for _ in range(questions):
+            name, offset = read_name(packet, offset)
+            kind = struct.unpack_from("!H", packet, offset)[0]
+            offset += 4
+            if name.lower() == SERVICE and kind in (TYPE_PTR, TYPE_ANY):
+                return True
+        return False
+
+    def start(self) -> None:
+        """Announce the server, then answer questions for it in the
+        background"""
+        sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        sock.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, 1)
+        if hasattr(socket, "SO_REUSEPORT"):
+            sock.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEPORT, 1)
+        sock.bind(("", PORT))
+        membership = socket.inet_aton(GROUP) + socket.inet_aton(self.address)
+        sock.setsockopt(socket.IPPROTO_IP, socket.IP_ADD_MEMBERSHIP, membership)
+        sock.setsockopt(
+            socket.IPPROTO_IP, socket.IP_MULTICAST_IF, socket.inet_aton(self.address)
+        )
+        print(f"Announcing {self.instance} at {self.address}:{self.port}")
+        sock.sendto(self.response(), (GROUP, PORT))
+        threading.Thread(target=self.answer, args=(sock,), daemon=True).start()
+
+    def answer(self, sock: socket.socket) -> None:
+        """Answer the questions for the service"""
+        while True:
+            packet, _ = sock.recvfrom(9000)
+            try:
+                if self.asked(packet):
+                    sock.sendto(self.response(), (GROUP, PORT))
+            except (IndexError, struct.error, UnicodeDecodeError, ValueError):
+                pass
@@ -0,0 +1,136 @@
+//! mDNS on the LAN: finding a rom server and answering for the board
+//!
+//! The board answers for `udoo-key-<mac>.local`, with the mac address of
+//! the station in hex, and announces that name when it joins. Rom
+//! servers announce themselves as `_chip8rom._tcp.local`, see
+//! `udoo_core::mdns`.
+
+use core::fmt::Write as _;
+
+use esp_wifi::current_millis;
+use esp_wifi::wifi_interface::UdpSocket;
+use heapless::String;
+use log::{info, warn};
+use smoltcp::wire::{IpAddress, Ipv4Address};
+use udoo_core::mdns::{self, Host, MAX_MESSAGE};
+use udoo_core::rom::Protocol;
+
+/// `udoo-key-` and 12 hex digits
+const NAME_SIZE: usize = 21;
+/// Time between questions while looking for a rom server
+const QUERY_INTERVAL_MS: u64 = 1000;
+
+pub struct Mdns<'s> {
+    socket: UdpSocket<'s, 's>,
+    name: String<NAME_SIZE>,
+    address: [u8; 4],
+}
+
+impl<'s> Mdns<'s> {
+    /// Join the mDNS group on the address of the station and announce
+    /// the name of the board
+    pub fn new(mut socket: UdpSocket<'s, 's>, mac: [u8; 6], address: [u8; 4]) -> Self {
+        let mut name = String::new();
+        _ = name.push_str("udoo-key-");
+        for byte in mac {
+            _ = write!(name, "{byte:02x}");
+        }
+        if let Err(e) = socket.bind(mdns::PORT) {
+            warn!("Binding the mDNS port failed: {e:?}");
+        }
+        if let Err(e) = socket.join_multicast_group(group()) {
+            warn!("Joining the mDNS group failed: {e:?}");
+        }
+        let mut mdns = Self {
+            socket,
+            name,
+            address,
+        };
+        mdns.send_announcement();
+        info!("Answering mDNS as {}.local", mdns.name);
+        mdns
+    }
+
+    /// `udoo-key-<mac>`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Answer the questions for the name of the board, call it from the
+    /// main loop
+    pub fn poll(&mut self) {
+        let mut packet = [0_u8; MAX_MESSAGE];
+        self.socket.work();
+        while let Ok((len, _, _)) = self.socket.receive(&mut packet) {
+            self.answer(&packet[..len]);
+        }
+    }
+
+    /// Ask for a rom server until one answers or `timeout_ms` has passed,
+    /// returns its address and the protocol it speaks
+    pub fn find_rom_server(&mut self, timeout_ms: u64) -> Option<((IpAddress, u16), Protocol)> {
+        let mut query = [0_u8; MAX_MESSAGE];
+        let query_len = mdns::query(mdns::ROM_SERVICE, &mut query).ok()?;
+        let mut packet = [0_u8; MAX_MESSAGE];
+        let end = current_millis() + timeout_ms;
+        let mut next_query = 0;
+        while current_millis() < end {
+            if current_millis() >= next_query {
+                self.send(&query[..query_len]);
+                next_query = current_millis() + QUERY_INTERVAL_MS;
+            }
+            self.socket.work();
+            let Ok((len, _, _)) = self.socket.receive(&mut packet) else {
+                continue;
+            };
+            let packet = &packet[..len];
+            self.answer(packet);
+            match mdns::find_service(packet, mdns::ROM_SERVICE) {
+                Ok(Some(service)) => {
+                    let protocol = service
+                        .txt("protocol")
+                        .and_then(|protocol| protocol.parse().ok())
+                        .unwrap_or(Protocol::Legacy);
+                    let ip = IpAddress::Ipv4(Ipv4Address(service.address));
+                    info!("Found a {protocol} rom server at {ip}:{}", service.port);
+                    return Some(((ip, service.port), protocol));
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Bad mDNS response: {e:?}"),
+            }
+        }
+        None
+    }
+
+    fn host(&self) -> Host<'_> {
+        Host {
+            name: &self.name,
+            address: self.address,
+        }
+    }
+
+    fn answer(&mut self, packet: &[u8]) {
+        let mut response = [0_u8; MAX_MESSAGE];
+        if let Ok(Some(len)) = mdns::answer(packet, &self.host(), &mut response) {
+            self.send(&response[..len]);
+        }
+    }
+
+    fn send_announcement(&mut self) {
+        let mut response = [0_u8; MAX_MESSAGE];
+        if let Ok(len) = mdns::announce(&self.host(), &mut response) {
+            self.send(&response[..len]);
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) {
+        self.socket.work();
+        if let Err(e) = self.socket.send(group(), mdns::PORT, message) {
+            warn!("Sending an mDNS message failed: {e:?}");
+        }
+    }
+}
+
+fn group() -> IpAddress {
+    IpAddress::Ipv4(Ipv4Address(mdns::GROUP))
+}
@@ -0,0 +1,368 @@
+//! Telemetry and remote commands over MQTT, see `udoo_core::mqtt`
+//!
+//! The board connects to the broker as `udoo-key-<mac>` and publishes
+//! below that name:
+//!
+//! - `<name>/online`: `online`, and `offline` from the broker once the
+//!   board is gone, retained
+//! - `<name>/heartbeat`: the uptime in ms
+//! - `<name>/rom`: id and size of the running rom as JSON, retained
+//! - `<name>/rp2040`: `answering`, `silent` or `held in reset`, retained
+//! - `<name>/rssi`: the signal strength in dBm
+//!
+//! Console commands published to `<name>/command`, like `rom load 3`,
+//! `rp reset` or `led yellow breathing`, are run by the caller and what
+//! they print goes to `<name>/response`.
+//!
+//! Reading the socket waits for data, so the client pings the broker
+//! every [`POLL_INTERVAL_MS`] and takes the commands that arrive before
+//! the answer.
+
+use core::fmt::{self, Write as _};
+
+use embedded_io::blocking::{Read, Write};
+use esp_wifi::current_millis;
+use esp_wifi::wifi_interface::Socket;
+use heapless::String;
+use log::{info, warn};
+use smoltcp::wire::IpAddress;
+use udoo_core::mqtt::{self, Connect, Packet, MAX_PACKET, SUBSCRIPTION_FAILED};
+use udoo_core::shell::LINE_SIZE;
+
+/// Time between pings, a command waits for the next one
+pub const POLL_INTERVAL_MS: u64 = 1000;
+/// Time between telemetry messages
+pub const PUBLISH_INTERVAL_MS: u64 = 10 * 1000;
+/// Longest response to a command, the rest is cut off
+pub const RESPONSE_SIZE: usize = 384;
+/// Time before connecting again to a broker that went away
+const RETRY_MS: u64 = 30 * 1000;
+/// Seconds, the pings stay well inside it
+const KEEP_ALIVE_S: u16 = 60;
+/// Packet id of the subscription to the command topic
+const SUBSCRIPTION_ID: u16 = 1;
+/// `udoo-key-` and 12 hex digits
+const NAME_SIZE: usize = 21;
+/// The name and the longest topic below it
+const TOPIC_SIZE: usize = NAME_SIZE + 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The broker cannot be reached or went away
+    Io,
+    /// The broker refused the client with this return code
+    Refused(u8),
+    /// The broker refused the subscription to the command topic
+    Subscription,
+    Mqtt(mqtt::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io => write!(f, "the broker cannot be reached"),
+            Self::Refused(code) => write!(f, "the broker refused the client with code {code}"),
+            Self::Subscription => write!(f, "the broker refused the command topic"),
+            Self::Mqtt(mqtt::Error::TooLarge) => write!(f, "a packet is too large"),
+            Self::Mqtt(mqtt::Error::Malformed) => write!(f, "the broker sent a bad packet"),
+        }
+    }
+}
+
+impl From<mqtt::Error> for Error {
+    fn from(e: mqtt::Error) -> Self {
+        Self::Mqtt(e)
+    }
+}
+
+/// What the board publishes
+pub struct Telemetry<'a> {
+    /// Id of the rom on the rom server, `None` for an uploaded one
+    pub rom_id: Option<u16>,
+    pub rom_size: usize,
+    /// How the rp2040 is doing, like `answering`
+    pub rp2040: &'a str,
+    pub rssi: Option<i8>,
+}
+
+pub struct Client<'s> {
+    socket: Socket<'s, 's>,
+    broker: (IpAddress, u16),
+    user: Option<(&'static str, &'static str)>,
+    name: String<NAME_SIZE>,
+    connected: bool,
+    /// A ping was sent and its answer did not arrive yet
+    pinged: bool,
+    /// Packets that arrived and were not handled yet
+    input: [u8; MAX_PACKET],
+    input_len: usize,
+    retry_at: u64,
+    ping_at: u64,
+    publish_at: u64,
+}
+
+impl<'s> Client<'s> {
+    /// A client for the broker, named like the board, which connects on
+    /// the first [`Client::poll`]
+    pub fn new(
+        socket: Socket<'s, 's>,
+        broker: (IpAddress, u16),
+        name: &str,
+        user: Option<(&'static str, &'static str)>,
+    ) -> Self {
+        let mut own_name = String::new();
+        _ = own_name.push_str(name);
+        Self {
+            socket,
+            broker,
+            user,
+            name: own_name,
+            connected: false,
+            pinged: false,
+            input: [0; MAX_PACKET],
+            input_len: 0,
+            retry_at: 0,
+            ping_at: 0,
+            publish_at: 0,
+        }
+    }
+
+    /// Keep the connection, publish the telemetry and return a command
+    /// that arrived, call it from the main loop
+    ///
+    /// A broker that went away is tried again after a while.
+    pub fn poll<'t>(
+        &mut self,
+        now: u64,
+        telemetry: impl FnOnce() -> Telemetry<'t>,
+    ) -> Option<String<LINE_SIZE>> {
+        if !self.connected {
+            if now < self.retry_at {
+                return None;
+            }
+            if let Err(e) = self.connect() {
+                warn!("Connecting to the MQTT broker failed: {e}");
+                self.drop_connection(e);
+                return None;
+            }
+        }
+        match self.exchange(now, telemetry) {
+            Ok(command) => command,
+            Err(e) => {
+                warn!("Lost the MQTT broker: {e}");
+                self.drop_connection(e);
+                None
+            }
+        }
+    }
+
+    /// Publish what a command printed
+    pub fn respond(&mut self, output: &str) {
+        if !self.connected {
+            return;
+        }
+        if let Err(e) = self.publish("response", output.as_bytes(), false) {
+            warn!("Lost the MQTT broker: {e}");
+            self.drop_connection(e);
+        }
+    }
+
+    fn connect(&mut self) -> Result<(), Error> {
+        let (ip, port) = self.broker;
+        self.input_len = 0;
+        self.pinged = false;
+        self.socket.work();
+        self.socket.open(ip, port).map_err(|_| Error::Io)?;
+
+        let mut packet = [0u8; MAX_PACKET];
+        let will = self.topic("online");
+        let len = mqtt::connect(
+            &Connect {
+                client_id: &self.name,
+                keep_alive: KEEP_ALIVE_S,
+                will: Some((will.as_str(), &b"offline"[..])),
+                user: self.user,
+            },
+            &mut packet,
+        )?;
+        self.send(&packet[..len])?;
+        let len = self.next_packet()?;
+        let code = match mqtt::parse(&self.input[..len])? {
+            Some((Packet::ConnAck { code }, _)) => code,
+            _ => return Err(mqtt::Error::Malformed.into()),
+        };
+        self.consume(len);
+        if code != 0 {
+            return Err(Error::Refused(code));
+        }
+
+        let command = self.topic("command");
+        let len = mqtt::subscribe(SUBSCRIPTION_ID, &command, &mut packet)?;
+        self.send(&packet[..len])?;
+        loop {
+            let len = self.next_packet()?;
+            let granted = match mqtt::parse(&self.input[..len])? {
+                Some((
+                    Packet::SubAck {
+                        id: SUBSCRIPTION_ID,
+                        granted,
+                    },
+                    _,
+                )) => Some(granted),
+                _ => None,
+            };
+            self.consume(len);
+            match granted {
+                Some(SUBSCRIPTION_FAILED) => return Err(Error::Subscription),
+                Some(_) => break,
+                None => {}
+            }
+        }
+
+        self.publish("online", b"online", true)?;
+        self.connected = true;
+        let now = current_millis();
+        self.publish_at = now;
+        self.ping_at = now + POLL_INTERVAL_MS;
+        info!(
+            "Connected to the MQTT broker at {ip}:{port} as {}",
+            self.name
+        );
+        Ok(())
+    }
+
+    /// Publish when it is time, then ping and read up to the answer, a
+    /// command on the way is returned and the rest read on the next call
+    fn exchange<'t>(
+        &mut self,
+        now: u64,
+        telemetry: impl FnOnce() -> Telemetry<'t>,
+    ) -> Result<Option<String<LINE_SIZE>>, Error> {
+        if now >= self.publish_at {
+            self.publish_at = now + PUBLISH_INTERVAL_MS;
+            self.publish_telemetry(now, &telemetry())?;
+        }
+        if !self.pinged {
+            if now < self.ping_at {
+                return Ok(None);
+            }
+            self.ping_at = now + POLL_INTERVAL_MS;
+            self.send(&mqtt::ping())?;
+            self.pinged = true;
+        }
+        while self.pinged {
+            let len = self.next_packet()?;
+            let command = match mqtt::parse(&self.input[..len])? {
+                Some((Packet::PingResp, _)) => {
+                    self.pinged = false;
+                    None
+                }
+                Some((Packet::Publish { topic, payload }, _)) if self.is_command(topic) => {
+                    command_line(payload)
+                }
+                _ => None,
+            };
+            self.consume(len);
+            if command.is_some() {
+                return Ok(command);
+            }
+        }
+        Ok(None)
+    }
+
+    fn publish_telemetry(&mut self, now: u64, telemetry: &Telemetry<'_>) -> Result<(), Error> {
+        let mut text: String<48> = String::new();
+        _ = write!(text, "{now}");
+        self.publish("heartbeat", text.as_bytes(), false)?;
+
+        text.clear();
+        _ = match telemetry.rom_id {
+            Some(id) => write!(text, "{{\"id\": {id}, \"size\": {}}}", telemetry.rom_size),
+            None => write!(text, "{{\"id\": null, \"size\": {}}}", telemetry.rom_size),
+        };
+        self.publish("rom", text.as_bytes(), true)?;
+        self.publish("rp2040", telemetry.rp2040.as_bytes(), true)?;
+        if let Some(rssi) = telemetry.rssi {
+            text.clear();
+            _ = write!(text, "{rssi}");
+            self.publish("rssi", text.as_bytes(), false)?;
+        }
+        Ok(())
+    }
+
+    fn publish(&mut self, name: &str, payload: &[u8], retain: bool) -> Result<(), Error> {
+        let topic = self.topic(name);
+        let mut packet = [0u8; MAX_PACKET];
+        let len = mqtt::publish(&topic, payload, retain, &mut packet)?;
+        self.send(&packet[..len])
+    }
+
+    /// `<name>/<topic>`
+    fn topic(&self, topic: &str) -> String<TOPIC_SIZE> {
+        let mut full = String::new();
+        _ = write!(full, "{}/{topic}", self.name);
+        full
+    }
+
+    fn is_command(&self, topic: &str) -> bool {
+        topic.strip_prefix(self.name.as_str()) == Some("/command")
+    }
+
+    fn send(&mut self, packet: &[u8]) -> Result<(), Error> {
+        self.socket.work();
+        self.socket.write_all(packet).map_err(|_| Error::Io)?;
+        self.socket.flush().map_err(|_| Error::Io)
+    }
+
+    /// Read until a whole packet is at the start of the input, returns
+    /// its length
+    fn next_packet(&mut self) -> Result<usize, Error> {
+        loop {
+            if let Some((_, len)) = mqtt::parse(&self.input[..self.input_len])? {
+                return Ok(len);
+            }
+            let free = &mut self.input[self.input_len..];
+            if free.is_empty() {
+                return Err(mqtt::Error::TooLarge.into());
+            }
+            self.socket.work();
+            self.input_len += self.socket.read(free).map_err(|_| Error::Io)?;
+        }
+    }
+
+    /// Drop the first `len` bytes of the input
+    fn consume(&mut self, len: usize) {
+        self.input.copy_within(len..self.input_len, 0);
+        self.input_len -= len;
+    }
+
+    /// Close the connection and try again later
+    ///
+    /// A broker that is still there is told the board leaves on purpose,
+    /// so it does not publish the will.
+    fn drop_connection(&mut self, error: Error) {
+        if !matches!(error, Error::Io | Error::Refused(_)) {
+            _ = self.publish("online", b"offline", true);
+            _ = self.send(&mqtt::disconnect());
+        }
+        self.socket.disconnect();
+        self.connected = false;
+        self.retry_at = current_millis() + RETRY_MS;
+    }
+}
+
+/// The payload of a command message as a console line
+fn command_line(payload: &[u8]) -> Option<String<LINE_SIZE>> {
+    let mut line = String::new();
+    match core::str::from_utf8(payload) {
+        Ok(text) if line.push_str(text).is_ok() => Some(line),
+        Ok(_) => {
+            warn!("Dropped an MQTT command of {} bytes", payload.len());
+            None
+        }
+        Err(_) => {
+            warn!("Dropped an MQTT command that is not text");
+            None
+        }
+    }
+}
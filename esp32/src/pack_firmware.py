@@ -0,0 +1,112 @@
+"""
+Packs a firmware image for over the air updates
+
+The packed image starts with a 48 byte header holding the target chip,
+a firmware version, the payload length and the SHA-256 of the payload.
+rp2040 payloads are UF2 files, use elf2uf2-rs to convert an ELF file.
+esp32 payloads are app images made with `espflash save-image` and have
+to be signed with an Ed25519 key, the signature of the header follows
+the header.
+
+Usage:
+    python src/pack_firmware.py keygen ota_key.pem
+    python src/pack_firmware.py rp2040 2 chip8.uf2 firmware/rp2040.ukfw
+    python src/pack_firmware.py esp32 2 esp32.bin firmware/esp32.ukfw ota_key.pem
+"""
+import hashlib
+import struct
+import sys
+from typing import Optional
+
+from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+
+MAGIC = b"UKFW"
+FORMAT_VERSION = 1
+FLAG_SIGNED = 0x1
+TARGETS = {"rp2040": 0, "esp32": 1}
+ESP32_APP_MAGIC = 0xE9
+
+
+def pack(
+    target: str, version: int, payload: bytes, key: Optional[Ed25519PrivateKey] = None
+) -> bytes:
+    """Prefix the payload with an update header and the optional signature"""
+    header = struct.pack(
+        "<4sBBHII32s",
+        MAGIC,
+        FORMAT_VERSION,
+        TARGETS[target],
+        0 if key is None else FLAG_SIGNED,
+        version,
+        len(payload),
+        hashlib.sha256(payload).digest(),
+    )
+    signature = b"" if key is None else key.sign(header)
+    return header + signature + payload
+
+
+def public_key_hex(key: Ed25519PrivateKey) -> str:
+    """The public key as expected in OTA_PUBLIC_KEY"""
+    raw = key.public_key().public_bytes(
+        serialization.Encoding.Raw, serialization.PublicFormat.Raw
+    )
+    return raw.hex()
+
+
+def keygen(path: str) -> None:
+    """Write a new signing key and print its public key"""
+    key = Ed25519PrivateKey.generate()
+    with open(path, "wb") as file:
+        file.write(
+            key.private_bytes(
+                serialization.Encoding.PEM,
+                serialization.PrivateFormat.PKCS8,
+                serialization.NoEncryption(),
+            )
+        )
+    print(public_key_hex(key))
+
+
+def load_key(path: str) -> Ed25519PrivateKey:
+    """Read a signing key written by keygen"""
+    with open(path, "rb") as file:
+        key = serialization.load_pem_private_key(file.read(), password=None)
+    if not isinstance(key, Ed25519PrivateKey):
+        print("error: the signing key has to be an Ed25519 key")
+        sys.exit(1)
+    return key
+
+
+def main():
+    """Pack the input file into the output file"""
+    if len(sys.argv) == 3 and sys.argv[1] == "keygen":
+        keygen(sys.argv[2])
+        return
+    if len(sys.argv) not in (5, 6) or sys.argv[1] not in TARGETS:
+        print(
+            "error: expects pack_firmware.py rp2040|esp32 version input output [key]"
+            " or pack_firmware.py keygen key"
+        )
+        sys.exit(1)
+
+    target, version, input_path, output_path = sys.argv[1:5]
+    key = load_key(sys.argv[5]) if len(sys.argv) == 6 else None
+    with open(input_path, "rb") as file:
+        payload = file.read()
+    if target == "rp2040" and not payload.startswith(b"UF2\n"):
+        print("error: rp2040 images have to be UF2 files")
+        sys.exit(1)
+    if target == "esp32":
+        if not payload or payload[0] != ESP32_APP_MAGIC:
+            print("error: esp32 images have to be app images from espflash save-image")
+            sys.exit(1)
+        if key is None:
+            print("error: esp32 images have to be signed")
+            sys.exit(1)
+    with open(output_path, "wb") as file:
+        file.write(pack(target, int(version), payload, key))
+
+
+if __name__ == "__main__":
+    main()
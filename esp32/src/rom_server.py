@@ -1,27 +1,39 @@
 """
 This is a socket server that serves chip8 roms
 
-The socket server accepts two commands, REQUEST_ROM_LIST (0x1) and
-REQUEST_ROM (0x2).
+The socket server accepts three commands, REQUEST_ROM_LIST (0x1),
+REQUEST_ROM (0x2) and REQUEST_FIRMWARE (0x3). Firmware images are packed
+with pack_firmware.py and served from an optional firmware directory as
+rp2040.ukfw and esp32.ukfw.
+
+The server announces itself on the LAN with mDNS as a
+_chip8rom._tcp.local service, so an esp32 built without ADDRESS finds it.
 
 Usage:
     python src/rom_server.py localhost:5000 roms
     python src/rom_server.py :4321 roms
+    python src/rom_server.py :4321 roms firmware
 """
 import os
 import socket
 import sys
 from typing import Optional, Tuple
 from command import Command, command_from_int
+from mdns import Announcer
+
+FIRMWARE_FILES = {0: "rp2040.ukfw", 1: "esp32.ukfw"}
 
 
 class RomServer:
     """Simple socket server for serving up chip8 roms"""
 
-    def __init__(self, host: str, port: int, directory: str):
+    def __init__(
+        self, host: str, port: int, directory: str, firmware: Optional[str] = None
+    ):
         self.host = host
         self.port = port
         self.rom_directory = directory
+        self.firmware_directory = firmware
         self.roms = list(enumerate(os.listdir(self.rom_directory)))
 
     def receive_command(self, conn) -> Tuple[Optional[Command], Optional[int]]:
@@ -38,6 +50,8 @@ class RomServer:
                     command_arg = (command, None)
                 case Command.REQUEST_ROM:
                     command_arg = (command, rom_id)
+                case Command.REQUEST_FIRMWARE:
+                    command_arg = (command, rom_id)
         return command_arg
 
     def respond(self, conn, command: Command, arg: Optional[int]) -> None:
@@ -66,6 +80,16 @@ class RomServer:
                         length = len(rom_bytes).to_bytes(2, "big")
                         conn.sendall(length)
                         conn.sendall(rom_bytes)
+                case Command.REQUEST_FIRMWARE:
+                    print("Requesting firmware")
+                    name = FIRMWARE_FILES.get(arg)
+                    if self.firmware_directory is None or name is None:
+                        return
+                    path = os.path.join(self.firmware_directory, name)
+                    if os.path.exists(path):
+                        with open(path, "rb") as file:
+                            # the packed header carries the length
+                            conn.sendall(file.read())
 
     def run(self) -> None:
         """start the socket server"""
@@ -84,6 +108,14 @@ class RomServer:
                     self.respond(conn, command[0], argument)
 
 
+def announce(port: int, protocol: str) -> None:
+    """Announce the server with mDNS if the LAN can be reached"""
+    try:
+        Announcer(port, protocol).start()
+    except OSError as error:
+        print(f"Not announcing the server: {error}")
+
+
 def main():
     """Simple program to serve up chip8 roms over a socket"""
     host, port = None, None
@@ -96,8 +128,10 @@ def main():
 
     host, port = sys.argv[1].split(":")
     rom_dir = sys.argv[2]
+    firmware_dir = sys.argv[3] if len(sys.argv) > 3 else None
 
-    server = RomServer(host, int(port), rom_dir)
+    server = RomServer(host, int(port), rom_dir, firmware_dir)
+    announce(int(port), "legacy")
     server.run()
 
 
@@ -0,0 +1,74 @@
+"""
+This is a web server that serves chip8 roms as a rom catalogue
+
+GET /roms answers with the list of roms as JSON and GET /roms/<id> with
+the rom itself. The ids are the same rom_server.py hands out for the
+directory, so both servers can run side by side. Any web server that
+serves the same paths works as well.
+
+Like rom_server.py the server announces itself with mDNS, with "http"
+as its protocol.
+
+Usage:
+    python src/rom_web_server.py :8000 roms
+    python src/rom_web_server.py localhost:8000 roms
+"""
+import json
+import os
+import sys
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+from rom_server import announce
+
+
+class RomHandler(BaseHTTPRequestHandler):
+    """Answers the requests of the esp32"""
+
+    rom_directory = "."
+    roms: list = []
+
+    def do_GET(self) -> None:  # pylint: disable=invalid-name
+        """Serves the rom list or one rom"""
+        if self.path == "/roms":
+            entries = [{"id": rom_id, "name": name} for rom_id, name in self.roms]
+            self.respond("application/json", json.dumps(entries).encode("utf-8"))
+            return
+        prefix, _, rom_id = self.path.rpartition("/")
+        if prefix == "/roms" and rom_id.isdigit() and int(rom_id) < len(self.roms):
+            name = self.roms[int(rom_id)][1]
+            with open(os.path.join(self.rom_directory, name), "rb") as file:
+                self.respond("application/octet-stream", file.read())
+            return
+        self.send_error(404)
+
+    def respond(self, content_type: str, body: bytes) -> None:
+        """Sends a complete response with its length"""
+        self.send_response(200)
+        self.send_header("Content-Type", content_type)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+
+def main():
+    """Simple program to serve up chip8 roms over HTTP"""
+    try:
+        sys.argv[2]
+    except IndexError:
+        print("error: expects rom_web_server.py host:port directory")
+        sys.exit(1)
+
+    host, port = sys.argv[1].split(":")
+    RomHandler.rom_directory = sys.argv[2]
+    RomHandler.roms = list(enumerate(os.listdir(RomHandler.rom_directory)))
+
+    server = ThreadingHTTPServer((host, int(port)), RomHandler)
+    announce(int(port), "http")
+    server.serve_forever()
+
+
+if __name__ == "__main__":
+    try:
+        main()
+    except KeyboardInterrupt:
+        sys.exit()
@@ -0,0 +1,276 @@
+//! The setup access point
+//!
+//! A board without wifi credentials, or one that could not join its
+//! network, opens the open access point [`SSID`] instead. Its clients get
+//! an address from `udoo_core::dhcp` and every name they look up leads to
+//! the esp32, so phones show the setup page on their own. The page adds
+//! the SSID, the password and the priority of a network to the known ones
+//! in the `settings` partition, see `udoo_core::settings`, along with the
+//! rom server, and the esp32 restarts into station mode.
+
+use core::fmt::{Debug, Write as _};
+use core::ptr::addr_of_mut;
+
+use embedded_io::blocking::Write;
+use embedded_svc::ipv4::{self, ClientSettings, Interface, Ipv4Addr, Mask, Subnet};
+use embedded_svc::wifi::{AccessPointConfiguration, AuthMethod, Configuration, Wifi};
+use esp32_hal::{macros::ram, reset::software_reset};
+use esp_storage::FlashStorage;
+use esp_wifi::current_millis;
+use esp_wifi::wifi_interface::{Socket, UdpSocket, WifiStack};
+use log::{error, info, warn};
+use smoltcp::socket::udp::PacketMetadata;
+use smoltcp::wire::{IpAddress, Ipv4Address};
+use udoo_core::settings::{self, Settings, Store};
+use udoo_core::{dhcp, dns, http};
+
+use crate::web::{self, respond};
+
+/// The access point is open, so anyone can join it to set the board up
+pub const SSID: &str = "udoo-key-setup";
+/// The esp32 on the access point
+pub const ADDRESS: [u8; 4] = [192, 168, 4, 1];
+
+/// Time without a request to the page before the esp32 tries its network
+/// again
+const TIMEOUT_MS: u64 = 10 * 60 * 1000;
+/// Largest form the page sends
+const FORM_SIZE: usize = 512;
+const PAGE: &str = include_str!("setup.html");
+const HTML: &str = "text/html; charset=utf-8";
+const TEXT: &str = "text/plain; charset=utf-8";
+/// Marks the next start for the access point
+const REQUEST_MAGIC: u32 = 0x5345_5455;
+
+#[ram(rtc_fast, uninitialized)]
+static mut REQUEST: u32 = 0;
+
+fn with_request<R>(f: impl FnOnce(&mut u32) -> R) -> R {
+    // Safety: the word is only touched inside a critical section
+    critical_section::with(|_| f(unsafe { &mut *addr_of_mut!(REQUEST) }))
+}
+
+/// Restart into the setup access point
+pub fn restart() -> ! {
+    with_request(|request| *request = REQUEST_MAGIC);
+    software_reset();
+    loop {}
+}
+
+/// The last run asked for the setup access point with [`restart`], asks
+/// only once
+pub fn requested() -> bool {
+    with_request(|request| core::mem::replace(request, 0) == REQUEST_MAGIC)
+}
+
+/// Open the access point and serve the setup page until the settings are
+/// saved, then restart
+pub fn run<W>(
+    controller: &mut W,
+    stack: &WifiStack<'_>,
+    mut store: Option<Store<FlashStorage>>,
+) -> !
+where
+    W: Wifi,
+    W::Error: Debug,
+{
+    let config = Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: SSID.into(),
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    });
+    if let Err(e) = controller.set_configuration(&config) {
+        error!("Configuring the access point failed: {e:?}");
+    }
+    if let Err(e) = controller.start() {
+        error!("Starting the access point failed: {e:?}");
+    }
+    let address = Ipv4Addr::from(ADDRESS);
+    let fixed = ipv4::ClientConfiguration::Fixed(ClientSettings {
+        ip: address,
+        subnet: Subnet {
+            gateway: address,
+            mask: Mask(24),
+        },
+        dns: None,
+        secondary_dns: None,
+    });
+    if let Err(e) = stack.set_iface_configuration(&ipv4::Configuration::Client(fixed)) {
+        error!("Configuring the address failed: {e:?}");
+    }
+    info!("Join {SSID} and open http://{address}/ to set the board up");
+
+    let mut dhcp_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut dhcp_rx_buffer = [0u8; 1536];
+    let mut dhcp_tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut dhcp_tx_buffer = [0u8; 1536];
+    let mut dhcp_socket = stack.get_udp_socket(
+        &mut dhcp_rx_meta,
+        &mut dhcp_rx_buffer,
+        &mut dhcp_tx_meta,
+        &mut dhcp_tx_buffer,
+    );
+    bind(&mut dhcp_socket, dhcp::SERVER_PORT);
+    let mut dhcp_server = dhcp::Server::new(ADDRESS);
+
+    let mut dns_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut dns_rx_buffer = [0u8; 1024];
+    let mut dns_tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut dns_tx_buffer = [0u8; 1024];
+    let mut dns_socket = stack.get_udp_socket(
+        &mut dns_rx_meta,
+        &mut dns_rx_buffer,
+        &mut dns_tx_meta,
+        &mut dns_tx_buffer,
+    );
+    bind(&mut dns_socket, dns::PORT);
+
+    let mut web_rx_buffer = [0u8; 1536];
+    let mut web_tx_buffer = [0u8; 1536];
+    let mut web_socket = stack.get_socket(&mut web_rx_buffer, &mut web_tx_buffer);
+
+    let mut deadline = current_millis() + TIMEOUT_MS;
+    loop {
+        answer_dhcp(&mut dhcp_socket, &mut dhcp_server);
+        answer_dns(&mut dns_socket);
+        if serve(&mut web_socket, &mut store) {
+            deadline = current_millis() + TIMEOUT_MS;
+        }
+        if current_millis() > deadline {
+            warn!("Nobody set the board up, trying the network again");
+            software_reset();
+        }
+    }
+}
+
+fn bind(socket: &mut UdpSocket<'_, '_>, port: u16) {
+    if let Err(e) = socket.bind(port) {
+        warn!("Binding port {port} failed: {e:?}");
+    }
+}
+
+fn answer_dhcp(socket: &mut UdpSocket<'_, '_>, server: &mut dhcp::Server) {
+    let mut packet = [0u8; dhcp::MAX_MESSAGE];
+    let mut answer = [0u8; dhcp::MAX_MESSAGE];
+    socket.work();
+    while let Ok((len, _, _)) = socket.receive(&mut packet) {
+        match server.answer(&packet[..len], &mut answer) {
+            Ok(Some(len)) => {
+                // The client has no address yet
+                let broadcast = IpAddress::Ipv4(Ipv4Address::BROADCAST);
+                if let Err(e) = socket.send(broadcast, dhcp::CLIENT_PORT, &answer[..len]) {
+                    warn!("Answering a DHCP request failed: {e:?}");
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Bad DHCP request: {e:?}"),
+        }
+    }
+}
+
+fn answer_dns(socket: &mut UdpSocket<'_, '_>) {
+    let mut packet = [0u8; dns::MAX_MESSAGE];
+    let mut answer = [0u8; dns::MAX_MESSAGE];
+    socket.work();
+    while let Ok((len, ip, port)) = socket.receive(&mut packet) {
+        if let Ok(Some(len)) = dns::answer_all(&packet[..len], ADDRESS, &mut answer) {
+            let client = IpAddress::Ipv4(Ipv4Address(ip));
+            if let Err(e) = socket.send(client, port, &answer[..len]) {
+                warn!("Answering a DNS query failed: {e:?}");
+            }
+        }
+    }
+}
+
+/// Answer a request to the page, returns whether there was one. Saved
+/// settings restart the esp32.
+fn serve(socket: &mut Socket<'_, '_>, store: &mut Option<Store<FlashStorage>>) -> bool {
+    socket.work();
+    if !socket.is_open() {
+        if let Err(e) = socket.listen(web::PORT) {
+            warn!("Listening for the setup page failed: {e:?}");
+        }
+        return false;
+    }
+    if !socket.is_connected() {
+        return false;
+    }
+    let mut head = [0_u8; http::HEAD_SIZE];
+    let saved = match http::read_request(socket, &mut head) {
+        // Any other page is the setup page, which phones take as a sign
+        // to show it
+        Ok((request, early)) => match (request.method, request.path) {
+            ("POST", "/settings") => save(socket, store, request.content_length, early),
+            ("GET", _) => {
+                _ = respond(socket, 200, HTML, PAGE.as_bytes());
+                false
+            }
+            _ => {
+                _ = respond(socket, 405, TEXT, b"method not allowed\n");
+                false
+            }
+        },
+        Err(e) => {
+            warn!("Bad request: {e:?}");
+            _ = respond(socket, 400, TEXT, b"bad request\n");
+            false
+        }
+    };
+    socket.flush().ok();
+    socket.close();
+    if saved {
+        // Let the answer reach the browser
+        let end = current_millis() + 500;
+        while current_millis() < end {
+            socket.work();
+        }
+        software_reset();
+    }
+    true
+}
+
+/// Check and save the settings of the form, returns whether they were
+/// saved
+fn save(
+    socket: &mut Socket<'_, '_>,
+    store: &mut Option<Store<FlashStorage>>,
+    content_length: Option<usize>,
+    early: &[u8],
+) -> bool {
+    let Some(len) = content_length.filter(|&len| len <= FORM_SIZE) else {
+        _ = respond(socket, 413, TEXT, b"the form is too large\n");
+        return false;
+    };
+    let mut form = [0_u8; FORM_SIZE];
+    if http::read_body(socket, early, &mut form[..len]).is_err() {
+        return false;
+    }
+    // The form adds a network to the saved ones
+    let saved = store.as_mut().and_then(|store| store.load().ok()).flatten();
+    let settings = core::str::from_utf8(&form[..len])
+        .map_err(|_| settings::Error::Form)
+        .and_then(|form| Settings::from_form(form, saved.as_ref()));
+    let saved = settings.and_then(|settings| match store {
+        Some(store) => store.save(&settings).map(|_| settings),
+        None => Err(settings::Error::MissingPartition),
+    });
+    let mut message = heapless::String::<160>::new();
+    match saved {
+        Ok(settings) => {
+            let networks = settings.networks().count();
+            info!("Saved the settings with {networks} networks, restarting");
+            _ = writeln!(
+                message,
+                "Saved, {networks} networks are known. The board restarts and joins the best one in sight."
+            );
+            _ = respond(socket, 200, TEXT, message.as_bytes());
+            true
+        }
+        Err(e) => {
+            warn!("The settings cannot be saved: {e}");
+            _ = writeln!(message, "{e}");
+            _ = respond(socket, 400, TEXT, message.as_bytes());
+            false
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Status patterns on the yellow and blue leds
+//!
+//! [`start`] puts the leds on high speed LEDC channels so they can be
+//! dimmed, and hands them with timer 1 of timer group 0 to an interrupt
+//! that plays the patterns. After that any part of the program can change
+//! them with [`show`]. The LEDC driver keeps a reference to the clocks,
+//! so they have to live in a `static`. The program has to forward the
+//! timer interrupt:
+//!
+//! ```ignore
+//! #[interrupt]
+//! fn TG0_T1_LEVEL() {
+//!     status::on_timer();
+//! }
+//! ```
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use esp32_hal::{
+    clock::Clocks,
+    gpio::{Gpio32, Gpio33, Output, OutputPin, PushPull},
+    interrupt,
+    ledc::{
+        channel::{self, Channel, ChannelHW, ChannelIFace},
+        timer::{self, TimerIFace},
+        HighSpeed, LEDC,
+    },
+    peripherals::{self, Interrupt, TIMG0},
+    prelude::*,
+    system::PeripheralClockControl,
+    timer::{Timer, Timer1},
+};
+use static_cell::StaticCell;
+use udoo_core::led::{gamma, Color, Led, Pattern, StatusLed, TICK_MS};
+use udoo_key_bsp::Leds;
+
+pub type StatusTimer = Timer<Timer1<TIMG0>>;
+
+/// Resolution of the LEDC duty cycle
+const DUTY_BITS: u32 = 13;
+const PWM_FREQUENCY_KHZ: u32 = 5;
+
+/// A led on a high speed LEDC channel, the brightness is gamma corrected
+struct LedcLed<O: OutputPin + 'static>(Channel<'static, HighSpeed, O>);
+
+impl<O: OutputPin> Led for LedcLed<O> {
+    fn set_brightness(&mut self, brightness: u8) {
+        self.0.set_duty_hw(gamma(brightness, 1 << DUTY_BITS));
+    }
+}
+
+struct Status {
+    yellow: StatusLed<LedcLed<Gpio33<Output<PushPull>>>>,
+    blue: StatusLed<LedcLed<Gpio32<Output<PushPull>>>>,
+    /// Pattern for the green led of the rp2040 that has not been sent yet
+    green: Option<Pattern>,
+    timer: StatusTimer,
+}
+
+impl Status {
+    fn show(&mut self, color: Color, pattern: Pattern) {
+        match color {
+            Color::Yellow => self.yellow.show(pattern),
+            Color::Blue => self.blue.show(pattern),
+            Color::Green => self.green = Some(pattern),
+        }
+    }
+
+    fn pattern(&self, color: Color) -> Option<Pattern> {
+        match color {
+            Color::Yellow => Some(self.yellow.pattern()),
+            Color::Blue => Some(self.blue.pattern()),
+            Color::Green => self.green,
+        }
+    }
+}
+
+static STATUS: Mutex<RefCell<Option<Status>>> = Mutex::new(RefCell::new(None));
+static LEDC_DRIVER: StaticCell<LEDC<'static>> = StaticCell::new();
+static LEDC_TIMER: StaticCell<timer::Timer<'static, HighSpeed>> = StaticCell::new();
+
+/// Start playing patterns, both leds start off. Can only be called once.
+pub fn start(
+    leds: Leds,
+    ledc: peripherals::LEDC,
+    clocks: &'static Clocks<'static>,
+    peripheral_clock_control: &mut PeripheralClockControl,
+    mut timer: StatusTimer,
+) {
+    let ledc = LEDC_DRIVER.init(LEDC::new(ledc, clocks, peripheral_clock_control));
+    let ledc_timer = LEDC_TIMER.init(ledc.get_timer::<HighSpeed>(timer::Number::Timer0));
+    ledc_timer
+        .configure(timer::config::Config {
+            duty: timer::config::Duty::Duty13Bit,
+            clock_source: timer::HSClockSource::APBClk,
+            frequency: PWM_FREQUENCY_KHZ.kHz(),
+        })
+        .unwrap();
+    let ledc_timer: &'static _ = ledc_timer;
+
+    let config = channel::config::Config {
+        timer: ledc_timer,
+        duty_pct: 0,
+    };
+    let mut yellow = ledc.get_channel(channel::Number::Channel0, leds.yellow);
+    yellow.configure(config).unwrap();
+    let mut blue = ledc.get_channel(channel::Number::Channel1, leds.blue);
+    blue.configure(config).unwrap();
+
+    timer.start((TICK_MS as u64).millis());
+    timer.listen();
+    critical_section::with(|cs| {
+        STATUS.borrow_ref_mut(cs).replace(Status {
+            yellow: StatusLed::new(LedcLed(yellow)),
+            blue: StatusLed::new(LedcLed(blue)),
+            green: None,
+            timer,
+        });
+    });
+    interrupt::enable(Interrupt::TG0_T1_LEVEL, interrupt::Priority::Priority1).unwrap();
+}
+
+/// Play `pattern` on a led, does nothing before [`start`]. Patterns for
+/// the green led are kept for the program to send to the rp2040, see
+/// [`take_green`].
+pub fn show(color: Color, pattern: Pattern) {
+    critical_section::with(|cs| {
+        if let Some(status) = STATUS.borrow_ref_mut(cs).as_mut() {
+            status.show(color, pattern);
+        }
+    });
+}
+
+/// The pattern a led is playing, or the unsent pattern of the green led
+pub fn pattern(color: Color) -> Option<Pattern> {
+    critical_section::with(|cs| {
+        STATUS
+            .borrow_ref(cs)
+            .as_ref()
+            .and_then(|status| status.pattern(color))
+    })
+}
+
+/// A pattern for the green led that should be sent to the rp2040 with
+/// `udoo_core::led::message`
+pub fn take_green() -> Option<Pattern> {
+    critical_section::with(|cs| {
+        STATUS
+            .borrow_ref_mut(cs)
+            .as_mut()
+            .and_then(|status| status.green.take())
+    })
+}
+
+/// Advance the patterns, call from the `TG0_T1_LEVEL` interrupt
+pub fn on_timer() {
+    critical_section::with(|cs| {
+        if let Some(status) = STATUS.borrow_ref_mut(cs).as_mut() {
+            status.yellow.tick(TICK_MS);
+            status.blue.tick(TICK_MS);
+            status.timer.clear_interrupt();
+            status.timer.start((TICK_MS as u64).millis());
+        }
+    });
+}
@@ -0,0 +1,135 @@
+//! Getting files from a TFTP server over UDP, see `udoo_core::tftp`
+//!
+//! The request and each ack are sent again when the server stays quiet
+//! for [`TIMEOUT_MS`], a few times before the transfer is given up.
+
+use core::fmt;
+
+use esp_wifi::current_millis;
+use esp_wifi::wifi_interface::UdpSocket;
+use log::warn;
+use smoltcp::wire::{IpAddress, Ipv4Address};
+use udoo_core::tftp::{self, Download, MAX_PACKET};
+
+/// Time to wait for the next block before sending the last packet again
+pub const TIMEOUT_MS: u64 = 1000;
+/// Port the transfers come back to
+pub const LOCAL_PORT: u16 = 49154;
+/// Times the last packet is sent again before giving up
+const RETRIES: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Sending to the server failed
+    Io,
+    /// The server stopped answering
+    Timeout,
+    Tftp(tftp::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io => write!(f, "the TFTP server cannot be reached"),
+            Self::Timeout => write!(f, "the TFTP server stopped answering"),
+            Self::Tftp(tftp::Error::Server(tftp::NOT_FOUND)) => {
+                write!(f, "the TFTP server has no such file")
+            }
+            Self::Tftp(tftp::Error::Server(code)) => {
+                write!(f, "the TFTP server failed with code {code}")
+            }
+            Self::Tftp(tftp::Error::TooLarge) => write!(f, "the file is too large"),
+            Self::Tftp(tftp::Error::Name) => write!(f, "the file name cannot be sent"),
+            Self::Tftp(tftp::Error::Malformed) => write!(f, "the TFTP server sent a bad packet"),
+        }
+    }
+}
+
+impl From<tftp::Error> for Error {
+    fn from(e: tftp::Error) -> Self {
+        Self::Tftp(e)
+    }
+}
+
+/// Read `file` from the server into `buffer`, returns its length
+///
+/// The socket is bound to [`LOCAL_PORT`] and serves one transfer at a
+/// time.
+pub fn get(
+    socket: &mut UdpSocket<'_, '_>,
+    server: (IpAddress, u16),
+    file: &str,
+    buffer: &mut [u8],
+) -> Result<usize, Error> {
+    let (ip, port) = server;
+    let mut packet = [0u8; MAX_PACKET];
+    // Whatever is left of an earlier transfer
+    socket.work();
+    while socket.receive(&mut packet).is_ok() {}
+
+    // The request, then the last ack
+    let mut last = [0u8; MAX_PACKET];
+    let mut last_len = tftp::read_request(file, &mut last)?;
+    // The server answers from a port of its own, which names the transfer
+    let mut transfer = None;
+    let mut download = Download::new(buffer);
+    let mut retries = 0;
+    socket.work();
+    socket
+        .send(ip, port, &last[..last_len])
+        .map_err(|_| Error::Io)?;
+    let mut deadline = current_millis() + TIMEOUT_MS;
+    while !download.is_done() {
+        socket.work();
+        if let Ok((len, from, from_port)) = socket.receive(&mut packet) {
+            if IpAddress::Ipv4(Ipv4Address(from)) != ip {
+                continue;
+            }
+            if transfer.is_some_and(|transfer| transfer != from_port) {
+                refuse(
+                    socket,
+                    ip,
+                    from_port,
+                    tftp::UNKNOWN_TRANSFER,
+                    "unknown transfer",
+                );
+                continue;
+            }
+            match download.receive(&packet[..len]) {
+                Ok(Some(ack)) => {
+                    transfer = Some(from_port);
+                    last[..4].copy_from_slice(&ack);
+                    last_len = 4;
+                    _ = socket.send(ip, from_port, &ack);
+                    retries = 0;
+                    deadline = current_millis() + TIMEOUT_MS;
+                }
+                Ok(None) => {}
+                Err(e @ tftp::Error::TooLarge) => {
+                    refuse(socket, ip, from_port, tftp::DISK_FULL, "file too large");
+                    return Err(e.into());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if current_millis() > deadline {
+            retries += 1;
+            if retries > RETRIES {
+                return Err(Error::Timeout);
+            }
+            warn!("No answer from the TFTP server for {file}, sending again");
+            socket.work();
+            _ = socket.send(ip, transfer.unwrap_or(port), &last[..last_len]);
+            deadline = current_millis() + TIMEOUT_MS;
+        }
+    }
+    socket.work();
+    Ok(download.len())
+}
+
+fn refuse(socket: &mut UdpSocket<'_, '_>, ip: IpAddress, port: u16, code: u16, message: &str) {
+    let mut packet = [0u8; 32];
+    if let Ok(len) = tftp::error(code, message, &mut packet) {
+        _ = socket.send(ip, port, &packet[..len]);
+    }
+}
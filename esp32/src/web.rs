@@ -0,0 +1,480 @@
+//! Web server for a browser on the LAN
+//!
+//! Serves a page at `/` that drives the board through these endpoints:
+//!
+//! |Request|Answer|
+//! |---|---|
+//! |`GET /status`|The state of the board as JSON|
+//! |`GET /roms`|The uploaded roms, in the format of `udoo_core::catalogue`|
+//! |`GET /roms/<id>`|An uploaded rom|
+//! |`POST /roms?name=<name>`|Keeps the rom in the body, answers its id|
+//! |`POST /roms/<id>/launch`|Sends a rom to the rp2040|
+//! |`POST /rp2040/reset`|Resets the rp2040|
+//!
+//! Uploaded roms are kept in RAM until the esp32 resets, the oldest one
+//! makes room for a new one. The server answers one request per
+//! connection from the main loop. The head of the request is read as it
+//! arrives, a client that sends no head within [`REQUEST_TIMEOUT_MS`] is
+//! hung up on. The body of an upload is read in one go.
+//!
+//! The page also shows the chip8 screen and a keypad through a WebSocket
+//! at `/live` on [`LIVE_PORT`], see [`Live`].
+
+use core::fmt::{self, Write as _};
+
+use embedded_io::blocking::{Read, ReadReady, Write};
+use esp_wifi::wifi_interface::Socket;
+use log::{info, warn};
+use udoo_core::catalogue;
+use udoo_core::http::{self, Request, Writer};
+use udoo_core::rom::{RomCache, MAX_ROM_SIZE};
+use udoo_core::screen::{self, Screen};
+use udoo_core::websocket::{self, Opcode};
+
+pub const PORT: u16 = 80;
+/// Port of the WebSocket of [`Live`]
+pub const LIVE_PORT: u16 = 81;
+/// Uploaded roms that are kept
+pub const CACHE_SIZE: usize = 4;
+/// Time a client has to send the head of its request
+pub const REQUEST_TIMEOUT_MS: u64 = 2000;
+
+const INDEX: &str = include_str!("index.html");
+const TEXT: &str = "text/plain; charset=utf-8";
+const JSON: &str = "application/json";
+/// Time after which a browser that sends nothing is hung up on, the page
+/// sends its keys every 40 ms
+pub const LIVE_TIMEOUT_MS: u64 = 5000;
+/// Largest frame a browser sends to [`Live`], a ping of 125 bytes
+const FRAME_SIZE: usize = 2 + 4 + 125;
+
+/// What the web server does with the board
+pub trait Control {
+    /// Send a rom to the rp2040
+    fn launch(&mut self, rom: &[u8]);
+    /// Reset the rp2040
+    fn reset_rp2040(&mut self);
+    /// Write the state of the board as the members of a JSON object
+    fn status(&mut self, out: &mut dyn fmt::Write) -> fmt::Result;
+}
+
+pub struct Server<'s> {
+    socket: Socket<'s, 's>,
+    port: u16,
+    cache: &'s mut RomCache<CACHE_SIZE>,
+    head: Head,
+}
+
+impl<'s> Server<'s> {
+    pub fn new(socket: Socket<'s, 's>, port: u16, cache: &'s mut RomCache<CACHE_SIZE>) -> Self {
+        Self {
+            socket,
+            port,
+            cache,
+            head: Head::new(),
+        }
+    }
+
+    /// Answer a request once a client has sent its head, call it from the
+    /// main loop
+    pub fn poll(&mut self, now: u64, control: &mut dyn Control) {
+        self.socket.work();
+        if !self.socket.is_open() {
+            if let Err(e) = self.socket.listen(self.port) {
+                warn!("Listening on port {} failed: {e:?}", self.port);
+            }
+            return;
+        }
+        if !self.socket.is_connected() {
+            self.head.clear();
+            return;
+        }
+        match self.head.receive(&mut self.socket, now) {
+            Ok(true) => {}
+            Ok(false) if !self.head.expired(now) => return,
+            Ok(false) => {
+                warn!("No request within {REQUEST_TIMEOUT_MS} ms");
+                self.hang_up();
+                return;
+            }
+            Err(e) => {
+                warn!("Reading a request failed: {e:?}");
+                self.hang_up();
+                return;
+            }
+        }
+        let mut head = [0_u8; http::HEAD_SIZE];
+        match http::read_request(&mut self.head.bytes(), &mut head) {
+            Ok((request, early)) => {
+                let result = handle(&mut self.socket, self.cache, control, &request, early);
+                if let Err(e) = result {
+                    warn!(
+                        "Answering {} {} failed: {e:?}",
+                        request.method, request.path
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("Bad request: {e:?}");
+                _ = respond(&mut self.socket, 400, TEXT, b"bad request\n");
+            }
+        }
+        self.socket.flush().ok();
+        self.hang_up();
+    }
+
+    fn hang_up(&mut self) {
+        self.socket.close();
+        self.head.clear();
+    }
+}
+
+/// The head of a request as it arrives, so that the main loop does not
+/// wait for a slow client
+struct Head {
+    bytes: [u8; http::HEAD_SIZE],
+    len: usize,
+    /// When the client has to have sent the head, set on the first read
+    deadline: Option<u64>,
+}
+
+impl Head {
+    fn new() -> Self {
+        Self {
+            bytes: [0; http::HEAD_SIZE],
+            len: 0,
+            deadline: None,
+        }
+    }
+
+    /// Forget the request of the last client
+    fn clear(&mut self) {
+        self.len = 0;
+        self.deadline = None;
+    }
+
+    /// The bytes read so far, the head and whatever followed it
+    fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Read what the client has sent without waiting for more, returns
+    /// whether the head is complete or fills the buffer
+    fn receive(&mut self, socket: &mut Socket<'_, '_>, now: u64) -> Result<bool, http::Error> {
+        self.deadline.get_or_insert(now + REQUEST_TIMEOUT_MS);
+        while !self.complete() && socket.read_ready().map_err(|_| http::Error::Io)? {
+            match socket.read(&mut self.bytes[self.len..]) {
+                Ok(0) | Err(_) => return Err(http::Error::Io),
+                Ok(n) => self.len += n,
+            }
+        }
+        Ok(self.complete())
+    }
+
+    /// The client has sent no complete head in time
+    fn expired(&self, now: u64) -> bool {
+        matches!(self.deadline, Some(deadline) if now >= deadline)
+    }
+
+    /// The head has ended or there is no room for more of it
+    fn complete(&self) -> bool {
+        self.len == self.bytes.len() || self.bytes().windows(4).any(|w| w == b"\r\n\r\n")
+    }
+}
+
+/// The chip8 screen and keypad in a browser
+///
+/// The rows of the screen that the rp2040 sends are kept and passed on
+/// to the browser as they change, each in a binary message of the first
+/// row and the pixels as in a `Screen` message. The page sends the held
+/// keys every 40 ms as a binary message of a big endian `u16`, bit `n`
+/// for chip8 key `n`. Frames are read as they arrive, a browser that
+/// sends nothing for [`LIVE_TIMEOUT_MS`] is hung up on.
+pub struct Live<'s> {
+    socket: Socket<'s, 's>,
+    port: u16,
+    screen: Screen,
+    head: Head,
+    /// The connection has become a WebSocket
+    upgraded: bool,
+    /// Frames of the browser that have arrived, the last one may be cut
+    /// short
+    input: [u8; FRAME_SIZE],
+    input_len: usize,
+    last_heard: u64,
+}
+
+impl<'s> Live<'s> {
+    pub fn new(socket: Socket<'s, 's>, port: u16) -> Self {
+        Self {
+            socket,
+            port,
+            screen: Screen::new(),
+            head: Head::new(),
+            upgraded: false,
+            input: [0; FRAME_SIZE],
+            input_len: 0,
+            last_heard: 0,
+        }
+    }
+
+    /// Keep rows of a `Screen` message from the rp2040
+    pub fn apply(&mut self, row: u8, pixels: &[u8]) {
+        self.screen.apply(row, pixels);
+    }
+
+    /// Pass changed rows on to the browser and read its keys, call it
+    /// from the main loop. Returns the keys to send to the rp2040, which
+    /// are released when the browser goes away.
+    pub fn poll(&mut self, now: u64) -> Option<u16> {
+        self.socket.work();
+        if !self.socket.is_open() {
+            if let Err(e) = self.socket.listen(self.port) {
+                warn!("Listening on port {} failed: {e:?}", self.port);
+            }
+            return None;
+        }
+        if !self.socket.is_connected() {
+            if self.upgraded {
+                info!("The live view went away");
+                return self.close();
+            }
+            self.head.clear();
+            return None;
+        }
+        if !self.upgraded {
+            self.upgrade(now);
+            return None;
+        }
+        if let Err(e) = self.send_rows() {
+            return self.hang_up(e);
+        }
+        if let Err(e) = self.fill() {
+            return self.hang_up(e);
+        }
+        let mut keys = None;
+        while let Some(size) = websocket::frame_size(&self.input[..self.input_len]) {
+            if size > self.input.len() {
+                return self.hang_up(websocket::Error::TooLarge);
+            }
+            if size > self.input_len {
+                break;
+            }
+            let mut payload = [0_u8; 125];
+            let frame = websocket::read(&mut &self.input[..size], &mut payload);
+            self.input.copy_within(size..self.input_len, 0);
+            self.input_len -= size;
+            self.last_heard = now;
+            match frame {
+                Ok((Opcode::Binary, 2)) => {
+                    keys = Some(u16::from_be_bytes([payload[0], payload[1]]))
+                }
+                Ok((Opcode::Ping, len)) => {
+                    if let Err(e) =
+                        websocket::write(&mut self.socket, Opcode::Pong, &payload[..len])
+                    {
+                        return self.hang_up(e);
+                    }
+                }
+                Ok((Opcode::Close, _)) => {
+                    _ = websocket::write(&mut self.socket, Opcode::Close, &[]);
+                    info!("The live view closed");
+                    return self.close();
+                }
+                Ok(_) => {}
+                Err(e) => return self.hang_up(e),
+            }
+        }
+        if now >= self.last_heard + LIVE_TIMEOUT_MS {
+            warn!("The live view sent nothing for {LIVE_TIMEOUT_MS} ms");
+            return self.close();
+        }
+        keys
+    }
+
+    /// Read what the browser has sent without waiting for more
+    fn fill(&mut self) -> Result<(), websocket::Error> {
+        while self.input_len < self.input.len()
+            && self.socket.read_ready().map_err(|_| websocket::Error::Io)?
+        {
+            match self.socket.read(&mut self.input[self.input_len..]) {
+                Ok(0) | Err(_) => return Err(websocket::Error::Io),
+                Ok(n) => self.input_len += n,
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer the request of a browser that has connected
+    fn upgrade(&mut self, now: u64) {
+        match self.head.receive(&mut self.socket, now) {
+            Ok(true) => {}
+            Ok(false) if !self.head.expired(now) => return,
+            Ok(false) => {
+                warn!("No request for the live view within {REQUEST_TIMEOUT_MS} ms");
+                self.close();
+                return;
+            }
+            Err(e) => {
+                warn!("Reading a request for the live view failed: {e:?}");
+                self.close();
+                return;
+            }
+        }
+        let mut head = [0_u8; http::HEAD_SIZE];
+        let accepted = match http::read_request(&mut self.head.bytes(), &mut head) {
+            Ok((request, _)) => match (request.method, request.path, request.websocket_key) {
+                ("GET", "/live", Some(key)) => websocket::accept(&mut self.socket, key).is_ok(),
+                (_, "/live", _) => {
+                    _ = respond(&mut self.socket, 400, TEXT, b"expected a WebSocket\n");
+                    false
+                }
+                _ => {
+                    _ = respond(&mut self.socket, 404, TEXT, b"not found\n");
+                    false
+                }
+            },
+            Err(e) => {
+                warn!("Bad request for the live view: {e:?}");
+                false
+            }
+        };
+        self.head.clear();
+        if !accepted {
+            self.socket.flush().ok();
+            self.socket.close();
+            return;
+        }
+        info!("The live view opened");
+        self.upgraded = true;
+        self.last_heard = now;
+        // The browser starts with a blank screen
+        self.screen.refresh(screen::HEIGHT);
+    }
+
+    fn send_rows(&mut self) -> Result<(), websocket::Error> {
+        let mut message = [0_u8; 1 + screen::ROW_SIZE * screen::HEIGHT];
+        while let Some((row, pixels)) = self.screen.take_update(screen::HEIGHT) {
+            message[0] = row;
+            message[1..1 + pixels.len()].copy_from_slice(pixels);
+            websocket::write(
+                &mut self.socket,
+                Opcode::Binary,
+                &message[..1 + pixels.len()],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn hang_up(&mut self, e: websocket::Error) -> Option<u16> {
+        warn!("Lost the live view: {e:?}");
+        self.close()
+    }
+
+    /// Close the connection, the keys of the browser are released
+    fn close(&mut self) -> Option<u16> {
+        self.socket.close();
+        self.head.clear();
+        self.upgraded = false;
+        self.input_len = 0;
+        Some(0)
+    }
+}
+
+fn handle(
+    socket: &mut Socket<'_, '_>,
+    cache: &mut RomCache<CACHE_SIZE>,
+    control: &mut dyn Control,
+    request: &Request<'_>,
+    early: &[u8],
+) -> Result<(), http::Error> {
+    // `/roms/<id>` and `/roms/<id>/launch`
+    if let Some(rest) = request.path.strip_prefix("/roms/") {
+        let (id, action) = rest.split_once('/').unwrap_or((rest, ""));
+        let Some((_, rom)) = id.parse().ok().and_then(|id| cache.get(id)) else {
+            return respond(socket, 404, TEXT, b"no such rom\n");
+        };
+        return match (request.method, action) {
+            ("GET", "") => respond(socket, 200, "application/octet-stream", rom),
+            ("POST", "launch") => {
+                control.launch(rom);
+                respond(socket, 204, TEXT, b"")
+            }
+            (_, "" | "launch") => respond(socket, 405, TEXT, b"method not allowed\n"),
+            _ => respond(socket, 404, TEXT, b"not found\n"),
+        };
+    }
+    match (request.method, request.path) {
+        ("GET", "/") => respond(socket, 200, "text/html; charset=utf-8", INDEX.as_bytes()),
+        ("GET", "/status") => respond_json(socket, 200, |out| {
+            out.write_char('{')?;
+            control.status(out)?;
+            out.write_char('}')
+        }),
+        ("GET", "/roms") => respond_json(socket, 200, |out| catalogue::write(out, cache.iter())),
+        ("POST", "/roms") => upload(socket, cache, request, early),
+        ("POST", "/rp2040/reset") => {
+            control.reset_rp2040();
+            info!("Reset the rp2040 for a web client");
+            respond(socket, 204, TEXT, b"")
+        }
+        (_, "/" | "/status" | "/roms" | "/rp2040/reset") => {
+            respond(socket, 405, TEXT, b"method not allowed\n")
+        }
+        _ => respond(socket, 404, TEXT, b"not found\n"),
+    }
+}
+
+/// Keep the rom in the body of a `POST /roms`
+fn upload(
+    socket: &mut Socket<'_, '_>,
+    cache: &mut RomCache<CACHE_SIZE>,
+    request: &Request<'_>,
+    early: &[u8],
+) -> Result<(), http::Error> {
+    let Some(size) = request.content_length else {
+        return respond(socket, 411, TEXT, b"the upload needs a Content-Length\n");
+    };
+    if size == 0 {
+        return respond(socket, 400, TEXT, b"the rom is empty\n");
+    }
+    if size > MAX_ROM_SIZE {
+        return respond(socket, 413, TEXT, b"roms are at most 3584 bytes\n");
+    }
+    let mut rom = [0_u8; MAX_ROM_SIZE];
+    http::read_body(socket, early, &mut rom[..size])?;
+
+    // Longer names are cut off by the cache
+    let mut decoded = [0_u8; 2 * catalogue::NAME_SIZE];
+    let name = match request.param("name") {
+        Some(name) => match http::percent_decode(name, &mut decoded) {
+            Ok(name) => name,
+            Err(_) => return respond(socket, 400, TEXT, b"bad rom name\n"),
+        },
+        None => "upload",
+    };
+    // The size was checked
+    let id = cache.insert(name, &rom[..size]).unwrap_or_default();
+    info!("Keeping the rom {name} of {size} bytes as {id}");
+    respond_json(socket, 201, |out| write!(out, "{{\"id\": {id}}}"))
+}
+
+pub(crate) fn respond(
+    socket: &mut Socket<'_, '_>,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), http::Error> {
+    http::write_head(socket, status, content_type, Some(body.len()))?;
+    socket.write_all(body).map_err(|_| http::Error::Io)
+}
+
+/// Answer with JSON written by `body`, the connection is closed after it
+fn respond_json(
+    socket: &mut Socket<'_, '_>,
+    status: u16,
+    body: impl FnOnce(&mut dyn fmt::Write) -> fmt::Result,
+) -> Result<(), http::Error> {
+    http::write_head(socket, status, JSON, None)?;
+    body(&mut Writer(socket)).map_err(|_| http::Error::Io)
+}
@@ -0,0 +1,287 @@
+//! Helpers for bringing up the wifi station
+
+use core::cmp::Reverse;
+use core::fmt::{self, Debug, Display, Write};
+
+use embedded_svc::ipv4::{
+    self, ClientSettings, DHCPClientSettings, Interface, IpInfo, Ipv4Addr, Mask, Subnet,
+};
+use embedded_svc::wifi::{AccessPointInfo, ClientConfiguration, Configuration, Wifi};
+use esp_wifi::binary::include::{esp_wifi_sta_get_ap_info, wifi_ap_record_t};
+use esp_wifi::wifi_interface::WifiStack;
+use log::{error, info, warn};
+use udoo_core::ip_config::{self, Addressing};
+use udoo_core::settings::{self, Network};
+use udoo_core::shell::{self, Args, Command};
+
+/// Access points a scan reports
+pub const MAX_SCAN: usize = 16;
+
+/// Configure the station, start it and block until it is associated
+pub fn connect<W>(controller: &mut W, ssid: &str, password: &str)
+where
+    W: Wifi,
+    W::Error: Debug,
+{
+    let client_config = Configuration::Client(ClientConfiguration {
+        ssid: ssid.into(),
+        password: password.into(),
+        ..Default::default()
+    });
+    let res = controller.set_configuration(&client_config);
+    info!("wifi_set_configuration returned {:?}", res);
+
+    controller.start().unwrap();
+    info!("is wifi started: {:?}", controller.is_started());
+
+    info!("wifi_connect {:?}", controller.connect());
+    info!("Wait to get connected");
+    loop {
+        match controller.is_connected() {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(err) => {
+                warn!("Connecting failed, retrying: {:?}", err);
+                _ = controller.connect();
+            }
+        }
+    }
+    info!("is_connected: {:?}", controller.is_connected());
+}
+
+/// Start the station and scan for access points, the strongest first
+pub fn scan<W>(controller: &mut W) -> heapless::Vec<AccessPointInfo, MAX_SCAN>
+where
+    W: Wifi,
+    W::Error: Debug,
+{
+    if !controller.is_started().unwrap_or(false) {
+        if let Err(e) = controller.set_configuration(&Configuration::Client(Default::default())) {
+            warn!("Configuring the station failed: {e:?}");
+        }
+        if let Err(e) = controller.start() {
+            warn!("Starting the station failed: {e:?}");
+        }
+    }
+    match controller.scan_n::<MAX_SCAN>() {
+        Ok((mut seen, _)) => {
+            seen.sort_unstable_by_key(|ap| Reverse(ap.signal_strength));
+            seen
+        }
+        Err(e) => {
+            warn!("Scanning for access points failed: {e:?}");
+            heapless::Vec::new()
+        }
+    }
+}
+
+/// Configure the station for the known network to join, see
+/// [`settings::choose`], and its strongest access point
+///
+/// Without a known network in sight the one with the highest priority is
+/// tried, it may hide its SSID. `None` if there are no known networks.
+pub fn configure_known<'k, W>(controller: &mut W, known: &'k [Network]) -> Option<&'k Network>
+where
+    W: Wifi,
+    W::Error: Debug,
+{
+    let seen = scan(controller);
+    info!("Found {} access points", seen.len());
+    let (network, ap) = match settings::choose(known, seen.iter().map(access_point)) {
+        Some((network, index)) => (network, seen.get(index)),
+        None => (known.iter().max_by_key(|n| n.priority())?, None),
+    };
+    match ap {
+        Some(ap) => info!(
+            "Joining {} at {} on channel {}, {} dBm",
+            network.ssid(),
+            Bssid(ap.bssid),
+            ap.channel,
+            ap.signal_strength
+        ),
+        None => warn!("No known network in sight, trying {}", network.ssid()),
+    }
+    let client_config = Configuration::Client(ClientConfiguration {
+        ssid: network.ssid().into(),
+        password: network.password().into(),
+        bssid: ap.map(|ap| ap.bssid),
+        channel: ap.map(|ap| ap.channel),
+        ..Default::default()
+    });
+    if let Err(e) = controller.set_configuration(&client_config) {
+        error!("Configuring the station failed: {e:?}");
+    }
+    Some(network)
+}
+
+/// Signal strength of the access point the station is connected to, in
+/// dBm
+pub fn rssi() -> Option<i8> {
+    // A plain C struct, all zeroes is a valid value
+    let mut record: wifi_ap_record_t = unsafe { core::mem::zeroed() };
+    let result = unsafe { esp_wifi_sta_get_ap_info(&mut record) };
+    (result == 0).then_some(record.rssi)
+}
+
+fn access_point(ap: &AccessPointInfo) -> (&str, i8) {
+    (ap.ssid.as_str(), ap.signal_strength)
+}
+
+struct Bssid([u8; 6]);
+
+impl Display for Bssid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// How the station gets its address, from the `STATIC_IP`, `GATEWAY`,
+/// `DNS` and `HOSTNAME` build variables
+pub fn addressing() -> Result<Addressing<'static>, ip_config::Error> {
+    Addressing::parse(
+        option_env!("STATIC_IP"),
+        option_env!("GATEWAY"),
+        option_env!("DNS"),
+        option_env!("HOSTNAME"),
+    )
+}
+
+/// Give the interface its static address, or block until DHCP has given
+/// it one
+///
+/// Build variables that cannot be used are logged and DHCP is asked
+/// instead.
+pub fn wait_for_ip(wifi_stack: &WifiStack) {
+    let config = match addressing() {
+        Ok(Addressing::Dhcp { hostname }) => ipv4::ClientConfiguration::DHCP(DHCPClientSettings {
+            hostname: hostname.map(Into::into),
+        }),
+        Ok(Addressing::Static(fixed)) => ipv4::ClientConfiguration::Fixed(ClientSettings {
+            ip: Ipv4Addr::from(fixed.address),
+            subnet: Subnet {
+                gateway: Ipv4Addr::from(fixed.gateway),
+                mask: Mask(fixed.prefix),
+            },
+            dns: fixed.dns.map(Ipv4Addr::from),
+            secondary_dns: None,
+        }),
+        Err(e) => {
+            error!("The address settings cannot be used, asking DHCP: {e}");
+            ipv4::ClientConfiguration::DHCP(DHCPClientSettings::default())
+        }
+    };
+    if let Err(e) = wifi_stack.set_iface_configuration(&ipv4::Configuration::Client(config)) {
+        warn!("Configuring the address failed: {e:?}");
+    }
+
+    info!("Wait to get an ip address");
+    loop {
+        wifi_stack.work();
+
+        if wifi_stack.is_iface_up() {
+            match wifi_stack.get_ip_info() {
+                Ok(info) => info!("Got {}", Lease(&info)),
+                Err(e) => warn!("Reading the address failed: {e:?}"),
+            }
+            break;
+        }
+    }
+}
+
+/// The address of the interface and where it came from
+struct Lease<'i>(&'i IpInfo);
+
+impl<'i> Display for Lease<'i> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let info = self.0;
+        write!(f, "address {}/{}", info.ip, info.subnet.mask.0)?;
+        match addressing() {
+            Ok(Addressing::Static(_)) => write!(f, " (static)")?,
+            Ok(Addressing::Dhcp {
+                hostname: Some(hostname),
+            }) => write!(f, " (DHCP as {hostname})")?,
+            _ => write!(f, " (DHCP)")?,
+        }
+        write!(f, ", gateway {}", info.subnet.gateway)?;
+        match (info.dns, info.secondary_dns) {
+            (Some(dns), Some(secondary)) => write!(f, ", dns {dns} and {secondary}"),
+            (Some(dns), None) | (None, Some(dns)) => write!(f, ", dns {dns}"),
+            (None, None) => write!(f, ", no dns"),
+        }
+    }
+}
+
+/// The `wifi status`, `wifi scan` and `wifi setup` console commands
+pub struct Commands<'a, 's, W> {
+    pub controller: &'a mut W,
+    pub stack: &'a WifiStack<'s>,
+    /// Marked in the scan results
+    pub known: &'a [Network],
+}
+
+impl<'a, 's, W> shell::Commands for Commands<'a, 's, W>
+where
+    W: Wifi,
+    W::Error: Debug,
+{
+    fn commands(&self) -> &'static [Command] {
+        &[
+            Command {
+                name: "wifi status",
+                args: "",
+                help: "Show the access point, address, gateway and dns",
+            },
+            Command {
+                name: "wifi scan",
+                args: "",
+                help: "List the access points in sight, the known ones with their priority",
+            },
+            Command {
+                name: "wifi setup",
+                args: "",
+                help: "Restart into the setup access point",
+            },
+        ]
+    }
+
+    fn run(&mut self, name: &str, _: Args<'_>, out: &mut dyn Write) -> Result<(), shell::Error> {
+        if name == "wifi setup" {
+            _ = write!(out, "restarting into {}\r\n", crate::setup::SSID);
+            crate::setup::restart();
+        }
+        if name == "wifi scan" {
+            for ap in scan(self.controller) {
+                _ = write!(
+                    out,
+                    "{:>4} dBm  ch {:>2}  {}  {}",
+                    ap.signal_strength,
+                    ap.channel,
+                    Bssid(ap.bssid),
+                    ap.ssid
+                );
+                if let Some(network) = self.known.iter().find(|n| n.ssid() == ap.ssid.as_str()) {
+                    _ = write!(out, "  (known, priority {})", network.priority());
+                }
+                _ = write!(out, "\r\n");
+            }
+            return Ok(());
+        }
+        let connected = self
+            .controller
+            .is_connected()
+            .map_err(|_| shell::Error::Failed("the wifi driver is not running"))?;
+        match self.controller.get_configuration() {
+            Ok(Configuration::Client(client)) if connected => {
+                _ = write!(out, "connected to {}\r\n", client.ssid);
+            }
+            _ if connected => _ = write!(out, "connected\r\n"),
+            _ => _ = write!(out, "not connected\r\n"),
+        }
+        match self.stack.get_ip_info() {
+            Ok(info) => _ = write!(out, "{}\r\n", Lease(&info)),
+            Err(_) => _ = write!(out, "no address\r\n"),
+        }
+        Ok(())
+    }
+}
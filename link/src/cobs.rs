@@ -0,0 +1,119 @@
+//! Consistent Overhead Byte Stuffing
+//!
+//! COBS removes every zero from a frame at the cost of one byte per
+//! 254 bytes of data, which leaves `0x00` free to mark the end of a frame.
+
+/// Worst case size of `len` bytes after encoding
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// Encode `data` into `out`, returns the encoded length or `None`
+/// if `out` is too small
+pub fn encode(data: &[u8], out: &mut [u8]) -> Option<usize> {
+    if out.len() < max_encoded_len(data.len()) {
+        return None;
+    }
+    let mut code_index = 0;
+    let mut code = 1_u8;
+    let mut index = 1;
+    for &byte in data {
+        if byte == 0 {
+            out[code_index] = code;
+            code_index = index;
+            index += 1;
+            code = 1;
+        } else {
+            out[index] = byte;
+            index += 1;
+            code += 1;
+            if code == 0xff {
+                out[code_index] = code;
+                code_index = index;
+                index += 1;
+                code = 1;
+            }
+        }
+    }
+    out[code_index] = code;
+    Some(index)
+}
+
+/// Decode a frame in place, returns the decoded length or `None`
+/// if the frame is malformed
+pub fn decode_in_place(data: &mut [u8]) -> Option<usize> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < data.len() {
+        let code = data[read] as usize;
+        if code == 0 || read + code > data.len() {
+            return None;
+        }
+        read += 1;
+        for _ in 1..code {
+            data[write] = data[read];
+            write += 1;
+            read += 1;
+        }
+        if code != 0xff && read != data.len() {
+            data[write] = 0;
+            write += 1;
+        }
+    }
+    Some(write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0; max_encoded_len(data.len())];
+        let len = encode(data, &mut out).unwrap();
+        out.truncate(len);
+        out
+    }
+
+    #[test]
+    fn vectors() {
+        assert_eq!(encoded(&[]), [0x01]);
+        assert_eq!(encoded(&[0x00]), [0x01, 0x01]);
+        assert_eq!(encoded(&[0x00, 0x00]), [0x01, 0x01, 0x01]);
+        assert_eq!(
+            encoded(&[0x11, 0x22, 0x00, 0x33]),
+            [0x03, 0x11, 0x22, 0x02, 0x33]
+        );
+        assert_eq!(
+            encoded(&[0x11, 0x00, 0x00, 0x00]),
+            [0x02, 0x11, 0x01, 0x01, 0x01]
+        );
+    }
+
+    #[test]
+    fn long_runs() {
+        let data: Vec<u8> = (1..=254).collect();
+        let out = encoded(&data);
+        assert_eq!(out[0], 0xff);
+        assert_eq!(&out[1..255], &data[..]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data: Vec<u8> = (0..600).map(|i| (i % 7) as u8 * 40).collect();
+        let mut out = encoded(&data);
+        assert!(!out.contains(&0));
+        let len = decode_in_place(&mut out).unwrap();
+        assert_eq!(&out[..len], &data[..]);
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(decode_in_place(&mut [0x00, 0x11]), None);
+        assert_eq!(decode_in_place(&mut [0x05, 0x11]), None);
+    }
+
+    #[test]
+    fn small_buffers() {
+        assert_eq!(encode(&[1, 2, 3], &mut [0; 3]), None);
+    }
+}
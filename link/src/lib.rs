@@ -0,0 +1,374 @@
+//! Messages exchanged over the serial connection between the esp32 and
+//! the rp2040
+//!
+//! See the README for the layout of a frame on the wire.
+#![cfg_attr(not(test), no_std)]
+
+pub mod cobs;
+
+/// Largest payload a single message can carry
+pub const MAX_PAYLOAD: usize = 240;
+/// Kind byte, payload and CRC
+const MAX_MESSAGE: usize = MAX_PAYLOAD + 3;
+/// Largest frame on the wire including the terminating zero
+pub const MAX_FRAME: usize = cobs::max_encoded_len(MAX_MESSAGE) + 1;
+/// Largest chunk of rom sent in a single [`Message::RomData`]
+pub const ROM_CHUNK: usize = MAX_PAYLOAD - 2;
+/// Size of an encoded led pattern in a [`Message::Led`]
+pub const LED_PATTERN_SIZE: usize = 5;
+/// Bytes of a row of the chip8 screen in a [`Message::Screen`]
+pub const SCREEN_ROW_SIZE: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The frame did not fit into the buffer
+    Overflow,
+    /// The frame is not valid COBS
+    Encoding,
+    /// The CRC at the end of the frame does not match
+    Crc,
+    /// The kind byte is not a known message
+    UnknownKind(u8),
+    /// The payload is too short or too long for the message kind
+    Length,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Ping = 0x01,
+    Pong = 0x02,
+    RomBegin = 0x10,
+    RomData = 0x11,
+    RomEnd = 0x12,
+    Led = 0x20,
+    Log = 0x30,
+    Screen = 0x40,
+    Keys = 0x41,
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            x if x == Self::Ping as u8 => Ok(Self::Ping),
+            x if x == Self::Pong as u8 => Ok(Self::Pong),
+            x if x == Self::RomBegin as u8 => Ok(Self::RomBegin),
+            x if x == Self::RomData as u8 => Ok(Self::RomData),
+            x if x == Self::RomEnd as u8 => Ok(Self::RomEnd),
+            x if x == Self::Led as u8 => Ok(Self::Led),
+            x if x == Self::Log as u8 => Ok(Self::Log),
+            x if x == Self::Screen as u8 => Ok(Self::Screen),
+            x if x == Self::Keys as u8 => Ok(Self::Keys),
+            x => Err(Error::UnknownKind(x)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Message<'a> {
+    /// Heartbeat request from the esp32
+    Ping(u16),
+    /// Heartbeat answer from the rp2040, echoes the ping sequence
+    Pong(u16),
+    /// Start of a rom transfer of the given size
+    RomBegin(u16),
+    /// Part of a rom starting at `offset`
+    RomData { offset: u16, data: &'a [u8] },
+    /// The whole rom has been sent and can be loaded
+    RomEnd,
+    /// Play a pattern on one of the leds of the receiving chip, the
+    /// encoding of both fields is up to `udoo-core`
+    Led {
+        color: u8,
+        pattern: [u8; LED_PATTERN_SIZE],
+    },
+    /// A piece of the defmt log stream of the rp2040
+    Log(&'a [u8]),
+    /// Consecutive rows of the chip8 screen starting at `row`, one bit
+    /// per pixel with the leftmost in the high bit
+    Screen { row: u8, pixels: &'a [u8] },
+    /// The keys held on the remote keypad, bit `n` for chip8 key `n`
+    Keys(u16),
+}
+
+/// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn u16_at(payload: &[u8], offset: usize) -> Result<u16, Error> {
+    payload
+        .get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or(Error::Length)
+}
+
+impl<'a> Message<'a> {
+    fn kind(&self) -> Kind {
+        match self {
+            Message::Ping(_) => Kind::Ping,
+            Message::Pong(_) => Kind::Pong,
+            Message::RomBegin(_) => Kind::RomBegin,
+            Message::RomData { .. } => Kind::RomData,
+            Message::RomEnd => Kind::RomEnd,
+            Message::Led { .. } => Kind::Led,
+            Message::Log(_) => Kind::Log,
+            Message::Screen { .. } => Kind::Screen,
+            Message::Keys(_) => Kind::Keys,
+        }
+    }
+
+    /// Write the payload into `out`, returns the payload length
+    fn write_payload(&self, out: &mut [u8]) -> Result<usize, Error> {
+        let mut put = |bytes: &[u8], at: usize| -> Result<usize, Error> {
+            out.get_mut(at..at + bytes.len())
+                .ok_or(Error::Overflow)?
+                .copy_from_slice(bytes);
+            Ok(at + bytes.len())
+        };
+        match self {
+            Message::Ping(seq)
+            | Message::Pong(seq)
+            | Message::RomBegin(seq)
+            | Message::Keys(seq) => put(&seq.to_be_bytes(), 0),
+            Message::RomData { offset, data } => {
+                let at = put(&offset.to_be_bytes(), 0)?;
+                put(data, at)
+            }
+            Message::RomEnd => Ok(0),
+            Message::Led { color, pattern } => {
+                let at = put(&[*color], 0)?;
+                put(pattern, at)
+            }
+            Message::Log(data) => put(data, 0),
+            Message::Screen { row, pixels } => {
+                let at = put(&[*row], 0)?;
+                put(pixels, at)
+            }
+        }
+    }
+
+    /// Parse a decoded frame without its CRC
+    fn parse(kind: u8, payload: &'a [u8]) -> Result<Self, Error> {
+        let exact = |len: usize| {
+            if payload.len() == len {
+                Ok(())
+            } else {
+                Err(Error::Length)
+            }
+        };
+        match Kind::try_from(kind)? {
+            Kind::Ping => exact(2).and(u16_at(payload, 0).map(Message::Ping)),
+            Kind::Pong => exact(2).and(u16_at(payload, 0).map(Message::Pong)),
+            Kind::RomBegin => exact(2).and(u16_at(payload, 0).map(Message::RomBegin)),
+            Kind::RomData => Ok(Message::RomData {
+                offset: u16_at(payload, 0)?,
+                data: &payload[2..],
+            }),
+            Kind::RomEnd => exact(0).map(|_| Message::RomEnd),
+            Kind::Led => exact(1 + LED_PATTERN_SIZE).map(|_| {
+                let mut pattern = [0; LED_PATTERN_SIZE];
+                pattern.copy_from_slice(&payload[1..]);
+                Message::Led {
+                    color: payload[0],
+                    pattern,
+                }
+            }),
+            Kind::Log => Ok(Message::Log(payload)),
+            Kind::Screen => match payload.split_first() {
+                Some((&row, pixels)) if pixels.len() % SCREEN_ROW_SIZE == 0 => {
+                    Ok(Message::Screen { row, pixels })
+                }
+                _ => Err(Error::Length),
+            },
+            Kind::Keys => exact(2).and(u16_at(payload, 0).map(Message::Keys)),
+        }
+    }
+
+    /// Encode the message into a complete frame including the
+    /// terminating zero, returns the number of bytes to send
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, Error> {
+        let mut message = [0_u8; MAX_MESSAGE];
+        message[0] = self.kind() as u8;
+        let len = 1 + self.write_payload(&mut message[1..MAX_MESSAGE - 2])?;
+        let crc = crc16(&message[..len]);
+        message[len..len + 2].copy_from_slice(&crc.to_be_bytes());
+        let encoded = cobs::encode(&message[..len + 2], out).ok_or(Error::Overflow)?;
+        *out.get_mut(encoded).ok_or(Error::Overflow)? = 0;
+        Ok(encoded + 1)
+    }
+}
+
+/// Collects bytes from the serial connection into frames
+pub struct Decoder {
+    buffer: [u8; MAX_FRAME],
+    len: usize,
+    overflow: bool,
+    complete: bool,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; MAX_FRAME],
+            len: 0,
+            overflow: false,
+            complete: false,
+        }
+    }
+
+    /// Feed a received byte, returns a message once a whole frame
+    /// has been received
+    pub fn push(&mut self, byte: u8) -> Option<Result<Message<'_>, Error>> {
+        if self.complete {
+            self.len = 0;
+            self.overflow = false;
+            self.complete = false;
+        }
+        if byte != 0 {
+            if self.len < self.buffer.len() {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+        self.complete = true;
+        if self.len == 0 {
+            // Back to back delimiters carry no message
+            return None;
+        }
+        if self.overflow {
+            return Some(Err(Error::Overflow));
+        }
+        Some(self.decode())
+    }
+
+    fn decode(&mut self) -> Result<Message<'_>, Error> {
+        let len = cobs::decode_in_place(&mut self.buffer[..self.len]).ok_or(Error::Encoding)?;
+        if len < 3 {
+            return Err(Error::Length);
+        }
+        let (message, crc) = self.buffer[..len].split_at(len - 2);
+        if crc16(message) != u16::from_be_bytes([crc[0], crc[1]]) {
+            return Err(Error::Crc);
+        }
+        Message::parse(message[0], &message[1..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(message: Message) {
+        let mut frame = [0_u8; MAX_FRAME];
+        let len = message.encode(&mut frame).unwrap();
+        assert_eq!(frame[len - 1], 0);
+        assert!(!frame[..len - 1].contains(&0));
+        let mut decoder = Decoder::new();
+        for &byte in &frame[..len - 1] {
+            assert_eq!(decoder.push(byte), None);
+        }
+        assert_eq!(decoder.push(0), Some(Ok(message)));
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn messages_roundtrip() {
+        roundtrip(Message::Ping(0));
+        roundtrip(Message::Pong(0x1234));
+        roundtrip(Message::RomBegin(0x0e00));
+        roundtrip(Message::RomData {
+            offset: 0x100,
+            data: &[0; ROM_CHUNK],
+        });
+        roundtrip(Message::RomEnd);
+        roundtrip(Message::Led {
+            color: 3,
+            pattern: [9, 0, 255, 0x03, 0xe8],
+        });
+        roundtrip(Message::Log(&[0x01, 0x00, 0x7f]));
+        roundtrip(Message::Screen {
+            row: 30,
+            pixels: &[0x80, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0, 0, 0, 0, 0, 0, 0],
+        });
+        roundtrip(Message::Keys(0b1000_0000_0001_0010));
+    }
+
+    #[test]
+    fn partial_screen_rows() {
+        let message = Message::Screen {
+            row: 0,
+            pixels: &[0xff; SCREEN_ROW_SIZE + 4],
+        };
+        let mut frame = [0_u8; MAX_FRAME];
+        let len = message.encode(&mut frame).unwrap();
+        let mut decoder = Decoder::new();
+        for &byte in &frame[..len - 1] {
+            assert_eq!(decoder.push(byte), None);
+        }
+        assert_eq!(decoder.push(0), Some(Err(Error::Length)));
+    }
+
+    #[test]
+    fn oversized_payload() {
+        let message = Message::RomData {
+            offset: 0,
+            data: &[1; ROM_CHUNK + 1],
+        };
+        assert_eq!(message.encode(&mut [0; MAX_FRAME]), Err(Error::Overflow));
+    }
+
+    #[test]
+    fn corrupted_frames() {
+        let mut frame = [0_u8; MAX_FRAME];
+        let len = Message::Ping(7).encode(&mut frame).unwrap();
+        frame[3] ^= 0x10;
+        let mut decoder = Decoder::new();
+        for &byte in &frame[..len - 1] {
+            assert_eq!(decoder.push(byte), None);
+        }
+        assert_eq!(decoder.push(0), Some(Err(Error::Crc)));
+        // The decoder recovers on the next frame
+        let len = Message::Ping(8).encode(&mut frame).unwrap();
+        for &byte in &frame[..len - 1] {
+            assert_eq!(decoder.push(byte), None);
+        }
+        assert_eq!(decoder.push(0), Some(Ok(Message::Ping(8))));
+    }
+
+    #[test]
+    fn runaway_frames_overflow() {
+        let mut decoder = Decoder::new();
+        for _ in 0..MAX_FRAME + 1 {
+            assert_eq!(decoder.push(1), None);
+        }
+        assert_eq!(decoder.push(0), Some(Err(Error::Overflow)));
+        assert_eq!(decoder.push(0), None);
+    }
+}
@@ -0,0 +1,93 @@
+//! Prints the defmt log of the rp2040
+//!
+//! The esp32 chip8 program connects to this server and passes on the log
+//! the rp2040 sends over the link. The log is decoded with the ELF file
+//! of the rp2040 program, like `defmt-print` does for RTT.
+
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::{env, fs, process};
+
+use defmt_decoder::{DecodeError, Frame, Locations, Table};
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0:5002";
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let Some(elf_path) = args.next() else {
+        fail("usage: udoo-log-server <rp2040 elf> [address:port]");
+    };
+    let address = args.next().unwrap_or_else(|| DEFAULT_ADDRESS.into());
+
+    let elf = fs::read(&elf_path).unwrap_or_else(|e| fail(&format!("{elf_path}: {e}")));
+    let table = match Table::parse(&elf) {
+        Ok(Some(table)) => table,
+        Ok(None) => fail(&format!("{elf_path} has no defmt data")),
+        Err(e) => fail(&format!("{elf_path}: {e}")),
+    };
+    // Without locations the log is still readable
+    let locations = table.get_locations(&elf).unwrap_or_default();
+
+    let listener = TcpListener::bind(&address).unwrap_or_else(|e| fail(&format!("{address}: {e}")));
+    println!("Waiting for the esp32 on {address}");
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => print_log(stream, &table, &locations),
+            Err(e) => eprintln!("Accepting a connection failed: {e}"),
+        }
+    }
+}
+
+/// Decode the log from one connection until the esp32 goes away
+fn print_log(mut stream: TcpStream, table: &Table, locations: &Locations) {
+    match stream.peer_addr() {
+        Ok(peer) => println!("Connected to {peer}"),
+        Err(_) => println!("Connected"),
+    }
+    let mut decoder = table.new_stream_decoder();
+    let mut buffer = [0; 1024];
+    loop {
+        let len = match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) => {
+                eprintln!("Reading the log failed: {e}");
+                break;
+            }
+        };
+        decoder.received(&buffer[..len]);
+        loop {
+            match decoder.decode() {
+                Ok(frame) => print_frame(&frame, locations),
+                Err(DecodeError::UnexpectedEof) => break,
+                // Frames lost on the way, the decoder picks up at the
+                // next frame
+                Err(DecodeError::Malformed) if table.encoding().can_recover() => {
+                    eprintln!("Skipped a malformed frame");
+                }
+                Err(DecodeError::Malformed) => {
+                    eprintln!("Malformed frame, the rest of the log cannot be decoded");
+                    return;
+                }
+            }
+        }
+    }
+    println!("Disconnected");
+}
+
+fn print_frame(frame: &Frame, locations: &Locations) {
+    println!("{}", frame.display(true));
+    if let Some(location) = locations.get(&frame.index()) {
+        println!(
+            "└─ {} @ {}:{}",
+            location.module,
+            location.file.display(),
+            location.line
+        );
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    process::exit(1);
+}
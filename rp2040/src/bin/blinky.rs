@@ -1,33 +1,55 @@
 #![no_std]
 #![no_main]
 
+#[cfg(not(feature = "link-log"))]
 use defmt_rtt as _;
-use embedded_hal::digital::v2::OutputPin;
-use panic_probe as _;
-use rp2040_hal::entry;
+use rp2040_hal::{entry, pac::interrupt};
 
 use rp2040_hal::{
     clocks::{init_clocks_and_plls, Clock},
-    gpio, pac,
-    sio::Sio,
+    pwm::Slices,
+    timer::Timer,
     watchdog::Watchdog,
 };
+use udoo_core::led::Pattern;
+use udoo_key_bsp::{Board, XOSC_CRYSTAL_FREQ};
+use udoo_rp2040::{crash, status};
 
 #[link_section = ".boot2"]
 #[used]
 pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_GENERIC_03H;
 
-const XTAL_FREQ_HZ: u32 = 12_000_000u32;
+/// Every status pattern, each one is shown for a few seconds
+const PATTERNS: [Pattern; 10] = [
+    Pattern::Boot,
+    Pattern::WifiConnecting,
+    Pattern::Transfer,
+    Pattern::Heartbeat,
+    Pattern::Breathing,
+    Pattern::Error(2),
+    Pattern::Error(4),
+    Pattern::Level(16),
+    Pattern::Level(128),
+    Pattern::Fade {
+        from: 255,
+        to: 0,
+        ms: 4000,
+    },
+];
 
 #[entry]
 fn main() -> ! {
-    let mut pac = pac::Peripherals::take().unwrap();
-    let core = pac::CorePeripherals::take().unwrap();
+    let Board {
+        leds,
+        peripherals: mut pac,
+        core,
+        ..
+    } = Board::take().unwrap();
+    crash::report();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
-    let sio = Sio::new(pac.SIO);
 
     let clocks = init_clocks_and_plls(
-        XTAL_FREQ_HZ,
+        XOSC_CRYSTAL_FREQ,
         pac.XOSC,
         pac.CLOCKS,
         pac.PLL_SYS,
@@ -40,21 +62,21 @@ fn main() -> ! {
 
     let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
 
-    let pins = gpio::Pins::new(
-        pac.IO_BANK0,
-        pac.PADS_BANK0,
-        sio.gpio_bank0,
-        &mut pac.RESETS,
-    );
-
-    let mut led_pin = pins.gpio25.into_push_pull_output();
+    let mut timer = Timer::new(pac.TIMER, &mut pac.RESETS);
+    let pwm_slices = Slices::new(pac.PWM, &mut pac.RESETS);
+    status::start(leds.into_pwm(pwm_slices.pwm4), timer.alarm_0().unwrap());
 
     loop {
-        led_pin.set_high().unwrap();
-        delay.delay_ms(500);
-        led_pin.set_low().unwrap();
-        delay.delay_ms(500);
+        for pattern in PATTERNS {
+            status::show(pattern);
+            delay.delay_ms(5000);
+        }
     }
 }
 
+#[interrupt]
+fn TIMER_IRQ_0() {
+    status::on_alarm();
+}
+
 // End of file
@@ -4,31 +4,22 @@
 use core::cell::RefCell;
 use cortex_m_rt::entry;
 use critical_section::Mutex;
+#[cfg(not(feature = "link-log"))]
 use defmt_rtt as _;
-use embedded_graphics::{draw_target::DrawTarget, pixelcolor::Rgb565, prelude::Size, prelude::*};
 use embedded_hal::{
     serial::Write,
     timer::{Cancel, CountDown},
 };
 use fugit::ExtU32;
-use fugit::RateExtU32;
-use panic_probe as _;
 
 use rp2040_hal as hal;
 
 use hal::{
     clocks::{init_clocks_and_plls, Clock},
-    gpio::{
-        bank0::{Gpio0, Gpio1},
-        dynpin::DynPin,
-        FunctionUart, Pin, Pins,
-    },
-    pac::UART0,
-    pac::{self, interrupt},
+    pac::interrupt,
+    pwm::Slices,
     rosc::RingOscillator,
-    sio::Sio,
     timer::Timer,
-    uart::{DataBits, Enabled, StopBits, UartConfig, UartPeripheral},
     watchdog::Watchdog,
 };
 
@@ -36,6 +27,17 @@ use chip8::fonts;
 use chip8::keypad::KeyPad;
 use chip8::Chip8;
 
+use udoo_core::led::{self, Color, Pattern};
+use udoo_core::rom::RomLoader;
+use udoo_key_bsp::{Board, LinkUart, XOSC_CRYSTAL_FREQ};
+use udoo_link::{Decoder, Message, MAX_FRAME};
+use udoo_rp2040::live::{self, Column, LinkDisplay, Row};
+use udoo_rp2040::{crash, status};
+
+#[link_section = ".boot2"]
+#[used]
+pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_GENERIC_03H;
+
 enum Rom<'a> {
     Bytes(&'a [u8]),
 }
@@ -47,51 +49,30 @@ impl<'a> Rom<'a> {
     }
 }
 
-/// Mock display because this example
-/// is more about using the bytes sent
-/// from the esp32
-struct Chip8MockDisplay;
-
-impl OriginDimensions for Chip8MockDisplay {
-    fn size(&self) -> Size {
-        Size::new(100, 100)
-    }
-}
-
-impl DrawTarget for Chip8MockDisplay {
-    type Error = core::convert::Infallible;
-    type Color = Rgb565;
-    fn draw_iter<I>(&mut self, _: I) -> Result<(), Self::Error>
-    where
-        I: IntoIterator<Item = Pixel<Self::Color>>,
-    {
-        Ok(())
-    }
-}
-
-type SerialTxPin = Pin<Gpio0, FunctionUart>;
-type SerialRxPin = Pin<Gpio1, FunctionUart>;
-type Uart = UartPeripheral<Enabled, UART0, (SerialTxPin, SerialRxPin)>;
-type GlobalSerial = Mutex<RefCell<Option<Uart>>>;
+type GlobalSerial = Mutex<RefCell<Option<LinkUart>>>;
 
 // Global serial connection to the esp32
 static ESP_SERIAL: GlobalSerial = Mutex::new(RefCell::new(None));
-static ROM_BUFFER: Mutex<RefCell<Option<[u8; 1024]>>> = Mutex::new(RefCell::new(None));
-static ROM_SIZE: Mutex<RefCell<Option<usize>>> = Mutex::new(RefCell::new(None));
-// Some(()) for new rom available, None for no new rom available
-static ROM_LOAD_STATE: Mutex<RefCell<Option<()>>> = Mutex::new(RefCell::new(None));
+// Frames received from the esp32
+static LINK_DECODER: Mutex<RefCell<Decoder>> = Mutex::new(RefCell::new(Decoder::new()));
+// Rom transfers from the esp32
+static ROM_LOADER: Mutex<RefCell<RomLoader>> = Mutex::new(RefCell::new(RomLoader::new()));
 
 #[entry]
 fn main() -> ! {
-    let mut pac = pac::Peripherals::take().unwrap();
-    let core = pac::CorePeripherals::take().unwrap();
+    let Board {
+        leds,
+        esp_link,
+        pins,
+        peripherals: mut pac,
+        core,
+        ..
+    } = Board::take().unwrap();
+    crash::report();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
-    let sio = Sio::new(pac.SIO);
 
-    // External high-speed crystal on the pico board is 12Mhz
-    let external_xtal_freq_hz = 12_000_000u32;
     let clocks = init_clocks_and_plls(
-        external_xtal_freq_hz,
+        XOSC_CRYSTAL_FREQ,
         pac.XOSC,
         pac.CLOCKS,
         pac.PLL_SYS,
@@ -102,47 +83,39 @@ fn main() -> ! {
     .ok()
     .unwrap();
 
-    let timer = Timer::new(pac.TIMER, &mut pac.RESETS);
+    let mut timer = Timer::new(pac.TIMER, &mut pac.RESETS);
     let mut countdown = timer.count_down();
 
-    let delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+    // Blinks until the first rom has arrived
+    let pwm_slices = Slices::new(pac.PWM, &mut pac.RESETS);
+    status::start(leds.into_pwm(pwm_slices.pwm4), timer.alarm_0().unwrap());
+    status::show(Pattern::Boot);
 
-    let pins = Pins::new(
-        pac.IO_BANK0,
-        pac.PADS_BANK0,
-        sio.gpio_bank0,
-        &mut pac.RESETS,
-    );
-
-    let uart_pins = (
-        pins.gpio0.into_mode::<FunctionUart>(),
-        pins.gpio1.into_mode::<FunctionUart>(),
-    );
+    let delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
 
-    let mut uart = UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS)
-        .enable(
-            UartConfig::new(9600.Hz(), DataBits::Eight, None, StopBits::One),
-            clocks.peripheral_clock.freq(),
-        )
+    let mut uart = esp_link
+        .into_uart(&mut pac.RESETS, clocks.peripheral_clock.freq())
         .unwrap();
 
     // Enable enterrupt on rx
     uart.enable_rx_interrupt();
 
-    let display = Chip8MockDisplay;
+    // The screen goes to a browser through the esp32
+    let display = LinkDisplay;
 
-    let keypad = KeyPad::<DynPin, DynPin>::new(
+    // Keys held in a browser are pressed along with the keys on the board
+    let keypad = KeyPad::<Row, Column>::new(
         [
-            pins.gpio19.into_push_pull_output().into(),
-            pins.gpio18.into_push_pull_output().into(),
-            pins.gpio17.into_push_pull_output().into(),
-            pins.gpio16.into_push_pull_output().into(),
+            Row::new(pins.gpio19.into_push_pull_output().into(), 0),
+            Row::new(pins.gpio18.into_push_pull_output().into(), 1),
+            Row::new(pins.gpio17.into_push_pull_output().into(), 2),
+            Row::new(pins.gpio16.into_push_pull_output().into(), 3),
         ],
         [
-            pins.gpio26.into_pull_up_input().into(),
-            pins.gpio22.into_pull_up_input().into(),
-            pins.gpio21.into_pull_up_input().into(),
-            pins.gpio20.into_pull_up_input().into(),
+            Column::new(pins.gpio26.into_pull_up_input().into(), 0),
+            Column::new(pins.gpio22.into_pull_up_input().into(), 1),
+            Column::new(pins.gpio21.into_pull_up_input().into(), 2),
+            Column::new(pins.gpio20.into_pull_up_input().into(), 3),
         ],
     );
 
@@ -155,9 +128,6 @@ fn main() -> ! {
 
     // Store items in global variables
     critical_section::with(|cs| {
-        ROM_BUFFER.borrow(cs).replace(Some([0; 1024]));
-        ROM_SIZE.borrow(cs).replace(None);
-        ROM_LOAD_STATE.borrow(cs).replace(None);
         ESP_SERIAL.borrow(cs).replace(Some(uart));
     });
 
@@ -166,49 +136,72 @@ fn main() -> ! {
         hal::pac::NVIC::unmask(hal::pac::Interrupt::UART0_IRQ);
     }
 
+    defmt::info!("Waiting for a rom");
     loop {
         chip8.tick();
         countdown.start(5_u32.millis());
         let _ = nb::block!(countdown.wait());
         countdown.cancel().unwrap();
         critical_section::with(|cs| {
-            let mut rom_load_state = ROM_LOAD_STATE.borrow_ref_mut(cs);
-            let mut rom_size = ROM_SIZE.borrow_ref_mut(cs);
-            let rom_size = rom_size.as_mut().unwrap();
-            if rom_load_state.is_some() {
-                let mut rom_buffer = ROM_BUFFER.borrow_ref_mut(cs);
-                let rom_buffer = rom_buffer.as_mut().unwrap();
-                chip8.load_program(&rom_buffer[0..*rom_size]);
-                *rom_load_state = None;
+            let mut rom_loader = ROM_LOADER.borrow_ref_mut(cs);
+            if let Some(rom) = rom_loader.take() {
+                defmt::info!("Loading a rom of {=usize} bytes", rom.len());
+                chip8.load_program(rom);
+                status::show(Pattern::Heartbeat);
+                // Far enough that a crash is not a boot loop
+                crash::healthy();
+            }
+        });
+        // The screen and the log share the link with the rom transfers
+        critical_section::with(|cs| {
+            if let Some(serial) = ESP_SERIAL.borrow_ref_mut(cs).as_mut() {
+                live::send(serial);
+                #[cfg(feature = "link-log")]
+                udoo_rp2040::link_log::send(serial);
             }
         });
     }
 }
 
+// Interrupt is triggered when bytes arrive from the esp32. Bytes
+// are collected into link frames, pings are answered straight away,
+// keys of the browser are kept and rom transfers are handed to the
+// rom loader
 #[interrupt]
 fn UART0_IRQ() {
     critical_section::with(|cs| {
-        let mut rom_buffer = ROM_BUFFER.borrow_ref_mut(cs);
-        let rom_buffer = rom_buffer.as_mut().unwrap();
-        let mut rom_size = ROM_SIZE.borrow_ref_mut(cs);
-        let rom_size = rom_size.as_mut().unwrap();
-        let mut rom_load_state = ROM_LOAD_STATE.borrow_ref_mut(cs);
-        let rom_load_state = rom_load_state.as_mut().unwrap();
+        let mut rom_loader = ROM_LOADER.borrow_ref_mut(cs);
+        let mut decoder = LINK_DECODER.borrow_ref_mut(cs);
         let mut esp_serial = ESP_SERIAL.borrow_ref_mut(cs);
         let esp_serial = esp_serial.as_mut().unwrap();
-        if esp_serial.uart_is_readable() {
-            let mut buff = [0_u8; 2];
-            if esp_serial.read_full_blocking(&mut buff).is_ok() {
-                let read = (buff[0] << 8 | buff[1]) as usize;
-                if read > 0 {
-                    *rom_size = read;
-                    _ = esp_serial.flush();
+        let mut byte = [0_u8; 1];
+        while let Ok(1) = esp_serial.read_raw(&mut byte) {
+            match decoder.push(byte[0]) {
+                Some(Ok(Message::Ping(seq))) => {
+                    let mut frame = [0_u8; MAX_FRAME];
+                    if let Ok(len) = Message::Pong(seq).encode(&mut frame) {
+                        esp_serial.write_full_blocking(&frame[..len]);
+                    }
                 }
-            }
-            if esp_serial.read_full_blocking(&mut rom_buffer[0..*rom_size]).is_ok() {
-                *rom_load_state = ();
-                _ = esp_serial.flush();
+                Some(Ok(Message::Keys(keys))) => live::set_keys(keys),
+                Some(Ok(message)) => {
+                    if let Message::RomBegin(_) = message {
+                        status::show(Pattern::Transfer);
+                    }
+                    // The esp32 can drive the green led
+                    if let Some((Color::Green, pattern)) = led::parse_message(&message) {
+                        status::show(pattern);
+                    }
+                    rom_loader.handle(&message);
+                }
+                Some(Err(e)) => defmt::warn!("Bad link frame: {}", defmt::Debug2Format(&e)),
+                None => {}
             }
         }
     });
 }
+
+#[interrupt]
+fn TIMER_IRQ_0() {
+    status::on_alarm();
+}
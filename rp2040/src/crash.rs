@@ -0,0 +1,63 @@
+//! Crash reports of the rp2040
+//!
+//! The panic handler keeps the message and the uptime in RAM that the
+//! startup code leaves alone, then resets the chip. [`report`] logs the
+//! crash with defmt on the next boot, with the `link-log` feature that log
+//! reaches the network through the esp32. Crashes in a row are counted
+//! until the program calls [`healthy`].
+
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+
+use cortex_m::peripheral::SCB;
+use rp2040_hal::pac;
+use udoo_core::crash::{CrashArea, CrashRecord};
+
+#[link_section = ".uninit.CRASH"]
+static mut CRASH: MaybeUninit<CrashArea> = MaybeUninit::uninit();
+
+fn with_area<R>(f: impl FnOnce(&mut CrashArea) -> R) -> R {
+    // Safety: every bit pattern is a valid `CrashArea` and it is only
+    // touched inside a critical section
+    critical_section::with(|_| f(unsafe { (*addr_of_mut!(CRASH)).assume_init_mut() }))
+}
+
+/// Log the crash of the last run, call it once at start up
+pub fn report() {
+    let boot = with_area(CrashArea::boot);
+    if let Some(crash) = boot.crash {
+        defmt::error!("The last run crashed: {}", defmt::Display2Format(&crash));
+    }
+    if boot.failures > 0 {
+        defmt::warn!("{=u32} crashes since the last healthy start", boot.failures);
+    }
+}
+
+/// The program works, the crash counter starts over
+pub fn healthy() {
+    with_area(CrashArea::healthy);
+}
+
+/// Milliseconds since the timer started counting
+fn uptime_ms() -> u64 {
+    // Safety: only reads the free running counter
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    loop {
+        let high = timer.timerawh.read().bits();
+        let low = timer.timerawl.read().bits();
+        // The low word did not wrap between the reads
+        if timer.timerawh.read().bits() == high {
+            return ((high as u64) << 32 | low as u64) / 1000;
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // The location of the panic is part of the message, there is no
+    // unwinder for a backtrace
+    let record = CrashRecord::new(uptime_ms(), 0, &[], format_args!("{info}"));
+    with_area(|area| area.record(record));
+    SCB::sys_reset();
+}
@@ -0,0 +1,10 @@
+//! Code shared between the rp2040 programs
+//!
+//! Everything that does not need the rp2040 itself is in `udoo-core`.
+#![no_std]
+
+pub mod crash;
+#[cfg(feature = "link-log")]
+pub mod link_log;
+pub mod live;
+pub mod status;
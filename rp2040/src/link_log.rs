@@ -0,0 +1,120 @@
+//! defmt log over the link to the esp32
+//!
+//! With the `link-log` feature this module is the global defmt logger
+//! instead of `defmt-rtt`, so the log can be read without a debug probe.
+//! Each log call is encoded into a frame that waits in a queue, the
+//! program hands the link uart to [`send`] now and then to pass the frames
+//! on in `Log` messages. The esp32 forwards them over the network.
+//!
+//! The link is slow, so a message carries no more than the transmit FIFO
+//! holds and is only written when the FIFO is empty. That way sending
+//! never waits inside the critical section and a `Log` message is never
+//! mixed up with a `Pong` written from the uart interrupt.
+
+use core::cell::RefCell;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use critical_section::{CriticalSection, Mutex, RestoreState};
+use udoo_core::log_queue::LogQueue;
+use udoo_key_bsp::LinkUart;
+use udoo_link::Message;
+
+/// Bytes of frames that wait for the link
+const QUEUE_SIZE: usize = 1024;
+/// Longest frame, longer ones are dropped
+const FRAME_SIZE: usize = 128;
+/// Log bytes in a message, the whole frame fits into the 32 byte
+/// transmit FIFO
+const CHUNK_SIZE: usize = 24;
+/// A `Log` message with [`CHUNK_SIZE`] bytes on the wire
+const MESSAGE_SIZE: usize = 32;
+
+static QUEUE: Mutex<RefCell<LogQueue<QUEUE_SIZE>>> = Mutex::new(RefCell::new(LogQueue::new()));
+
+/// The frame being encoded, only touched between `acquire` and `release`
+struct Frame {
+    encoder: defmt::Encoder,
+    bytes: [u8; FRAME_SIZE],
+    len: usize,
+    overflow: bool,
+}
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static mut RESTORE: RestoreState = RestoreState::invalid();
+static mut FRAME: Frame = Frame {
+    encoder: defmt::Encoder::new(),
+    bytes: [0; FRAME_SIZE],
+    len: 0,
+    overflow: false,
+};
+
+#[defmt::global_logger]
+struct LinkLogger;
+
+unsafe impl defmt::Logger for LinkLogger {
+    fn acquire() {
+        // Safety: released in `release`, which defmt always calls
+        let restore = unsafe { critical_section::acquire() };
+        if TAKEN.swap(true, Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        // Safety: the critical section is held, so nothing else touches
+        // the frame
+        unsafe {
+            RESTORE = restore;
+            let frame = &mut *addr_of_mut!(FRAME);
+            frame.len = 0;
+            frame.overflow = false;
+            frame.encoder.start_frame(write);
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        let frame = &mut *addr_of_mut!(FRAME);
+        frame.encoder.end_frame(write);
+        if !frame.overflow {
+            // The critical section of `acquire` is still held
+            let cs = CriticalSection::new();
+            QUEUE.borrow_ref_mut(cs).push(&frame.bytes[..frame.len]);
+        }
+        TAKEN.store(false, Ordering::Relaxed);
+        critical_section::release(RESTORE);
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        let frame = &mut *addr_of_mut!(FRAME);
+        frame.encoder.write(bytes, write);
+    }
+}
+
+/// Output of the encoder
+fn write(bytes: &[u8]) {
+    // Safety: only called by the encoder while the logger is acquired
+    let frame = unsafe { &mut *addr_of_mut!(FRAME) };
+    match frame.bytes.get_mut(frame.len..frame.len + bytes.len()) {
+        Some(space) => {
+            space.copy_from_slice(bytes);
+            frame.len += bytes.len();
+        }
+        None => frame.overflow = true,
+    }
+}
+
+/// Send the next piece of the log to the esp32 if the link is idle
+pub fn send(uart: &mut LinkUart) {
+    if uart.uart_is_busy() {
+        return;
+    }
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut message = [0; MESSAGE_SIZE];
+    let len = critical_section::with(|cs| QUEUE.borrow_ref_mut(cs).take(&mut chunk));
+    if len == 0 {
+        return;
+    }
+    if let Ok(len) = Message::Log(&chunk[..len]).encode(&mut message) {
+        uart.write_full_blocking(&message[..len]);
+    }
+}
@@ -0,0 +1,167 @@
+//! The chip8 screen and keypad in a browser
+//!
+//! [`LinkDisplay`] draws the chip8 screen into a [`Screen`], [`send`]
+//! passes the changed rows on to the esp32 in `Screen` messages. The
+//! esp32 sends the keys held in the browser in `Keys` messages, they are
+//! kept with [`set_keys`] and the keypad pins [`Row`] and [`Column`] hold
+//! them down on the keypad matrix next to the keys of the board.
+//!
+//! Like the log, a message fits into the 32 byte transmit FIFO and is only
+//! written when the FIFO is empty, so rows are sent while the link is idle.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+
+use critical_section::Mutex;
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use rp2040_hal::gpio::{self, dynpin::DynPin};
+use udoo_core::screen::{self, Screen};
+use udoo_key_bsp::LinkUart;
+use udoo_link::Message;
+
+/// Rows in a `Screen` message, the whole frame fits into the transmit
+/// FIFO
+const ROWS_PER_MESSAGE: usize = 3;
+/// A `Screen` message with [`ROWS_PER_MESSAGE`] rows on the wire
+const MESSAGE_SIZE: usize = 32;
+/// Calls of [`send`] between rows that are sent again unchanged, the
+/// link drops frames with a bad CRC and this brings lost rows back
+const REFRESH_PERIOD: u8 = 20;
+/// No row of the keypad is being scanned
+const NO_ROW: u8 = u8::MAX;
+/// Chip8 keys on the keypad matrix by row and column
+const LAYOUT: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xc],
+    [0x4, 0x5, 0x6, 0xd],
+    [0x7, 0x8, 0x9, 0xe],
+    [0xa, 0x0, 0xb, 0xf],
+];
+
+static SCREEN: Mutex<RefCell<Screen>> = Mutex::new(RefCell::new(Screen::new()));
+static PASSES: AtomicU8 = AtomicU8::new(0);
+/// Keys held in the browser, bit `n` for chip8 key `n`
+static KEYS: AtomicU16 = AtomicU16::new(0);
+/// The row of the keypad matrix that is driven low
+static SCANNED_ROW: AtomicU8 = AtomicU8::new(NO_ROW);
+
+/// Display of the chip8 that draws one pixel per chip8 pixel
+pub struct LinkDisplay;
+
+impl OriginDimensions for LinkDisplay {
+    fn size(&self) -> Size {
+        Size::new(screen::WIDTH as u32, screen::HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for LinkDisplay {
+    type Error = core::convert::Infallible;
+    type Color = Rgb565;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        critical_section::with(|cs| {
+            let mut screen = SCREEN.borrow_ref_mut(cs);
+            for Pixel(point, color) in pixels {
+                if let (Ok(x), Ok(y)) = (usize::try_from(point.x), usize::try_from(point.y)) {
+                    screen.set(x, y, color != Rgb565::BLACK);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Send the next changed rows to the esp32 if the link is idle
+pub fn send(uart: &mut LinkUart) {
+    if uart.uart_is_busy() {
+        return;
+    }
+    let mut message = [0; MESSAGE_SIZE];
+    let len = critical_section::with(|cs| {
+        let mut screen = SCREEN.borrow_ref_mut(cs);
+        let passes = PASSES.load(Ordering::Relaxed) + 1;
+        if passes == REFRESH_PERIOD {
+            screen.refresh(ROWS_PER_MESSAGE);
+            PASSES.store(0, Ordering::Relaxed);
+        } else {
+            PASSES.store(passes, Ordering::Relaxed);
+        }
+        let (row, pixels) = screen.take_update(ROWS_PER_MESSAGE)?;
+        Message::Screen { row, pixels }.encode(&mut message).ok()
+    });
+    if let Some(len) = len {
+        uart.write_full_blocking(&message[..len]);
+    }
+}
+
+/// Keep the keys of a `Keys` message
+pub fn set_keys(keys: u16) {
+    KEYS.store(keys, Ordering::Relaxed);
+}
+
+/// A row of the keypad matrix, driven low while it is scanned
+pub struct Row {
+    pin: DynPin,
+    index: u8,
+}
+
+impl Row {
+    pub fn new(pin: DynPin, index: u8) -> Self {
+        Self { pin, index }
+    }
+}
+
+impl OutputPin for Row {
+    type Error = gpio::Error;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        SCANNED_ROW.store(self.index, Ordering::Relaxed);
+        self.pin.set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        if SCANNED_ROW.load(Ordering::Relaxed) == self.index {
+            SCANNED_ROW.store(NO_ROW, Ordering::Relaxed);
+        }
+        self.pin.set_high()
+    }
+}
+
+/// A column of the keypad matrix, low while the key in the scanned row
+/// is held on the board or in the browser
+pub struct Column {
+    pin: DynPin,
+    index: u8,
+}
+
+impl Column {
+    pub fn new(pin: DynPin, index: u8) -> Self {
+        Self { pin, index }
+    }
+
+    fn held_in_browser(&self) -> bool {
+        let row = SCANNED_ROW.load(Ordering::Relaxed) as usize;
+        match LAYOUT
+            .get(row)
+            .and_then(|keys| keys.get(self.index as usize))
+        {
+            Some(&key) => KEYS.load(Ordering::Relaxed) & 1 << key != 0,
+            None => false,
+        }
+    }
+}
+
+impl InputPin for Column {
+    type Error = gpio::Error;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        self.is_low().map(|low| !low)
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.pin.is_low()? || self.held_in_browser())
+    }
+}
@@ -0,0 +1,77 @@
+//! Status patterns on the green led
+//!
+//! [`start`] hands the led, on PWM slice 4 so it can be dimmed, and
+//! alarm 0 of the timer to an interrupt that plays the patterns, after
+//! which any part of the program can change them with [`show`]. The
+//! program has to forward the alarm interrupt:
+//!
+//! ```ignore
+//! #[interrupt]
+//! fn TIMER_IRQ_0() {
+//!     status::on_alarm();
+//! }
+//! ```
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use fugit::ExtU32;
+use rp2040_hal::{
+    pac,
+    timer::{Alarm, Alarm0},
+};
+use udoo_core::led::{Dimmed, Pattern, StatusLed, TICK_MS};
+use udoo_key_bsp::GreenPwm;
+
+struct Status {
+    green: StatusLed<Dimmed<GreenPwm>>,
+    alarm: Alarm0,
+}
+
+static STATUS: Mutex<RefCell<Option<Status>>> = Mutex::new(RefCell::new(None));
+
+/// Start playing patterns, the led starts off
+pub fn start(green: GreenPwm, mut alarm: Alarm0) {
+    alarm.schedule((TICK_MS * 1000).micros()).unwrap();
+    alarm.enable_interrupt();
+    critical_section::with(|cs| {
+        STATUS.borrow_ref_mut(cs).replace(Status {
+            green: StatusLed::new(Dimmed(green)),
+            alarm,
+        });
+    });
+    // unmask interrupt
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0);
+    }
+}
+
+/// Play `pattern` on the green led, does nothing before [`start`]
+pub fn show(pattern: Pattern) {
+    critical_section::with(|cs| {
+        if let Some(status) = STATUS.borrow_ref_mut(cs).as_mut() {
+            status.green.show(pattern);
+        }
+    });
+}
+
+/// The pattern the green led is playing
+pub fn pattern() -> Option<Pattern> {
+    critical_section::with(|cs| {
+        STATUS
+            .borrow_ref(cs)
+            .as_ref()
+            .map(|status| status.green.pattern())
+    })
+}
+
+/// Advance the pattern, call from the `TIMER_IRQ_0` interrupt
+pub fn on_alarm() {
+    critical_section::with(|cs| {
+        if let Some(status) = STATUS.borrow_ref_mut(cs).as_mut() {
+            status.green.tick(TICK_MS);
+            status.alarm.clear_interrupt();
+            _ = status.alarm.schedule((TICK_MS * 1000).micros());
+        }
+    });
+}